@@ -0,0 +1,19 @@
+/* Flash layout shared with `../memory-ota.x` and `../src/ota.rs`, the LED store takes the last sector */
+MEMORY
+{
+    FLASH            : ORIGIN = 0x08000000, LENGTH = 128K
+    BOOTLOADER_STATE : ORIGIN = 0x08020000, LENGTH = 128K
+    ACTIVE           : ORIGIN = 0x08040000, LENGTH = 768K
+    DFU              : ORIGIN = 0x08100000, LENGTH = 896K /* One sector larger than ACTIVE, for the swap */
+    RAM              : ORIGIN = 0x24000000, LENGTH = 512K
+}
+
+/* Offsets from the start of the flash */
+__bootloader_state_start = ORIGIN(BOOTLOADER_STATE) - ORIGIN(FLASH);
+__bootloader_state_end = ORIGIN(BOOTLOADER_STATE) + LENGTH(BOOTLOADER_STATE) - ORIGIN(FLASH);
+
+__bootloader_active_start = ORIGIN(ACTIVE) - ORIGIN(FLASH);
+__bootloader_active_end = ORIGIN(ACTIVE) + LENGTH(ACTIVE) - ORIGIN(FLASH);
+
+__bootloader_dfu_start = ORIGIN(DFU) - ORIGIN(FLASH);
+__bootloader_dfu_end = ORIGIN(DFU) + LENGTH(DFU) - ORIGIN(FLASH);
@@ -0,0 +1,41 @@
+//! Bootloader for the `ota` build of the demo.
+//!
+//! Swaps in an image written to the DFU partition by `POST /ota`, or reverts it if it was never marked as booted,
+//! then starts the application in the active partition.
+
+#![no_std]
+#![no_main]
+
+use core::cell::RefCell;
+
+use cortex_m_rt::{entry, exception};
+use embassy_boot_stm32::{BootLoader, BootLoaderConfig};
+use embassy_stm32::flash::Flash;
+use embassy_sync::blocking_mutex::{raw::NoopRawMutex, Mutex};
+
+/// Start of the internal flash, which the partition offsets are relative to.
+const FLASH_BASE: u32 = 0x0800_0000;
+
+#[entry]
+fn main() -> ! {
+    let p = embassy_stm32::init(Default::default());
+
+    let flash = Mutex::<NoopRawMutex, _>::new(RefCell::new(Flash::new_blocking(p.FLASH)));
+    let config = BootLoaderConfig::from_linkerfile_blocking(&flash);
+    let active_offset = config.active.offset();
+
+    // The buffer must divide the 128 KiB sectors and be a multiple of the 32 byte flash word
+    let bootloader = BootLoader::prepare::<_, _, _, 2048>(config);
+
+    unsafe { bootloader.load(FLASH_BASE + active_offset) }
+}
+
+#[exception]
+unsafe fn HardFault(_frame: &cortex_m_rt::ExceptionFrame) -> ! {
+    cortex_m::peripheral::SCB::sys_reset();
+}
+
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    cortex_m::asm::udf();
+}
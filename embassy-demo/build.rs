@@ -1,4 +1,23 @@
+use std::path::PathBuf;
+
 fn main() {
+    // The `ota` build is linked in the active partition of the bootloader instead of at the start of the flash,
+    // and the `dual-bank` build within the first bank
+    let memory_x = if std::env::var_os("CARGO_FEATURE_OTA").is_some() {
+        "memory-ota.x"
+    } else if std::env::var_os("CARGO_FEATURE_DUAL_BANK").is_some() {
+        "memory-dual-bank.x"
+    } else {
+        "memory-standalone.x"
+    };
+
+    let out_dir = PathBuf::from(std::env::var("OUT_DIR").unwrap());
+    std::fs::copy(memory_x, out_dir.join("memory.x")).unwrap();
+    println!("cargo:rustc-link-search={}", out_dir.display());
+    println!("cargo:rerun-if-changed=memory-standalone.x");
+    println!("cargo:rerun-if-changed=memory-ota.x");
+    println!("cargo:rerun-if-changed=memory-dual-bank.x");
+
     println!("cargo:rustc-link-arg-bins=--nmagic");
     println!("cargo:rustc-link-arg-bins=-Tlink.x");
     println!("cargo:rustc-link-arg-bins=-Tdefmt.x");
@@ -0,0 +1,6 @@
+/* Application of the `dual-bank` build, in the bank mapped first. The last sector holds the LED store */
+MEMORY
+{
+    FLASH : ORIGIN = 0x08000000, LENGTH = 896K
+    RAM   : ORIGIN = 0x24000000, LENGTH = 512K
+}
@@ -0,0 +1,6 @@
+/* Application started by `bootloader/`, see `bootloader/memory.x` for the whole layout */
+MEMORY
+{
+    FLASH : ORIGIN = 0x08040000, LENGTH = 768K /* ACTIVE partition */
+    RAM   : ORIGIN = 0x24000000, LENGTH = 512K
+}
@@ -0,0 +1,6 @@
+/* Application flashed on its own, without `bootloader/` */
+MEMORY
+{
+    FLASH : ORIGIN = 0x08000000, LENGTH = 2048K /* BANK_1 + BANK_2 */
+    RAM   : ORIGIN = 0x24000000, LENGTH =  512K
+}
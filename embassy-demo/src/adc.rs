@@ -0,0 +1,126 @@
+//! Internal temperature sensor and VREFINT of the STM32H743, sampled through ADC3 and served by `GET /api/adc`.
+//!
+//! [adc_task] samples both channels every [SAMPLE_INTERVAL] and converts them with the factory calibration, keeping
+//! the last [WINDOW] values of each for their [Aggregate](smolweb_core::analog::Aggregate).
+
+use core::cell::RefCell;
+
+use defmt::*;
+use embassy_stm32::adc::{Adc, SampleTime};
+use embassy_stm32::peripherals::ADC3;
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Timer};
+use picoserve::response::{Json, StatusCode};
+use smolweb_core::{
+    analog::{AdcReadout, RollingWindow},
+    auth::RequireAuth,
+    error::ApiError,
+};
+
+use crate::DIAGNOSTICS;
+
+/// How often both channels are sampled.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Samples aggregated per channel, a minute at [SAMPLE_INTERVAL].
+const WINDOW: usize = 60;
+
+/// Factory calibration of the temperature sensor at 30 °C and 110 °C, and of VREFINT, all measured with a VDDA of
+/// 3.3 V and a 16-bit resolution. From the "Temperature sensor" and "VREFINT" sections of the STM32H743 datasheet.
+const TS_CAL1: *const u16 = 0x1FF1_E820 as *const u16;
+const TS_CAL2: *const u16 = 0x1FF1_E840 as *const u16;
+const VREFINT_CAL: *const u16 = 0x1FF1_E860 as *const u16;
+const CALIBRATION_VDDA: f32 = 3.3;
+
+struct Channels {
+    temperature: RollingWindow<WINDOW>,
+    vdda: RollingWindow<WINDOW>,
+    samples: u32,
+}
+
+static CHANNELS: Mutex<CriticalSectionRawMutex, RefCell<Channels>> =
+    Mutex::new(RefCell::new(Channels {
+        temperature: RollingWindow::new(),
+        vdda: RollingWindow::new(),
+        samples: 0,
+    }));
+
+/// The calibration values, read once.
+struct Calibration {
+    ts_cal1: f32,
+    ts_cal2: f32,
+    vrefint_cal: f32,
+}
+
+impl Calibration {
+    fn read() -> Self {
+        // Addresses of the system memory, always readable
+        let read = |address: *const u16| f32::from(unsafe { address.read_volatile() });
+
+        Self {
+            ts_cal1: read(TS_CAL1),
+            ts_cal2: read(TS_CAL2),
+            vrefint_cal: read(VREFINT_CAL),
+        }
+    }
+
+    /// VDDA in volts, from a raw VREFINT sample.
+    fn vdda(&self, vrefint: u16) -> f32 {
+        CALIBRATION_VDDA * self.vrefint_cal / f32::from(vrefint.max(1))
+    }
+
+    /// Temperature in °C, from a raw sample of the sensor taken with `vdda`.
+    fn temperature(&self, sample: u16, vdda: f32) -> f32 {
+        // The calibration was measured at 3.3 V, so the sample is scaled to what it would have been then
+        let sample = f32::from(sample) * vdda / CALIBRATION_VDDA;
+        30.0 + (110.0 - 30.0) * (sample - self.ts_cal1) / (self.ts_cal2 - self.ts_cal1)
+    }
+}
+
+/// Samples the temperature sensor and VREFINT every [SAMPLE_INTERVAL].
+#[embassy_executor::task]
+pub async fn adc_task(mut adc: Adc<'static, ADC3>) -> ! {
+    let calibration = Calibration::read();
+
+    // The longest sample time, as both internal channels need at least 9 µs
+    adc.set_sample_time(SampleTime::Cycles810_5);
+    let mut temperature = adc.enable_temperature();
+    let mut vrefint = adc.enable_vrefint();
+
+    loop {
+        DIAGNOSTICS.heartbeat("adc");
+        let vdda = calibration.vdda(adc.read_internal(&mut vrefint));
+        let celsius = calibration.temperature(adc.read_internal(&mut temperature), vdda);
+
+        CHANNELS.lock(|channels| {
+            let mut channels = channels.borrow_mut();
+            channels.temperature.push(celsius);
+            channels.vdda.push(vdda);
+            channels.samples = channels.samples.wrapping_add(1);
+        });
+        trace!("ADC: {} °C, VDDA {} V", celsius, vdda);
+
+        DIAGNOSTICS.report_stack("adc");
+        Timer::after(SAMPLE_INTERVAL).await;
+    }
+}
+
+/// `GET /api/adc`: the aggregated channels, or 503 until the first sample.
+pub async fn get_adc(_: RequireAuth) -> Result<Json<AdcReadout>, ApiError> {
+    CHANNELS
+        .lock(|channels| {
+            let channels = channels.borrow();
+
+            Some(AdcReadout {
+                temperature_c: channels.temperature.aggregate()?,
+                vdda_v: channels.vdda.aggregate()?,
+                window_seconds: (WINDOW as u64 * SAMPLE_INTERVAL.as_secs()) as u32,
+                samples: channels.samples,
+            })
+        })
+        .map(Json)
+        .ok_or(ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "No ADC sample yet",
+        ))
+}
@@ -0,0 +1,65 @@
+//! A piezo buzzer on PA4, on the morpho header, driven by channel 1 of DAC1 and enabled by the `buzzer` feature.
+//!
+//! [buzzer_task] plays the [Tone]s of `POST /api/tone` as a square wave, switching the output of the DAC between 0 and
+//! [LEVEL] every half period. The timer ticks at 32.768 kHz, so high tones are played a little off pitch.
+
+use defmt::*;
+use embassy_futures::select::{select, Either};
+use embassy_stm32::dac::{DacCh1, Value};
+use embassy_stm32::dma::NoDma;
+use embassy_stm32::peripherals::DAC1;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Timer};
+use smolweb_core::tone::Tone;
+
+use crate::DIAGNOSTICS;
+
+type Dac = DacCh1<'static, DAC1, NoDma>;
+
+/// Output of the high half of the wave, the whole 3.3 V of the DAC.
+const LEVEL: u8 = u8::MAX;
+
+/// The next tone to play, set by `SharedControl::play_tone`, which cuts short the one playing.
+pub static TONES: Signal<CriticalSectionRawMutex, Tone> = Signal::new();
+
+/// Play `tone` until its duration is over.
+async fn play(dac: &mut Dac, tone: Tone) {
+    let half_period = Duration::from_micros(tone.period().as_micros() as u64 / 2);
+    let start = Instant::now();
+    let end = start + Duration::from_millis(tone.duration_ms.into());
+
+    // Each half is timed from the start of the tone, so that the time taken to switch doesn't lower the pitch
+    let mut next = start;
+    let mut high = true;
+    while next < end {
+        dac.set(Value::Bit8(if high { LEVEL } else { 0 }));
+        high = !high;
+        next += half_period;
+        Timer::at(next).await;
+    }
+}
+
+/// Plays the [TONES] on the buzzer, silent in between.
+#[embassy_executor::task]
+pub async fn buzzer_task(mut dac: Dac) -> ! {
+    dac.set(Value::Bit8(0));
+
+    loop {
+        let mut tone = TONES.wait().await;
+
+        loop {
+            info!(
+                "Playing {} Hz for {} ms",
+                tone.frequency_hz, tone.duration_ms
+            );
+            match select(play(&mut dac, tone), TONES.wait()).await {
+                Either::First(()) => break,
+                Either::Second(next) => tone = next,
+            }
+        }
+
+        dac.set(Value::Bit8(0));
+        DIAGNOSTICS.report_stack("buzzer");
+    }
+}
@@ -0,0 +1,177 @@
+//! `defmt` logs over TCP instead of RTT, enabled by the `defmt-tcp` feature, for boards without a probe attached.
+//!
+//! [TcpLogger] replaces `defmt-rtt` as the global logger and queues the encoded frames in [FRAMES], which
+//! [defmt_tcp_task] sends to the client connected to [DEFMT_PORT], one at a time, e.g.
+//! `defmt-print -e target/thumbv7em-none-eabihf/release/embassy-demo tcp --host <ip>`.
+//!
+//! Logging never waits for the network: while no client is connected, or while it is too slow, frames which don't fit
+//! in [FRAMES] are dropped, and the queue starts over with each connection so that the client only reads whole
+//! frames. A frame cut short is still closed by the delimiter of the encoding, so `defmt-print` skips it and decodes
+//! the next one.
+
+use core::{
+    ptr::addr_of_mut,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use cortex_m::register::primask;
+use defmt::*;
+use embassy_futures::select::{select, Either};
+use embassy_net::{tcp::TcpSocket, Stack};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, pipe::Pipe};
+
+use crate::EthDevice;
+
+/// Port `defmt-print` connects to, its default one.
+pub const DEFMT_PORT: u16 = 19021;
+
+/// Bytes of frames queued for the client.
+const QUEUE_SIZE: usize = 4096;
+
+/// Frames encoded by [TcpLogger], waiting for [defmt_tcp_task].
+static FRAMES: Pipe<CriticalSectionRawMutex, QUEUE_SIZE> = Pipe::new();
+
+/// Set while a frame is being written, to catch a panic in the logger logging again.
+static TAKEN: AtomicBool = AtomicBool::new(false);
+
+/// State of the frame being written, only touched with interrupts disabled.
+struct LoggerState {
+    encoder: defmt::Encoder,
+    /// Whether interrupts were enabled before [TcpLogger::acquire] disabled them.
+    interrupts_were_enabled: bool,
+    /// Set once a byte of the frame didn't fit, so that the rest of it is dropped too.
+    dropping: bool,
+    /// Set when the delimiter closing a frame cut short didn't fit either, so that the next frame writes it first.
+    needs_delimiter: bool,
+}
+
+static mut STATE: LoggerState = LoggerState {
+    encoder: defmt::Encoder::new(),
+    interrupts_were_enabled: false,
+    dropping: false,
+    needs_delimiter: false,
+};
+
+/// The logger state, which must only be used with interrupts disabled.
+fn state() -> &'static mut LoggerState {
+    // Single core, and every caller has interrupts disabled
+    unsafe { &mut *addr_of_mut!(STATE) }
+}
+
+/// Queue `bytes` of the current frame, or drop them and the rest of the frame if the queue is full.
+fn queue(bytes: &[u8]) {
+    let state = state();
+    if !state.dropping && !matches!(FRAMES.try_write(bytes), Ok(written) if written == bytes.len())
+    {
+        state.dropping = true;
+    }
+}
+
+/// Queue the delimiter ending a frame cut short, so that the decoder skips it instead of merging it with the next one.
+fn delimit() {
+    let state = state();
+    state.needs_delimiter = FRAMES.try_write(&[0]).is_err();
+}
+
+#[defmt::global_logger]
+struct TcpLogger;
+
+unsafe impl defmt::Logger for TcpLogger {
+    fn acquire() {
+        let interrupts_were_enabled = primask::read().is_active();
+        cortex_m::interrupt::disable();
+
+        if TAKEN.swap(true, Ordering::Relaxed) {
+            panic!("defmt logger taken reentrantly");
+        }
+
+        let state = state();
+        state.interrupts_were_enabled = interrupts_were_enabled;
+        if state.needs_delimiter {
+            delimit();
+        }
+        state.dropping = state.needs_delimiter;
+        state.encoder.start_frame(queue);
+    }
+
+    unsafe fn flush() {
+        // Frames are sent by `defmt_tcp_task`, which can't run while interrupts are disabled
+    }
+
+    unsafe fn release() {
+        let state = state();
+        state.encoder.end_frame(queue);
+        if state.dropping && !state.needs_delimiter {
+            delimit();
+        }
+
+        TAKEN.store(false, Ordering::Relaxed);
+        if state.interrupts_were_enabled {
+            cortex_m::interrupt::enable();
+        }
+    }
+
+    unsafe fn write(bytes: &[u8]) {
+        state().encoder.write(bytes, queue);
+    }
+}
+
+/// Drop the queued frames, which a new client would read from the middle of one, so that it starts with the next
+/// frame logged.
+fn start_stream() {
+    cortex_m::interrupt::free(|_| {
+        FRAMES.clear();
+        state().needs_delimiter = false;
+    });
+}
+
+/// Write all of `bytes` to `socket`.
+async fn send(socket: &mut TcpSocket<'_>, mut bytes: &[u8]) -> Result<(), embassy_net::tcp::Error> {
+    while !bytes.is_empty() {
+        let written = socket.write(bytes).await?;
+        if written == 0 {
+            return Err(embassy_net::tcp::Error::ConnectionReset);
+        }
+        bytes = &bytes[written..];
+    }
+    Ok(())
+}
+
+/// Sends the logs to the client connected to [DEFMT_PORT], until it disconnects and the next one connects.
+#[embassy_executor::task]
+pub async fn defmt_tcp_task(stack: &'static Stack<EthDevice>) -> ! {
+    let mut rx_buffer = [0; 64];
+    let mut tx_buffer = [0; 1024];
+    let mut frames = [0; 256];
+    let mut ignored = [0; 64];
+
+    loop {
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+
+        if let Err(err) = socket.accept(DEFMT_PORT).await {
+            warn!("defmt-tcp: Accept error: {}", err);
+            continue;
+        }
+
+        start_stream();
+        info!("defmt-tcp: Streaming logs to {}", socket.remote_endpoint());
+
+        loop {
+            // Reading tells when the client disconnects, even if nothing is logged meanwhile
+            let event = select(FRAMES.read(&mut frames), socket.read(&mut ignored)).await;
+            match event {
+                Either::First(length) => {
+                    if send(&mut socket, &frames[..length]).await.is_err() {
+                        break;
+                    }
+                }
+                Either::Second(Ok(0) | Err(_)) => break,
+                Either::Second(Ok(_)) => {}
+            }
+        }
+
+        socket.abort();
+        let _ = socket.flush().await;
+        info!("defmt-tcp: Client disconnected");
+    }
+}
@@ -0,0 +1,273 @@
+//! Firmware updates swapping the two flash banks of the STM32H743, enabled by the `dual-bank` feature.
+//!
+//! `POST /firmware` streams the image into the bank mapped at `0x0810_0000`, which is never the running one,
+//! checks it against its trailer, then toggles the `SWAP_BANK` option bit and resets.
+//! After the reset the new image is mapped at `0x0800_0000`, where `memory-dual-bank.x` links the application,
+//! and the previous one becomes the inactive bank for the next update.
+//!
+//! The last sector of each bank is left to [Store](crate::persist::Store), so the saved LED state and settings are
+//! the ones from before the previous update until they are saved again.
+
+use defmt::*;
+use embassy_stm32::flash::{MAX_ERASE_SIZE, WRITE_SIZE};
+use embassy_stm32::pac;
+use picoserve::{
+    extract::FromRequestParts,
+    io::Read,
+    request::Request,
+    response::{IntoResponse, ResponseWriter, StatusCode},
+    routing::RequestHandlerService,
+    ResponseSent,
+};
+use smolweb_core::{auth::RequireAuth, upload::UploadLock};
+
+use crate::{AppState, SharedFlash, RESET_REQUESTED};
+
+/// Offset from the start of the flash of the inactive bank.
+const INACTIVE_OFFSET: u32 = 0x10_0000;
+
+/// Size of an image, all of a bank but its last sector.
+const IMAGE_SIZE: u32 = 0x10_0000 - MAX_ERASE_SIZE as u32;
+
+/// Bytes after the image: its length then its CRC-32, both little endian.
+const TRAILER_SIZE: usize = 8;
+
+/// Bytes of the image written to flash at once, a multiple of [WRITE_SIZE].
+const CHUNK_SIZE: usize = 1024;
+
+/// The CRC of the trailer, as computed by `crc32` or Python's `zlib.crc32`.
+const CRC: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
+
+/// Keys unlocking the option bytes, from the reference manual.
+const OPTION_KEYS: [u32; 2] = [0x0819_2A3B, 0x4C5D_6E7F];
+
+/// Held while an image is being received, so that two uploads don't write the inactive bank at the same time.
+static FIRMWARE_UPLOAD: UploadLock = UploadLock::new();
+
+/// Response to a refused or failed upload.
+type UploadError = (StatusCode, &'static str);
+
+const FLASH_ERROR: UploadError = (
+    StatusCode::INTERNAL_SERVER_ERROR,
+    "Failed to write the image\n",
+);
+
+/// Toggle which bank is mapped at `0x0800_0000`, taking effect at the next reset.
+fn swap_banks() {
+    let flash = pac::FLASH;
+
+    if flash.optcr().read().optlock() {
+        for key in OPTION_KEYS {
+            flash.optkeyr().write_value(key);
+        }
+    }
+
+    let swapped = flash.optsr_cur().read().swap_bank_opt();
+    flash.optsr_prg().modify(|w| w.set_swap_bank_opt(!swapped));
+    flash.optcr().modify(|w| w.set_optstart(true));
+    while flash.optsr_cur().read().opt_busy() {}
+    flash.optcr().modify(|w| w.set_optlock(true));
+
+    info!(
+        "Bank {} will be mapped first after the reset",
+        if swapped { 1 } else { 2 }
+    );
+}
+
+/// CRC of the first `length` bytes of the inactive bank, read back from the flash rather than from the request.
+fn inactive_crc(
+    flash: &'static SharedFlash,
+    length: u32,
+) -> Result<u32, embassy_stm32::flash::Error> {
+    let mut digest = CRC.digest();
+    let mut chunk = [0; CHUNK_SIZE];
+    let mut offset = 0;
+
+    while offset < length {
+        let read_len = (length - offset).min(CHUNK_SIZE as u32) as usize;
+        flash.lock(|flash| {
+            flash
+                .borrow_mut()
+                .blocking_read(INACTIVE_OFFSET + offset, &mut chunk[..read_len])
+        })?;
+        digest.update(&chunk[..read_len]);
+        offset += read_len as u32;
+    }
+
+    Ok(digest.finalize())
+}
+
+/// Read exactly `buffer.len()` bytes of the body, or return `false` if it ends first.
+async fn read_exact<R: Read>(reader: &mut R, buffer: &mut [u8]) -> Result<bool, R::Error> {
+    let mut filled = 0;
+
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]).await? {
+            0 => return Ok(false),
+            read => filled += read,
+        }
+    }
+
+    Ok(true)
+}
+
+/// Stream the image of the request body into the inactive bank and check it against the trailer.
+///
+/// Each 128 KiB sector is erased when the image reaches it, which stalls the other tasks for a second or two, while
+/// interrupts keep being served.
+async fn receive<R: Read>(
+    flash: &'static SharedFlash,
+    request: &mut Request<'_, R>,
+) -> Result<Result<(), UploadError>, R::Error> {
+    let content_length = request.body_connection.content_length();
+
+    let Some(length) = content_length
+        .checked_sub(TRAILER_SIZE)
+        .filter(|&length| length > 0)
+    else {
+        return Ok(Err((
+            StatusCode::LENGTH_REQUIRED,
+            "The image and its trailer must be sent with a Content-Length\n",
+        )));
+    };
+
+    if length > IMAGE_SIZE as usize {
+        return Ok(Err((
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "The image is larger than a flash bank\n",
+        )));
+    }
+
+    info!("Receiving a {} byte firmware image", length);
+
+    let short_body = (
+        StatusCode::BAD_REQUEST,
+        "The body is shorter than its Content-Length\n",
+    );
+
+    let mut reader = request.body_connection.body().reader();
+    let mut chunk = [0; CHUNK_SIZE];
+    let mut received = 0;
+    let mut erased = 0;
+
+    while received < length {
+        let filled = CHUNK_SIZE.min(length - received);
+
+        if !read_exact(&mut reader, &mut chunk[..filled]).await? {
+            warn!(
+                "Firmware image ended after at most {} bytes",
+                received + filled
+            );
+            return Ok(Err(short_body));
+        }
+
+        // Pad the end of the image to the flash write size, erased flash reads as 0xFF
+        let write_len = filled.next_multiple_of(WRITE_SIZE);
+        chunk[filled..write_len].fill(0xFF);
+
+        let offset = received as u32;
+        let end = offset + write_len as u32;
+
+        let written = flash.lock(|flash| {
+            let mut flash = flash.borrow_mut();
+
+            while erased < end {
+                flash.blocking_erase(
+                    INACTIVE_OFFSET + erased,
+                    INACTIVE_OFFSET + erased + MAX_ERASE_SIZE as u32,
+                )?;
+                erased += MAX_ERASE_SIZE as u32;
+            }
+
+            flash.blocking_write(INACTIVE_OFFSET + offset, &chunk[..write_len])
+        });
+
+        if let Err(err) = written {
+            warn!("Failed to write the inactive bank: {}", err);
+            return Ok(Err(FLASH_ERROR));
+        }
+
+        received += filled;
+    }
+
+    let mut trailer = [0; TRAILER_SIZE];
+    if !read_exact(&mut reader, &mut trailer).await? {
+        return Ok(Err(short_body));
+    }
+
+    let [l0, l1, l2, l3, c0, c1, c2, c3] = trailer;
+    let expected_length = u32::from_le_bytes([l0, l1, l2, l3]);
+    let expected_crc = u32::from_le_bytes([c0, c1, c2, c3]);
+
+    if expected_length as usize != length {
+        warn!(
+            "Trailer gives {} bytes, the image has {}",
+            expected_length, length
+        );
+        return Ok(Err((
+            StatusCode::BAD_REQUEST,
+            "The length in the trailer doesn't match the image\n",
+        )));
+    }
+
+    match inactive_crc(flash, length as u32) {
+        Ok(crc) if crc == expected_crc => {}
+        Ok(crc) => {
+            warn!(
+                "Image CRC is {:08x}, the trailer expects {:08x}",
+                crc, expected_crc
+            );
+            return Ok(Err((
+                StatusCode::BAD_REQUEST,
+                "The CRC in the trailer doesn't match the image\n",
+            )));
+        }
+        Err(err) => {
+            warn!("Failed to read back the inactive bank: {}", err);
+            return Ok(Err(FLASH_ERROR));
+        }
+    }
+
+    info!("Firmware image written and checked");
+
+    Ok(Ok(()))
+}
+
+/// Handler of `POST /firmware`, which takes the raw image followed by its trailer and resets once it is written.
+pub struct FirmwareUpload;
+
+impl RequestHandlerService<AppState, ()> for FirmwareUpload {
+    async fn call_request_handler_service<R: Read, W: ResponseWriter<Error = R::Error>>(
+        &self,
+        state: &AppState,
+        _path_parameters: (),
+        mut request: Request<'_, R>,
+        response_writer: W,
+    ) -> Result<ResponseSent, W::Error> {
+        let result = match RequireAuth::from_request_parts(state, &request.parts).await {
+            Err(rejection) => {
+                return rejection
+                    .write_to(request.body_connection.finalize().await?, response_writer)
+                    .await;
+            }
+            Ok(RequireAuth) => match FIRMWARE_UPLOAD.acquire() {
+                Some(_guard) => receive(state.flash, &mut request).await?,
+                None => Err((StatusCode::CONFLICT, "Another update is in progress\n")),
+            },
+        };
+
+        let connection = request.body_connection.finalize().await?;
+
+        match result {
+            Ok(()) => {
+                swap_banks();
+                // Leaves the reset to `reset_task`, so that the response is written first
+                RESET_REQUESTED.signal(());
+                "Update received, rebooting into the other bank\n"
+                    .write_to(connection, response_writer)
+                    .await
+            }
+            Err(err) => err.write_to(connection, response_writer).await,
+        }
+    }
+}
@@ -2,45 +2,410 @@
 #![no_main]
 #![feature(type_alias_impl_trait)]
 
+use core::cell::RefCell;
 use defmt::*;
+#[cfg(not(feature = "defmt-tcp"))]
+use defmt_rtt as _;
 use embassy_executor::Spawner;
-use embassy_net::{Stack, StackResources};
+use embassy_futures::select::{select, Either};
+use embassy_net::{tcp::TcpSocket, Stack, StackResources};
 use embassy_stm32::eth::generic_smi::GenericSMI;
 use embassy_stm32::eth::{Ethernet, PacketQueue};
-use embassy_stm32::gpio::{Level, Output, Speed};
+use embassy_stm32::exti::ExtiInput;
+use embassy_stm32::flash::{Blocking, Flash};
+use embassy_stm32::gpio::{AnyPin, Input, Level, Output, OutputType, Pull, Speed};
+use embassy_stm32::i2c;
 use embassy_stm32::peripherals::ETH;
 use embassy_stm32::rng::Rng;
+use embassy_stm32::time::khz;
+use embassy_stm32::timer::simple_pwm::{PwmPin, SimplePwm};
+use embassy_stm32::timer::{self, CountingMode};
 use embassy_stm32::{bind_interrupts, eth, peripherals, rng, Config};
-use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
-use embassy_time::{Duration, Timer};
-use picoserve::{
-    response::DebugValue,
-    routing::{get, parse_path_segment},
+use embassy_sync::blocking_mutex::{
+    raw::{CriticalSectionRawMutex, ThreadModeRawMutex},
+    Mutex,
 };
+use embassy_sync::channel::Channel;
+use embassy_sync::pubsub::{PubSubChannel, Subscriber};
+use embassy_sync::signal::Signal;
+use embassy_time::{with_timeout, Duration, Instant, TimeoutError, Timer};
+use picoserve::routing::post;
 use rand_core::RngCore;
+use smolweb_core::{
+    access_log::ConnectionId,
+    api::Rebooting,
+    auth::{Credentials, RequireAuth},
+    buffers::BufferConfig,
+    button::ButtonStats,
+    cors::{api_get, api_post, CorsOrigins},
+    diagnostics::Diagnostics,
+    events::{BoardEvent, BoardEvents, EventSubscriber, LedChange},
+    limits::{CutShort, HeadCut, LimitedSocket, Limits},
+    metrics::Metrics,
+    patterns::{Pattern, PatternPlayer},
+    rate_limit::{
+        ClientAddress, ClientRateLimit, ToggleRateLimit, CLIENT_BURST, CLIENT_REFILL_INTERVAL,
+    },
+    schedules::{Scheduler, CHECK_INTERVAL},
+    sensors::SensorStats,
+    session::{SessionContext, Sessions, KEY_LEN},
+    status_page::LocalAddress,
+    time::Clock,
+    LedControl,
+};
 use static_cell::make_static;
 use static_cell::StaticCell;
-use {defmt_rtt as _, panic_probe as _};
 
-use picoserve::extract::State;
+#[cfg(all(feature = "ota", feature = "dual-bank"))]
+compile_error!(
+    "The `ota` and `dual-bank` features are two ways of updating the firmware, enable only one"
+);
+
+#[cfg(feature = "tls")]
+compile_error!(
+    "HTTPS is deferred until `embedded-tls` can accept connections, see the README for a reverse proxy meanwhile"
+);
+
+mod adc;
+#[cfg(feature = "buzzer")]
+mod buzzer;
+#[cfg(feature = "defmt-tcp")]
+mod defmt_tcp;
+#[cfg(feature = "dual-bank")]
+mod dual_bank;
+#[cfg(feature = "mqtt")]
+mod mqtt;
+#[cfg(feature = "ota")]
+mod ota;
+mod panic_handler;
+mod persist;
+#[cfg(feature = "sdcard")]
+mod sdcard;
+mod watchdog;
+
+use smolweb_core::network::NetworkConfig;
+use smolweb_core::settings::SettingsStore;
+use smolweb_core::sntp::SntpClock;
 
 bind_interrupts!(struct Irqs {
     ETH => eth::InterruptHandler;
     RNG => rng::InterruptHandler<peripherals::RNG>;
+    I2C1_EV => i2c::EventInterruptHandler<peripherals::I2C1>;
+    I2C1_ER => i2c::ErrorInterruptHandler<peripherals::I2C1>;
 });
 
-type EthDevice = Ethernet<'static, ETH, GenericSMI>;
+type EthDevice = watchdog::Supervised<Ethernet<'static, ETH, GenericSMI>>;
+
+/// Address used when DHCP doesn't answer within [DHCP_TIMEOUT], or straight away with the `static-ip` feature.
+mod static_ip {
+    use embassy_net::Ipv4Address;
+
+    pub const ADDRESS: Ipv4Address = Ipv4Address::new(192, 168, 1, 50);
+    pub const PREFIX_LEN: u8 = 24;
+    pub const GATEWAY: Ipv4Address = Ipv4Address::new(192, 168, 1, 1);
+    pub const DNS_SERVERS: [Ipv4Address; 1] = [Ipv4Address::new(192, 168, 1, 1)];
+}
+
+/// How long to wait for a DHCP lease before falling back to [static_ip].
+const DHCP_TIMEOUT: Duration = Duration::from_secs(15);
+
+#[cfg_attr(not(feature = "ipv6"), allow(unused_variables))]
+fn network_config(mac_addr: [u8; 6], hostname: heapless::String<32>) -> NetworkConfig {
+    NetworkConfig {
+        dhcp_timeout: if cfg!(feature = "static-ip") {
+            None
+        } else {
+            Some(DHCP_TIMEOUT)
+        },
+        static_config: Some(embassy_net::StaticConfigV4 {
+            address: embassy_net::Ipv4Cidr::new(static_ip::ADDRESS, static_ip::PREFIX_LEN),
+            gateway: Some(static_ip::GATEWAY),
+            dns_servers: unwrap!(heapless::Vec::from_slice(&static_ip::DNS_SERVERS)),
+        }),
+        hostname: Some(hostname),
+        #[cfg(feature = "ipv6")]
+        ipv6: Some(smolweb_core::network::ipv6_link_local(mac_addr)),
+    }
+}
 
 #[embassy_executor::task]
 async fn net_task(stack: &'static Stack<EthDevice>) -> ! {
     stack.run().await
 }
 
+/// Server queried for the time, resolved through DNS.
+const NTP_SERVER: &str = "pool.ntp.org";
+
+#[embassy_executor::task]
+async fn sntp_task(stack: &'static Stack<EthDevice>) -> ! {
+    smolweb_core::sntp::run(stack, NTP_SERVER).await
+}
+
+/// The board answers mDNS queries for `<hostname>.local`, with the hostname of the [Settings](smolweb_core::settings::Settings).
+#[embassy_executor::task]
+async fn mdns_task(stack: &'static Stack<EthDevice>, hostname: heapless::String<32>) -> ! {
+    smolweb_core::mdns::run(stack, &hostname, smolweb_core::DEFAULT_PORT).await
+}
+
+/// Redirects `http://<board>/` to the web tasks, so that the port can be left out of the URL.
+#[embassy_executor::task]
+async fn redirect_task(stack: &'static Stack<EthDevice>) -> ! {
+    smolweb_core::redirect::run(stack, smolweb_core::DEFAULT_PORT).await
+}
+
+/// Serves the LEDs over CoAP, mirroring the LED API, changed with the API key set when building by the clients within
+/// their budget of the HTTP routes.
+#[cfg(feature = "coap")]
+#[embassy_executor::task]
+async fn coap_task(
+    stack: &'static Stack<EthDevice>,
+    shared_control: SharedControl,
+    metrics: &'static Metrics,
+    toggle_rate_limit: &'static ToggleRateLimit,
+    client_rate_limit: &'static ClientRateLimit,
+) -> ! {
+    smolweb_core::coap::run(
+        stack,
+        shared_control,
+        SntpClock,
+        &Credentials::from_build_env(),
+        metrics,
+        toggle_rate_limit,
+        client_rate_limit,
+    )
+    .await
+}
+
+/// Broadcasts the address of the board under its hostname, for `discover` on the LAN.
+#[embassy_executor::task]
+async fn discovery_task(stack: &'static Stack<EthDevice>, hostname: heapless::String<32>) -> ! {
+    smolweb_core::discovery::run(
+        stack,
+        &hostname,
+        smolweb_core::DEFAULT_PORT,
+        env!("CARGO_PKG_VERSION"),
+    )
+    .await
+}
+
+/// Subscribers to [BOARD_EVENTS]: one per web task, plus [persist::persist_task] and the MQTT task if enabled.
+///
+/// A web task serves one connection at a time, so it never has more than one WebSocket or event stream open.
+const BOARD_EVENT_SUBSCRIBERS: usize = WEB_TASK_POOL_SIZE + 1 + cfg!(feature = "mqtt") as usize;
+
+/// Events buffered for a subscriber before the oldest ones are dropped.
+const BOARD_EVENT_CAPACITY: usize = 4;
+
+/// Notifies subscribers of LED changes, whether they come from HTTP or from the button, button presses and uptime.
+static BOARD_EVENTS: PubSubChannel<
+    CriticalSectionRawMutex,
+    BoardEvent,
+    BOARD_EVENT_CAPACITY,
+    BOARD_EVENT_SUBSCRIBERS,
+    0,
+> = PubSubChannel::new();
+
+fn publish(event: BoardEvent) {
+    BOARD_EVENTS.immediate_publisher().publish_immediate(event);
+}
+
+/// Subscription to [BOARD_EVENTS] held by an open WebSocket or event stream.
+struct BoardEventSubscriber(
+    Subscriber<
+        'static,
+        CriticalSectionRawMutex,
+        BoardEvent,
+        BOARD_EVENT_CAPACITY,
+        BOARD_EVENT_SUBSCRIBERS,
+        0,
+    >,
+);
+
+impl EventSubscriber for BoardEventSubscriber {
+    async fn next_event(&mut self) -> BoardEvent {
+        self.0.next_message_pure().await
+    }
+}
+
+/// An LED of the board, switched by a GPIO or dimmed by a PWM channel.
+enum Led {
+    Gpio(Output<'static, AnyPin>),
+    Pwm {
+        pwm: SimplePwm<'static, peripherals::TIM12>,
+        channel: timer::Channel,
+        /// Brightness in percent, as the duty cycle can't be read back.
+        brightness: u8,
+    },
+}
+
+impl Led {
+    fn is_on(&self) -> bool {
+        match self {
+            Led::Gpio(output) => output.is_set_high(),
+            Led::Pwm { brightness, .. } => *brightness > 0,
+        }
+    }
+
+    fn set(&mut self, on: bool) {
+        match self {
+            Led::Gpio(output) => output.set_level(Level::from(on)),
+            Led::Pwm { .. } => self.dim(if on { 100 } else { 0 }),
+        }
+    }
+
+    fn brightness(&self) -> Option<u8> {
+        match self {
+            Led::Gpio(_) => None,
+            Led::Pwm { brightness, .. } => Some(*brightness),
+        }
+    }
+
+    /// Set the duty cycle of a PWM LED, GPIO LEDs can't be dimmed.
+    fn dim(&mut self, percent: u8) {
+        if let Led::Pwm {
+            pwm,
+            channel,
+            brightness,
+        } = self
+        {
+            let duty = u32::from(pwm.get_max_duty()) * u32::from(percent) / 100;
+            pwm.set_duty(*channel, duty as u16);
+            *brightness = percent;
+        }
+    }
+}
+
+/// The three user LEDs of the Nucleo, numbered from LED1 like on the silkscreen.
+type LedRegistry = [Led; 3];
+
+/// Number of the first LED of the [LedRegistry].
+const FIRST_LED: u8 = 1;
+
+/// Shared by the web tasks and [button_task].
+///
+/// The blocking mutex is only held for the duration of a closure which never awaits,
+/// so a button press and an HTTP request can never deadlock on it.
 #[derive(Clone, Copy)]
-struct SharedControl(&'static Mutex<CriticalSectionRawMutex, Output<'static, peripherals::PE1>>);
+struct SharedControl(&'static Mutex<CriticalSectionRawMutex, RefCell<LedRegistry>>);
+
+impl SharedControl {
+    fn notify(&self, led: u8) {
+        let on = self.state(led);
+        publish(BoardEvent::Led(LedChange { led, on }));
+    }
+
+    /// Run `f` on LED `led`, or return `None` if there is no such LED.
+    fn with_led<R>(&self, led: u8, f: impl FnOnce(&mut Led) -> R) -> Option<R> {
+        let index = usize::from(led.checked_sub(FIRST_LED)?);
+        self.0.lock(|leds| leds.borrow_mut().get_mut(index).map(f))
+    }
+}
+
+impl LedControl for SharedControl {
+    fn has_led(&self, led: u8) -> bool {
+        self.with_led(led, |_| ()).is_some()
+    }
+
+    fn toggle(&self, led: u8) {
+        self.with_led(led, |led| led.set(!led.is_on()));
+        self.notify(led);
+    }
+
+    fn set(&self, led: u8, on: bool) {
+        self.with_led(led, |led| led.set(on));
+        self.notify(led);
+    }
+
+    fn state(&self, led: u8) -> bool {
+        self.with_led(led, |led| led.is_on()).unwrap_or(false)
+    }
+
+    fn brightness(&self, led: u8) -> Option<u8> {
+        self.with_led(led, |led| led.brightness()).flatten()
+    }
+
+    fn set_brightness(&self, led: u8, percent: u8) {
+        self.with_led(led, |led| led.dim(percent));
+        self.notify(led);
+    }
+
+    fn set_pattern(&self, led: u8, pattern: Pattern) -> bool {
+        PATTERN_COMMANDS.try_send((led, pattern)).is_ok()
+    }
+
+    fn has_buzzer(&self) -> bool {
+        cfg!(feature = "buzzer")
+    }
+
+    #[cfg(feature = "buzzer")]
+    fn play_tone(&self, tone: smolweb_core::tone::Tone) -> bool {
+        buzzer::TONES.signal(tone);
+        true
+    }
+}
+
+impl BoardEvents for SharedControl {
+    type Subscriber = BoardEventSubscriber;
+
+    fn subscribe(&self) -> Option<BoardEventSubscriber> {
+        BOARD_EVENTS.subscriber().ok().map(BoardEventSubscriber)
+    }
+}
+
+/// Minimum interval between two toggles of the same LED.
+const MIN_TOGGLE_INTERVAL: core::time::Duration = core::time::Duration::from_millis(500);
+
+/// The internal flash, shared by [persist::Store] and the firmware updates.
+///
+/// Only the tasks of the thread-mode executor lock it, so it is a [ThreadModeRawMutex]: interrupts, like those of the
+/// Ethernet, keep being served while a sector is erased for a second or two, which a critical section would hold off.
+type SharedFlash = Mutex<ThreadModeRawMutex, RefCell<Flash<'static, Blocking>>>;
+
+/// Where `/static` is read from before falling back to the embedded files.
+#[cfg(feature = "sdcard")]
+type Assets = sdcard::SdCardAssets;
+#[cfg(not(feature = "sdcard"))]
+type Assets = smolweb_core::assets::NoAssetStore;
 
 struct AppState {
     shared_control: SharedControl,
+    metrics: &'static Metrics,
+    diagnostics: &'static Diagnostics,
+    toggle_rate_limit: &'static ToggleRateLimit,
+    client_rate_limit: &'static ClientRateLimit,
+    sessions: &'static Sessions,
+    button_stats: &'static ButtonStats,
+    sensor_stats: &'static SensorStats,
+    /// Set for each accepted connection, so that its requests are logged with it.
+    connection: ConnectionId,
+    /// Address the connection was accepted on, shown by the page.
+    local_address: LocalAddress,
+    /// Address the connection was accepted from, whose requests the [ClientRateLimit] counts.
+    client_address: ClientAddress,
+    /// Set by the [LimitedSocket] of the connection when it cuts a head short, so that the request is refused.
+    head_cut: HeadCut,
+    assets: Assets,
+    store: &'static persist::Store,
+    #[cfg(any(feature = "ota", feature = "dual-bank"))]
+    flash: &'static SharedFlash,
+}
+
+impl picoserve::extract::FromRef<AppState> for LocalAddress {
+    fn from_ref(state: &AppState) -> Self {
+        state.local_address
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for ClientAddress {
+    fn from_ref(state: &AppState) -> Self {
+        state.client_address
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for ConnectionId {
+    fn from_ref(state: &AppState) -> Self {
+        state.connection
+    }
 }
 
 impl picoserve::extract::FromRef<AppState> for SharedControl {
@@ -49,42 +414,443 @@ impl picoserve::extract::FromRef<AppState> for SharedControl {
     }
 }
 
+impl picoserve::extract::FromRef<AppState> for &'static persist::Store {
+    fn from_ref(state: &AppState) -> Self {
+        state.store
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for SntpClock {
+    fn from_ref(_state: &AppState) -> Self {
+        SntpClock
+    }
+}
+
+/// Set when building, see [Credentials::from_build_env].
+impl picoserve::extract::FromRef<AppState> for Credentials {
+    fn from_ref(_state: &AppState) -> Self {
+        Credentials::from_build_env()
+    }
+}
+
+/// Set when building, see [CorsOrigins::from_build_env].
+impl picoserve::extract::FromRef<AppState> for CorsOrigins {
+    fn from_ref(_state: &AppState) -> Self {
+        CorsOrigins::from_build_env()
+    }
+}
+
+/// Those of the buffers of the web workers, which [LimitedSocket::new] also cuts heads to.
+impl picoserve::extract::FromRef<AppState> for Limits {
+    fn from_ref(_state: &AppState) -> Self {
+        Limits::WEB
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for CutShort {
+    fn from_ref(state: &AppState) -> Self {
+        state.head_cut.get()
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for SessionContext {
+    fn from_ref(state: &AppState) -> Self {
+        SessionContext {
+            sessions: state.sessions,
+            uptime: SntpClock.uptime(),
+        }
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for &'static Diagnostics {
+    fn from_ref(state: &AppState) -> Self {
+        state.diagnostics
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for &'static Metrics {
+    fn from_ref(state: &AppState) -> Self {
+        state.metrics
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for &'static ToggleRateLimit {
+    fn from_ref(state: &AppState) -> Self {
+        state.toggle_rate_limit
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for &'static ClientRateLimit {
+    fn from_ref(state: &AppState) -> Self {
+        state.client_rate_limit
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for &'static ButtonStats {
+    fn from_ref(state: &AppState) -> Self {
+        state.button_stats
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for &'static SensorStats {
+    fn from_ref(state: &AppState) -> Self {
+        state.sensor_stats
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for Assets {
+    fn from_ref(state: &AppState) -> Self {
+        state.assets
+    }
+}
+
 type AppRouter = impl picoserve::routing::PathRouter<AppState>;
 
+/// Connections served at the same time, one per task of the web task pool, each with its own [BufferConfig::WEB].
+///
+/// A browser opens several connections to load the page, its stylesheet and its script in parallel, which would
+/// otherwise wait for each other.
 const WEB_TASK_POOL_SIZE: usize = 4;
 
+/// The buffers of a web task, which are part of its future and so of the static task pool.
+const WORKER_BUFFERS: BufferConfig = BufferConfig::WEB;
+
+/// Stack headroom of the tasks shown by `/api/sysinfo`, reported at the end of each loop, and the heartbeats of the
+/// background tasks `/readyz` waits for, beaten at the start of each loop.
+static DIAGNOSTICS: Diagnostics = Diagnostics::new(
+    env!("CARGO_PKG_VERSION"),
+    &[
+        "web",
+        "uptime",
+        "button",
+        "patterns",
+        "schedules",
+        "buzzer",
+        "adc",
+        "persist",
+    ],
+)
+.with_heartbeats(&["uptime", "patterns", "schedules", "adc", "persist"]);
+
+extern "C" {
+    /// End of the static data, placed by `cortex-m-rt`. The stack of `main`, which runs every task, grows down to it.
+    static __sheap: u8;
+}
+
+/// Sockets used on top of the web tasks: one each for DHCP, DNS, SNTP, mDNS, the discovery beacon and the port 80
+/// redirect, plus MQTT, CoAP and the `defmt` stream if enabled.
+const STACK_SOCKETS: usize = 6
+    + cfg!(feature = "mqtt") as usize
+    + cfg!(feature = "coap") as usize
+    + cfg!(feature = "defmt-tcp") as usize;
+
 #[embassy_executor::task(pool_size = WEB_TASK_POOL_SIZE)]
 async fn web_task(
     id: usize,
     stack: &'static Stack<EthDevice>,
     app: &'static picoserve::Router<AppRouter, AppState>,
     config: &'static picoserve::Config<Duration>,
-    state: AppState,
+    mut state: AppState,
 ) -> ! {
-    let port = 8080;
-    let mut tcp_rx_buffer = [0; 1024];
-    let mut tcp_tx_buffer = [0; 1024];
-    let mut http_buffer = [0; 2048];
-
-    picoserve::listen_and_serve_with_state(
-        id,
-        app,
-        config,
-        stack,
-        port,
-        &mut tcp_rx_buffer,
-        &mut tcp_tx_buffer,
-        &mut http_buffer,
-        &state,
-    )
-    .await
+    let port = smolweb_core::DEFAULT_PORT;
+    // Each worker owns its socket buffers, so every task in the pool can hold a connection open at the same time.
+    let mut tcp_rx_buffer = [0; WORKER_BUFFERS.tcp_rx];
+    let mut tcp_tx_buffer = [0; WORKER_BUFFERS.tcp_tx];
+    let mut http_buffer = [0; WORKER_BUFFERS.http];
+
+    // Listed by `/api/workers` even before its first connection
+    state.metrics.start_worker(id);
+
+    loop {
+        // Beats after each connection served or wait for one, so that a worker stuck in a blocking call stops feeding
+        // the watchdog
+        watchdog::beat(watchdog::web_task(id));
+
+        // Don't listen while the stack has no address, e.g. after the cable was unplugged or the DHCP lease expired
+        if !stack.is_config_up() {
+            info!("{}: Waiting for the network", id);
+            let _ = with_timeout(watchdog::IDLE_TIMEOUT, stack.wait_config_up()).await;
+            continue;
+        }
+
+        let mut socket = TcpSocket::new(stack, &mut tcp_rx_buffer, &mut tcp_tx_buffer);
+
+        match with_timeout(watchdog::IDLE_TIMEOUT, socket.accept(port)).await {
+            Ok(Ok(())) => (),
+            Ok(Err(err)) => {
+                warn!("{}: Accept error: {}", id, err);
+                continue;
+            }
+            // Listens again straight away, with nothing awaited in between
+            Err(TimeoutError) => continue,
+        }
+
+        let remote_endpoint = socket.remote_endpoint();
+        state.connection = ConnectionId::next();
+        state.local_address = LocalAddress::of_socket(&socket);
+        state.client_address = ClientAddress::of_socket(&socket);
+        info!(
+            "{}: Connection {} from {}",
+            id, state.connection, remote_endpoint
+        );
+        let connection = state.metrics.open_connection(id);
+        let socket = LimitedSocket::new(connection.count_bytes(socket), &state.head_cut);
+
+        match with_timeout(
+            watchdog::SERVE_TIMEOUT,
+            picoserve::serve_with_state(app, config, &mut http_buffer, socket, &state),
+        )
+        .await
+        {
+            Ok(Ok(handled_requests_count)) => {
+                connection.served(handled_requests_count);
+                info!(
+                    "{}: {} requests handled from {}",
+                    id, handled_requests_count, remote_endpoint
+                )
+            }
+            Ok(Err(err)) => warn!("{}: {}", id, Debug2Format(&err)),
+            Err(TimeoutError) => info!("{}: Closing the connection from {}", id, remote_endpoint),
+        }
+
+        DIAGNOSTICS.report_stack("web");
+    }
+}
+
+/// How often [network_monitor_task] checks the link and the IP configuration.
+const NETWORK_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The red LED, blinking while the Ethernet link is down.
+const LINK_DOWN_LED: u8 = 3;
+
+/// How long [LINK_DOWN_LED] stays on or off while blinking, and how often the link is checked while it is down.
+const LINK_DOWN_BLINK: Duration = Duration::from_millis(250);
+
+/// Watches the Ethernet link and the IP configuration, logging their changes and announcing the new address.
+///
+/// The link state is the one `GenericSMI` reads from the PHY, which the Ethernet driver polls for the stack. While
+/// it is down, [LINK_DOWN_LED] blinks and `/api/sysinfo` shows `"link_up":false`. Once it is back, DHCP starts over,
+/// as the board may have been plugged into another network.
+#[embassy_executor::task]
+async fn network_monitor_task(
+    stack: &'static Stack<EthDevice>,
+    network_config: &'static NetworkConfig,
+    shared_control: SharedControl,
+) -> ! {
+    let mut link_up = stack.is_link_up();
+    DIAGNOSTICS.set_link_up(link_up);
+    let mut address = stack.config_v4().map(|config| config.address);
+    DIAGNOSTICS.set_network_up(address.is_some());
+    // Whether the LED was on and its brightness before blinking, given back once the link is up
+    let mut led_before = None;
+
+    loop {
+        Timer::after(if link_up {
+            NETWORK_POLL_INTERVAL
+        } else {
+            LINK_DOWN_BLINK
+        })
+        .await;
+
+        if !link_up {
+            // Not notified, so that subscribers don't get an event every blink
+            shared_control.with_led(LINK_DOWN_LED, |led| led.set(!led.is_on()));
+        }
+
+        if stack.is_link_up() != link_up {
+            link_up = !link_up;
+            DIAGNOSTICS.set_link_up(link_up);
+
+            if link_up {
+                info!("Ethernet link up, restarting DHCP");
+                if let Some((on, brightness)) = led_before.take() {
+                    shared_control.with_led(LINK_DOWN_LED, |led| match brightness {
+                        Some(percent) => led.dim(percent),
+                        None => led.set(on),
+                    });
+                }
+                network_config.restart_dhcp(stack).await;
+            } else {
+                warn!("Ethernet link down");
+                led_before =
+                    shared_control.with_led(LINK_DOWN_LED, |led| (led.is_on(), led.brightness()));
+            }
+        }
+
+        let new_address = if link_up {
+            stack.config_v4().map(|config| config.address)
+        } else {
+            None
+        };
+
+        if new_address == address {
+            continue;
+        }
+
+        match new_address {
+            Some(new_address) => {
+                info!("Network up, IP address: {}", new_address);
+                #[cfg(feature = "ipv6")]
+                if let Some(config) = stack.config_v6() {
+                    info!("IPv6 address: {}", config.address);
+                }
+            }
+            None => warn!(
+                "Network down (link up: {}, config up: {})",
+                link_up,
+                stack.is_config_up()
+            ),
+        }
+
+        address = new_address;
+        DIAGNOSTICS.set_network_up(address.is_some());
+    }
+}
+
+/// Longest time taken to write a response before the connection is dropped.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Delay between `POST /reset` and the reset.
+///
+/// Longer than [WRITE_TIMEOUT], so that the response reaches the client,
+/// and than [persist::SAVE_DELAY], so that an LED change made just before is saved.
+const RESET_DELAY: Duration = Duration::from_secs(3);
+
+/// Signalled by `POST /reset`.
+static RESET_REQUESTED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Resets the MCU once [RESET_REQUESTED] is signalled.
+#[embassy_executor::task]
+async fn reset_task() -> ! {
+    RESET_REQUESTED.wait().await;
+    warn!("Resetting in {} ms", RESET_DELAY.as_millis());
+    Timer::after(RESET_DELAY).await;
+    cortex_m::peripheral::SCB::sys_reset()
+}
+
+/// Handler of `POST /reset`, which leaves the reset to [reset_task] so that the response is written first.
+async fn reset(_: RequireAuth) -> &'static str {
+    RESET_REQUESTED.signal(());
+    "Rebooting\n"
 }
 
+/// Handler of `POST /api/reboot`, [reset] for scripts.
+async fn api_reboot(_: RequireAuth) -> picoserve::response::Json<Rebooting> {
+    RESET_REQUESTED.signal(());
+    picoserve::response::Json(Rebooting {
+        reboot_in_ms: RESET_DELAY.as_millis(),
+    })
+}
+
+/// Publishes [BoardEvent::Uptime] every [smolweb_core::events::UPTIME_INTERVAL].
 #[embassy_executor::task]
-async fn blinky_task(mut led: Output<'static, peripherals::PB0>) -> ! {
+async fn uptime_task() -> ! {
+    let interval = Duration::from_secs(smolweb_core::events::UPTIME_INTERVAL.as_secs());
+
     loop {
-        led.toggle();
-        Timer::after_secs(1).await;
+        DIAGNOSTICS.heartbeat("uptime");
+        Timer::after(interval).await;
+        publish(BoardEvent::Uptime(Instant::now().as_secs()));
+        DIAGNOSTICS.report_stack("uptime");
+    }
+}
+
+/// The I2C bus of the sensor, Arduino D15 (SCL) and D14 (SDA) on Nucleo.
+#[cfg(feature = "sht31")]
+type SensorI2c = i2c::I2c<'static, peripherals::I2C1, peripherals::DMA1_CH4, peripherals::DMA1_CH5>;
+
+/// Samples the SHT31 every [SENSOR_INTERVAL](smolweb_core::sensors::SENSOR_INTERVAL) into `sensor_stats`, publishing
+/// each reading as a [BoardEvent::Sensor].
+#[cfg(feature = "sht31")]
+#[embassy_executor::task]
+async fn sensor_task(i2c: SensorI2c, sensor_stats: &'static SensorStats) -> ! {
+    use smolweb_core::sensors::{Sht31, SENSOR_INTERVAL, SHT31_DEFAULT_ADDRESS};
+
+    let mut sensor = Sht31::new(i2c, SHT31_DEFAULT_ADDRESS);
+    let interval = Duration::from_secs(SENSOR_INTERVAL.as_secs());
+
+    loop {
+        match sensor.measure(&mut embassy_time::Delay).await {
+            Ok(reading) => {
+                debug!("Sensor reading: {}", reading);
+                sensor_stats.record(reading, SntpClock.uptime());
+                publish(BoardEvent::Sensor(reading));
+            }
+            Err(err) => warn!("Failed to read the SHT31: {}", err),
+        }
+
+        Timer::after(interval).await;
+    }
+}
+
+/// Patterns selected with `POST /api/leds/<n>/pattern`, waiting for [pattern_task].
+static PATTERN_COMMANDS: Channel<CriticalSectionRawMutex, (u8, Pattern), 4> = Channel::new();
+
+/// Plays the patterns of [PATTERN_COMMANDS] on the LEDs, sleeping until the next change of any of them.
+#[embassy_executor::task]
+async fn pattern_task(shared_control: SharedControl) -> ! {
+    let mut player = PatternPlayer::new();
+
+    loop {
+        DIAGNOSTICS.heartbeat("patterns");
+        watchdog::beat(watchdog::PATTERN_TASK);
+        let next_change = player.update(&shared_control, SntpClock.uptime());
+        // Wakes up within the idle timeout of the watchdog even while no LED plays a pattern
+        let wait = next_change.map_or(watchdog::IDLE_TIMEOUT, |wait| {
+            Duration::from_micros(wait.as_micros() as u64).min(watchdog::IDLE_TIMEOUT)
+        });
+        let command = match select(PATTERN_COMMANDS.receive(), Timer::after(wait)).await {
+            Either::First(command) => Some(command),
+            Either::Second(()) => None,
+        };
+
+        if let Some((led, pattern)) = command {
+            info!("LED{} now plays {}", led, pattern);
+            player.set(&shared_control, led, pattern, SntpClock.uptime());
+        }
+
+        DIAGNOSTICS.report_stack("patterns");
+    }
+}
+
+/// Runs the schedules of `/api/schedules` kept in the `store`, once the SNTP clock is set.
+#[embassy_executor::task]
+async fn schedule_task(shared_control: SharedControl, store: &'static persist::Store) -> ! {
+    let mut scheduler = Scheduler::new();
+    let interval = Duration::from_secs(CHECK_INTERVAL.as_secs());
+
+    loop {
+        DIAGNOSTICS.heartbeat("schedules");
+        Timer::after(interval).await;
+        scheduler.run_due(&shared_control, &SntpClock, &store);
+        DIAGNOSTICS.report_stack("schedules");
+    }
+}
+
+/// How long the button must stay pressed to count, to ignore contact bounce.
+const BUTTON_DEBOUNCE: Duration = Duration::from_millis(20);
+
+/// Toggles LED2 on each press of the user button (B1).
+#[embassy_executor::task]
+async fn button_task(
+    mut button: ExtiInput<'static, peripherals::PC13>,
+    shared_control: SharedControl,
+    button_stats: &'static ButtonStats,
+) -> ! {
+    loop {
+        button.wait_for_rising_edge().await;
+        Timer::after(BUTTON_DEBOUNCE).await;
+
+        if button.is_high() {
+            info!("Button pressed");
+            button_stats.record_press(SntpClock.uptime());
+            publish(BoardEvent::ButtonPressed);
+            shared_control.toggle(2);
+        }
+
+        button.wait_for_low().await;
+        Timer::after(BUTTON_DEBOUNCE).await;
+        DIAGNOSTICS.report_stack("button");
     }
 }
 
@@ -111,16 +877,58 @@ async fn main(spawner: Spawner) {
         config.rcc.apb3_pre = APBPrescaler::DIV2; // 100 Mhz
         config.rcc.apb4_pre = APBPrescaler::DIV2; // 100 Mhz
         config.rcc.voltage_scale = VoltageScale::Scale1;
+        config.rcc.adc_clock_source = AdcClockSource::PER; // HSI, 64 Mhz, divided down by the ADC driver
     }
     let p = embassy_stm32::init(config);
 
-    let led1 = Output::new(p.PB0, Level::High, Speed::Low); // green LED on Nucleo
-    let led2 = Output::new(p.PE1, Level::High, Speed::Low); // yellow LED on Nucleo
-    let led3 = Output::new(p.PB14, Level::High, Speed::Low); // red LED on Nucleo
+    let reset_flags = watchdog::read_reset_flags();
+    info!("Reset flags: {}", reset_flags.as_slice());
+    DIAGNOSTICS.set_reset_flags(&reset_flags);
+    panic_handler::init();
+
+    // PLL1_P, as configured above
+    DIAGNOSTICS.set_cpu_frequency(400_000_000);
+    // Only the address of the symbol is taken, it is never read, and there is no heap above the static data
+    unsafe { DIAGNOSTICS.paint_stack(core::ptr::addr_of!(__sheap) as usize) };
+
+    // Restore LED2 from flash, defaulting to on when nothing was saved yet
+    let flash: &'static SharedFlash =
+        make_static!(Mutex::new(RefCell::new(Flash::new_blocking(p.FLASH))));
+    let store: &'static persist::Store = make_static!(persist::Store::new(flash));
+    let settings = store.settings();
+    let led2_level = Level::from(store.led2().unwrap_or(settings.led_on_at_boot(2)));
+
+    let led1_level = Level::from(settings.led_on_at_boot(1));
+    let led1 = Output::new(p.PB0, led1_level, Speed::Low).degrade(); // green LED on Nucleo
+    let led2 = Output::new(p.PE1, led2_level, Speed::Low).degrade(); // yellow LED on Nucleo
+
+    // Red LED on Nucleo, dimmed by channel 1 of TIM12 at 1 kHz, fast enough not to flicker
+    let mut led3_pwm = SimplePwm::new(
+        p.TIM12,
+        Some(PwmPin::new_ch1(p.PB14, OutputType::PushPull)),
+        None,
+        None,
+        None,
+        khz(1),
+        CountingMode::EdgeAlignedUp,
+    );
+    led3_pwm.enable(timer::Channel::Ch1);
+    let mut led3 = Led::Pwm {
+        pwm: led3_pwm,
+        channel: timer::Channel::Ch1,
+        brightness: 0,
+    };
+    led3.dim(if settings.led_on_at_boot(3) { 100 } else { 0 });
 
     info!("Hello World!");
 
-    unwrap!(spawner.spawn(blinky_task(led1)));
+    unwrap!(spawner.spawn(persist::persist_task(store)));
+    unwrap!(spawner.spawn(reset_task()));
+    unwrap!(spawner.spawn(uptime_task()));
+    unwrap!(spawner.spawn(adc::adc_task(embassy_stm32::adc::Adc::new(
+        p.ADC3,
+        &mut embassy_time::Delay
+    ))));
 
     // Generate random seed.
     let mut rng = Rng::new(p.RNG, Irqs);
@@ -128,12 +936,17 @@ async fn main(spawner: Spawner) {
     rng.fill_bytes(&mut seed);
     let seed = u64::from_le_bytes(seed);
 
+    // Drawn at each boot, so that session cookies don't outlive it
+    let mut session_key = [0; KEY_LEN];
+    rng.fill_bytes(&mut session_key);
+    let sessions = make_static!(Sessions::new(session_key));
+
     let mac_addr = [0x00, 0x00, 0xDE, 0xAD, 0xBE, 0xEF];
 
     static PACKETS: StaticCell<PacketQueue<4, 4>> = StaticCell::new();
     // warning: Not all STM32H7 devices have the exact same pins here
     // for STM32H747XIH, replace p.PB13 for PG12
-    let device = Ethernet::new(
+    let device = watchdog::Supervised(Ethernet::new(
         PACKETS.init(PacketQueue::<4, 4>::new()),
         p.ETH,
         Irqs,
@@ -148,54 +961,62 @@ async fn main(spawner: Spawner) {
         p.PG11, // TX_EN: Transmit Enable
         GenericSMI::new(0),
         mac_addr,
-    );
+    ));
 
-    let config = embassy_net::Config::dhcpv4(Default::default());
+    let network_config = make_static!(network_config(mac_addr, settings.hostname.clone()));
 
     // Init network stack
     static STACK: StaticCell<Stack<EthDevice>> = StaticCell::new();
-    static RESOURCES: StaticCell<StackResources<WEB_TASK_POOL_SIZE>> = StaticCell::new();
+    static RESOURCES: StaticCell<StackResources<{ WEB_TASK_POOL_SIZE + STACK_SOCKETS }>> =
+        StaticCell::new();
     let stack = &*STACK.init(Stack::new(
         device,
-        config,
-        RESOURCES.init(StackResources::<WEB_TASK_POOL_SIZE>::new()),
+        network_config.initial(),
+        RESOURCES.init(StackResources::<{ WEB_TASK_POOL_SIZE + STACK_SOCKETS }>::new()),
         seed,
     ));
 
     // Launch network task
-    unwrap!(spawner.spawn(net_task(&stack)));
+    unwrap!(spawner.spawn(net_task(stack)));
 
-    // Ensure DHCP configuration is up before trying connect
-    stack.wait_config_up().await;
+    // Ensure network configuration is up before trying connect
+    network_config.wait_config_up(stack).await;
 
     info!("Network task initialized");
 
+    #[cfg(feature = "ota")]
+    ota::mark_booted(flash);
+
+    unwrap!(spawner.spawn(sntp_task(stack)));
+    unwrap!(spawner.spawn(mdns_task(stack, settings.hostname.clone())));
+    unwrap!(spawner.spawn(discovery_task(stack, settings.hostname.clone())));
+    unwrap!(spawner.spawn(redirect_task(stack)));
+    #[cfg(feature = "defmt-tcp")]
+    unwrap!(spawner.spawn(defmt_tcp::defmt_tcp_task(stack)));
+
     fn make_app() -> picoserve::Router<AppRouter, AppState> {
-        picoserve::Router::new()
-            .route(
-                "/",
-                get(|| picoserve::response::File::html(include_str!("index.html"))),
-            )
-            .route(
-                "/index.css",
-                get(|| picoserve::response::File::css(include_str!("index.css"))),
-            )
-            .route(
-                "/index.js",
-                get(|| picoserve::response::File::javascript(include_str!("index.js"))),
-            )
-            .route(
-                ("/toggle_led", parse_path_segment()),
-                get(
-                    |led_type: u8, State(SharedControl(control)): State<SharedControl>| async move {
-                        info!("Toggling LED{}", led_type);
-                        let mut control = control.lock().await;
-                        control.toggle();
-                        let led_state = control.is_set_high();
-                        DebugValue(if led_state { "ON" } else { "OFF" })
-                    },
-                ),
-            )
+        let routes = smolweb_core::make_routes::<
+            AppState,
+            SharedControl,
+            SntpClock,
+            Assets,
+            &'static persist::Store,
+        >()
+        .route("/reset", post(reset))
+        .route("/api/reboot", api_post(api_reboot))
+        .route("/api/adc", api_get(adc::get_adc))
+        .route("/api/panic", api_get(panic_handler::get_panic));
+        #[cfg(feature = "ota")]
+        let routes = routes.route(
+            "/ota",
+            picoserve::routing::post_service(ota::FirmwareUpload),
+        );
+        #[cfg(feature = "dual-bank")]
+        let routes = routes.route(
+            "/firmware",
+            picoserve::routing::post_service(dual_bank::FirmwareUpload),
+        );
+        smolweb_core::add_middleware::<AppState, SntpClock, _>(routes)
     }
 
     let app = make_static!(make_app());
@@ -203,20 +1024,108 @@ async fn main(spawner: Spawner) {
     let config = make_static!(picoserve::Config::new(picoserve::Timeouts {
         start_read_request: Some(Duration::from_secs(5)),
         read_request: Some(Duration::from_secs(1)),
-        write: Some(Duration::from_secs(1)),
+        write: Some(WRITE_TIMEOUT),
     })
     .keep_connection_alive());
 
-    let shared_control = SharedControl(make_static!(Mutex::new(led2)));
+    // `SharedControl` is `Copy`, so every worker gets a copy of the same `&'static Mutex`.
+    let shared_control = SharedControl(make_static!(Mutex::new(RefCell::new([
+        Led::Gpio(led1),
+        Led::Gpio(led2),
+        led3,
+    ]))));
+
+    // User button on Nucleo, pulled down externally
+    let button = ExtiInput::new(Input::new(p.PC13, Pull::None), p.EXTI13);
+    let button_stats = make_static!(ButtonStats::new());
+    unwrap!(spawner.spawn(button_task(button, shared_control, button_stats)));
+    unwrap!(spawner.spawn(pattern_task(shared_control)));
+    #[cfg(feature = "buzzer")]
+    unwrap!(
+        spawner.spawn(buzzer::buzzer_task(embassy_stm32::dac::DacCh1::new(
+            p.DAC1,
+            embassy_stm32::dma::NoDma,
+            p.PA4,
+        )))
+    );
+    unwrap!(spawner.spawn(schedule_task(shared_control, store)));
+    unwrap!(spawner.spawn(network_monitor_task(stack, network_config, shared_control)));
+
+    let sensor_stats = make_static!(SensorStats::new());
+    #[cfg(feature = "sht31")]
+    {
+        let i2c: SensorI2c = i2c::I2c::new(
+            p.I2C1,
+            p.PB8,
+            p.PB9,
+            Irqs,
+            p.DMA1_CH4,
+            p.DMA1_CH5,
+            khz(100),
+            Default::default(),
+        );
+        unwrap!(spawner.spawn(sensor_task(i2c, sensor_stats)));
+    }
+
+    #[cfg(feature = "mqtt")]
+    unwrap!(spawner.spawn(mqtt::mqtt_task(
+        stack,
+        shared_control,
+        button_stats,
+        settings.hostname.clone(),
+    )));
+
+    #[cfg(feature = "sdcard")]
+    let assets = sdcard::SdCardAssets::new(p.SPI1, p.PA5, p.PB5, p.PA6, p.PD14);
+    #[cfg(not(feature = "sdcard"))]
+    let assets = smolweb_core::assets::NoAssetStore;
+
+    let metrics = make_static!(Metrics::new());
+    let toggle_rate_limit = make_static!(ToggleRateLimit::new(MIN_TOGGLE_INTERVAL));
+    let client_rate_limit =
+        make_static!(ClientRateLimit::new(CLIENT_BURST, CLIENT_REFILL_INTERVAL));
 
-    // for id in 0..WEB_TASK_POOL_SIZE {
-    for id in 0..1 {
+    #[cfg(feature = "coap")]
+    unwrap!(spawner.spawn(coap_task(
+        stack,
+        shared_control,
+        metrics,
+        toggle_rate_limit,
+        client_rate_limit,
+    )));
+
+    info!(
+        "{} web workers with {} bytes of buffers each",
+        WEB_TASK_POOL_SIZE,
+        WORKER_BUFFERS.total()
+    );
+    for id in 0..WEB_TASK_POOL_SIZE {
         spawner.must_spawn(web_task(
             id,
             stack,
             app,
             config,
-            AppState { shared_control },
+            AppState {
+                shared_control,
+                metrics,
+                diagnostics: &DIAGNOSTICS,
+                toggle_rate_limit,
+                client_rate_limit,
+                sessions,
+                button_stats,
+                sensor_stats,
+                connection: ConnectionId(0),
+                local_address: LocalAddress::default(),
+                client_address: ClientAddress::default(),
+                head_cut: HeadCut::new(),
+                assets,
+                store,
+                #[cfg(any(feature = "ota", feature = "dual-bank"))]
+                flash,
+            },
         ));
     }
+
+    // Last, so that the tasks it supervises are running
+    unwrap!(spawner.spawn(watchdog::supervisor_task(watchdog::new_watchdog(p.IWDG1))));
 }
@@ -0,0 +1,278 @@
+//! MQTT client publishing the LEDs and telemetry of the board and taking LED commands, enabled by the `mqtt` feature.
+//!
+//! The topics are under `smolweb/<hostname>`, or `MQTT_TOPIC` if set when building:
+//! - `<prefix>/status`: `online` once connected, then `offline` as the last will, retained.
+//! - `<prefix>/led/<n>`: `ON` or `OFF`, retained, published at each connection and on every change.
+//! - `<prefix>/led/<n>/set`: subscribed, taking `ON`, `OFF` or `TOGGLE`.
+//! - `<prefix>/button`: `pressed` on each press of the user button.
+//! - `<prefix>/telemetry`: e.g. `{"uptime_seconds":120,"button_presses":3}`, every [UPTIME_INTERVAL].
+//! - `<prefix>/sensor`: e.g. `{"temperature_c":21.50,"humidity_percent":40.25}`, on each sample of the `sht31` sensor.
+
+use core::convert::Infallible;
+use core::fmt::Write as _;
+
+use defmt::*;
+use embassy_futures::select::{select, Either};
+use embassy_net::{dns::DnsQueryType, tcp::TcpSocket, IpEndpoint, Stack};
+use embassy_time::{Duration, Timer};
+use rust_mqtt::client::client::MqttClient;
+use rust_mqtt::client::client_config::{ClientConfig, MqttVersion};
+use rust_mqtt::packet::v5::publish_packet::QualityOfService;
+use rust_mqtt::packet::v5::reason_codes::ReasonCode;
+use rust_mqtt::utils::rng_generator::CountingRng;
+use smolweb_core::button::ButtonStats;
+use smolweb_core::events::{BoardEvent, EventSubscriber, LedChange, UPTIME_INTERVAL};
+use smolweb_core::LedControl;
+
+use crate::{BoardEventSubscriber, EthDevice, SharedControl, BOARD_EVENTS, FIRST_LED};
+
+/// Broker connected to, an address or a DNS name, set with `MQTT_BROKER` when building.
+const MQTT_BROKER: &str = match option_env!("MQTT_BROKER") {
+    Some(broker) => broker,
+    None => "192.168.1.10",
+};
+const MQTT_PORT: u16 = 1883;
+
+/// Set with `MQTT_USERNAME` and `MQTT_PASSWORD` when building, for brokers which require them.
+const MQTT_USERNAME: Option<&str> = option_env!("MQTT_USERNAME");
+const MQTT_PASSWORD: Option<&str> = option_env!("MQTT_PASSWORD");
+
+/// Delay before connecting again after failing to connect or losing the broker.
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Largest packet sent or received, which the topics and telemetry are far from.
+const PACKET_BUFFER_SIZE: usize = 256;
+
+type Topic = heapless::String<64>;
+
+#[derive(Debug)]
+enum MqttError {
+    Dns(embassy_net::dns::Error),
+    NoAddress,
+    Connect(embassy_net::tcp::ConnectError),
+    Broker(ReasonCode),
+}
+
+impl From<ReasonCode> for MqttError {
+    fn from(reason: ReasonCode) -> Self {
+        Self::Broker(reason)
+    }
+}
+
+/// `<prefix>/<suffix>`, e.g. `smolweb/smolweb/status`.
+fn topic(prefix: &str, suffix: core::fmt::Arguments) -> Topic {
+    let mut topic = Topic::new();
+    // The prefix is at most 40 bytes, leaving room for the longest suffix
+    let _ = write!(topic, "{prefix}/{suffix}");
+    topic
+}
+
+/// The LED commanded by `topic` and its new state, `None` meaning a toggle, if it is an LED command.
+fn parse_command(prefix: &str, topic: &str, payload: &[u8]) -> Option<(u8, Option<bool>)> {
+    let led = topic
+        .strip_prefix(prefix)?
+        .strip_prefix("/led/")?
+        .strip_suffix("/set")?
+        .parse()
+        .ok()?;
+
+    let state = match payload {
+        b"ON" => Some(true),
+        b"OFF" => Some(false),
+        b"TOGGLE" => None,
+        _ => return None,
+    };
+
+    Some((led, state))
+}
+
+async fn publish_led(
+    client: &mut MqttClient<'_, TcpSocket<'_>, 5, CountingRng>,
+    prefix: &str,
+    change: LedChange,
+) -> Result<(), ReasonCode> {
+    let payload: &[u8] = if change.on { b"ON" } else { b"OFF" };
+    client
+        .send_message(
+            &topic(prefix, format_args!("led/{}", change.led)),
+            payload,
+            QualityOfService::QoS0,
+            true,
+        )
+        .await
+}
+
+/// Connect to [MQTT_BROKER] and serve it until the connection fails.
+async fn run_session(
+    stack: &'static Stack<EthDevice>,
+    shared_control: SharedControl,
+    button_stats: &'static ButtonStats,
+    client_id: &str,
+    prefix: &str,
+    events: &mut BoardEventSubscriber,
+) -> Result<Infallible, MqttError> {
+    let address = *stack
+        .dns_query(MQTT_BROKER, DnsQueryType::A)
+        .await
+        .map_err(MqttError::Dns)?
+        .first()
+        .ok_or(MqttError::NoAddress)?;
+
+    let mut rx_buffer = [0; 1024];
+    let mut tx_buffer = [0; 1024];
+    let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+    socket
+        .connect(IpEndpoint::new(address, MQTT_PORT))
+        .await
+        .map_err(MqttError::Connect)?;
+
+    let status_topic = topic(prefix, format_args!("status"));
+    let command_topic = topic(prefix, format_args!("led/+/set"));
+
+    let mut config = ClientConfig::new(MqttVersion::MQTTv5, CountingRng(20000));
+    config.add_max_subscribe_qos(QualityOfService::QoS0);
+    config.add_client_id(client_id);
+    if let Some(username) = MQTT_USERNAME {
+        config.add_username(username);
+    }
+    if let Some(password) = MQTT_PASSWORD {
+        config.add_password(password);
+    }
+    config.add_will(&status_topic, b"offline", true);
+    config.max_packet_size = PACKET_BUFFER_SIZE as u32;
+
+    let mut write_buffer = [0; PACKET_BUFFER_SIZE];
+    let mut receive_buffer = [0; PACKET_BUFFER_SIZE];
+    let mut client = MqttClient::<_, 5, _>::new(
+        socket,
+        &mut write_buffer,
+        PACKET_BUFFER_SIZE,
+        &mut receive_buffer,
+        PACKET_BUFFER_SIZE,
+        config,
+    );
+
+    client.connect_to_broker().await?;
+    info!("Connected to MQTT broker {}", MQTT_BROKER);
+
+    client
+        .send_message(&status_topic, b"online", QualityOfService::QoS0, true)
+        .await?;
+    let mut led = FIRST_LED;
+    while shared_control.has_led(led) {
+        let on = shared_control.state(led);
+        publish_led(&mut client, prefix, LedChange { led, on }).await?;
+        led += 1;
+    }
+    client.subscribe_to_topic(&command_topic).await?;
+
+    loop {
+        // Dropping the receive between packets loses nothing, and a packet cut in the middle fails the next receive,
+        // which starts the session over
+        let event = match select(client.receive_message(), events.next_event()).await {
+            Either::First(message) => {
+                let (topic, payload) = message?;
+
+                match parse_command(prefix, topic, payload) {
+                    Some((led, _)) if !shared_control.has_led(led) => {
+                        warn!("MQTT command for unknown LED {}", led)
+                    }
+                    Some((led, Some(on))) => shared_control.set(led, on),
+                    Some((led, None)) => shared_control.toggle(led),
+                    None => debug!("Ignoring MQTT message on {}", topic),
+                }
+
+                continue;
+            }
+            Either::Second(event) => event,
+        };
+
+        match event {
+            BoardEvent::Led(change) => publish_led(&mut client, prefix, change).await?,
+            BoardEvent::ButtonPressed => {
+                client
+                    .send_message(
+                        &topic(prefix, format_args!("button")),
+                        b"pressed",
+                        QualityOfService::QoS0,
+                        false,
+                    )
+                    .await?
+            }
+            BoardEvent::Uptime(seconds) => {
+                let mut telemetry = heapless::String::<64>::new();
+                let _ = write!(
+                    telemetry,
+                    "{{\"uptime_seconds\":{},\"button_presses\":{}}}",
+                    seconds,
+                    button_stats.presses()
+                );
+
+                // Also keeps the connection alive, being sent more often than the keep-alive interval
+                client
+                    .send_message(
+                        &topic(prefix, format_args!("telemetry")),
+                        telemetry.as_bytes(),
+                        QualityOfService::QoS0,
+                        false,
+                    )
+                    .await?
+            }
+            BoardEvent::Sensor(reading) => {
+                let mut json = heapless::String::<64>::new();
+                let _ = write!(
+                    json,
+                    "{{\"temperature_c\":{:.2},\"humidity_percent\":{:.2}}}",
+                    reading.temperature_c, reading.humidity_percent
+                );
+
+                client
+                    .send_message(
+                        &topic(prefix, format_args!("sensor")),
+                        json.as_bytes(),
+                        QualityOfService::QoS0,
+                        false,
+                    )
+                    .await?
+            }
+        }
+    }
+}
+
+/// Stays connected to [MQTT_BROKER], connecting again [RETRY_INTERVAL] after each failure.
+#[embassy_executor::task]
+pub async fn mqtt_task(
+    stack: &'static Stack<EthDevice>,
+    shared_control: SharedControl,
+    button_stats: &'static ButtonStats,
+    hostname: heapless::String<32>,
+) -> ! {
+    let prefix = match option_env!("MQTT_TOPIC") {
+        Some(prefix) => unwrap!(heapless::String::<40>::try_from(prefix)),
+        None => {
+            let mut prefix = heapless::String::<40>::new();
+            // Fits, the hostname being at most 32 bytes
+            let _ = write!(prefix, "smolweb/{hostname}");
+            prefix
+        }
+    };
+    let mut events = BoardEventSubscriber(unwrap!(BOARD_EVENTS.subscriber()));
+
+    loop {
+        match run_session(
+            stack,
+            shared_control,
+            button_stats,
+            &hostname,
+            &prefix,
+            &mut events,
+        )
+        .await
+        {
+            Ok(never) => match never {},
+            Err(err) => warn!("MQTT connection failed: {}", Debug2Format(&err)),
+        }
+
+        Timer::after(RETRY_INTERVAL).await;
+    }
+}
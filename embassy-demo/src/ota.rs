@@ -0,0 +1,264 @@
+//! Firmware updates over HTTP with `embassy-boot`, enabled by the `ota` feature.
+//!
+//! `POST /ota` streams the image into the DFU partition, marks it for the bootloader and resets.
+//! The bootloader in `bootloader/` then swaps it into the active partition, where `memory-ota.x` links the application.
+
+use defmt::*;
+use embassy_boot::{AlignedBuffer, BlockingFirmwareState, State};
+use embassy_stm32::flash::{Error, MAX_ERASE_SIZE, READ_SIZE, WRITE_SIZE};
+use embedded_storage::nor_flash::{ErrorType, NorFlash, ReadNorFlash};
+use picoserve::{
+    extract::FromRequestParts,
+    io::Read,
+    request::Request,
+    response::{IntoResponse, ResponseWriter, StatusCode},
+    routing::RequestHandlerService,
+    ResponseSent,
+};
+use smolweb_core::{auth::RequireAuth, upload::UploadLock};
+
+use crate::{AppState, SharedFlash, RESET_REQUESTED};
+
+// Offsets from the start of the flash, matching `bootloader/memory.x`
+
+const STATE_OFFSET: u32 = 0x2_0000;
+const STATE_SIZE: u32 = 0x2_0000;
+
+/// Size of the active partition, the largest image which can be installed.
+const ACTIVE_SIZE: u32 = 768 * 1024;
+
+const DFU_OFFSET: u32 = 0x10_0000;
+/// One sector larger than the active partition, which the bootloader needs to swap them.
+const DFU_SIZE: u32 = ACTIVE_SIZE + MAX_ERASE_SIZE as u32;
+
+/// Bytes of the image written to flash at once, a multiple of [WRITE_SIZE].
+const CHUNK_SIZE: usize = 1024;
+
+/// A range of the shared flash, with offsets relative to its start, as `embassy-boot` expects.
+struct Partition {
+    flash: &'static SharedFlash,
+    offset: u32,
+    size: u32,
+}
+
+impl Partition {
+    fn state(flash: &'static SharedFlash) -> Self {
+        Self {
+            flash,
+            offset: STATE_OFFSET,
+            size: STATE_SIZE,
+        }
+    }
+
+    fn dfu(flash: &'static SharedFlash) -> Self {
+        Self {
+            flash,
+            offset: DFU_OFFSET,
+            size: DFU_SIZE,
+        }
+    }
+
+    fn check_bounds(&self, from: u32, to: u32) -> Result<(), Error> {
+        if from <= to && to <= self.size {
+            Ok(())
+        } else {
+            Err(Error::Size)
+        }
+    }
+}
+
+impl ErrorType for Partition {
+    type Error = Error;
+}
+
+impl ReadNorFlash for Partition {
+    const READ_SIZE: usize = READ_SIZE;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Error> {
+        self.check_bounds(offset, offset + bytes.len() as u32)?;
+        self.flash.lock(|flash| {
+            flash
+                .borrow_mut()
+                .blocking_read(self.offset + offset, bytes)
+        })
+    }
+
+    fn capacity(&self) -> usize {
+        self.size as usize
+    }
+}
+
+impl NorFlash for Partition {
+    const WRITE_SIZE: usize = WRITE_SIZE;
+    const ERASE_SIZE: usize = MAX_ERASE_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Error> {
+        self.check_bounds(from, to)?;
+        self.flash.lock(|flash| {
+            flash
+                .borrow_mut()
+                .blocking_erase(self.offset + from, self.offset + to)
+        })
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Error> {
+        self.check_bounds(offset, offset + bytes.len() as u32)?;
+        self.flash.lock(|flash| {
+            flash
+                .borrow_mut()
+                .blocking_write(self.offset + offset, bytes)
+        })
+    }
+}
+
+/// Confirm that the running firmware works, otherwise the bootloader reverts to the previous one on the next reset.
+///
+/// Called once the network is up, so an image which can't get that far is rolled back.
+pub fn mark_booted(flash: &'static SharedFlash) {
+    let mut aligned = AlignedBuffer([0; WRITE_SIZE]);
+    let mut state = BlockingFirmwareState::new(Partition::state(flash), &mut aligned.0);
+
+    match state.get_state() {
+        Ok(State::Swap) => match state.mark_booted() {
+            Ok(()) => info!("Running the updated firmware"),
+            Err(err) => warn!("Failed to confirm the updated firmware: {}", err),
+        },
+        Ok(_) => {}
+        Err(err) => warn!("Failed to read the bootloader state: {}", err),
+    }
+}
+
+/// Held while an image is being received, so that two uploads don't write the DFU partition at the same time.
+static FIRMWARE_UPLOAD: UploadLock = UploadLock::new();
+
+/// Response to a refused or failed upload.
+type UploadError = (StatusCode, &'static str);
+
+/// Stream the request body into the DFU partition, and mark it for the bootloader once all of it is written.
+///
+/// Each 128 KiB sector is erased when the image reaches it, which stalls the other tasks for a second or two, while
+/// interrupts keep being served.
+async fn receive<R: Read>(
+    flash: &'static SharedFlash,
+    request: &mut Request<'_, R>,
+) -> Result<Result<(), UploadError>, R::Error> {
+    let length = request.body_connection.content_length();
+
+    if length == 0 {
+        return Ok(Err((
+            StatusCode::LENGTH_REQUIRED,
+            "The image must be sent with a Content-Length\n",
+        )));
+    }
+
+    if length > ACTIVE_SIZE as usize {
+        return Ok(Err((
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "The image is larger than the active partition\n",
+        )));
+    }
+
+    info!("Receiving a {} byte firmware image", length);
+
+    let flash_error = (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Failed to write the image\n",
+    );
+
+    let mut dfu = Partition::dfu(flash);
+    let mut reader = request.body_connection.body().reader();
+    let mut chunk = AlignedBuffer([0; CHUNK_SIZE]);
+    let mut received = 0;
+    let mut erased = 0;
+
+    while received < length {
+        let mut filled = 0;
+
+        while filled < CHUNK_SIZE && received + filled < length {
+            let read = reader.read(&mut chunk.0[filled..]).await?;
+
+            if read == 0 {
+                warn!("Firmware image ended after {} bytes", received + filled);
+                return Ok(Err((
+                    StatusCode::BAD_REQUEST,
+                    "The image is shorter than its Content-Length\n",
+                )));
+            }
+
+            filled += read;
+        }
+
+        // Pad the end of the image to the flash write size, erased flash reads as 0xFF
+        let write_len = filled.next_multiple_of(WRITE_SIZE);
+        chunk.0[filled..write_len].fill(0xFF);
+
+        let offset = received as u32;
+        let end = offset + write_len as u32;
+
+        while erased < end {
+            if let Err(err) = dfu.erase(erased, erased + MAX_ERASE_SIZE as u32) {
+                warn!("Failed to erase the DFU partition: {}", err);
+                return Ok(Err(flash_error));
+            }
+
+            erased += MAX_ERASE_SIZE as u32;
+        }
+
+        if let Err(err) = dfu.write(offset, &chunk.0[..write_len]) {
+            warn!("Failed to write the firmware image: {}", err);
+            return Ok(Err(flash_error));
+        }
+
+        received += filled;
+    }
+
+    let mut aligned = AlignedBuffer([0; WRITE_SIZE]);
+    if let Err(err) =
+        BlockingFirmwareState::new(Partition::state(flash), &mut aligned.0).mark_updated()
+    {
+        warn!("Failed to mark the firmware update: {}", err);
+        return Ok(Err(flash_error));
+    }
+
+    info!("Firmware image written");
+
+    Ok(Ok(()))
+}
+
+/// Handler of `POST /ota`, which takes the raw image as the body and resets once it is written.
+pub struct FirmwareUpload;
+
+impl RequestHandlerService<AppState, ()> for FirmwareUpload {
+    async fn call_request_handler_service<R: Read, W: ResponseWriter<Error = R::Error>>(
+        &self,
+        state: &AppState,
+        _path_parameters: (),
+        mut request: Request<'_, R>,
+        response_writer: W,
+    ) -> Result<ResponseSent, W::Error> {
+        let result = match RequireAuth::from_request_parts(state, &request.parts).await {
+            Err(rejection) => {
+                return rejection
+                    .write_to(request.body_connection.finalize().await?, response_writer)
+                    .await;
+            }
+            Ok(RequireAuth) => match FIRMWARE_UPLOAD.acquire() {
+                Some(_guard) => receive(state.flash, &mut request).await?,
+                None => Err((StatusCode::CONFLICT, "Another update is in progress\n")),
+            },
+        };
+
+        let connection = request.body_connection.finalize().await?;
+
+        match result {
+            Ok(()) => {
+                // Leaves the reset to `reset_task`, so that the response is written first
+                RESET_REQUESTED.signal(());
+                "Update received, rebooting\n"
+                    .write_to(connection, response_writer)
+                    .await
+            }
+            Err(err) => err.write_to(connection, response_writer).await,
+        }
+    }
+}
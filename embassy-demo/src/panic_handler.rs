@@ -0,0 +1,77 @@
+//! Panic and hard fault handlers recording what happened for `GET /api/panic`, then resetting the board.
+//!
+//! The [PanicRecord] lives in `.uninit` RAM, which survives the reset, and [init] reads it back at the next boot.
+//! With a debugger attached the handlers break instead of resetting, as `panic-probe` does, so that `probe-rs` still
+//! shows the backtrace. `defmt` panics, like `unwrap!`, only send their message over RTT, so they are recorded as
+//! the explicit panic of `defmt` they end in.
+
+use core::{cell::RefCell, mem::MaybeUninit, panic::PanicInfo, ptr::addr_of_mut};
+
+use cortex_m::peripheral::{DCB, SCB};
+use cortex_m_rt::{exception, ExceptionFrame};
+use defmt::*;
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use picoserve::response::{Json, StatusCode};
+use smolweb_core::{
+    auth::RequireAuth,
+    error::ApiError,
+    panic_record::{PanicRecord, PanicReport},
+};
+
+#[link_section = ".uninit.PANIC_RECORD"]
+static mut PANIC_RECORD: MaybeUninit<PanicRecord> = MaybeUninit::uninit();
+
+/// The panic taken from [PANIC_RECORD] at boot.
+static LAST_PANIC: Mutex<CriticalSectionRawMutex, RefCell<Option<PanicReport>>> =
+    Mutex::new(RefCell::new(None));
+
+/// The record in RAM, whatever it holds: every bit pattern is a valid [PanicRecord], which checks its magic number.
+fn record() -> &'static mut PanicRecord {
+    // Only used by `init` before the tasks start, and by the handlers with interrupts disabled
+    unsafe { &mut *addr_of_mut!(PANIC_RECORD).cast::<PanicRecord>() }
+}
+
+/// Record a failure and reset the board, or break into the debugger if one is attached.
+fn record_and_reset(message: core::fmt::Arguments<'_>, program_counter: Option<u32>) -> ! {
+    cortex_m::interrupt::disable();
+    record().write(message, program_counter);
+
+    if DCB::is_debugger_attached() {
+        cortex_m::asm::udf();
+    }
+    SCB::sys_reset()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    error!("{}", Display2Format(info));
+    record_and_reset(format_args!("{info}"), None)
+}
+
+#[exception]
+unsafe fn HardFault(frame: &ExceptionFrame) -> ! {
+    error!("HardFault at {:#010x}", frame.pc());
+    record_and_reset(format_args!("HardFault"), Some(frame.pc()))
+}
+
+/// Take the panic recorded before the reset for `GET /api/panic`, before the tasks start.
+pub fn init() {
+    let report = record().take();
+
+    if let Some(report) = &report {
+        warn!("Reset after a panic: {}", report.message.as_str());
+    }
+
+    LAST_PANIC.lock(|last_panic| *last_panic.borrow_mut() = report);
+}
+
+/// `GET /api/panic`: the panic or hard fault which reset the board last, `404` if it started otherwise.
+pub async fn get_panic(_: RequireAuth) -> Result<Json<PanicReport>, ApiError> {
+    LAST_PANIC
+        .lock(|last_panic| last_panic.borrow().clone())
+        .map(Json)
+        .ok_or(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "The last reset was not caused by a panic",
+        ))
+}
@@ -0,0 +1,271 @@
+//! Last known LED state and the settings, kept in the last sector of the internal flash.
+//!
+//! Each save appends one record and the sector is only erased once it is full, when the latest
+//! LED state and settings are written back, so a toggle costs a single write instead of a sector erase.
+
+use core::cell::RefCell;
+
+use defmt::*;
+use embassy_stm32::flash::{FLASH_SIZE, MAX_ERASE_SIZE, WRITE_SIZE};
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{with_timeout, Duration};
+
+use smolweb_core::events::{BoardEvent, EventSubscriber};
+use smolweb_core::settings::{Settings, SettingsStore, MAX_JSON_SIZE};
+
+use crate::{BoardEventSubscriber, SharedFlash, BOARD_EVENTS, DIAGNOSTICS};
+
+const SECTOR_OFFSET: u32 = (FLASH_SIZE - MAX_ERASE_SIZE) as u32;
+/// Write units in the sector, each record taking one or more.
+const UNIT_COUNT: u32 = (MAX_ERASE_SIZE / WRITE_SIZE) as u32;
+
+/// First byte of a record holding the state of LED2, erased flash reads as `0xFF`.
+const LED_MAGIC: u8 = 0xA5;
+/// First byte of a record holding the settings, followed by the length of their JSON as a little endian `u16`, then the JSON.
+const SETTINGS_MAGIC: u8 = 0x5A;
+const SETTINGS_HEADER_SIZE: usize = 3;
+/// Bytes taken by the largest settings record.
+const MAX_SETTINGS_RECORD_SIZE: usize =
+    (SETTINGS_HEADER_SIZE + MAX_JSON_SIZE).div_ceil(WRITE_SIZE) * WRITE_SIZE;
+
+/// Changes closer together than this are coalesced into a single write.
+pub const SAVE_DELAY: Duration = Duration::from_secs(2);
+
+struct Contents {
+    /// Index of the first free write unit, [UNIT_COUNT] if the sector must be erased first.
+    next_unit: u32,
+    led2: Option<bool>,
+    settings: Option<Settings>,
+}
+
+pub struct Store {
+    flash: &'static SharedFlash,
+    /// Held while records are written and the sector erased, so only locked by the tasks of the thread-mode executor,
+    /// like the [SharedFlash], leaving interrupts enabled.
+    contents: Mutex<ThreadModeRawMutex, RefCell<Contents>>,
+}
+
+fn unit_offset(index: u32) -> u32 {
+    SECTOR_OFFSET + index * WRITE_SIZE as u32
+}
+
+fn led2_record(led2: bool) -> [u8; WRITE_SIZE] {
+    let mut record = [0; WRITE_SIZE];
+    record[0] = LED_MAGIC;
+    record[1] = u8::from(led2);
+    record
+}
+
+/// The record of `settings`, padded to whole write units, and its length.
+fn settings_record(settings: &Settings) -> ([u8; MAX_SETTINGS_RECORD_SIZE], usize) {
+    let json = settings.to_json();
+    let mut record = [0; MAX_SETTINGS_RECORD_SIZE];
+    record[0] = SETTINGS_MAGIC;
+    record[1..SETTINGS_HEADER_SIZE].copy_from_slice(&(json.len() as u16).to_le_bytes());
+    record[SETTINGS_HEADER_SIZE..][..json.len()].copy_from_slice(&json);
+    (
+        record,
+        (SETTINGS_HEADER_SIZE + json.len()).div_ceil(WRITE_SIZE) * WRITE_SIZE,
+    )
+}
+
+impl Contents {
+    /// Scan the sector for the latest records. A blank sector yields nothing saved.
+    fn read(flash: &SharedFlash) -> Self {
+        let mut contents = Self::erased();
+        let read = |offset: u32, bytes: &mut [u8]| {
+            flash.lock(|flash| flash.borrow_mut().blocking_read(offset, bytes))
+        };
+
+        let mut index = 0;
+        while index < UNIT_COUNT {
+            let mut record = [0; MAX_SETTINGS_RECORD_SIZE];
+
+            if let Err(err) = read(unit_offset(index), &mut record[..WRITE_SIZE]) {
+                warn!("Failed to read the store from flash: {}", err);
+                break;
+            }
+
+            if record[..WRITE_SIZE].iter().all(|&b| b == 0xFF) {
+                contents.next_unit = index;
+                break;
+            }
+
+            match record[0] {
+                LED_MAGIC => {
+                    contents.led2 = Some(record[1] != 0);
+                    index += 1;
+                }
+                SETTINGS_MAGIC => {
+                    let length = usize::from(u16::from_le_bytes([record[1], record[2]]));
+                    let size = (SETTINGS_HEADER_SIZE + length).div_ceil(WRITE_SIZE) * WRITE_SIZE;
+                    let units = (size / WRITE_SIZE) as u32;
+
+                    if size > MAX_SETTINGS_RECORD_SIZE || index + units > UNIT_COUNT {
+                        warn!("Truncated settings record, ignoring the store");
+                        return Self::erased();
+                    }
+
+                    if let Err(err) = read(unit_offset(index), &mut record[..size]) {
+                        warn!("Failed to read settings from flash: {}", err);
+                        break;
+                    }
+
+                    contents.settings =
+                        Settings::from_json(&record[SETTINGS_HEADER_SIZE..][..length]);
+                    if contents.settings.is_none() {
+                        warn!("Invalid settings in flash, using the defaults");
+                    }
+                    index += units;
+                }
+                _ => {
+                    // Not written by us, erase the sector before the next save
+                    warn!("Unexpected data in the store sector, ignoring it");
+                    return Self::erased();
+                }
+            }
+        }
+
+        contents
+    }
+
+    /// Nothing saved, with the sector to be erased before the next save.
+    fn erased() -> Self {
+        Self {
+            next_unit: UNIT_COUNT,
+            led2: None,
+            settings: None,
+        }
+    }
+}
+
+impl Store {
+    pub fn new(flash: &'static SharedFlash) -> Self {
+        Self {
+            flash,
+            contents: Mutex::new(RefCell::new(Contents::read(flash))),
+        }
+    }
+
+    /// State of LED2 at the last save, if any.
+    pub fn led2(&self) -> Option<bool> {
+        self.contents.lock(|contents| contents.borrow().led2)
+    }
+
+    fn write(&self, contents: &mut Contents, record: &[u8]) -> bool {
+        let offset = unit_offset(contents.next_unit);
+        // Even a failed write may have programmed part of the record, so it is never written over
+        contents.next_unit += (record.len() / WRITE_SIZE) as u32;
+
+        match self
+            .flash
+            .lock(|flash| flash.borrow_mut().blocking_write(offset, record))
+        {
+            Ok(()) => true,
+            Err(err) => {
+                warn!("Failed to write to the store: {}", err);
+                false
+            }
+        }
+    }
+
+    /// Append `record`, already applied to `contents`.
+    ///
+    /// When it doesn't fit, the sector is erased and the latest of every record written back instead.
+    fn append(&self, contents: &mut Contents, record: &[u8]) -> bool {
+        if contents.next_unit + (record.len() / WRITE_SIZE) as u32 <= UNIT_COUNT {
+            return self.write(contents, record);
+        }
+
+        // Stalls the other tasks while the sector is erased, which only happens once the sector is full
+        info!("Erasing store sector");
+
+        if let Err(err) = self.flash.lock(|flash| {
+            flash
+                .borrow_mut()
+                .blocking_erase(SECTOR_OFFSET, SECTOR_OFFSET + MAX_ERASE_SIZE as u32)
+        }) {
+            warn!("Failed to erase store sector: {}", err);
+            return false;
+        }
+
+        contents.next_unit = 0;
+
+        let led2 = contents.led2.map(led2_record);
+        let settings = contents.settings.as_ref().map(settings_record);
+
+        let led2_written = led2.map_or(true, |record| self.write(contents, &record));
+        let settings_written =
+            settings.map_or(true, |(record, size)| self.write(contents, &record[..size]));
+
+        led2_written && settings_written
+    }
+
+    fn save_led2(&self, led2: bool) {
+        self.contents.lock(|contents| {
+            let mut contents = contents.borrow_mut();
+
+            let saved = contents.led2;
+            if saved == Some(led2) {
+                return;
+            }
+
+            contents.led2 = Some(led2);
+            if self.append(&mut contents, &led2_record(led2)) {
+                debug!("Saved LED2 state: {}", led2);
+            } else {
+                // Tried again at the next change
+                contents.led2 = saved;
+            }
+        })
+    }
+}
+
+/// Settings are loaded at boot by `main`, so saved ones take effect at the next boot.
+impl SettingsStore for &'static Store {
+    fn settings(&self) -> Settings {
+        self.contents
+            .lock(|contents| contents.borrow().settings.clone())
+            .unwrap_or_default()
+    }
+
+    fn save(&self, settings: &Settings) -> bool {
+        let (record, size) = settings_record(settings);
+
+        self.contents.lock(|contents| {
+            let mut contents = contents.borrow_mut();
+            contents.settings = Some(settings.clone());
+            self.append(&mut contents, &record[..size])
+        })
+    }
+}
+
+/// Wait for the next change of LED2, skipping other events.
+async fn next_led2(events: &mut BoardEventSubscriber) -> bool {
+    loop {
+        if let BoardEvent::Led(change) = events.next_event().await {
+            if change.led == 2 {
+                return change.on;
+            }
+        }
+    }
+}
+
+/// Writes LED2 back to flash once it stops changing.
+#[embassy_executor::task]
+pub async fn persist_task(store: &'static Store) -> ! {
+    let mut events = BoardEventSubscriber(unwrap!(BOARD_EVENTS.subscriber()));
+
+    loop {
+        DIAGNOSTICS.heartbeat("persist");
+        let mut led2 = next_led2(&mut events).await;
+
+        while let Ok(newer_led2) = with_timeout(SAVE_DELAY, next_led2(&mut events)).await {
+            led2 = newer_led2;
+        }
+
+        store.save_led2(led2);
+        DIAGNOSTICS.report_stack("persist");
+    }
+}
@@ -0,0 +1,221 @@
+//! Assets read from the FAT file system of an SD card on SPI1, enabled by the `sdcard` feature.
+//!
+//! Only 8.3 file names are supported, e.g. `/static/css/theme.css` but not `/static/stylesheet.css`. Files uploaded to
+//! `/api/upload` are written to the card, into directories which must already exist.
+
+use core::cell::RefCell;
+
+use defmt::*;
+use embassy_stm32::dma::NoDma;
+use embassy_stm32::gpio::{Level, Output, Speed};
+use embassy_stm32::peripherals::{PA5, PA6, PB5, PD14, SPI1};
+use embassy_stm32::spi::{self, Spi};
+use embassy_stm32::time::{khz, mhz};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::Delay;
+use embedded_hal_bus::spi::ExclusiveDevice;
+use embedded_sdmmc::{
+    Mode, RawDirectory, RawFile, RawVolume, SdCard, TimeSource, Timestamp, VolumeIdx, VolumeManager,
+};
+use smolweb_core::assets::{AssetFile, AssetStore};
+use static_cell::make_static;
+
+type Card =
+    SdCard<ExclusiveDevice<Spi<'static, SPI1, NoDma, NoDma>, Output<'static>, Delay>, Delay>;
+
+type Volumes = VolumeManager<Card, NoTime>;
+
+/// The card is only accessed in short blocking transfers, so like [SharedFlash](crate::SharedFlash) it is shared by all web tasks.
+type SharedVolumes = Mutex<CriticalSectionRawMutex, RefCell<Volumes>>;
+
+type CardError = embedded_sdmmc::Error<embedded_sdmmc::SdCardError>;
+
+/// The board has no calendar clock when the card is opened, so uploaded files all get the same modification time.
+struct NoTime;
+
+impl TimeSource for NoTime {
+    fn get_timestamp(&self) -> Timestamp {
+        Timestamp::from_calendar(1980, 1, 1, 0, 0, 0).unwrap()
+    }
+}
+
+/// The first partition of the SD card, or no files if there is no card.
+#[derive(Clone, Copy)]
+pub struct SdCardAssets {
+    volumes: &'static SharedVolumes,
+    volume: Option<RawVolume>,
+}
+
+impl SdCardAssets {
+    /// Open the card wired to SCK = PA5 (D13), MISO = PA6 (D12), MOSI = PB5 (D22) and CS = PD14 (D10).
+    ///
+    /// MOSI isn't on D11, as PA7 is the RMII CRS_DV pin of the Ethernet PHY.
+    pub fn new(spi: SPI1, sck: PA5, mosi: PB5, miso: PA6, cs: PD14) -> Self {
+        // Cards must be initialized at 400 kHz at most
+        let mut config = spi::Config::default();
+        config.frequency = khz(400);
+        let spi = Spi::new_blocking(spi, sck, mosi, miso, config);
+        let cs = Output::new(cs, Level::High, Speed::VeryHigh);
+        let card = SdCard::new(ExclusiveDevice::new(spi, cs, Delay), Delay);
+
+        match card.num_bytes() {
+            Ok(size) => {
+                info!("SD card of {} MB", size / 1_000_000);
+                config.frequency = mhz(16);
+                card.spi(|device| unwrap!(device.bus_mut().set_config(&config)));
+            }
+            Err(err) => warn!("No SD card: {:?}", Debug2Format(&err)),
+        }
+
+        let mut volumes = VolumeManager::new(card, NoTime);
+        let volume = volumes
+            .open_raw_volume(VolumeIdx(0))
+            .inspect_err(|err| warn!("No FAT volume on the SD card: {:?}", Debug2Format(err)))
+            .ok();
+
+        Self {
+            volumes: make_static!(Mutex::new(RefCell::new(volumes))),
+            volume,
+        }
+    }
+}
+
+/// Open the directory of the file at `path`, closing those on the way, and return it with the name of the file.
+fn open_parent<'a>(
+    volumes: &mut Volumes,
+    volume: RawVolume,
+    path: &'a str,
+) -> Result<(RawDirectory, &'a str), CardError> {
+    let mut segments = path.split('/').filter(|segment| !segment.is_empty());
+    let name = segments
+        .next_back()
+        .ok_or(embedded_sdmmc::Error::NotFound)?;
+
+    let mut dir = volumes.open_root_dir(volume)?;
+    for segment in segments {
+        let child = volumes.open_dir(dir, segment);
+        volumes.close_dir(dir)?;
+        dir = child?;
+    }
+
+    Ok((dir, name))
+}
+
+/// Open the file at `path` in `mode`, closing the directories on the way.
+fn open_file(
+    volumes: &mut Volumes,
+    volume: RawVolume,
+    path: &str,
+    mode: Mode,
+) -> Result<RawFile, CardError> {
+    let (dir, name) = open_parent(volumes, volume, path)?;
+    let file = volumes.open_file_in_dir(dir, name, mode);
+    volumes.close_dir(dir)?;
+    file
+}
+
+/// Write `bytes` at `offset` of the file at `path`, creating it or emptying it at offset 0.
+fn write_file(
+    volumes: &mut Volumes,
+    volume: RawVolume,
+    path: &str,
+    offset: usize,
+    bytes: &[u8],
+) -> Result<(), CardError> {
+    let file = match offset {
+        0 => open_file(volumes, volume, path, Mode::ReadWriteCreateOrTruncate)?,
+        _ => open_file(volumes, volume, path, Mode::ReadWriteAppend)?,
+    };
+
+    let written = volumes
+        .file_seek_from_start(file, offset as u32)
+        .and_then(|()| volumes.write(file, bytes));
+    // Closing the file updates its size in the directory
+    volumes.close_file(file)?;
+    written
+}
+
+impl AssetStore for SdCardAssets {
+    type File = SdCardFile;
+
+    async fn open(&self, path: &str) -> Option<SdCardFile> {
+        let volume = self.volume?;
+
+        self.volumes.lock(|volumes| {
+            let mut volumes = volumes.borrow_mut();
+            let file = open_file(&mut volumes, volume, path, Mode::ReadOnly).ok()?;
+
+            match volumes.file_length(file) {
+                Ok(size) => Some(SdCardFile {
+                    volumes: self.volumes,
+                    file,
+                    size: size as usize,
+                }),
+                Err(_) => {
+                    let _ = volumes.close_file(file);
+                    None
+                }
+            }
+        })
+    }
+
+    fn is_writable(&self) -> bool {
+        self.volume.is_some()
+    }
+
+    async fn write(&self, path: &str, offset: usize, bytes: &[u8]) -> bool {
+        let Some(volume) = self.volume else {
+            return false;
+        };
+
+        self.volumes
+            .lock(|volumes| write_file(&mut volumes.borrow_mut(), volume, path, offset, bytes))
+            .inspect_err(|err| warn!("SD card write failed: {:?}", Debug2Format(err)))
+            .is_ok()
+    }
+
+    async fn remove(&self, path: &str) -> bool {
+        let Some(volume) = self.volume else {
+            return false;
+        };
+
+        self.volumes
+            .lock(|volumes| {
+                let mut volumes = volumes.borrow_mut();
+                let (dir, name) = open_parent(&mut volumes, volume, path)?;
+                let removed = volumes.delete_file_in_dir(dir, name);
+                volumes.close_dir(dir)?;
+                removed
+            })
+            .inspect_err(|err| warn!("SD card delete failed: {:?}", Debug2Format(err)))
+            .is_ok()
+    }
+}
+
+/// A file open on the SD card, closed when dropped.
+pub struct SdCardFile {
+    volumes: &'static SharedVolumes,
+    file: RawFile,
+    size: usize,
+}
+
+impl AssetFile for SdCardFile {
+    fn size(&self) -> usize {
+        self.size
+    }
+
+    async fn read(&mut self, buffer: &mut [u8]) -> Option<usize> {
+        self.volumes
+            .lock(|volumes| volumes.borrow_mut().read(self.file, buffer))
+            .inspect_err(|err| warn!("SD card read failed: {:?}", Debug2Format(err)))
+            .ok()
+    }
+}
+
+impl Drop for SdCardFile {
+    fn drop(&mut self) {
+        self.volumes.lock(|volumes| {
+            let _ = volumes.borrow_mut().close_file(self.file);
+        });
+    }
+}
@@ -0,0 +1,170 @@
+//! Independent watchdog, fed only while every supervised task keeps sending heartbeats.
+//!
+//! Each task calls [beat] in its own loop once it made progress, and waits for work at most [IDLE_TIMEOUT] so that
+//! an idle task still beats. A task stuck in a blocking loop, or whose future is never woken again, stops beating and
+//! the IWDG resets the board, which then reports [ResetCause::Watchdog] in `/api/sysinfo`. The network stack beats
+//! through its [Supervised] driver, each time it polls it.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::task::Context;
+
+use defmt::*;
+use embassy_net::driver::{Capabilities, Driver, HardwareAddress, LinkState};
+use embassy_stm32::peripherals::IWDG1;
+use embassy_stm32::wdg::IndependentWatchdog;
+use embassy_time::{Duration, Instant, Timer};
+use smolweb_core::diagnostics::ResetCause;
+
+use crate::WEB_TASK_POOL_SIZE;
+
+/// How often the supervisor checks the heartbeats.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A task whose last heartbeat is older than this is considered hung.
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest a supervised task waits for work before beating again.
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Longest a web task serves a connection, which then closes so that the task beats again. The page reopens its
+/// streams, `/events` and `/ws`, when they close.
+pub const SERVE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Time left to the firmware once the watchdog is no longer fed.
+const WATCHDOG_TIMEOUT: Duration = Duration::from_secs(8);
+
+/// Supervised tasks, each the index of its heartbeat.
+pub const NET_TASK: usize = 0;
+pub const PATTERN_TASK: usize = 1;
+/// Heartbeat of web task `id`.
+pub const fn web_task(id: usize) -> usize {
+    2 + id
+}
+const TASK_COUNT: usize = 2 + WEB_TASK_POOL_SIZE;
+
+#[allow(clippy::declare_interior_mutable_const)] // Only used to initialize the array
+const NEVER: AtomicU32 = AtomicU32::new(0);
+
+/// Time of the last heartbeat of each task, in milliseconds since boot wrapping around every 49 days.
+static HEARTBEATS: [AtomicU32; TASK_COUNT] = [NEVER; TASK_COUNT];
+
+fn now_millis() -> u32 {
+    Instant::now().as_millis() as u32
+}
+
+/// Record that `task` made progress.
+pub fn beat(task: usize) {
+    HEARTBEATS[task].store(now_millis(), Ordering::Relaxed);
+}
+
+/// How long `task` may go without beating: [HEARTBEAT_TIMEOUT], on top of [SERVE_TIMEOUT] for the web tasks.
+fn heartbeat_timeout(task: usize) -> Duration {
+    if task >= web_task(0) {
+        SERVE_TIMEOUT + HEARTBEAT_TIMEOUT
+    } else {
+        HEARTBEAT_TIMEOUT
+    }
+}
+
+/// The first supervised task whose heartbeat is older than its [heartbeat_timeout], if any.
+fn hung_task() -> Option<usize> {
+    let now = now_millis();
+
+    HEARTBEATS.iter().enumerate().position(|(task, heartbeat)| {
+        let age = now.wrapping_sub(heartbeat.load(Ordering::Relaxed));
+        u64::from(age) > heartbeat_timeout(task).as_millis()
+    })
+}
+
+/// Network driver beating for [NET_TASK] each time the stack polls it, which it does on every packet, socket change
+/// and timer of the stack, e.g. whenever a web task starts listening again.
+pub struct Supervised<D>(pub D);
+
+impl<D: Driver> Driver for Supervised<D> {
+    type RxToken<'a>
+        = D::RxToken<'a>
+    where
+        Self: 'a;
+    type TxToken<'a>
+        = D::TxToken<'a>
+    where
+        Self: 'a;
+
+    fn receive(&mut self, cx: &mut Context) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        self.0.receive(cx)
+    }
+
+    fn transmit(&mut self, cx: &mut Context) -> Option<Self::TxToken<'_>> {
+        self.0.transmit(cx)
+    }
+
+    // Called once per poll of the stack
+    fn link_state(&mut self, cx: &mut Context) -> LinkState {
+        beat(NET_TASK);
+        self.0.link_state(cx)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.0.capabilities()
+    }
+
+    fn hardware_address(&self) -> HardwareAddress {
+        self.0.hardware_address()
+    }
+}
+
+/// The flags of `RCC_RSR` telling why the board started, which are then cleared for the next reset.
+pub fn read_reset_flags() -> heapless::Vec<ResetCause, 6> {
+    let rcc = embassy_stm32::pac::RCC;
+    let rsr = rcc.rsr().read();
+    rcc.rsr().modify(|w| w.set_rmvf(true));
+
+    [
+        (rsr.porrstf(), ResetCause::PowerOn),
+        (rsr.borrstf(), ResetCause::Brownout),
+        (rsr.iwdg1rstf() || rsr.wwdg1rstf(), ResetCause::Watchdog),
+        (rsr.sftrstf(), ResetCause::Software),
+        (rsr.lpwrrstf(), ResetCause::LowPower),
+        (rsr.pinrstf(), ResetCause::Pin),
+    ]
+    .into_iter()
+    .filter(|&(set, _)| set)
+    .map(|(_, flag)| flag)
+    .collect()
+}
+
+/// Feeds the IWDG while every supervised task beats, and stops once one of them doesn't.
+#[embassy_executor::task]
+pub async fn supervisor_task(mut watchdog: IndependentWatchdog<'static, IWDG1>) -> ! {
+    // The tasks get a full timeout to send their first heartbeat
+    for task in 0..TASK_COUNT {
+        beat(task);
+    }
+
+    watchdog.unleash();
+    info!(
+        "Watchdog started, resetting {} ms after a task hangs, {} ms for a web task",
+        (HEARTBEAT_TIMEOUT + WATCHDOG_TIMEOUT).as_millis(),
+        (heartbeat_timeout(web_task(0)) + WATCHDOG_TIMEOUT).as_millis()
+    );
+
+    loop {
+        match hung_task() {
+            None => watchdog.pet(),
+            Some(task) => {
+                // Not fed again, the reset follows within WATCHDOG_TIMEOUT
+                error!("Task {} stopped sending heartbeats", task);
+                loop {
+                    Timer::after(HEARTBEAT_INTERVAL).await;
+                }
+            }
+        }
+
+        Timer::after(HEARTBEAT_INTERVAL).await;
+    }
+}
+
+/// The IWDG of the board, expiring after [WATCHDOG_TIMEOUT] without being fed.
+pub fn new_watchdog(iwdg: IWDG1) -> IndependentWatchdog<'static, IWDG1> {
+    IndependentWatchdog::new(iwdg, WATCHDOG_TIMEOUT.as_micros() as u32)
+}
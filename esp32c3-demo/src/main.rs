@@ -0,0 +1,618 @@
+#![no_std]
+#![no_main]
+#![recursion_limit = "256"]
+
+use core::cell::RefCell;
+
+use embassy_executor::Spawner;
+use embassy_futures::join::join_array;
+use embassy_net::{tcp::TcpSocket, Stack, StackResources};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::pubsub::{PubSubChannel, Subscriber};
+use embassy_time::{Duration, Instant, Timer};
+use esp_backtrace as _;
+use esp_hal::clock::ClockControl;
+use esp_hal::gpio::{GpioPin, Input, Io, Level, Output, Pull};
+use esp_hal::peripherals::Peripherals;
+use esp_hal::rng::Rng;
+use esp_hal::system::SystemControl;
+use esp_hal::timer::{
+    systimer::SystemTimer, timg::TimerGroup, ErasedTimer, OneShotTimer, PeriodicTimer,
+};
+use esp_wifi::wifi::{
+    ClientConfiguration, Configuration, WifiController, WifiDevice, WifiEvent, WifiStaDevice,
+    WifiState,
+};
+use esp_wifi::EspWifiInitFor;
+use log::{info, warn};
+use smolweb_core::sntp::SntpClock;
+use smolweb_core::{
+    access_log::ConnectionId,
+    assets::NoAssetStore,
+    auth::Credentials,
+    buffers::BufferConfig,
+    button::ButtonStats,
+    cors::CorsOrigins,
+    diagnostics::Diagnostics,
+    events::{BoardEvent, BoardEvents, EventSubscriber, LedChange},
+    limits::{CutShort, HeadCut, LimitedSocket, Limits},
+    logs::TeeLogger,
+    metrics::Metrics,
+    rate_limit::{
+        ClientAddress, ClientRateLimit, ToggleRateLimit, CLIENT_BURST, CLIENT_REFILL_INTERVAL,
+    },
+    sensors::SensorStats,
+    session::{SessionContext, Sessions, KEY_LEN},
+    settings::NoSettingsStore,
+    status_page::LocalAddress,
+    time::Clock,
+    LedControl,
+};
+use static_cell::StaticCell;
+
+/// Prints records to the serial console like `esp_println::logger`, while [LOGGER] keeps them for `/logs`.
+struct SerialLogger;
+
+impl log::Log for SerialLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &log::Record) {
+        esp_println::println!("{} - {}", record.level(), record.args());
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: TeeLogger<SerialLogger> = TeeLogger(SerialLogger);
+
+/// Network joined at boot, set with `WIFI_SSID` and `WIFI_PASSWORD` when building.
+const WIFI_SSID: &str = match option_env!("WIFI_SSID") {
+    Some(ssid) => ssid,
+    None => "smolweb",
+};
+const WIFI_PASSWORD: &str = match option_env!("WIFI_PASSWORD") {
+    Some(password) => password,
+    None => "smolweb-password",
+};
+const _: () = assert!(WIFI_SSID.len() <= 32, "WIFI_SSID is longer than 32 bytes");
+const _: () = assert!(
+    WIFI_PASSWORD.len() <= 64,
+    "WIFI_PASSWORD is longer than 64 bytes"
+);
+
+/// Delay before trying again after failing to join [WIFI_SSID] or losing it.
+const JOIN_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+type WifiStack = Stack<WifiDevice<'static, WifiStaDevice>>;
+
+/// Joins [WIFI_SSID], and joins it again whenever the access point is lost.
+#[embassy_executor::task]
+async fn wifi_task(mut controller: WifiController<'static>) -> ! {
+    loop {
+        if esp_wifi::wifi::get_wifi_state() == WifiState::StaConnected {
+            controller.wait_for_event(WifiEvent::StaDisconnected).await;
+            warn!("Lost {}", WIFI_SSID);
+            Timer::after(JOIN_RETRY_INTERVAL).await;
+        }
+
+        if !matches!(controller.is_started(), Ok(true)) {
+            let config = Configuration::Client(ClientConfiguration {
+                // Both fit, as they are checked when building
+                ssid: WIFI_SSID.try_into().unwrap(),
+                password: WIFI_PASSWORD.try_into().unwrap(),
+                ..Default::default()
+            });
+            controller.set_configuration(&config).unwrap();
+            controller.start().await.unwrap();
+        }
+
+        match controller.connect().await {
+            Ok(()) => info!("Joined {}", WIFI_SSID),
+            Err(err) => {
+                warn!("Failed to join {}: {:?}", WIFI_SSID, err);
+                Timer::after(JOIN_RETRY_INTERVAL).await;
+            }
+        }
+    }
+}
+
+#[embassy_executor::task]
+async fn net_task(stack: &'static WifiStack) -> ! {
+    stack.run().await
+}
+
+/// Server queried for the time, resolved through DNS.
+const NTP_SERVER: &str = "pool.ntp.org";
+
+#[embassy_executor::task]
+async fn sntp_task(stack: &'static WifiStack) -> ! {
+    smolweb_core::sntp::run(stack, NTP_SERVER).await
+}
+
+/// Redirects `http://<board>/` to the web servers, so that the port can be left out of the URL.
+#[embassy_executor::task]
+async fn redirect_task(stack: &'static WifiStack) -> ! {
+    smolweb_core::redirect::run(stack, smolweb_core::DEFAULT_PORT).await
+}
+
+/// Broadcasts the address of the board for `discover` on the LAN, under the default hostname as nothing is stored.
+#[embassy_executor::task]
+async fn discovery_task(stack: &'static WifiStack) -> ! {
+    smolweb_core::discovery::run(
+        stack,
+        "smolweb",
+        smolweb_core::DEFAULT_PORT,
+        env!("CARGO_PKG_VERSION"),
+    )
+    .await
+}
+
+/// Events buffered for a subscriber before the oldest ones are dropped.
+const BOARD_EVENT_CAPACITY: usize = 4;
+
+/// Notifies the WebSockets and event streams of LED changes, button presses and uptime, one subscriber per web server.
+static BOARD_EVENTS: PubSubChannel<
+    CriticalSectionRawMutex,
+    BoardEvent,
+    BOARD_EVENT_CAPACITY,
+    WEB_SERVER_COUNT,
+    0,
+> = PubSubChannel::new();
+
+fn publish(event: BoardEvent) {
+    BOARD_EVENTS.immediate_publisher().publish_immediate(event);
+}
+
+/// Subscription to [BOARD_EVENTS] held by an open WebSocket or event stream.
+struct BoardEventSubscriber(
+    Subscriber<
+        'static,
+        CriticalSectionRawMutex,
+        BoardEvent,
+        BOARD_EVENT_CAPACITY,
+        WEB_SERVER_COUNT,
+        0,
+    >,
+);
+
+impl EventSubscriber for BoardEventSubscriber {
+    async fn next_event(&mut self) -> BoardEvent {
+        self.0.next_message_pure().await
+    }
+}
+
+/// Shown by `/api/sysinfo`. The stack isn't measured here, so no task reports into it, and only [uptime_task] beats for
+/// `/readyz`.
+static DIAGNOSTICS: Diagnostics =
+    Diagnostics::new(env!("CARGO_PKG_VERSION"), &[]).with_heartbeats(&["uptime"]);
+
+/// Publishes [BoardEvent::Uptime] every [smolweb_core::events::UPTIME_INTERVAL].
+#[embassy_executor::task]
+async fn uptime_task() -> ! {
+    let interval = Duration::from_secs(smolweb_core::events::UPTIME_INTERVAL.as_secs());
+
+    loop {
+        DIAGNOSTICS.heartbeat("uptime");
+        Timer::after(interval).await;
+        publish(BoardEvent::Uptime(Instant::now().as_secs()));
+    }
+}
+
+/// The onboard LED of ESP32-C3 SuperMini boards on GPIO8, lit while the pin is low.
+///
+/// It is LED2, so the page and the API are the same as on the Nucleo.
+type Led = Output<'static, GpioPin<8>>;
+
+/// Shared by the web servers and [button_task].
+///
+/// The blocking mutex is only held for the duration of a closure which never awaits.
+#[derive(Clone, Copy)]
+struct SharedControl(&'static Mutex<CriticalSectionRawMutex, RefCell<Led>>);
+
+impl SharedControl {
+    fn notify(&self, led: u8) {
+        let on = self.state(led);
+        publish(BoardEvent::Led(LedChange { led, on }));
+    }
+
+    fn with_led<R>(&self, f: impl FnOnce(&mut Led) -> R) -> R {
+        self.0.lock(|led| f(&mut led.borrow_mut()))
+    }
+}
+
+impl LedControl for SharedControl {
+    fn has_led(&self, led: u8) -> bool {
+        led == 2
+    }
+
+    fn toggle(&self, led: u8) {
+        self.with_led(Led::toggle);
+        self.notify(led);
+    }
+
+    fn set(&self, led: u8, on: bool) {
+        self.with_led(|pin| pin.set_level(if on { Level::Low } else { Level::High }));
+        self.notify(led);
+    }
+
+    fn state(&self, _led: u8) -> bool {
+        self.with_led(|pin| pin.is_set_low())
+    }
+}
+
+impl BoardEvents for SharedControl {
+    type Subscriber = BoardEventSubscriber;
+
+    fn subscribe(&self) -> Option<BoardEventSubscriber> {
+        BOARD_EVENTS.subscriber().ok().map(BoardEventSubscriber)
+    }
+}
+
+/// Time for the contacts of the button to settle after a change.
+const BUTTON_DEBOUNCE: Duration = Duration::from_millis(20);
+
+/// Toggles LED2 when BOOT (GPIO9, low while pressed) is pressed.
+#[embassy_executor::task]
+async fn button_task(
+    mut button: Input<'static, GpioPin<9>>,
+    shared_control: SharedControl,
+    button_stats: &'static ButtonStats,
+) -> ! {
+    loop {
+        button.wait_for_falling_edge().await;
+        Timer::after(BUTTON_DEBOUNCE).await;
+
+        if button.is_low() {
+            info!("Button pressed");
+            button_stats.record_press(SntpClock.uptime());
+            publish(BoardEvent::ButtonPressed);
+            shared_control.toggle(2);
+        }
+
+        button.wait_for_high().await;
+        Timer::after(BUTTON_DEBOUNCE).await;
+    }
+}
+
+/// Minimum interval between two toggles of the same LED.
+const MIN_TOGGLE_INTERVAL: core::time::Duration = core::time::Duration::from_millis(500);
+
+struct AppState {
+    shared_control: SharedControl,
+    metrics: &'static Metrics,
+    diagnostics: &'static Diagnostics,
+    toggle_rate_limit: &'static ToggleRateLimit,
+    client_rate_limit: &'static ClientRateLimit,
+    sessions: &'static Sessions,
+    button_stats: &'static ButtonStats,
+    /// Never sampled, there is no sensor on this board.
+    sensor_stats: &'static SensorStats,
+    /// Set for each accepted connection, so that its requests are logged with it.
+    connection: ConnectionId,
+    /// Address the connection was accepted on, shown by the page.
+    local_address: LocalAddress,
+    /// Address the connection was accepted from, whose requests the [ClientRateLimit] counts.
+    client_address: ClientAddress,
+    /// Set by the [LimitedSocket] of the connection when it cuts a head short, so that the request is refused.
+    head_cut: HeadCut,
+}
+
+impl picoserve::extract::FromRef<AppState> for LocalAddress {
+    fn from_ref(state: &AppState) -> Self {
+        state.local_address
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for ClientAddress {
+    fn from_ref(state: &AppState) -> Self {
+        state.client_address
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for ConnectionId {
+    fn from_ref(state: &AppState) -> Self {
+        state.connection
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for SharedControl {
+    fn from_ref(state: &AppState) -> Self {
+        state.shared_control
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for SntpClock {
+    fn from_ref(_state: &AppState) -> Self {
+        SntpClock
+    }
+}
+
+/// Set when building, see [Credentials::from_build_env].
+impl picoserve::extract::FromRef<AppState> for Credentials {
+    fn from_ref(_state: &AppState) -> Self {
+        Credentials::from_build_env()
+    }
+}
+
+/// Set when building, see [CorsOrigins::from_build_env].
+impl picoserve::extract::FromRef<AppState> for CorsOrigins {
+    fn from_ref(_state: &AppState) -> Self {
+        CorsOrigins::from_build_env()
+    }
+}
+
+/// Those of the buffers of the web workers, which [LimitedSocket::new] also cuts heads to.
+impl picoserve::extract::FromRef<AppState> for Limits {
+    fn from_ref(_state: &AppState) -> Self {
+        Limits::WEB
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for CutShort {
+    fn from_ref(state: &AppState) -> Self {
+        state.head_cut.get()
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for SessionContext {
+    fn from_ref(state: &AppState) -> Self {
+        SessionContext {
+            sessions: state.sessions,
+            uptime: SntpClock.uptime(),
+        }
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for NoAssetStore {
+    fn from_ref(_state: &AppState) -> Self {
+        NoAssetStore
+    }
+}
+
+/// Nothing is stored, so the default settings always apply.
+impl picoserve::extract::FromRef<AppState> for NoSettingsStore {
+    fn from_ref(_state: &AppState) -> Self {
+        NoSettingsStore
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for &'static Diagnostics {
+    fn from_ref(state: &AppState) -> Self {
+        state.diagnostics
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for &'static Metrics {
+    fn from_ref(state: &AppState) -> Self {
+        state.metrics
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for &'static ToggleRateLimit {
+    fn from_ref(state: &AppState) -> Self {
+        state.toggle_rate_limit
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for &'static ClientRateLimit {
+    fn from_ref(state: &AppState) -> Self {
+        state.client_rate_limit
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for &'static ButtonStats {
+    fn from_ref(state: &AppState) -> Self {
+        state.button_stats
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for &'static SensorStats {
+    fn from_ref(state: &AppState) -> Self {
+        state.sensor_stats
+    }
+}
+
+const WEB_SERVER_COUNT: usize = 4;
+
+/// Sockets used on top of the web servers: one each for DHCP, DNS, SNTP, the discovery beacon and the port 80
+/// redirect.
+const STACK_SOCKETS: usize = 5;
+
+/// Serves `app` on one socket at a time, with the same loop as the other demos.
+///
+/// Like on the Pico W, the servers run joined inside `main` so that the router type never has to be named.
+async fn web_server(
+    id: usize,
+    stack: &'static WifiStack,
+    app: &picoserve::Router<impl picoserve::routing::PathRouter<AppState>, AppState>,
+    config: &picoserve::Config<Duration>,
+    mut state: AppState,
+) -> ! {
+    let port = smolweb_core::DEFAULT_PORT;
+    let mut tcp_rx_buffer = [0; BufferConfig::WEB.tcp_rx];
+    let mut tcp_tx_buffer = [0; BufferConfig::WEB.tcp_tx];
+    let mut http_buffer = [0; BufferConfig::WEB.http];
+
+    // Listed by `/api/workers` even before its first connection
+    state.metrics.start_worker(id);
+
+    loop {
+        // Don't listen while the stack has no address, e.g. after losing the access point
+        if !stack.is_config_up() {
+            DIAGNOSTICS.set_network_up(false);
+            info!("{}: Waiting for the network", id);
+            stack.wait_config_up().await;
+        }
+        DIAGNOSTICS.set_network_up(true);
+
+        let mut socket = TcpSocket::new(stack, &mut tcp_rx_buffer, &mut tcp_tx_buffer);
+
+        if let Err(err) = socket.accept(port).await {
+            warn!("{}: Accept error: {:?}", id, err);
+            continue;
+        }
+
+        let remote_endpoint = socket.remote_endpoint();
+        state.connection = ConnectionId::next();
+        state.local_address = LocalAddress::of_socket(&socket);
+        state.client_address = ClientAddress::of_socket(&socket);
+        info!(
+            "{}: Connection {} from {:?}",
+            id, state.connection, remote_endpoint
+        );
+        let connection = state.metrics.open_connection(id);
+        let socket = LimitedSocket::new(connection.count_bytes(socket), &state.head_cut);
+
+        match picoserve::serve_with_state(app, config, &mut http_buffer, socket, &state).await {
+            Ok(handled_requests_count) => {
+                connection.served(handled_requests_count);
+                info!(
+                    "{}: {} requests handled from {:?}",
+                    id, handled_requests_count, remote_endpoint
+                )
+            }
+            Err(err) => warn!("{}: {:?}", id, err),
+        }
+    }
+}
+
+#[esp_hal_embassy::main]
+async fn main(spawner: Spawner) {
+    // The ESP32-C3 has no atomic compare-and-swap, and nothing logs before this
+    unsafe {
+        let level = option_env!("ESP_LOGLEVEL")
+            .and_then(|level| level.parse().ok())
+            .unwrap_or(log::LevelFilter::Info);
+        log::set_max_level_racy(level);
+        let _ = log::set_logger_racy(&LOGGER);
+    }
+
+    let peripherals = Peripherals::take();
+    let system = SystemControl::new(peripherals.SYSTEM);
+    let clocks = ClockControl::max(system.clock_control).freeze();
+    DIAGNOSTICS.set_cpu_frequency(clocks.cpu_clock.to_Hz());
+
+    info!("Hello World!");
+
+    // Generate random seed.
+    let mut rng = Rng::new(peripherals.RNG);
+    let seed = u64::from(rng.random()) << 32 | u64::from(rng.random());
+
+    // Drawn at each boot, so that session cookies don't outlive it
+    let mut session_key = [0; KEY_LEN];
+    for word in session_key.chunks_mut(4) {
+        word.copy_from_slice(&rng.random().to_le_bytes());
+    }
+    static SESSIONS: StaticCell<Sessions> = StaticCell::new();
+    let sessions: &'static Sessions = SESSIONS.init(Sessions::new(session_key));
+
+    let timg0 = TimerGroup::new(peripherals.TIMG0, &clocks, None);
+    let wifi_init = esp_wifi::initialize(
+        EspWifiInitFor::Wifi,
+        PeriodicTimer::new(ErasedTimer::from(timg0.timer0)),
+        rng,
+        peripherals.RADIO_CLK,
+        &clocks,
+    )
+    .unwrap();
+
+    static WIFI_INIT: StaticCell<esp_wifi::EspWifiInitialization> = StaticCell::new();
+    let (device, controller) =
+        esp_wifi::wifi::new_with_mode(WIFI_INIT.init(wifi_init), peripherals.WIFI, WifiStaDevice)
+            .unwrap();
+
+    static TIMERS: StaticCell<[OneShotTimer<ErasedTimer>; 1]> = StaticCell::new();
+    let systimer = SystemTimer::new(peripherals.SYSTIMER);
+    esp_hal_embassy::init(
+        &clocks,
+        TIMERS.init([OneShotTimer::new(ErasedTimer::from(systimer.alarm0))]),
+    );
+
+    let io = Io::new(peripherals.GPIO, peripherals.IO_MUX);
+    static LED: StaticCell<Mutex<CriticalSectionRawMutex, RefCell<Led>>> = StaticCell::new();
+    // Lit at boot, like the LEDs of the other demos
+    let led = Output::new(io.pins.gpio8, Level::Low);
+    let shared_control = SharedControl(LED.init(Mutex::new(RefCell::new(led))));
+
+    let config = embassy_net::Config::dhcpv4(Default::default());
+
+    // Init network stack
+    static STACK: StaticCell<WifiStack> = StaticCell::new();
+    static RESOURCES: StaticCell<StackResources<{ WEB_SERVER_COUNT + STACK_SOCKETS }>> =
+        StaticCell::new();
+    let stack = &*STACK.init(Stack::new(
+        device,
+        config,
+        RESOURCES.init(StackResources::<{ WEB_SERVER_COUNT + STACK_SOCKETS }>::new()),
+        seed,
+    ));
+
+    spawner.must_spawn(wifi_task(controller));
+    spawner.must_spawn(net_task(stack));
+
+    // Ensure network configuration is up before trying connect
+    stack.wait_config_up().await;
+
+    info!("Network task initialized");
+
+    if let Some(config) = stack.config_v4() {
+        info!("IP address: {}", config.address);
+    }
+
+    static BUTTON_STATS: ButtonStats = ButtonStats::new();
+    static SENSOR_STATS: SensorStats = SensorStats::new();
+    let button = Input::new(io.pins.gpio9, Pull::Up);
+
+    spawner.must_spawn(sntp_task(stack));
+    spawner.must_spawn(discovery_task(stack));
+    spawner.must_spawn(redirect_task(stack));
+    spawner.must_spawn(uptime_task());
+    spawner.must_spawn(button_task(button, shared_control, &BUTTON_STATS));
+
+    let app = smolweb_core::make_app::<
+        AppState,
+        SharedControl,
+        SntpClock,
+        NoAssetStore,
+        NoSettingsStore,
+    >();
+
+    let config = picoserve::Config::new(picoserve::Timeouts {
+        start_read_request: Some(Duration::from_secs(5)),
+        read_request: Some(Duration::from_secs(1)),
+        write: Some(Duration::from_secs(1)),
+    })
+    .keep_connection_alive();
+
+    static METRICS: Metrics = Metrics::new();
+    static TOGGLE_RATE_LIMIT: ToggleRateLimit = ToggleRateLimit::new(MIN_TOGGLE_INTERVAL);
+    static CLIENT_RATE_LIMIT: ClientRateLimit =
+        ClientRateLimit::new(CLIENT_BURST, CLIENT_REFILL_INTERVAL);
+
+    join_array(core::array::from_fn::<_, WEB_SERVER_COUNT, _>(|id| {
+        web_server(
+            id,
+            stack,
+            &app,
+            &config,
+            AppState {
+                shared_control,
+                metrics: &METRICS,
+                diagnostics: &DIAGNOSTICS,
+                toggle_rate_limit: &TOGGLE_RATE_LIMIT,
+                client_rate_limit: &CLIENT_RATE_LIMIT,
+                sessions,
+                button_stats: &BUTTON_STATS,
+                sensor_stats: &SENSOR_STATS,
+                connection: ConnectionId(0),
+                local_address: LocalAddress::default(),
+                client_address: ClientAddress::default(),
+                head_cut: HeadCut::new(),
+            },
+        )
+    }))
+    .await;
+}
@@ -0,0 +1,730 @@
+#![no_std]
+#![no_main]
+#![recursion_limit = "256"]
+
+use core::cell::RefCell;
+use core::sync::atomic::Ordering;
+use cyw43_pio::PioSpi;
+use defmt::*;
+use embassy_executor::Spawner;
+use embassy_futures::join::join_array;
+use embassy_futures::select::select;
+use embassy_net::{tcp::TcpSocket, Stack, StackResources};
+use embassy_rp::bind_interrupts;
+use embassy_rp::clocks::RoscRng;
+use embassy_rp::flash::Flash;
+use embassy_rp::gpio::{Level, Output};
+use embassy_rp::peripherals::{DMA_CH0, PIN_23, PIN_25, PIO0, PIO1};
+use embassy_rp::pio::{InterruptHandler, Pio};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::pubsub::{PubSubChannel, Subscriber};
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Timer};
+use portable_atomic::AtomicBool;
+use rand_core::RngCore;
+use smolweb_core::provisioning::{self, WifiCredentials};
+use smolweb_core::sntp::SntpClock;
+use smolweb_core::{
+    access_log::ConnectionId,
+    assets::NoAssetStore,
+    auth::Credentials,
+    buffers::BufferConfig,
+    button::ButtonStats,
+    cors::CorsOrigins,
+    diagnostics::Diagnostics,
+    events::{BoardEvent, BoardEvents, EventSubscriber, LedChange},
+    limits::{CutShort, HeadCut, LimitedSocket, Limits},
+    metrics::Metrics,
+    rate_limit::{
+        ClientAddress, ClientRateLimit, ToggleRateLimit, CLIENT_BURST, CLIENT_REFILL_INTERVAL,
+    },
+    sensors::SensorStats,
+    session::{SessionContext, Sessions, KEY_LEN},
+    settings::NoSettingsStore,
+    status_page::LocalAddress,
+    strip::StripCommand,
+    time::Clock,
+    LedControl,
+};
+use static_cell::StaticCell;
+use wifi_store::{FlashProvisioner, SharedFlash};
+use {defmt_rtt as _, panic_probe as _};
+
+mod wifi_store;
+mod ws2812;
+
+bind_interrupts!(struct Irqs {
+    PIO0_IRQ_0 => InterruptHandler<PIO0>;
+    PIO1_IRQ_0 => InterruptHandler<PIO1>;
+});
+
+/// Network joined when none was saved from the setup form, set with `WIFI_SSID` and `WIFI_PASSWORD` when building.
+fn build_credentials() -> Option<WifiCredentials> {
+    Some(WifiCredentials {
+        ssid: option_env!("WIFI_SSID")?.try_into().ok()?,
+        password: option_env!("WIFI_PASSWORD").unwrap_or("").try_into().ok()?,
+    })
+}
+
+/// Attempts at joining the network before opening the setup access point instead.
+const JOIN_ATTEMPTS: u32 = 5;
+
+/// Delay before trying again after failing to join the network.
+const JOIN_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Open access point serving the setup form when there is no network to join.
+const SETUP_SSID: &str = "smolweb-setup";
+const SETUP_CHANNEL: u8 = 6;
+
+/// Time for the response to the setup form to be sent before rebooting.
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+type WifiDevice = cyw43::NetDriver<'static>;
+
+#[embassy_executor::task]
+async fn wifi_task(
+    runner: cyw43::Runner<
+        'static,
+        Output<'static, PIN_23>,
+        PioSpi<'static, PIN_25, PIO0, 0, DMA_CH0>,
+    >,
+) -> ! {
+    runner.run().await
+}
+
+/// Join the network of `credentials`, returning false if every attempt failed.
+async fn join(control: &mut cyw43::Control<'static>, credentials: &WifiCredentials) -> bool {
+    let ssid = credentials.ssid.as_str();
+
+    for attempt in 1..=JOIN_ATTEMPTS {
+        let result = if credentials.password.is_empty() {
+            control.join_open(ssid).await
+        } else {
+            control.join_wpa2(ssid, &credentials.password).await
+        };
+
+        match result {
+            Ok(()) => {
+                info!("Joined {}", ssid);
+                return true;
+            }
+            Err(err) => {
+                warn!(
+                    "Failed to join {} ({}/{}), status {}",
+                    ssid, attempt, JOIN_ATTEMPTS, err.status
+                );
+                Timer::after(JOIN_RETRY_INTERVAL).await;
+            }
+        }
+    }
+
+    false
+}
+
+#[embassy_executor::task]
+async fn net_task(stack: &'static Stack<WifiDevice>) -> ! {
+    stack.run().await
+}
+
+/// Server queried for the time, resolved through DNS.
+const NTP_SERVER: &str = "pool.ntp.org";
+
+#[embassy_executor::task]
+async fn sntp_task(stack: &'static Stack<WifiDevice>) -> ! {
+    smolweb_core::sntp::run(stack, NTP_SERVER).await
+}
+
+/// Redirects `http://<board>/` to the web servers, so that the port can be left out of the URL.
+#[embassy_executor::task]
+async fn redirect_task(stack: &'static Stack<WifiDevice>) -> ! {
+    smolweb_core::redirect::run(stack, smolweb_core::DEFAULT_PORT).await
+}
+
+/// Broadcasts the address of the board for `discover` on the LAN, under the default hostname as nothing is stored.
+#[embassy_executor::task]
+async fn discovery_task(stack: &'static Stack<WifiDevice>) -> ! {
+    smolweb_core::discovery::run(
+        stack,
+        "smolweb",
+        smolweb_core::DEFAULT_PORT,
+        env!("CARGO_PKG_VERSION"),
+    )
+    .await
+}
+
+#[embassy_executor::task]
+async fn captive_dns_task(stack: &'static Stack<WifiDevice>) -> ! {
+    provisioning::run_dns(stack).await
+}
+
+#[embassy_executor::task]
+async fn dhcp_server_task(stack: &'static Stack<WifiDevice>) -> ! {
+    provisioning::run_dhcp(stack).await
+}
+
+/// State of the onboard LED, which is wired to the CYW43 rather than to the RP2040.
+static LED_ON: AtomicBool = AtomicBool::new(true);
+
+/// Signalled with the new state of the LED for [led_task] to apply.
+static LED_UPDATE: Signal<CriticalSectionRawMutex, bool> = Signal::new();
+
+/// Drives the onboard LED through `cyw43::Control`, which needs an async call the handlers can't make.
+#[embassy_executor::task]
+async fn led_task(mut control: cyw43::Control<'static>) -> ! {
+    loop {
+        DIAGNOSTICS.heartbeat("led");
+        let on = LED_UPDATE.wait().await;
+        control.gpio_set(0, on).await;
+        DIAGNOSTICS.report_stack("led");
+    }
+}
+
+/// Events buffered for a subscriber before the oldest ones are dropped.
+const BOARD_EVENT_CAPACITY: usize = 4;
+
+/// Notifies the WebSockets and event streams of LED changes and uptime, one subscriber per web server.
+static BOARD_EVENTS: PubSubChannel<
+    CriticalSectionRawMutex,
+    BoardEvent,
+    BOARD_EVENT_CAPACITY,
+    WEB_SERVER_COUNT,
+    0,
+> = PubSubChannel::new();
+
+fn publish(event: BoardEvent) {
+    BOARD_EVENTS.immediate_publisher().publish_immediate(event);
+}
+
+/// Subscription to [BOARD_EVENTS] held by an open WebSocket or event stream.
+struct BoardEventSubscriber(
+    Subscriber<
+        'static,
+        CriticalSectionRawMutex,
+        BoardEvent,
+        BOARD_EVENT_CAPACITY,
+        WEB_SERVER_COUNT,
+        0,
+    >,
+);
+
+impl EventSubscriber for BoardEventSubscriber {
+    async fn next_event(&mut self) -> BoardEvent {
+        self.0.next_message_pure().await
+    }
+}
+
+/// Stack headroom of the tasks shown by `/api/sysinfo`, reported at the end of each loop, and the heartbeats of the
+/// background tasks `/readyz` waits for.
+static DIAGNOSTICS: Diagnostics = Diagnostics::new(
+    env!("CARGO_PKG_VERSION"),
+    &["web", "uptime", "led", "strip"],
+)
+.with_heartbeats(&["uptime", "led", "strip"]);
+
+extern "C" {
+    /// End of the static data, placed by `cortex-m-rt`. The stack of `main`, which runs every task, grows down to it.
+    static __sheap: u8;
+}
+
+/// Publishes [BoardEvent::Uptime] every [smolweb_core::events::UPTIME_INTERVAL].
+#[embassy_executor::task]
+async fn uptime_task() -> ! {
+    let interval = Duration::from_secs(smolweb_core::events::UPTIME_INTERVAL.as_secs());
+
+    loop {
+        DIAGNOSTICS.heartbeat("uptime");
+        Timer::after(interval).await;
+        publish(BoardEvent::Uptime(Instant::now().as_secs()));
+        DIAGNOSTICS.report_stack("uptime");
+    }
+}
+
+/// The onboard LED is number 2, so the page and the API are the same as on the Nucleo.
+#[derive(Clone, Copy)]
+struct SharedControl;
+
+impl SharedControl {
+    fn apply(&self, led: u8, on: bool) {
+        LED_UPDATE.signal(on);
+        publish(BoardEvent::Led(LedChange { led, on }));
+    }
+}
+
+impl LedControl for SharedControl {
+    fn has_led(&self, led: u8) -> bool {
+        led == 2
+    }
+
+    fn toggle(&self, led: u8) {
+        let on = !LED_ON.fetch_xor(true, Ordering::Relaxed);
+        self.apply(led, on);
+    }
+
+    fn set(&self, led: u8, on: bool) {
+        LED_ON.store(on, Ordering::Relaxed);
+        self.apply(led, on);
+    }
+
+    fn state(&self, _led: u8) -> bool {
+        LED_ON.load(Ordering::Relaxed)
+    }
+
+    fn strip_length(&self) -> usize {
+        ws2812::STRIP_LENGTH
+    }
+
+    fn set_strip(&self, command: StripCommand) -> bool {
+        ws2812::STRIP_COMMANDS.try_send(command).is_ok()
+    }
+}
+
+impl BoardEvents for SharedControl {
+    type Subscriber = BoardEventSubscriber;
+
+    fn subscribe(&self) -> Option<BoardEventSubscriber> {
+        BOARD_EVENTS.subscriber().ok().map(BoardEventSubscriber)
+    }
+}
+
+/// Minimum interval between two toggles of the same LED.
+const MIN_TOGGLE_INTERVAL: core::time::Duration = core::time::Duration::from_millis(500);
+
+struct AppState {
+    shared_control: SharedControl,
+    metrics: &'static Metrics,
+    diagnostics: &'static Diagnostics,
+    toggle_rate_limit: &'static ToggleRateLimit,
+    client_rate_limit: &'static ClientRateLimit,
+    sessions: &'static Sessions,
+    button_stats: &'static ButtonStats,
+    /// Never sampled, there is no sensor on this board.
+    sensor_stats: &'static SensorStats,
+    /// Set for each accepted connection, so that its requests are logged with it.
+    connection: ConnectionId,
+    /// Address the connection was accepted on, shown by the page.
+    local_address: LocalAddress,
+    /// Address the connection was accepted from, whose requests the [ClientRateLimit] counts.
+    client_address: ClientAddress,
+    /// Set by the [LimitedSocket] of the connection when it cuts a head short, so that the request is refused.
+    head_cut: HeadCut,
+}
+
+impl picoserve::extract::FromRef<AppState> for LocalAddress {
+    fn from_ref(state: &AppState) -> Self {
+        state.local_address
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for ClientAddress {
+    fn from_ref(state: &AppState) -> Self {
+        state.client_address
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for ConnectionId {
+    fn from_ref(state: &AppState) -> Self {
+        state.connection
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for SharedControl {
+    fn from_ref(state: &AppState) -> Self {
+        state.shared_control
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for SntpClock {
+    fn from_ref(_state: &AppState) -> Self {
+        SntpClock
+    }
+}
+
+/// Set when building, see [Credentials::from_build_env].
+impl picoserve::extract::FromRef<AppState> for Credentials {
+    fn from_ref(_state: &AppState) -> Self {
+        Credentials::from_build_env()
+    }
+}
+
+/// Set when building, see [CorsOrigins::from_build_env].
+impl picoserve::extract::FromRef<AppState> for CorsOrigins {
+    fn from_ref(_state: &AppState) -> Self {
+        CorsOrigins::from_build_env()
+    }
+}
+
+/// Those of the buffers of the web workers, which [LimitedSocket::new] also cuts heads to.
+impl picoserve::extract::FromRef<AppState> for Limits {
+    fn from_ref(_state: &AppState) -> Self {
+        Limits::WEB
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for CutShort {
+    fn from_ref(state: &AppState) -> Self {
+        state.head_cut.get()
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for SessionContext {
+    fn from_ref(state: &AppState) -> Self {
+        SessionContext {
+            sessions: state.sessions,
+            uptime: SntpClock.uptime(),
+        }
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for NoAssetStore {
+    fn from_ref(_state: &AppState) -> Self {
+        NoAssetStore
+    }
+}
+
+/// Nothing is stored, so the default settings always apply.
+impl picoserve::extract::FromRef<AppState> for NoSettingsStore {
+    fn from_ref(_state: &AppState) -> Self {
+        NoSettingsStore
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for &'static Diagnostics {
+    fn from_ref(state: &AppState) -> Self {
+        state.diagnostics
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for &'static Metrics {
+    fn from_ref(state: &AppState) -> Self {
+        state.metrics
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for &'static ToggleRateLimit {
+    fn from_ref(state: &AppState) -> Self {
+        state.toggle_rate_limit
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for &'static ClientRateLimit {
+    fn from_ref(state: &AppState) -> Self {
+        state.client_rate_limit
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for &'static ButtonStats {
+    fn from_ref(state: &AppState) -> Self {
+        state.button_stats
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for &'static SensorStats {
+    fn from_ref(state: &AppState) -> Self {
+        state.sensor_stats
+    }
+}
+
+/// State of the router served while the setup access point is open.
+struct SetupState {
+    provisioner: FlashProvisioner,
+}
+
+impl picoserve::extract::FromRef<SetupState> for FlashProvisioner {
+    fn from_ref(state: &SetupState) -> Self {
+        state.provisioner
+    }
+}
+
+const WEB_SERVER_COUNT: usize = 4;
+
+/// Sockets used on top of the web servers: one each for DHCP, DNS, SNTP, the discovery beacon and the port 80
+/// redirect.
+const STACK_SOCKETS: usize = 5;
+
+/// Serves `app` on one socket at a time, with the same loop as the Ethernet demo's web tasks.
+///
+/// The router type can't be named without `type_alias_impl_trait`, which needs nightly,
+/// so the servers run joined inside `main` instead of in a task pool.
+async fn web_server(
+    id: usize,
+    stack: &'static Stack<WifiDevice>,
+    app: &picoserve::Router<impl picoserve::routing::PathRouter<AppState>, AppState>,
+    config: &picoserve::Config<Duration>,
+    mut state: AppState,
+) -> ! {
+    let port = smolweb_core::DEFAULT_PORT;
+    let mut tcp_rx_buffer = [0; BufferConfig::WEB.tcp_rx];
+    let mut tcp_tx_buffer = [0; BufferConfig::WEB.tcp_tx];
+    let mut http_buffer = [0; BufferConfig::WEB.http];
+
+    // Listed by `/api/workers` even before its first connection
+    state.metrics.start_worker(id);
+
+    loop {
+        // Don't listen while the stack has no address, e.g. after losing the access point
+        if !stack.is_config_up() {
+            DIAGNOSTICS.set_network_up(false);
+            info!("{}: Waiting for the network", id);
+            stack.wait_config_up().await;
+        }
+        DIAGNOSTICS.set_network_up(true);
+
+        let mut socket = TcpSocket::new(stack, &mut tcp_rx_buffer, &mut tcp_tx_buffer);
+
+        if let Err(err) = socket.accept(port).await {
+            warn!("{}: Accept error: {}", id, err);
+            continue;
+        }
+
+        let remote_endpoint = socket.remote_endpoint();
+        state.connection = ConnectionId::next();
+        state.local_address = LocalAddress::of_socket(&socket);
+        state.client_address = ClientAddress::of_socket(&socket);
+        info!(
+            "{}: Connection {} from {}",
+            id, state.connection, remote_endpoint
+        );
+        let connection = state.metrics.open_connection(id);
+        let socket = LimitedSocket::new(connection.count_bytes(socket), &state.head_cut);
+
+        match picoserve::serve_with_state(app, config, &mut http_buffer, socket, &state).await {
+            Ok(handled_requests_count) => {
+                connection.served(handled_requests_count);
+                info!(
+                    "{}: {} requests handled from {}",
+                    id, handled_requests_count, remote_endpoint
+                )
+            }
+            Err(err) => warn!("{}: {}", id, Debug2Format(&err)),
+        }
+
+        DIAGNOSTICS.report_stack("web");
+    }
+}
+
+/// Serves the setup form, until `main` reboots once it is filled in.
+async fn setup_server(
+    id: usize,
+    stack: &'static Stack<WifiDevice>,
+    app: &picoserve::Router<impl picoserve::routing::PathRouter<SetupState>, SetupState>,
+    config: &picoserve::Config<Duration>,
+    state: SetupState,
+) -> ! {
+    let mut tcp_rx_buffer = [0; BufferConfig::WEB.tcp_rx];
+    let mut tcp_tx_buffer = [0; BufferConfig::WEB.tcp_tx];
+    let mut http_buffer = [0; BufferConfig::WEB.http];
+
+    loop {
+        let mut socket = TcpSocket::new(stack, &mut tcp_rx_buffer, &mut tcp_tx_buffer);
+
+        if let Err(err) = socket.accept(provisioning::SETUP_PORT).await {
+            warn!("{}: Accept error: {}", id, err);
+            continue;
+        }
+
+        if let Err(err) =
+            picoserve::serve_with_state(app, config, &mut http_buffer, socket, &state).await
+        {
+            warn!("{}: {}", id, Debug2Format(&err));
+        }
+    }
+}
+
+/// Open the setup access point and serve the form until credentials are saved, then reboot to join them.
+async fn run_setup(
+    spawner: Spawner,
+    stack: &'static Stack<WifiDevice>,
+    mut control: cyw43::Control<'static>,
+    flash: &'static SharedFlash,
+) -> ! {
+    control.start_ap_open(SETUP_SSID, SETUP_CHANNEL).await;
+    info!(
+        "Join {} and open http://{}/setup",
+        SETUP_SSID,
+        provisioning::AP_ADDRESS
+    );
+
+    unwrap!(spawner.spawn(dhcp_server_task(stack)));
+    unwrap!(spawner.spawn(captive_dns_task(stack)));
+
+    let app = provisioning::make_setup_app::<SetupState, FlashProvisioner>();
+
+    let config = picoserve::Config::new(picoserve::Timeouts {
+        start_read_request: Some(Duration::from_secs(5)),
+        read_request: Some(Duration::from_secs(1)),
+        write: Some(Duration::from_secs(1)),
+    })
+    .close_connection_after_response();
+
+    let servers = join_array(core::array::from_fn::<_, WEB_SERVER_COUNT, _>(|id| {
+        setup_server(
+            id,
+            stack,
+            &app,
+            &config,
+            SetupState {
+                provisioner: FlashProvisioner(flash),
+            },
+        )
+    }));
+
+    let restart = async {
+        wifi_store::RESTART.wait().await;
+        Timer::after(RESTART_DELAY).await;
+    };
+
+    select(servers, restart).await;
+    cortex_m::peripheral::SCB::sys_reset()
+}
+
+#[embassy_executor::main]
+async fn main(spawner: Spawner) {
+    let p = embassy_rp::init(Default::default());
+
+    DIAGNOSTICS.set_cpu_frequency(embassy_rp::clocks::clk_sys_freq());
+    // Only the address of the symbol is taken, it is never read, and there is no heap above the static data
+    unsafe { DIAGNOSTICS.paint_stack(core::ptr::addr_of!(__sheap) as usize) };
+
+    info!("Hello World!");
+
+    // Download both files as described in cyw43-firmware/README.md
+    let fw = include_bytes!("../cyw43-firmware/43439A0.bin");
+    let clm = include_bytes!("../cyw43-firmware/43439A0_clm.bin");
+
+    let pwr = Output::new(p.PIN_23, Level::Low);
+    let cs = Output::new(p.PIN_25, Level::High);
+    let mut pio = Pio::new(p.PIO0, Irqs);
+    let spi = PioSpi::new(
+        &mut pio.common,
+        pio.sm0,
+        pio.irq0,
+        cs,
+        p.PIN_24,
+        p.PIN_29,
+        p.DMA_CH0,
+    );
+
+    // The strip has its own PIO, the state machines of PIO0 being for the CYW43
+    let mut pio1 = Pio::new(p.PIO1, Irqs);
+    let strip = ws2812::Ws2812::new(&mut pio1.common, pio1.sm0, p.DMA_CH1, p.PIN_16);
+    unwrap!(spawner.spawn(ws2812::strip_task(strip)));
+
+    static WIFI_STATE: StaticCell<cyw43::State> = StaticCell::new();
+    let (device, mut control, runner) =
+        cyw43::new(WIFI_STATE.init(cyw43::State::new()), pwr, spi, fw).await;
+    unwrap!(spawner.spawn(wifi_task(runner)));
+
+    control.init(clm).await;
+    control
+        .set_power_management(cyw43::PowerManagementMode::PowerSave)
+        .await;
+    control.gpio_set(0, LED_ON.load(Ordering::Relaxed)).await;
+
+    static FLASH: StaticCell<SharedFlash> = StaticCell::new();
+    let flash = &*FLASH.init(Mutex::new(RefCell::new(Flash::new_blocking(p.FLASH))));
+
+    let joined = match wifi_store::load(flash).or_else(build_credentials) {
+        Some(credentials) => join(&mut control, &credentials).await,
+        None => {
+            info!("No WiFi credentials saved");
+            false
+        }
+    };
+
+    // Generate random seed.
+    let seed = RoscRng.next_u64();
+
+    // Drawn at each boot, so that session cookies don't outlive it
+    let mut session_key = [0; KEY_LEN];
+    RoscRng.fill_bytes(&mut session_key);
+    static SESSIONS: StaticCell<Sessions> = StaticCell::new();
+    let sessions: &'static Sessions = SESSIONS.init(Sessions::new(session_key));
+
+    let config = if joined {
+        embassy_net::Config::dhcpv4(Default::default())
+    } else {
+        provisioning::ap_config()
+    };
+
+    // Init network stack
+    static STACK: StaticCell<Stack<WifiDevice>> = StaticCell::new();
+    static RESOURCES: StaticCell<StackResources<{ WEB_SERVER_COUNT + STACK_SOCKETS }>> =
+        StaticCell::new();
+    let stack = &*STACK.init(Stack::new(
+        device,
+        config,
+        RESOURCES.init(StackResources::<{ WEB_SERVER_COUNT + STACK_SOCKETS }>::new()),
+        seed,
+    ));
+
+    // Launch network task
+    unwrap!(spawner.spawn(net_task(stack)));
+
+    if !joined {
+        run_setup(spawner, stack, control, flash).await;
+    }
+
+    // Ensure network configuration is up before trying connect
+    stack.wait_config_up().await;
+
+    info!("Network task initialized");
+
+    if let Some(config) = stack.config_v4() {
+        info!("IP address: {}", config.address);
+    }
+
+    unwrap!(spawner.spawn(sntp_task(stack)));
+    unwrap!(spawner.spawn(discovery_task(stack)));
+    // Only once joined, as the setup form is served on port 80
+    unwrap!(spawner.spawn(redirect_task(stack)));
+    unwrap!(spawner.spawn(led_task(control)));
+    unwrap!(spawner.spawn(uptime_task()));
+
+    let app = smolweb_core::make_app::<
+        AppState,
+        SharedControl,
+        SntpClock,
+        NoAssetStore,
+        NoSettingsStore,
+    >();
+
+    let config = picoserve::Config::new(picoserve::Timeouts {
+        start_read_request: Some(Duration::from_secs(5)),
+        read_request: Some(Duration::from_secs(1)),
+        write: Some(Duration::from_secs(1)),
+    })
+    .keep_connection_alive();
+
+    static METRICS: Metrics = Metrics::new();
+    static TOGGLE_RATE_LIMIT: ToggleRateLimit = ToggleRateLimit::new(MIN_TOGGLE_INTERVAL);
+    static CLIENT_RATE_LIMIT: ClientRateLimit =
+        ClientRateLimit::new(CLIENT_BURST, CLIENT_REFILL_INTERVAL);
+    // The Pico W has no user button, only BOOTSEL
+    static BUTTON_STATS: ButtonStats = ButtonStats::new();
+    static SENSOR_STATS: SensorStats = SensorStats::new();
+
+    join_array(core::array::from_fn::<_, WEB_SERVER_COUNT, _>(|id| {
+        web_server(
+            id,
+            stack,
+            &app,
+            &config,
+            AppState {
+                shared_control: SharedControl,
+                metrics: &METRICS,
+                diagnostics: &DIAGNOSTICS,
+                toggle_rate_limit: &TOGGLE_RATE_LIMIT,
+                client_rate_limit: &CLIENT_RATE_LIMIT,
+                sessions,
+                button_stats: &BUTTON_STATS,
+                sensor_stats: &SENSOR_STATS,
+                connection: ConnectionId(0),
+                local_address: LocalAddress::default(),
+                client_address: ClientAddress::default(),
+                head_cut: HeadCut::new(),
+            },
+        )
+    }))
+    .await;
+}
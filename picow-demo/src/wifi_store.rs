@@ -0,0 +1,63 @@
+//! WiFi credentials entered in the setup form, kept in the last sector of the flash.
+
+use core::cell::RefCell;
+
+use defmt::*;
+use embassy_rp::flash::{Blocking, Flash, ERASE_SIZE};
+use embassy_rp::peripherals::FLASH;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::signal::Signal;
+use smolweb_core::provisioning::{Provisioner, WifiCredentials};
+
+/// Size of the flash chip of the Pico W.
+pub const FLASH_SIZE: usize = 2 * 1024 * 1024;
+
+/// Left out of `FLASH` in `memory.x`, so that the firmware never grows into it.
+const SECTOR_OFFSET: u32 = (FLASH_SIZE - ERASE_SIZE) as u32;
+
+pub type SharedFlash =
+    Mutex<CriticalSectionRawMutex, RefCell<Flash<'static, FLASH, Blocking, FLASH_SIZE>>>;
+
+/// The stored credentials, or `None` if the form was never filled in.
+pub fn load(flash: &SharedFlash) -> Option<WifiCredentials> {
+    let mut record = [0; WifiCredentials::RECORD_SIZE];
+
+    if let Err(err) =
+        flash.lock(|flash| flash.borrow_mut().blocking_read(SECTOR_OFFSET, &mut record))
+    {
+        warn!("Failed to read WiFi credentials from flash: {}", err);
+        return None;
+    }
+
+    WifiCredentials::from_record(&record)
+}
+
+/// Signalled once credentials are saved, for `main` to reboot into station mode.
+pub static RESTART: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Saves the credentials of the setup form to [SECTOR_OFFSET].
+#[derive(Clone, Copy)]
+pub struct FlashProvisioner(pub &'static SharedFlash);
+
+impl Provisioner for FlashProvisioner {
+    fn save(&self, credentials: &WifiCredentials) -> bool {
+        let record = credentials.to_record();
+
+        let result = self.0.lock(|flash| {
+            let mut flash = flash.borrow_mut();
+            flash.blocking_erase(SECTOR_OFFSET, SECTOR_OFFSET + ERASE_SIZE as u32)?;
+            flash.blocking_write(SECTOR_OFFSET, &record)
+        });
+
+        if let Err(err) = result {
+            warn!("Failed to write WiFi credentials to flash: {}", err);
+        }
+
+        result.is_ok()
+    }
+
+    fn restart_soon(&self) {
+        RESTART.signal(());
+    }
+}
@@ -0,0 +1,134 @@
+//! WS2812 strip on GPIO 16, driven by a state machine of PIO1 fed over DMA.
+//!
+//! `POST /api/strip` sends its [StripCommand] through [STRIP_COMMANDS] to [strip_task], which renders it and redraws
+//! animations every [FRAME_INTERVAL].
+
+use defmt::*;
+use embassy_futures::select::{select, Either};
+use embassy_rp::dma::{AnyChannel, Channel as DmaChannel};
+use embassy_rp::pio::{
+    Common, Config, FifoJoin, Instance, PioPin, ShiftConfig, ShiftDirection, StateMachine,
+};
+use embassy_rp::{clocks, into_ref, Peripheral, PeripheralRef};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_time::{Duration, Timer};
+use fixed::types::U24F8;
+use fixed_macro::fixed;
+use smolweb_core::strip::{Rgb, StripCommand, FRAME_INTERVAL};
+
+use crate::DIAGNOSTICS;
+
+/// Pixels of the strip, at most [MAX_STRIP_LENGTH](smolweb_core::strip::MAX_STRIP_LENGTH).
+pub const STRIP_LENGTH: usize = 30;
+
+/// Commands of `POST /api/strip`, waiting for [strip_task].
+pub static STRIP_COMMANDS: Channel<CriticalSectionRawMutex, StripCommand, 2> = Channel::new();
+
+/// Sends 24-bit GRB words, most significant bit first, at 800 kHz.
+pub struct Ws2812<'d, P: Instance, const S: usize> {
+    dma: PeripheralRef<'d, AnyChannel>,
+    sm: StateMachine<'d, P, S>,
+}
+
+impl<'d, P: Instance, const S: usize> Ws2812<'d, P, S> {
+    pub fn new(
+        pio: &mut Common<'d, P>,
+        mut sm: StateMachine<'d, P, S>,
+        dma: impl Peripheral<P = impl DmaChannel> + 'd,
+        pin: impl PioPin,
+    ) -> Self {
+        into_ref!(dma);
+
+        // Each bit is high for T1, then high or low for T2 depending on its value, then low for T3, in PIO cycles
+        const T1: u8 = 2;
+        const T2: u8 = 5;
+        const T3: u8 = 3;
+        const CYCLES_PER_BIT: u32 = (T1 + T2 + T3) as u32;
+
+        let side_set = pio::SideSet::new(false, 1, false);
+        let mut a: pio::Assembler<32> = pio::Assembler::new_with_side_set(side_set);
+
+        let mut wrap_target = a.label();
+        let mut wrap_source = a.label();
+        let mut do_zero = a.label();
+        a.set_with_side_set(pio::SetDestination::PINDIRS, 1, 0);
+        a.bind(&mut wrap_target);
+        a.out_with_delay_and_side_set(pio::OutDestination::X, 1, T3 - 1, 0);
+        a.jmp_with_delay_and_side_set(pio::JmpCondition::XIsZero, &mut do_zero, T1 - 1, 1);
+        a.jmp_with_delay_and_side_set(pio::JmpCondition::Always, &mut wrap_target, T2 - 1, 1);
+        a.bind(&mut do_zero);
+        a.nop_with_delay_and_side_set(T2 - 1, 0);
+        a.bind(&mut wrap_source);
+        let program = a.assemble_with_wrap(wrap_source, wrap_target);
+
+        let mut config = Config::default();
+        let out_pin = pio.make_pio_pin(pin);
+        config.set_out_pins(&[&out_pin]);
+        config.set_set_pins(&[&out_pin]);
+        config.use_program(&pio.load_program(&program), &[&out_pin]);
+
+        let clock_khz = U24F8::from_num(clocks::clk_sys_freq() / 1000);
+        config.clock_divider = clock_khz / (fixed!(800: U24F8) * CYCLES_PER_BIT);
+        config.fifo_join = FifoJoin::TxOnly;
+        config.shift_out = ShiftConfig {
+            auto_fill: true,
+            threshold: 24,
+            direction: ShiftDirection::Left,
+        };
+
+        sm.set_config(&config);
+        sm.set_enable(true);
+
+        Self {
+            dma: dma.map_into(),
+            sm,
+        }
+    }
+
+    pub async fn write(&mut self, pixels: &[Rgb; STRIP_LENGTH]) {
+        let words = pixels
+            .map(|[r, g, b]| (u32::from(g) << 24) | (u32::from(r) << 16) | (u32::from(b) << 8));
+        self.sm.tx().dma_push(self.dma.reborrow(), &words).await;
+
+        // The strip latches the colors after staying low for 50 µs
+        Timer::after_micros(55).await;
+    }
+}
+
+/// Shows each command of [STRIP_COMMANDS], redrawing animations every [FRAME_INTERVAL].
+#[embassy_executor::task]
+pub async fn strip_task(mut strip: Ws2812<'static, embassy_rp::peripherals::PIO1, 0>) -> ! {
+    let frame_interval = Duration::from_millis(FRAME_INTERVAL.as_millis() as u64);
+
+    let mut pixels = [[0; 3]; STRIP_LENGTH];
+    // Dark until the first command
+    strip.write(&pixels).await;
+    DIAGNOSTICS.heartbeat("strip");
+    let mut command = STRIP_COMMANDS.receive().await;
+    let mut frame = 0_u32;
+
+    loop {
+        command.render(frame, &mut pixels);
+        strip.write(&pixels).await;
+        DIAGNOSTICS.report_stack("strip");
+
+        let next = if command.is_animated() {
+            match select(STRIP_COMMANDS.receive(), Timer::after(frame_interval)).await {
+                Either::First(next) => Some(next),
+                Either::Second(()) => None,
+            }
+        } else {
+            Some(STRIP_COMMANDS.receive().await)
+        };
+
+        match next {
+            Some(next) => {
+                debug!("New strip command");
+                command = next;
+                frame = 0;
+            }
+            None => frame = frame.wrapping_add(1),
+        }
+    }
+}
@@ -0,0 +1,126 @@
+async function toggle_led(led) {
+    let response = await fetch(`/toggle_led/${led}`);
+    let response_text = await response.text();
+    document.getElementById(`led${led}Label`).innerText = response_text;
+}
+
+// Keep the labels in sync with changes made from other tabs or on the board
+function watch_leds() {
+    let protocol = location.protocol === "https:" ? "wss:" : "ws:";
+    let socket = new WebSocket(`${protocol}//${location.host}/ws`);
+
+    socket.onmessage = (event) => {
+        let change = JSON.parse(event.data);
+        let label = document.getElementById(`led${change.led}Label`);
+        if (label) {
+            label.innerText = change.state.toUpperCase();
+        }
+    };
+
+    socket.onclose = () => setTimeout(watch_leds, 5000);
+}
+
+watch_leds();
+
+async function set_brightness(led, brightness) {
+    await fetch(`/api/leds/${led}/brightness`, {
+        method: "POST",
+        headers: { "Content-Type": "application/json" },
+        body: JSON.stringify({ brightness: Number(brightness) }),
+    });
+}
+
+async function post_strip(body) {
+    await fetch("/api/strip", {
+        method: "POST",
+        headers: { "Content-Type": "application/json" },
+        body: JSON.stringify(body),
+    });
+}
+
+// A single pixel is repeated along the whole strip
+function fill_strip(color) {
+    let rgb = [1, 3, 5].map((i) => parseInt(color.slice(i, i + 2), 16));
+    post_strip({ pixels: [rgb] });
+}
+
+// Choosing no animation leaves the strip as it is, until a color is picked
+function animate_strip(animation) {
+    if (animation) {
+        post_strip({ animation });
+    }
+}
+
+// The same as `Tone::BEEP` in `tone.rs`
+async function beep() {
+    await fetch("/api/tone", {
+        method: "POST",
+        headers: { "Content-Type": "application/json" },
+        body: JSON.stringify({ frequency_hz: 2000, duration_ms: 100 }),
+    });
+}
+
+async function update_button() {
+    let response = await fetch("/api/button");
+    let button = await response.json();
+    document.getElementById("buttonPresses").innerText = button.presses;
+}
+
+// The same as `Uptime` in `status_page.rs`, which rendered the first value
+function format_uptime(seconds) {
+    let days = Math.floor(seconds / 86400);
+    let time = [3600, 60, 1]
+        .map((unit, i) => String(Math.floor(seconds / unit) % (i === 0 ? 24 : 60)).padStart(2, "0"))
+        .join(":");
+    return days > 0 ? `${days} d ${time}` : time;
+}
+
+// The page is rendered with the state of the board, so only changes are fetched
+let events = new EventSource("/events");
+events.addEventListener("button", update_button);
+events.addEventListener("uptime", (event) => {
+    let uptime = JSON.parse(event.data);
+    document.getElementById("uptime").innerText = format_uptime(uptime.seconds);
+});
+
+// The same as the `sensor` value of `status_page.rs`, which rendered the first reading
+function format_sensor(reading) {
+    return `${reading.temperature_c.toFixed(1)} °C, ${reading.humidity_percent.toFixed(1)} % humidity`;
+}
+
+// Readings since the page was opened, the oldest dropped once the chart is full
+const SENSOR_HISTORY = 60;
+let sensor_readings = [];
+
+// Temperature in red and humidity in blue, each scaled to its own range so that both changes are visible
+function draw_sensor_chart() {
+    let canvas = document.getElementById("sensorChart");
+    let context = canvas.getContext("2d");
+    context.clearRect(0, 0, canvas.width, canvas.height);
+
+    for (let [field, color] of [["temperature_c", "#c33"], ["humidity_percent", "#36c"]]) {
+        let values = sensor_readings.map((reading) => reading[field]);
+        let min = Math.min(...values);
+        let range = Math.max(Math.max(...values) - min, 1);
+
+        context.strokeStyle = color;
+        context.beginPath();
+        values.forEach((value, i) => {
+            let x = (i * canvas.width) / (SENSOR_HISTORY - 1);
+            let y = canvas.height - 5 - ((value - min) * (canvas.height - 10)) / range;
+            i === 0 ? context.moveTo(x, y) : context.lineTo(x, y);
+        });
+        context.stroke();
+    }
+}
+
+events.addEventListener("sensor", (event) => {
+    let reading = JSON.parse(event.data);
+    document.getElementById("sensor").innerText = format_sensor(reading);
+
+    sensor_readings.push(reading);
+    if (sensor_readings.length > SENSOR_HISTORY) {
+        sensor_readings.shift();
+    }
+    draw_sensor_chart();
+});
@@ -0,0 +1,270 @@
+//! Embeds every file under `assets/` and `static/` into the binary, see `src/static_files.rs`, compressing the text
+//! files of `assets/` with gzip, and sets `SMOLWEB_GIT_HASH` for `src/diagnostics.rs`.
+
+use std::{
+    fmt::Write as _,
+    io::Write as _,
+    path::{Path, PathBuf},
+};
+
+use flate2::{write::GzEncoder, Compression};
+
+const STATIC_DIR: &str = "static";
+
+/// The assets of the page, served at the root, e.g. `assets/index.css` as `/index.css`.
+///
+/// `index.html` is rendered for each request by `src/status_page.rs`, so it stays in `src/`.
+const ASSETS_DIR: &str = "assets";
+
+/// `Cache-Control` of the files under [ASSETS_DIR]: browsers reuse them for 5 minutes, then revalidate them with
+/// `If-None-Match`.
+const ASSETS_CACHE_CONTROL: &str = "max-age=300";
+
+/// `Cache-Control` of each file under [STATIC_DIR], see the comments in the file.
+const CACHE_CONTROL_FILE: &str = "cache-control.txt";
+
+#[path = "src/mime.rs"]
+mod mime;
+
+fn content_type(path: &Path) -> &'static str {
+    mime::content_type(
+        path.extension()
+            .and_then(|extension| extension.to_str())
+            .unwrap_or_default(),
+    )
+}
+
+/// Returns true for the text formats worth compressing, images and fonts already being compressed.
+fn is_compressible(content_type: &str) -> bool {
+    content_type.starts_with("text/")
+        || content_type.starts_with("application/javascript")
+        || content_type == "application/json"
+        || content_type == "image/svg+xml"
+}
+
+/// Write `path` compressed to `gzip_path`, returning false if gzip doesn't make it smaller.
+fn compress(path: &Path, gzip_path: &Path) -> bool {
+    let plain = std::fs::read(path)
+        .unwrap_or_else(|err| panic!("Failed to read {}: {err}", path.display()));
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+    encoder.write_all(&plain).unwrap();
+    let gzip = encoder.finish().unwrap();
+
+    if gzip.len() >= plain.len() {
+        return false;
+    }
+
+    std::fs::write(gzip_path, gzip).unwrap();
+    true
+}
+
+/// Read the `(path, value)` rules of [CACHE_CONTROL_FILE].
+fn read_cache_control(path: &Path) -> Vec<(String, String)> {
+    std::fs::read_to_string(path)
+        .unwrap_or_else(|err| panic!("Failed to read {}: {err}", path.display()))
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let (path, value) = line
+                .split_once(char::is_whitespace)
+                .unwrap_or_else(|| panic!("No value for {line:?} in {CACHE_CONTROL_FILE}"));
+            (path.to_owned(), value.trim().to_owned())
+        })
+        .collect()
+}
+
+/// The value of the longest rule matching `url_path`, either exactly or as a directory ending in `/`.
+fn cache_control<'a>(rules: &'a [(String, String)], url_path: &str) -> Option<&'a str> {
+    rules
+        .iter()
+        .filter(|(path, _)| {
+            path == url_path || (path.ends_with('/') && url_path.starts_with(path.as_str()))
+        })
+        .max_by_key(|(path, _)| path.len())
+        .map(|(_, value)| value.as_str())
+}
+
+/// Source of the headers of a file, `Cache-Control` if any rule covers it followed by `extra`.
+fn headers(cache_control: Option<&str>, extra: &[(&str, &str)]) -> String {
+    let headers = cache_control
+        .map(|value| ("Cache-Control", value))
+        .into_iter()
+        .chain(extra.iter().copied())
+        .map(|(name, value)| format!("({name:?}, {value:?})"))
+        .collect::<Vec<_>>();
+
+    format!("&[{}]", headers.join(", "))
+}
+
+/// Collect the files under `dir`, sorted so the generated table is stable between builds.
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    let mut entries = std::fs::read_dir(dir)
+        .unwrap_or_else(|err| panic!("Failed to read {}: {err}", dir.display()))
+        .map(|entry| entry.expect("Failed to read directory entry").path())
+        .collect::<Vec<_>>();
+
+    entries.sort();
+
+    for path in entries {
+        if path.is_dir() {
+            collect_files(&path, files);
+        } else {
+            files.push(path);
+        }
+    }
+}
+
+/// The URL path of `path` below `dir`, e.g. `/fonts/mono.woff2`.
+fn url_path(dir: &Path, path: &Path) -> String {
+    path.strip_prefix(dir)
+        .unwrap()
+        .components()
+        .fold(String::new(), |url_path, component| {
+            url_path + "/" + component.as_os_str().to_str().expect("Non UTF-8 file name")
+        })
+}
+
+/// Append the `(url_path, StaticFile)` entry of the file at `path` to `table`, with the gzip variant at `gzip_path`
+/// if any.
+fn write_entry(
+    table: &mut String,
+    url_path: &str,
+    path: &Path,
+    gzip_path: Option<&Path>,
+    cache_control: Option<&str>,
+) {
+    let content_type = content_type(path);
+    let path = path.to_str().expect("Non UTF-8 path");
+
+    if let Some(gzip_path) = gzip_path {
+        let gzip_path = gzip_path.to_str().expect("Non UTF-8 path");
+        // Caches must keep both variants apart
+        let vary = ("Vary", "Accept-Encoding");
+        let plain_headers = headers(cache_control, &[vary]);
+        let gzip_headers = headers(cache_control, &[("Content-Encoding", "gzip"), vary]);
+
+        writeln!(
+            table,
+            "    ({url_path:?}, StaticFile {{ \
+                plain: CachedFile::new({content_type:?}, include_bytes!({path:?}), {plain_headers}), \
+                gzip: Some(CachedFile::new({content_type:?}, include_bytes!({gzip_path:?}), {gzip_headers})), \
+            }}),",
+        )
+        .unwrap();
+    } else {
+        let plain_headers = headers(cache_control, &[]);
+
+        writeln!(
+            table,
+            "    ({url_path:?}, StaticFile {{ \
+                plain: CachedFile::new({content_type:?}, include_bytes!({path:?}), {plain_headers}), \
+                gzip: None, \
+            }}),",
+        )
+        .unwrap();
+    }
+}
+
+/// The table of the files under [ASSETS_DIR], each text file with a gzip variant compressed into `out_dir`.
+fn assets_table(assets_dir: &Path, out_dir: &Path) -> String {
+    let mut files = Vec::new();
+    collect_files(assets_dir, &mut files);
+
+    let mut table = String::from("&[\n");
+
+    for (index, path) in files.iter().enumerate() {
+        let url_path = url_path(assets_dir, path);
+
+        // Numbered, as files of different directories may have the same name
+        let gzip_path = out_dir.join(format!("asset-{index}.gz"));
+        let gzip_path = (is_compressible(content_type(path)) && compress(path, &gzip_path))
+            .then_some(gzip_path.as_path());
+
+        write_entry(
+            &mut table,
+            &url_path,
+            path,
+            gzip_path,
+            Some(ASSETS_CACHE_CONTROL),
+        );
+    }
+
+    table.push(']');
+    table
+}
+
+/// The table of the files under [STATIC_DIR], with the gzip variants found next to them.
+fn static_table(static_dir: &Path, cache_control_rules: &[(String, String)]) -> String {
+    let mut files = Vec::new();
+    collect_files(static_dir, &mut files);
+
+    let mut table = String::from("&[\n");
+
+    for path in &files {
+        // `foo.css.gz` is served as the gzip variant of `foo.css` rather than as a file of its own
+        if path.extension().is_some_and(|extension| extension == "gz")
+            && files.contains(&path.with_extension(""))
+        {
+            continue;
+        }
+
+        let url_path = url_path(static_dir, path);
+
+        let mut gzip_path = path.clone().into_os_string();
+        gzip_path.push(".gz");
+        let gzip_path = PathBuf::from(gzip_path);
+
+        write_entry(
+            &mut table,
+            &url_path,
+            path,
+            files.contains(&gzip_path).then_some(gzip_path.as_path()),
+            cache_control(cache_control_rules, &url_path),
+        );
+    }
+
+    table.push(']');
+    table
+}
+
+/// Short hash of the commit checked out, or `unknown` without git.
+fn git_hash() -> String {
+    std::process::Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|hash| hash.trim().to_owned())
+        .unwrap_or_else(|| "unknown".to_owned())
+}
+
+fn main() {
+    let manifest_dir = PathBuf::from(std::env::var("CARGO_MANIFEST_DIR").unwrap());
+    let out_dir = PathBuf::from(std::env::var("OUT_DIR").unwrap());
+
+    println!("cargo:rerun-if-changed={ASSETS_DIR}");
+    println!("cargo:rerun-if-changed={STATIC_DIR}");
+    println!("cargo:rerun-if-changed={CACHE_CONTROL_FILE}");
+    println!("cargo:rerun-if-changed=src/mime.rs");
+
+    // HEAD names the branch, whose ref changes with each commit
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+    println!("cargo:rerun-if-changed=../.git/refs/heads");
+    println!("cargo:rustc-env=SMOLWEB_GIT_HASH={}", git_hash());
+
+    std::fs::write(
+        out_dir.join("assets.rs"),
+        assets_table(&manifest_dir.join(ASSETS_DIR), &out_dir),
+    )
+    .unwrap();
+
+    let cache_control_rules = read_cache_control(&manifest_dir.join(CACHE_CONTROL_FILE));
+    std::fs::write(
+        out_dir.join("static_files.rs"),
+        static_table(&manifest_dir.join(STATIC_DIR), &cache_control_rules),
+    )
+    .unwrap();
+}
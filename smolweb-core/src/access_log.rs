@@ -0,0 +1,129 @@
+use core::{fmt, marker::PhantomData, sync::atomic::Ordering};
+
+use picoserve::{
+    extract::FromRef,
+    io::Read,
+    request::RequestParts,
+    response::{Body, Connection, HeadersIter, Response, ResponseWriter},
+    routing::{Layer, Next},
+    ResponseSent,
+};
+use portable_atomic::{AtomicU16, AtomicU32};
+
+use crate::time::Clock;
+
+/// Number of a connection, logged as `#<n>` with each of its requests,
+/// so that the logs of connections served at the same time by different workers can be told apart.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ConnectionId(pub u32);
+
+impl ConnectionId {
+    /// The id of a newly accepted connection, one more than the previous one.
+    pub fn next() -> Self {
+        static NEXT: AtomicU32 = AtomicU32::new(0);
+        Self(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for ConnectionId {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "#{}", self.0)
+    }
+}
+
+/// Passes the response through, remembering its status code.
+///
+/// The code is kept in an atomic rather than a `Cell` so that the connection future stays `Send`.
+struct RecordStatus<'a, W> {
+    inner: W,
+    status_code: &'a AtomicU16,
+}
+
+impl<W: ResponseWriter> ResponseWriter for RecordStatus<'_, W> {
+    type Error = W::Error;
+
+    async fn write_response<R: Read<Error = Self::Error>, H: HeadersIter, B: Body>(
+        self,
+        connection: Connection<'_, R>,
+        response: Response<H, B>,
+    ) -> Result<ResponseSent, Self::Error> {
+        self.status_code
+            .store(response.status_code().as_u16(), Ordering::Relaxed);
+        self.inner.write_response(connection, response).await
+    }
+}
+
+/// [Layer] logging the [ConnectionId], method, path, status and duration of every request, timed with the [Clock] `T`.
+///
+/// The duration covers the handler and writing the response, as picoserve streams the body from the handler.
+pub struct LogRequests<T>(PhantomData<fn() -> T>);
+
+impl<T> LogRequests<T> {
+    pub const fn new() -> Self {
+        Self(PhantomData)
+    }
+}
+
+impl<T> Default for LogRequests<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<State, PathParameters, T> Layer<State, PathParameters> for LogRequests<T>
+where
+    T: Clock + FromRef<State>,
+    ConnectionId: FromRef<State>,
+{
+    type NextState = State;
+    type NextPathParameters = PathParameters;
+
+    async fn call_layer<
+        R: Read,
+        NextLayer: Next<R, Self::NextState, Self::NextPathParameters>,
+        W: ResponseWriter<Error = R::Error>,
+    >(
+        &self,
+        next: NextLayer,
+        state: &State,
+        path_parameters: PathParameters,
+        request_parts: RequestParts<'_>,
+        response_writer: W,
+    ) -> Result<ResponseSent, W::Error> {
+        let clock = T::from_ref(state);
+        let status_code = AtomicU16::new(0);
+        let started = clock.uptime();
+
+        let result = next
+            .run(
+                state,
+                path_parameters,
+                RecordStatus {
+                    inner: response_writer,
+                    status_code: &status_code,
+                },
+            )
+            .await;
+
+        let elapsed = clock.uptime().saturating_sub(started);
+
+        // A status of 0 means the connection failed before a response was written
+        log_info!(
+            "{} {} {} {} {}us",
+            ConnectionId::from_ref(state),
+            request_parts.method(),
+            request_parts.path().encoded(),
+            status_code.load(Ordering::Relaxed),
+            elapsed.as_micros() as u64,
+        );
+
+        result
+    }
+}
@@ -0,0 +1,76 @@
+//! Aggregation of analog samples, so that a client polling slowly still sees what happened between two polls.
+//!
+//! The board samples its ADC channels much more often than `GET /api/adc` is read, pushing each value into a
+//! [RollingWindow] whose [Aggregate] is served instead of the last value alone.
+
+/// Latest value of a channel with the lowest, highest and average value of its [RollingWindow].
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Aggregate {
+    pub latest: f32,
+    pub min: f32,
+    pub max: f32,
+    pub average: f32,
+}
+
+/// The last `N` samples of a channel.
+pub struct RollingWindow<const N: usize> {
+    samples: [f32; N],
+    /// Samples in [Self::samples], at most `N`.
+    len: usize,
+    /// Where the next sample goes, overwriting the oldest once full.
+    next: usize,
+}
+
+impl<const N: usize> RollingWindow<N> {
+    pub const fn new() -> Self {
+        Self {
+            samples: [0.0; N],
+            len: 0,
+            next: 0,
+        }
+    }
+
+    pub fn push(&mut self, sample: f32) {
+        self.samples[self.next] = sample;
+        self.next = (self.next + 1) % N;
+        self.len = (self.len + 1).min(N);
+    }
+
+    /// The [Aggregate] of the window, or `None` before the first sample.
+    pub fn aggregate(&self) -> Option<Aggregate> {
+        let samples = &self.samples[..self.len];
+        let latest = self.samples[(self.next + N - 1) % N];
+
+        let (min, max, sum) = samples.iter().fold(
+            (f32::INFINITY, f32::NEG_INFINITY, 0.0),
+            |(min, max, sum), &sample| (min.min(sample), max.max(sample), sum + sample),
+        );
+
+        (self.len > 0).then(|| Aggregate {
+            latest,
+            min,
+            max,
+            average: sum / self.len as f32,
+        })
+    }
+}
+
+impl<const N: usize> Default for RollingWindow<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Body of `GET /api/adc` on the STM32H743, e.g.
+/// `{"temperature_c":{"latest":41.2,"min":40.8,"max":41.9,"average":41.3},"vdda_v":{...},"window_seconds":60,"samples":1234}`.
+///
+/// `vdda_v` is the analog supply voltage, measured against the internal reference VREFINT. `samples` counts every
+/// sample since boot, the aggregates only cover the last `window_seconds`.
+#[derive(serde::Serialize)]
+pub struct AdcReadout {
+    pub temperature_c: Aggregate,
+    pub vdda_v: Aggregate,
+    pub window_seconds: u32,
+    pub samples: u32,
+}
@@ -0,0 +1,191 @@
+//! JSON API under `/api`, for scripts and home automation rather than the bundled page.
+
+use core::fmt::Write as _;
+
+use picoserve::{
+    extract::State,
+    response::{Json as JsonResponse, StatusCode},
+};
+
+use crate::{
+    auth::RequireAuth,
+    check_rate_limit,
+    json::Json,
+    metrics::Metrics,
+    rate_limit::{ToggleRateLimit, MAX_LEDS},
+    time::{Clock, Iso8601},
+    LedControl, ToggleError,
+};
+
+/// State of an LED as returned by the API, e.g. `{"led":2,"state":"on"}`.
+///
+/// LEDs which can be dimmed also have their brightness in percent, e.g. `{"led":3,"state":"on","brightness":40}`.
+#[derive(serde::Serialize)]
+pub struct LedStatus {
+    led: u8,
+    state: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    brightness: Option<u8>,
+}
+
+impl LedStatus {
+    pub(crate) fn read(control: &impl LedControl, led: u8) -> Self {
+        Self {
+            led,
+            state: if control.state(led) { "on" } else { "off" },
+            brightness: control.brightness(led),
+        }
+    }
+}
+
+/// What is done to an LED, `"on"`, `"off"` or `"toggle"`, now or by a [Schedule](crate::schedules::Schedule).
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LedCommand {
+    On,
+    Off,
+    Toggle,
+}
+
+/// Body of `POST /api/leds/<n>`, e.g. `{ "state": "toggle" }`, and of `PUT /leds/<n>` over [CoAP](crate::coap).
+#[derive(serde::Deserialize)]
+pub(crate) struct LedCommandRequest {
+    pub(crate) state: LedCommand,
+}
+
+/// The state of every LED of the board numbered below [MAX_LEDS].
+pub(crate) fn led_list(control: &impl LedControl) -> heapless::Vec<LedStatus, MAX_LEDS> {
+    (0..MAX_LEDS as u8)
+        .filter(|&led| control.has_led(led))
+        .map(|led| LedStatus::read(control, led))
+        .collect()
+}
+
+/// `GET /api/leds`: the state of every LED of the board numbered below [MAX_LEDS].
+pub(crate) async fn list_leds<C: LedControl>(
+    _: RequireAuth,
+    State(control): State<C>,
+) -> JsonResponse<heapless::Vec<LedStatus, MAX_LEDS>> {
+    JsonResponse(led_list(&control))
+}
+
+/// Turn LED `led` on or off, or toggle it within the [ToggleRateLimit], for the API and [CoAP](crate::coap) alike.
+pub(crate) fn apply_led_command(
+    control: &impl LedControl,
+    clock: &impl Clock,
+    metrics: &Metrics,
+    rate_limit: &ToggleRateLimit,
+    led: u8,
+    command: LedCommand,
+) -> Result<LedStatus, ToggleError> {
+    metrics.count_led();
+
+    // The LED is part of the resource path here, so an unknown one is not found rather than a bad request
+    if !control.has_led(led) {
+        return Err((StatusCode::NOT_FOUND, None, "Unknown LED\n"));
+    }
+
+    match command {
+        LedCommand::On => control.set(led, true),
+        LedCommand::Off => control.set(led, false),
+        LedCommand::Toggle => {
+            check_rate_limit(rate_limit, led, clock)?;
+            control.toggle(led);
+        }
+    }
+
+    log_debug!("LED{} set through the API", led);
+    Ok(LedStatus::read(control, led))
+}
+
+/// `POST /api/leds/<n>`: turn LED `n` on or off, or toggle it within the [ToggleRateLimit].
+pub(crate) async fn command_led<C: LedControl, T: Clock>(
+    led: u8,
+    _: RequireAuth,
+    State(control): State<C>,
+    State(clock): State<T>,
+    State(metrics): State<&'static Metrics>,
+    State(rate_limit): State<&'static ToggleRateLimit>,
+    Json(request): Json<LedCommandRequest>,
+) -> Result<JsonResponse<LedStatus>, ToggleError> {
+    apply_led_command(&control, &clock, metrics, rate_limit, led, request.state).map(JsonResponse)
+}
+
+/// Body of `POST /api/leds/<n>/brightness`, e.g. `{ "brightness": 40 }`.
+#[derive(serde::Deserialize)]
+pub(crate) struct BrightnessRequest {
+    brightness: u8,
+}
+
+/// `POST /api/leds/<n>/brightness`: dim LED `n` to a brightness between 0 and 100 percent.
+pub(crate) async fn set_brightness<C: LedControl>(
+    led: u8,
+    _: RequireAuth,
+    State(control): State<C>,
+    State(metrics): State<&'static Metrics>,
+    Json(request): Json<BrightnessRequest>,
+) -> Result<JsonResponse<LedStatus>, ToggleError> {
+    metrics.count_led();
+
+    if !control.has_led(led) {
+        return Err((StatusCode::NOT_FOUND, None, "Unknown LED\n"));
+    }
+
+    if control.brightness(led).is_none() {
+        return Err((
+            StatusCode::CONFLICT,
+            None,
+            "This LED can only be turned on and off\n",
+        ));
+    }
+
+    if request.brightness > 100 {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            None,
+            "Brightness must be between 0 and 100\n",
+        ));
+    }
+
+    control.set_brightness(led, request.brightness);
+
+    log_debug!("LED{} dimmed to {}%", led, request.brightness);
+    Ok(JsonResponse(LedStatus::read(&control, led)))
+}
+
+/// Body of `GET /api/time`, e.g. `{"time":"2024-05-01T12:34:56Z","unix_time":1714566896,"uptime_seconds":120}`.
+///
+/// `time` and `unix_time` are `null` until the clock is synchronized.
+#[derive(serde::Serialize)]
+pub struct TimeStatus {
+    time: Option<heapless::String<24>>,
+    unix_time: Option<u64>,
+    uptime_seconds: u64,
+}
+
+/// `GET /api/time`: the wall-clock time of the board and its uptime.
+pub(crate) async fn get_time<T: Clock>(
+    _: RequireAuth,
+    State(clock): State<T>,
+) -> JsonResponse<TimeStatus> {
+    let unix_time = clock.unix_time();
+
+    JsonResponse(TimeStatus {
+        time: unix_time.map(|unix_time| {
+            let mut time = heapless::String::new();
+            // An ISO-8601 timestamp is at most 20 bytes for any year below 10000
+            let _ = write!(time, "{}", Iso8601(unix_time));
+            time
+        }),
+        unix_time,
+        uptime_seconds: clock.uptime().as_secs(),
+    })
+}
+
+/// Body of the answer to `POST /api/reboot`, e.g. `{"reboot_in_ms":3000}`.
+///
+/// The route is added by the demos, which reboot in their own way once the response is written.
+#[derive(serde::Serialize)]
+pub struct Rebooting {
+    pub reboot_in_ms: u64,
+}
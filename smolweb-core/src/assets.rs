@@ -0,0 +1,137 @@
+//! Files read at runtime from storage such as an SD card, served under `/static` in front of the embedded ones.
+//!
+//! Assets can then be changed by replacing the files, without building and flashing new firmware, or over the network
+//! with [`POST /api/upload`](crate::upload) in stores which can write them.
+
+use picoserve::{
+    io::{Read, Write},
+    response::{Connection, Content},
+};
+
+/// Storage holding the files served under `/static`.
+#[allow(async_fn_in_trait)] // Only awaited with the concrete type known, where the future is `Send` if the implementation's is
+pub trait AssetStore {
+    type File: AssetFile;
+
+    /// Open the file at `path` below `/static`, e.g. `/css/theme.css`.
+    ///
+    /// The path has been checked to only contain plain file and directory names, never `..`.
+    /// Returns `None` if there is no such file, so that the embedded file of the same name is served.
+    async fn open(&self, path: &str) -> Option<Self::File>;
+
+    /// Returns true if files can be written with [Self::write], false for read-only stores.
+    fn is_writable(&self) -> bool {
+        false
+    }
+
+    /// Write `bytes` at `offset` of the file at `path`, checked like those of [Self::open]. Writing at offset 0
+    /// creates the file, or empties it first if it exists, and later writes follow each other.
+    ///
+    /// Returns false if the file could not be written.
+    async fn write(&self, _path: &str, _offset: usize, _bytes: &[u8]) -> bool {
+        false
+    }
+
+    /// Remove the file at `path`, one whose upload failed halfway, returning false if it could not be.
+    async fn remove(&self, _path: &str) -> bool {
+        false
+    }
+}
+
+/// A file opened by an [AssetStore].
+#[allow(async_fn_in_trait)]
+pub trait AssetFile {
+    /// Size of the file in bytes, sent as the `Content-Length`.
+    fn size(&self) -> usize;
+
+    /// Read the next bytes of the file into `buffer`, returning how many were read, or `None` on error.
+    async fn read(&mut self, buffer: &mut [u8]) -> Option<usize>;
+}
+
+/// [AssetStore] of demos without storage, which only serve the embedded files.
+#[derive(Clone, Copy)]
+pub struct NoAssetStore;
+
+/// The files of [NoAssetStore], of which there are none.
+pub enum NoAssetFile {}
+
+impl AssetStore for NoAssetStore {
+    type File = NoAssetFile;
+
+    async fn open(&self, _path: &str) -> Option<NoAssetFile> {
+        None
+    }
+}
+
+impl AssetFile for NoAssetFile {
+    fn size(&self) -> usize {
+        match *self {}
+    }
+
+    async fn read(&mut self, _buffer: &mut [u8]) -> Option<usize> {
+        match *self {}
+    }
+}
+
+/// Returns true if every segment of `path` is a plain name made of letters, digits, `-`, `_` and `.`, other than `.` and `..`.
+pub(crate) fn is_safe_path(path: &str) -> bool {
+    let Some(path) = path.strip_prefix('/') else {
+        return false;
+    };
+
+    path.split('/').all(|segment| {
+        !segment.is_empty()
+            && segment != "."
+            && segment != ".."
+            && segment
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'))
+    })
+}
+
+/// Bytes read from the store at once.
+const CHUNK_SIZE: usize = 512;
+
+/// An [AssetFile] sent as a response body, read from the store while it is written.
+pub(crate) struct StoredFile<F> {
+    pub(crate) file: F,
+    pub(crate) content_type: &'static str,
+}
+
+impl<F: AssetFile> Content for StoredFile<F> {
+    fn content_type(&self) -> &'static str {
+        self.content_type
+    }
+
+    fn content_length(&self) -> usize {
+        self.file.size()
+    }
+
+    async fn write_content<R: Read, W: Write>(
+        mut self,
+        _connection: Connection<'_, R>,
+        mut writer: W,
+    ) -> Result<(), W::Error> {
+        let mut buffer = [0; CHUNK_SIZE];
+        let mut remaining = self.file.size();
+
+        while remaining > 0 {
+            // The headers are already sent, so all that is left to do is stop and let the client see a short body
+            let Some(length) = self
+                .file
+                .read(&mut buffer)
+                .await
+                .filter(|&length| length > 0)
+            else {
+                log_warn!("Failed to read asset, {} bytes short", remaining);
+                break;
+            };
+
+            let length = length.min(remaining);
+            writer.write_all(&buffer[..length]).await?;
+            remaining -= length;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,204 @@
+use picoserve::{
+    extract::{FromRef, FromRequestParts},
+    io::Read,
+    request::RequestParts,
+    response::{Connection, IntoResponse, ResponseWriter, StatusCode},
+    ResponseSent,
+};
+
+use crate::{
+    error::Refusal,
+    limits::{self, CutShort, Limits},
+    rate_limit::{ClientAddress, ClientRateLimit},
+    session::SessionContext,
+};
+
+/// Longest decoded `username:password` accepted in an `Authorization` header.
+const MAX_CREDENTIALS_LEN: usize = 64;
+
+/// Unwrap an `option_env!` in a `const`, where `Option::unwrap_or` can't be called.
+macro_rules! env_or {
+    ($name:literal, $default:expr) => {
+        match option_env!($name) {
+            Some(value) => value,
+            None => $default,
+        }
+    };
+}
+
+/// Secrets checked by [RequireAuth], extracted from the application state.
+#[derive(Clone, Copy)]
+pub struct Credentials {
+    pub username: &'static str,
+    pub password: &'static str,
+    /// Accepted in an `X-Api-Key` header instead of Basic credentials, or `None` to only accept the latter.
+    pub api_key: Option<&'static str>,
+}
+
+impl Credentials {
+    /// The credentials set by the `SMOLWEB_USERNAME`, `SMOLWEB_PASSWORD` and `SMOLWEB_API_KEY` environment variables
+    /// when building, `admin` / `smolweb` without an API key by default.
+    pub const fn from_build_env() -> Self {
+        Self {
+            username: env_or!("SMOLWEB_USERNAME", "admin"),
+            password: env_or!("SMOLWEB_PASSWORD", "smolweb"),
+            api_key: option_env!("SMOLWEB_API_KEY"),
+        }
+    }
+}
+
+impl Credentials {
+    /// Returns true if `username` and `password` are these credentials.
+    pub(crate) fn are_valid(&self, username: &[u8], password: &[u8]) -> bool {
+        // Check both halves before combining the results so a wrong username takes as long as a wrong password
+        let username_is_valid = constant_time_eq(username, self.username.as_bytes());
+        let password_is_valid = constant_time_eq(password, self.password.as_bytes());
+
+        username_is_valid & password_is_valid
+    }
+}
+
+impl Default for Credentials {
+    fn default() -> Self {
+        Self::from_build_env()
+    }
+}
+
+// Not derived, so that the secrets don't end up in a log
+impl core::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .field("api_key", &self.api_key.map(|_| "<set>"))
+            .finish_non_exhaustive()
+    }
+}
+
+/// Extractor which rejects requests without valid HTTP Basic credentials or API key, taken from [Credentials], or the
+/// cookie of a session opened with them at `/login`, checked against the [SessionContext].
+///
+/// Before the credentials, it refuses the requests over the [Limits] of the state, and those over the
+/// [ClientRateLimit] of their [ClientAddress], so that guessing them is slowed down too.
+pub struct RequireAuth;
+
+/// Why [RequireAuth] rejected a request.
+pub enum AuthRejection {
+    /// Refused before its credentials were checked.
+    Refused(Refusal),
+    /// Without valid credentials, answered with `401 Unauthorized` asking for Basic credentials.
+    Unauthorized,
+}
+
+impl IntoResponse for AuthRejection {
+    async fn write_to<R: Read, W: ResponseWriter<Error = R::Error>>(
+        self,
+        connection: Connection<'_, R>,
+        response_writer: W,
+    ) -> Result<ResponseSent, W::Error> {
+        match self {
+            Self::Refused(refusal) => refusal.write_to(connection, response_writer).await,
+            Self::Unauthorized => {
+                (
+                    StatusCode::UNAUTHORIZED,
+                    ("WWW-Authenticate", "Basic realm=\"smolweb\""),
+                    "Unauthorized\n",
+                )
+                    .write_to(connection, response_writer)
+                    .await
+            }
+        }
+    }
+}
+
+/// Compares every byte of `expected` no matter where the first mismatch is,
+/// so response timing does not reveal how much of the credentials were correct.
+pub(crate) fn constant_time_eq(provided: &[u8], expected: &[u8]) -> bool {
+    let mut difference = provided.len() ^ expected.len();
+
+    for (index, &expected_byte) in expected.iter().enumerate() {
+        let provided_byte = provided.get(index).copied().unwrap_or(0);
+        difference |= usize::from(provided_byte ^ expected_byte);
+    }
+
+    difference == 0
+}
+
+fn basic_credentials_are_valid(credentials: &Credentials, header: &[u8]) -> bool {
+    let Some(encoded) = header
+        .strip_prefix(b"Basic ")
+        .or_else(|| header.strip_prefix(b"basic "))
+    else {
+        return false;
+    };
+
+    let mut decoded = [0; MAX_CREDENTIALS_LEN];
+
+    let Some(decoded) = data_encoding::BASE64
+        .decode_len(encoded.len())
+        .ok()
+        .and_then(|length| decoded.get_mut(..length))
+    else {
+        return false;
+    };
+
+    let Ok(length) = data_encoding::BASE64.decode_mut(encoded, decoded) else {
+        return false;
+    };
+
+    let Some((username, password)) = decoded[..length]
+        .iter()
+        .position(|&b| b == b':')
+        .map(|colon| (&decoded[..colon], &decoded[(colon + 1)..length]))
+    else {
+        return false;
+    };
+
+    credentials.are_valid(username, password)
+}
+
+impl<'r, State> FromRequestParts<'r, State> for RequireAuth
+where
+    Credentials: FromRef<State>,
+    SessionContext: FromRef<State>,
+    &'static ClientRateLimit: FromRef<State>,
+    ClientAddress: FromRef<State>,
+    Limits: FromRef<State>,
+    CutShort: FromRef<State>,
+{
+    type Rejection = AuthRejection;
+
+    async fn from_request_parts(
+        state: &'r State,
+        request_parts: &RequestParts<'r>,
+    ) -> Result<Self, Self::Rejection> {
+        let context = SessionContext::from_ref(state);
+
+        limits::check(state, request_parts).map_err(AuthRejection::Refused)?;
+        <&'static ClientRateLimit>::from_ref(state)
+            .admit(
+                ClientAddress::from_ref(state),
+                context.uptime,
+                request_parts,
+            )
+            .map_err(AuthRejection::Refused)?;
+
+        let credentials = Credentials::from_ref(state);
+        let headers = request_parts.headers();
+
+        let basic_is_valid = headers
+            .get("authorization")
+            .is_some_and(|header| basic_credentials_are_valid(&credentials, header.as_raw()));
+
+        let api_key_is_valid = credentials.api_key.is_some_and(|api_key| {
+            headers
+                .get("x-api-key")
+                .is_some_and(|header| constant_time_eq(header.as_raw(), api_key.as_bytes()))
+        });
+
+        let session_is_valid = context.request_has_session(request_parts);
+
+        (basic_is_valid || api_key_is_valid || session_is_valid)
+            .then_some(Self)
+            .ok_or(AuthRejection::Unauthorized)
+    }
+}
@@ -0,0 +1,48 @@
+//! Sizes of the buffers of a web worker, set in one place for every demo.
+//!
+//! A worker owns its buffers for as long as it runs, so the RAM of the web server is the sum of those of its workers,
+//! known when building. Large responses don't need large buffers, as picoserve streams bodies and the long ones, like
+//! `GET /logs`, are sent in [chunks](crate::chunked). The `http` buffer does bound the requests though, which the
+//! [Limits](crate::limits::Limits) keep within it.
+
+/// Sizes in bytes of the buffers of a web worker.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BufferConfig {
+    /// TCP receive window, how much a client can send ahead of the worker reading it.
+    pub tcp_rx: usize,
+    /// TCP send buffer, how much of a response can be in flight before writing waits for acknowledgements.
+    pub tcp_tx: usize,
+    /// Holds the request line, the headers and the part of the body read with them, so it bounds the largest request
+    /// head. Bodies are read through it, so they can be longer.
+    pub http: usize,
+}
+
+impl BufferConfig {
+    /// The buffers of the web workers of every demo.
+    ///
+    /// A `POST /api/strip` setting every pixel, about 900 bytes, still fits in `http` with its headers.
+    pub const WEB: Self = Self {
+        tcp_rx: 1024,
+        tcp_tx: 1024,
+        http: 2048,
+    };
+
+    /// The buffers of the port 80 [redirect](crate::redirect), which only reads the request head and answers a few
+    /// hundred bytes.
+    pub const REDIRECT: Self = Self {
+        tcp_rx: 512,
+        tcp_tx: 256,
+        http: 1024,
+    };
+
+    /// RAM taken by one worker.
+    pub const fn total(&self) -> usize {
+        self.tcp_rx + self.tcp_tx + self.http
+    }
+}
+
+impl Default for BufferConfig {
+    fn default() -> Self {
+        Self::WEB
+    }
+}
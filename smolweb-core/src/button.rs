@@ -0,0 +1,87 @@
+use core::{fmt::Write, sync::atomic::Ordering, time::Duration};
+
+use picoserve::{extract::State, response::Json};
+use portable_atomic::AtomicU32;
+
+use crate::{
+    auth::RequireAuth,
+    time::{Clock, Iso8601},
+};
+
+/// Presses of the user button shown by `GET /api/button`, shared as `&'static ButtonStats`.
+///
+/// Boards without a button keep one which is never pressed, so the page and the API are the same everywhere.
+pub struct ButtonStats {
+    presses: AtomicU32,
+    /// Uptime in milliseconds of the last press, plus one so that 0 means never.
+    ///
+    /// This wraps after 49 days, like the uptimes in [ToggleRateLimit](crate::rate_limit::ToggleRateLimit).
+    last_press: AtomicU32,
+}
+
+impl ButtonStats {
+    pub const fn new() -> Self {
+        Self {
+            presses: AtomicU32::new(0),
+            last_press: AtomicU32::new(0),
+        }
+    }
+
+    /// Presses since boot.
+    pub fn presses(&self) -> u32 {
+        self.presses.load(Ordering::Relaxed)
+    }
+
+    /// Record a press at `uptime`, called by the task watching the button.
+    pub fn record_press(&self, uptime: Duration) {
+        self.presses.fetch_add(1, Ordering::Relaxed);
+        self.last_press.store(
+            (uptime.as_millis() as u32).wrapping_add(1),
+            Ordering::Relaxed,
+        );
+    }
+}
+
+impl Default for ButtonStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Body of `GET /api/button`, e.g. `{"presses":3,"last_press_uptime_ms":5120,"last_press":"2024-05-01T12:34:56Z"}`.
+///
+/// The last press is `null` until the button is pressed, and its time until the clock is synchronized too.
+#[derive(serde::Serialize)]
+pub(crate) struct ButtonStatus {
+    presses: u32,
+    last_press_uptime_ms: Option<u32>,
+    last_press: Option<heapless::String<24>>,
+}
+
+pub(crate) async fn get_button<T: Clock>(
+    _: RequireAuth,
+    State(clock): State<T>,
+    State(button): State<&'static ButtonStats>,
+) -> Json<ButtonStatus> {
+    let last_press_uptime_ms = button.last_press.load(Ordering::Relaxed).checked_sub(1);
+
+    let last_press = last_press_uptime_ms
+        .zip(clock.unix_time())
+        .map(|(uptime_ms, unix_time)| {
+            let elapsed = (clock.uptime().as_millis() as u32).wrapping_sub(uptime_ms);
+            let mut last_press = heapless::String::new();
+            // An ISO-8601 timestamp is at most 21 bytes for any year below 10000
+            let _ = write!(
+                last_press,
+                "{}",
+                Iso8601(unix_time.saturating_sub(u64::from(elapsed / 1000)))
+            );
+            last_press
+        });
+
+    Json(ButtonStatus {
+        presses: button.presses.load(Ordering::Relaxed),
+        last_press_uptime_ms,
+        last_press,
+    })
+}
@@ -0,0 +1,131 @@
+use picoserve::{
+    io::{Read, Write},
+    request::Request,
+    response::{Connection, Content, File, Response, ResponseWriter, StatusCode},
+    routing::RequestHandlerService,
+    ResponseSent,
+};
+
+use crate::static_files::trim;
+
+/// Length of a quoted SHA-1 in hexadecimal.
+const ETAG_LEN: usize = 2 + 2 * 20;
+
+/// The SHA-1 of `body` as a quoted hexadecimal string, the same `ETag` as [File] sends.
+const fn etag(body: &[u8]) -> [u8; ETAG_LEN] {
+    const HEX: &[u8; 16] = b"0123456789abcdef";
+
+    let digest = const_sha1::sha1(body).as_bytes();
+    let mut etag = [b'"'; ETAG_LEN];
+
+    let mut i = 0;
+    while i < digest.len() {
+        etag[1 + 2 * i] = HEX[(digest[i] >> 4) as usize];
+        etag[2 + 2 * i] = HEX[(digest[i] & 0xf) as usize];
+        i += 1;
+    }
+
+    etag
+}
+
+/// Returns true if an `If-None-Match` header names `etag`, or is `*`.
+fn matches_etag(if_none_match: &[u8], etag: &[u8]) -> bool {
+    if_none_match.split(|&b| b == b',').any(|candidate| {
+        let candidate = trim(candidate);
+        // `If-None-Match` uses the weak comparison, which ignores the `W/` prefix
+        let candidate = candidate.strip_prefix(b"W/").unwrap_or(candidate);
+        candidate == b"*" || candidate == etag
+    })
+}
+
+/// Headers of a `304 Not Modified` response, describing the file the client already has.
+struct NotModified {
+    content_type: &'static str,
+    content_length: usize,
+}
+
+impl Content for NotModified {
+    fn content_type(&self) -> &'static str {
+        self.content_type
+    }
+
+    fn content_length(&self) -> usize {
+        self.content_length
+    }
+
+    async fn write_content<R: Read, W: Write>(
+        self,
+        _connection: Connection<'_, R>,
+        _writer: W,
+    ) -> Result<(), W::Error> {
+        Ok(())
+    }
+}
+
+/// An embedded [File] which answers a matching `If-None-Match` with `304 Not Modified` and no body.
+///
+/// [File] compares `If-None-Match` to its `ETag` without the quotes, so browsers, which send it back quoted, never match.
+/// Build it in a `const` or `static` so that the hash is computed at compile time.
+pub struct CachedFile {
+    file: File,
+    etag: [u8; ETAG_LEN],
+    content_type: &'static str,
+    content_length: usize,
+    headers: &'static [(&'static str, &'static str)],
+}
+
+impl CachedFile {
+    /// Like [File::with_content_type_and_headers], the headers are sent with both `200 OK` and `304 Not Modified`.
+    pub const fn new(
+        content_type: &'static str,
+        body: &'static [u8],
+        headers: &'static [(&'static str, &'static str)],
+    ) -> Self {
+        Self {
+            file: File::with_content_type_and_headers(content_type, body, headers),
+            etag: etag(body),
+            content_type,
+            content_length: body.len(),
+            headers,
+        }
+    }
+}
+
+impl<State, PathParameters> RequestHandlerService<State, PathParameters> for CachedFile {
+    async fn call_request_handler_service<R: Read, W: ResponseWriter<Error = R::Error>>(
+        &self,
+        state: &State,
+        path_parameters: PathParameters,
+        request: Request<'_, R>,
+        response_writer: W,
+    ) -> Result<ResponseSent, W::Error> {
+        let not_modified = request
+            .parts
+            .headers()
+            .get("If-None-Match")
+            .is_some_and(|if_none_match| matches_etag(if_none_match.as_raw(), &self.etag[..]));
+
+        if !not_modified {
+            return self
+                .file
+                .call_request_handler_service(state, path_parameters, request, response_writer)
+                .await;
+        }
+
+        // `Content-Length` is allowed in a 304 if it is the length of the body a 200 would have sent
+        let response = Response::new(
+            StatusCode::NOT_MODIFIED,
+            NotModified {
+                content_type: self.content_type,
+                content_length: self.content_length,
+            },
+        )
+        .with_headers(self.headers)
+        // Hexadecimal digits and quotes are ASCII
+        .with_header("ETag", core::str::from_utf8(&self.etag).unwrap_or_default());
+
+        response_writer
+            .write_response(request.body_connection.finalize().await?, response)
+            .await
+    }
+}
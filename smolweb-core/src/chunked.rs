@@ -0,0 +1,80 @@
+//! Responses streamed a [CHUNK_SIZE] piece at a time, for bodies larger than the buffers.
+//!
+//! A [ChunkSource] fills a buffer with the next part of the body, and [Chunked] writes each part as soon as it is
+//! filled, so the whole body is never held in memory and its size isn't bounded by any
+//! [BufferConfig](crate::buffers::BufferConfig). picoserve sends every body it is given as [Content], with a
+//! `Content-Length`, so a source knows its length up front. Bodies which never end, like the followed logs, are event
+//! streams instead.
+
+use picoserve::{
+    io::{Read, Write},
+    response::{Connection, Content, IntoResponse, Response, ResponseWriter},
+    ResponseSent,
+};
+
+/// Bytes read from a [ChunkSource] at a time, taken from the stack of the worker while the body is sent.
+pub const CHUNK_SIZE: usize = 256;
+
+/// Body of a [Chunked] response, read a part at a time.
+#[allow(async_fn_in_trait)] // Only awaited with the concrete type known, where the future is `Send` if the implementation's is
+pub trait ChunkSource {
+    fn content_type(&self) -> &'static str;
+
+    /// Length of the whole body, which [Self::fill] then delivers.
+    fn content_length(&self) -> usize;
+
+    /// Copy the next part of the body into `buffer`, returning its length, or 0 at the end of the body.
+    ///
+    /// A source ending before [Self::content_length] has its body padded with spaces, so that the response stays whole.
+    async fn fill(&mut self, buffer: &mut [u8]) -> usize;
+}
+
+/// [Content] sending the parts of a [ChunkSource].
+pub struct Chunked<S>(pub S);
+
+impl<S: ChunkSource> Content for Chunked<S> {
+    fn content_type(&self) -> &'static str {
+        self.0.content_type()
+    }
+
+    fn content_length(&self) -> usize {
+        self.0.content_length()
+    }
+
+    async fn write_content<R: Read, W: Write>(
+        mut self,
+        _connection: Connection<'_, R>,
+        mut writer: W,
+    ) -> Result<(), W::Error> {
+        let mut buffer = [0; CHUNK_SIZE];
+        let mut remaining = self.0.content_length();
+
+        while remaining > 0 {
+            let part = &mut buffer[..remaining.min(CHUNK_SIZE)];
+            let length = match self.0.fill(part).await {
+                0 => {
+                    part.fill(b' ');
+                    part.len()
+                }
+                length => length.min(part.len()),
+            };
+
+            writer.write_all(&part[..length]).await?;
+            remaining -= length;
+        }
+
+        Ok(())
+    }
+}
+
+impl<S: ChunkSource> IntoResponse for Chunked<S> {
+    async fn write_to<R: Read, W: ResponseWriter<Error = R::Error>>(
+        self,
+        connection: Connection<'_, R>,
+        response_writer: W,
+    ) -> Result<ResponseSent, W::Error> {
+        response_writer
+            .write_response(connection, Response::ok(self))
+            .await
+    }
+}
@@ -0,0 +1,459 @@
+//! CoAP server mirroring the LED API, for constrained-IoT tools such as `coap-client` or Copper.
+//!
+//! [respond] answers `GET /leds` and `GET /leds/<n>` with the JSON of `GET /api/leds`, and `PUT /leds/<n>` with a
+//! body like the one of `POST /api/leds/<n>`, e.g. `{"state":"toggle"}`, through the same handler code. CoAP has no
+//! Basic authentication, so `PUT` takes the API key of the [Credentials] as a `key=<key>` query instead, and is refused
+//! with `4.01 Unauthorized` without it, or when no API key is set. Like the HTTP routes taking credentials, each `PUT`
+//! takes a request from the bucket of its sender in the [ClientRateLimit] before the key is checked, and is refused
+//! with `4.29 Too Many Requests` and a Max-Age once it is empty. Reading the LEDs needs no key. With the `embassy`
+//! feature, [run] serves it on [COAP_PORT].
+
+use crate::{
+    api::{self, LedCommandRequest, LedStatus},
+    auth::{constant_time_eq, Credentials},
+    metrics::Metrics,
+    rate_limit::{ClientAddress, ClientRateLimit, ToggleRateLimit, MAX_LEDS},
+    time::Clock,
+    LedControl, ToggleError,
+};
+
+/// The UDP port of CoAP.
+pub const COAP_PORT: u16 = 5683;
+
+/// Largest request or response handled, enough for every LED of [MAX_LEDS] in a response.
+pub const MAX_MESSAGE_SIZE: usize = 512;
+
+const VERSION: u8 = 1;
+
+const TYPE_CONFIRMABLE: u8 = 0;
+const TYPE_NON_CONFIRMABLE: u8 = 1;
+const TYPE_ACKNOWLEDGEMENT: u8 = 2;
+const TYPE_RESET: u8 = 3;
+
+/// A code is a class and a detail, written `c.dd`.
+const fn code(class: u8, detail: u8) -> u8 {
+    (class << 5) | detail
+}
+
+const EMPTY: u8 = code(0, 0);
+const GET: u8 = code(0, 1);
+const PUT: u8 = code(0, 3);
+const CHANGED: u8 = code(2, 4);
+const CONTENT: u8 = code(2, 5);
+const BAD_REQUEST: u8 = code(4, 0);
+const UNAUTHORIZED: u8 = code(4, 1);
+const BAD_OPTION: u8 = code(4, 2);
+const NOT_FOUND: u8 = code(4, 4);
+const METHOD_NOT_ALLOWED: u8 = code(4, 5);
+const TOO_MANY_REQUESTS: u8 = code(4, 29);
+
+const OPTION_URI_HOST: u16 = 3;
+const OPTION_URI_PORT: u16 = 7;
+const OPTION_URI_PATH: u16 = 11;
+const OPTION_CONTENT_FORMAT: u16 = 12;
+const OPTION_MAX_AGE: u16 = 14;
+const OPTION_URI_QUERY: u16 = 15;
+
+/// Content formats, `text/plain;charset=utf-8` being 0.
+const FORMAT_TEXT: u16 = 0;
+const FORMAT_JSON: u16 = 50;
+
+/// Most segments of a path the server compares, `/leds/<n>` has two.
+const MAX_PATH_SEGMENTS: usize = 2;
+
+/// A request read by [Request::parse].
+struct Request<'a> {
+    confirmable: bool,
+    code: u8,
+    message_id: u16,
+    token: &'a [u8],
+    path: heapless::Vec<&'a [u8], MAX_PATH_SEGMENTS>,
+    /// Set when the path has more segments than any resource.
+    path_too_long: bool,
+    /// Set when the request has a critical option the server doesn't know, which fails it.
+    unknown_critical_option: bool,
+    /// The `key=` query, which `PUT` requires.
+    key: Option<&'a [u8]>,
+    payload: &'a [u8],
+}
+
+impl<'a> Request<'a> {
+    /// Reads `message`, returning `None` if it is malformed or not a request.
+    fn parse(message: &'a [u8]) -> Option<Self> {
+        let header = message.get(..4)?;
+        let token_length = usize::from(header[0] & 0x0F);
+        let message_type = (header[0] >> 4) & 0x03;
+
+        if header[0] >> 6 != VERSION
+            || token_length > 8
+            || !matches!(message_type, TYPE_CONFIRMABLE | TYPE_NON_CONFIRMABLE)
+        {
+            return None;
+        }
+
+        let mut request = Self {
+            confirmable: message_type == TYPE_CONFIRMABLE,
+            code: header[1],
+            message_id: u16::from_be_bytes([header[2], header[3]]),
+            token: message.get(4..(4 + token_length))?,
+            path: heapless::Vec::new(),
+            path_too_long: false,
+            unknown_critical_option: false,
+            key: None,
+            payload: &[],
+        };
+
+        let mut position = 4 + token_length;
+        let mut number = 0_u16;
+
+        while let Some(&byte) = message.get(position) {
+            position += 1;
+
+            if byte == 0xFF {
+                request.payload = &message[position..];
+                break;
+            }
+
+            let delta = extended(byte >> 4, message, &mut position)?;
+            let length = usize::from(extended(byte & 0x0F, message, &mut position)?);
+            let value = message.get(position..(position + length))?;
+            position += length;
+            number = number.checked_add(delta)?;
+
+            match number {
+                OPTION_URI_PATH => request.path_too_long |= request.path.push(value).is_err(),
+                // Other queries are ignored, like those of the HTTP routes
+                OPTION_URI_QUERY => request.key = value.strip_prefix(b"key=").or(request.key),
+                // The server answers on every host and port it is reached at
+                OPTION_URI_HOST | OPTION_URI_PORT => {}
+                // Odd options are critical, elective ones can be ignored
+                number if number % 2 == 1 => request.unknown_critical_option = true,
+                _ => {}
+            }
+        }
+
+        Some(request)
+    }
+
+    /// Returns true if the request has the API key of `credentials`, and one is set.
+    fn has_api_key(&self, credentials: &Credentials) -> bool {
+        match (self.key, credentials.api_key) {
+            (Some(key), Some(api_key)) => constant_time_eq(key, api_key.as_bytes()),
+            _ => false,
+        }
+    }
+}
+
+/// The value of the 4 bit delta or length of an option, read from the following bytes when it is 13 or 14.
+fn extended(nibble: u8, message: &[u8], position: &mut usize) -> Option<u16> {
+    match nibble {
+        13 => {
+            let value = u16::from(*message.get(*position)?) + 13;
+            *position += 1;
+            Some(value)
+        }
+        14 => {
+            let bytes = message.get(*position..(*position + 2))?;
+            *position += 2;
+            u16::from_be_bytes([bytes[0], bytes[1]]).checked_add(269)
+        }
+        // Only a payload marker has 15 as delta and length
+        15 => None,
+        nibble => Some(u16::from(nibble)),
+    }
+}
+
+/// Body of a [Reply].
+enum Body {
+    Text(&'static str),
+    Led(LedStatus),
+    Leds(heapless::Vec<LedStatus, MAX_LEDS>),
+}
+
+/// What a resource answers.
+struct Reply {
+    code: u8,
+    /// Seconds before the request may be made again, sent as Max-Age with `4.29 Too Many Requests`.
+    max_age: Option<u64>,
+    body: Body,
+}
+
+impl Reply {
+    fn new(code: u8, body: Body) -> Self {
+        Self {
+            code,
+            max_age: None,
+            body,
+        }
+    }
+
+    fn error(code: u8, message: &'static str) -> Self {
+        Self::new(code, Body::Text(message))
+    }
+}
+
+impl From<ToggleError> for Reply {
+    /// The same error as over HTTP, `429 Too Many Requests` becoming `4.29` with the `Retry-After` as Max-Age.
+    fn from((status, header, message): ToggleError) -> Self {
+        let status = status.as_u16();
+
+        Self {
+            code: code((status / 100) as u8, (status % 100) as u8),
+            max_age: header.map(|(_, retry_after)| retry_after),
+            body: Body::Text(message.trim_end()),
+        }
+    }
+}
+
+/// Answers `request` of `client` with the LED API.
+#[allow(clippy::too_many_arguments)] // The state the HTTP routes extract, each from the application state
+fn handle(
+    request: &Request,
+    control: &impl LedControl,
+    clock: &impl Clock,
+    credentials: &Credentials,
+    metrics: &Metrics,
+    rate_limit: &ToggleRateLimit,
+    client_rate_limit: &ClientRateLimit,
+    client: ClientAddress,
+) -> Reply {
+    if request.unknown_critical_option {
+        return Reply::error(BAD_OPTION, "Unsupported critical option");
+    }
+
+    let led = match (request.path.as_slice(), request.path_too_long) {
+        ([b"leds"], false) => {
+            return match request.code {
+                GET => Reply::new(CONTENT, Body::Leds(api::led_list(control))),
+                _ => Reply::error(METHOD_NOT_ALLOWED, "Only GET is allowed"),
+            };
+        }
+        ([b"leds", led], false) => core::str::from_utf8(led)
+            .ok()
+            .and_then(|led| led.parse::<u8>().ok()),
+        _ => return Reply::error(NOT_FOUND, "Not found"),
+    };
+
+    let Some(led) = led.filter(|&led| control.has_led(led)) else {
+        return Reply::error(NOT_FOUND, "Unknown LED");
+    };
+
+    match request.code {
+        GET => Reply::new(CONTENT, Body::Led(LedStatus::read(control, led))),
+        PUT => {
+            if let Some(client) = client.0 {
+                if let Err(retry_after) = client_rate_limit.try_request(client, clock.uptime()) {
+                    log_debug!("Too many requests from this client, refusing the CoAP PUT");
+                    return Reply {
+                        max_age: Some(
+                            retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0),
+                        ),
+                        ..Reply::error(TOO_MANY_REQUESTS, "Too many requests")
+                    };
+                }
+            }
+
+            if !request.has_api_key(credentials) {
+                return Reply::error(UNAUTHORIZED, "PUT requires the API key as ?key=<key>");
+            }
+
+            let Ok((command, _)) =
+                serde_json_core::from_slice::<LedCommandRequest>(request.payload)
+            else {
+                return Reply::error(BAD_REQUEST, r#"Expected {"state":"on"}, "off" or "toggle""#);
+            };
+
+            match api::apply_led_command(control, clock, metrics, rate_limit, led, command.state) {
+                Ok(status) => Reply::new(CHANGED, Body::Led(status)),
+                Err(err) => err.into(),
+            }
+        }
+        _ => Reply::error(METHOD_NOT_ALLOWED, "Only GET and PUT are allowed"),
+    }
+}
+
+/// Writes a message into a fixed buffer, failing once it is full.
+struct Writer<'a> {
+    buffer: &'a mut [u8],
+    length: usize,
+    /// Number of the last option written, from which the next one is a delta.
+    last_option: u16,
+}
+
+impl Writer<'_> {
+    fn bytes(&mut self, bytes: &[u8]) -> Option<()> {
+        self.buffer
+            .get_mut(self.length..(self.length + bytes.len()))?
+            .copy_from_slice(bytes);
+        self.length += bytes.len();
+        Some(())
+    }
+
+    /// Writes option `number`, which must not be lower than the previous one, with the minimal bytes of `value`.
+    fn uint_option(&mut self, number: u16, value: u64) -> Option<()> {
+        let bytes = value.to_be_bytes();
+        let value = &bytes[(value.leading_zeros() / 8) as usize..];
+        // Every option written has a number and a value below 13
+        let delta = (number - self.last_option) as u8;
+        self.last_option = number;
+
+        self.bytes(&[(delta << 4) | value.len() as u8])?;
+        self.bytes(value)
+    }
+
+    fn json(&mut self, value: &impl serde::Serialize) -> Option<()> {
+        let length = serde_json_core::to_slice(value, self.buffer.get_mut(self.length..)?).ok()?;
+        self.length += length;
+        Some(())
+    }
+}
+
+/// Builds the response to `message`, sent by `client`, into `response`, returning its length, or `None` if there is
+/// nothing to send.
+///
+/// A confirmable request is acknowledged with the response, a non-confirmable one is answered with `message_id`.
+#[allow(clippy::too_many_arguments)] // The state the HTTP routes extract, each from the application state
+pub fn respond(
+    message: &[u8],
+    control: &impl LedControl,
+    clock: &impl Clock,
+    credentials: &Credentials,
+    metrics: &Metrics,
+    rate_limit: &ToggleRateLimit,
+    client_rate_limit: &ClientRateLimit,
+    client: ClientAddress,
+    message_id: u16,
+    response: &mut [u8],
+) -> Option<usize> {
+    let request = Request::parse(message)?;
+
+    let mut writer = Writer {
+        buffer: response,
+        length: 0,
+        last_option: 0,
+    };
+
+    // An empty confirmable message is a ping, answered with a reset
+    if request.code == EMPTY {
+        if !request.confirmable {
+            return None;
+        }
+        writer.bytes(&[(VERSION << 6) | (TYPE_RESET << 4), EMPTY])?;
+        writer.bytes(&request.message_id.to_be_bytes())?;
+        return Some(writer.length);
+    }
+
+    // Responses sent to the server are none of its business
+    if request.code >> 5 != 0 {
+        return None;
+    }
+
+    let reply = handle(
+        &request,
+        control,
+        clock,
+        credentials,
+        metrics,
+        rate_limit,
+        client_rate_limit,
+        client,
+    );
+
+    let (message_type, message_id) = if request.confirmable {
+        (TYPE_ACKNOWLEDGEMENT, request.message_id)
+    } else {
+        (TYPE_NON_CONFIRMABLE, message_id)
+    };
+
+    writer.bytes(&[
+        (VERSION << 6) | (message_type << 4) | request.token.len() as u8,
+        reply.code,
+    ])?;
+    writer.bytes(&message_id.to_be_bytes())?;
+    writer.bytes(request.token)?;
+
+    let format = match reply.body {
+        Body::Text(_) => FORMAT_TEXT,
+        Body::Led(_) | Body::Leds(_) => FORMAT_JSON,
+    };
+    writer.uint_option(OPTION_CONTENT_FORMAT, u64::from(format))?;
+    if let Some(max_age) = reply.max_age {
+        writer.uint_option(OPTION_MAX_AGE, max_age)?;
+    }
+
+    writer.bytes(&[0xFF])?;
+    match &reply.body {
+        Body::Text(text) => writer.bytes(text.as_bytes())?,
+        Body::Led(status) => writer.json(status)?,
+        Body::Leds(statuses) => writer.json(statuses)?,
+    }
+
+    Some(writer.length)
+}
+
+/// Answer CoAP requests on [COAP_PORT] with the LEDs of `control`, changed with the API key of `credentials` by the
+/// clients within their budget of `client_rate_limit`.
+#[cfg(feature = "embassy")]
+pub async fn run<D: embassy_net::driver::Driver>(
+    stack: &embassy_net::Stack<D>,
+    control: impl LedControl,
+    clock: impl Clock,
+    credentials: &Credentials,
+    metrics: &Metrics,
+    rate_limit: &ToggleRateLimit,
+    client_rate_limit: &ClientRateLimit,
+) -> ! {
+    use embassy_net::udp::{PacketMetadata, UdpSocket};
+
+    let mut rx_meta = [PacketMetadata::EMPTY; 2];
+    let mut rx_buffer = [0; MAX_MESSAGE_SIZE];
+    let mut tx_meta = [PacketMetadata::EMPTY; 2];
+    let mut tx_buffer = [0; MAX_MESSAGE_SIZE];
+
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    socket
+        .bind(COAP_PORT)
+        .expect("Only the CoAP server binds the CoAP port");
+
+    let mut message = [0; MAX_MESSAGE_SIZE];
+    let mut response = [0; MAX_MESSAGE_SIZE];
+    let mut message_id = 0_u16;
+
+    log_info!("Answering CoAP requests on port {}", COAP_PORT);
+
+    loop {
+        let (length, sender) = match socket.recv_from(&mut message).await {
+            Ok(received) => received,
+            Err(err) => {
+                log_warn!("Failed to receive CoAP request: {:?}", err);
+                continue;
+            }
+        };
+
+        message_id = message_id.wrapping_add(1);
+        let Some(response_length) = respond(
+            &message[..length],
+            &control,
+            &clock,
+            credentials,
+            metrics,
+            rate_limit,
+            client_rate_limit,
+            ClientAddress::of_endpoint(sender),
+            message_id,
+            &mut response,
+        ) else {
+            continue;
+        };
+
+        if let Err(err) = socket.send_to(&response[..response_length], sender).await {
+            log_warn!("Failed to send CoAP response: {:?}", err);
+        }
+    }
+}
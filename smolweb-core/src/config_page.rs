@@ -0,0 +1,216 @@
+//! The settings page at `/config`, a form posted back to itself, for browsers as `/api/settings` is for scripts.
+//!
+//! Saving the form stores the [Settings] and applies what can be right away: the LEDs are set to their state at boot,
+//! and the next `blink` pattern takes the new period. The hostname is only picked up by mDNS and DHCP at the next boot.
+//! `config.html` is the template, rendered in pieces like the [control panel](crate::status_page).
+
+use core::fmt::Write as _;
+
+use picoserve::{
+    extract::{Form, State},
+    io::{Read, Write},
+    response::{Connection, Content, IntoResponse, Response, StatusCode},
+};
+
+use crate::{
+    auth::RequireAuth,
+    rate_limit::MAX_LEDS,
+    settings::{Settings, SettingsStore},
+    status_page::Piece,
+    LedControl,
+};
+
+const TEMPLATE: &str = include_str!("config.html");
+
+/// Body of `POST /config`, e.g. `hostname=smolweb&led1=on&led3=on&blink_period_ms=500`.
+#[derive(serde::Deserialize)]
+pub(crate) struct ConfigForm {
+    hostname: heapless::String<32>,
+    /// Read as text, so that a malformed number gets an explanation instead of a bare `400`.
+    blink_period_ms: heapless::String<8>,
+    // Browsers send checked boxes as `led<n>=on` and leave the others out
+    #[serde(default)]
+    led0: heapless::String<4>,
+    #[serde(default)]
+    led1: heapless::String<4>,
+    #[serde(default)]
+    led2: heapless::String<4>,
+    #[serde(default)]
+    led3: heapless::String<4>,
+    #[serde(default)]
+    led4: heapless::String<4>,
+    #[serde(default)]
+    led5: heapless::String<4>,
+    #[serde(default)]
+    led6: heapless::String<4>,
+    #[serde(default)]
+    led7: heapless::String<4>,
+}
+
+impl ConfigForm {
+    /// Whether the box of each LED was checked.
+    fn checked(&self) -> [bool; MAX_LEDS] {
+        [
+            &self.led0, &self.led1, &self.led2, &self.led3, &self.led4, &self.led5, &self.led6,
+            &self.led7,
+        ]
+        .map(|value| !value.is_empty())
+    }
+}
+
+/// The page, with the settings it was requested or saved with.
+pub(crate) struct ConfigPage {
+    settings: Settings,
+    leds: heapless::Vec<u8, MAX_LEDS>,
+    saved: bool,
+}
+
+impl ConfigPage {
+    fn new(control: &impl LedControl, settings: Settings, saved: bool) -> Self {
+        Self {
+            settings,
+            leds: (0..MAX_LEDS as u8)
+                .filter(|&led| control.has_led(led))
+                .collect(),
+            saved,
+        }
+    }
+
+    /// The template split at each `{{name}}`, with the value of the name in its place, and a row per LED for
+    /// `{{leds}}`.
+    fn pieces(&self) -> impl Iterator<Item = Piece> + '_ {
+        TEMPLATE
+            .split("{{")
+            .enumerate()
+            .flat_map(move |(index, part)| {
+                let (name, text) = match index {
+                    0 => (None, part),
+                    _ => {
+                        let (name, text) = part.split_once("}}").unwrap_or((part, ""));
+                        (Some(name), text)
+                    }
+                };
+
+                let rows = self
+                    .leds
+                    .iter()
+                    .filter(move |_| name == Some("leds"))
+                    .map(|&led| Piece::Value(self.led_row(led)));
+                let value = name
+                    .filter(|&name| name != "leds")
+                    .map(|name| Piece::Value(self.value(name)));
+
+                rows.chain(value).chain(core::iter::once(Piece::Text(text)))
+            })
+    }
+
+    /// The value of `{{name}}`, empty for unknown names.
+    fn value(&self, name: &str) -> heapless::String<512> {
+        let mut value = heapless::String::new();
+
+        let _ = match name {
+            // Valid settings only hold letters, digits and `-`, so the hostname needs no escaping
+            "hostname" => write!(value, "{}", self.settings.hostname),
+            "blink_period_ms" => write!(value, "{}", self.settings.blink_period_ms),
+            "message" if self.saved => write!(value, "    <p>Saved.</p>"),
+            _ => Ok(()),
+        };
+
+        value
+    }
+
+    /// The box of LED `led`, checked if it is on at boot.
+    fn led_row(&self, led: u8) -> heapless::String<512> {
+        let mut row = heapless::String::new();
+        let checked = if self.settings.led_on_at_boot(led) {
+            " checked"
+        } else {
+            ""
+        };
+
+        let _ = writeln!(
+            row,
+            "        <label><input type=\"checkbox\" name=\"led{led}\"{checked} /> LED{led}</label>"
+        );
+        row
+    }
+}
+
+impl Content for ConfigPage {
+    fn content_type(&self) -> &'static str {
+        "text/html; charset=utf-8"
+    }
+
+    fn content_length(&self) -> usize {
+        self.pieces().map(|piece| piece.as_bytes().len()).sum()
+    }
+
+    async fn write_content<R: Read, W: Write>(
+        self,
+        _connection: Connection<'_, R>,
+        mut writer: W,
+    ) -> Result<(), W::Error> {
+        for piece in self.pieces() {
+            writer.write_all(piece.as_bytes()).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// `GET /config`: the form, filled with the current settings.
+pub(crate) async fn get_config<C: LedControl, P: SettingsStore>(
+    _: RequireAuth,
+    State(control): State<C>,
+    State(store): State<P>,
+) -> impl IntoResponse {
+    Response::new(
+        StatusCode::OK,
+        ConfigPage::new(&control, store.settings(), false),
+    )
+    .with_header("Cache-Control", "no-store")
+}
+
+/// `POST /config`: save the form and apply it, answering with the page of the saved settings.
+pub(crate) async fn post_config<C: LedControl, P: SettingsStore>(
+    _: RequireAuth,
+    State(control): State<C>,
+    State(store): State<P>,
+    Form(form): Form<ConfigForm>,
+) -> Result<impl IntoResponse, (StatusCode, &'static str)> {
+    let Ok(blink_period_ms) = form.blink_period_ms.parse() else {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "blink_period_ms must be a number of milliseconds\n",
+        ));
+    };
+
+    let checked = form.checked();
+    let settings = Settings {
+        leds_on_at_boot: (0..MAX_LEDS as u8)
+            .filter(|&led| control.has_led(led) && checked[usize::from(led)])
+            .collect(),
+        hostname: form.hostname,
+        blink_period_ms,
+        schedules: store.settings().schedules,
+    };
+
+    if let Some(error) = settings.error() {
+        return Err((StatusCode::BAD_REQUEST, error));
+    }
+
+    if !store.save(&settings) {
+        return Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to save the settings\n",
+        ));
+    }
+
+    let page = ConfigPage::new(&control, settings, true);
+    for &led in &page.leds {
+        control.set(led, page.settings.led_on_at_boot(led));
+    }
+
+    log_debug!("Saved settings from /config");
+    Ok(Response::new(StatusCode::OK, page).with_header("Cache-Control", "no-store"))
+}
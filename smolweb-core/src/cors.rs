@@ -0,0 +1,296 @@
+//! Cross-origin requests to `/api`, so that web apps served from elsewhere on the LAN can call it from a browser.
+//!
+//! The routes of `/api` are added with [api_get], [api_post], [api_get_post] and [api_post_service], which answer the
+//! `OPTIONS` preflight of the browser with the methods they route. [Cors] adds `Access-Control-Allow-Origin` to the
+//! other responses of `/api` when the `Origin` of the request is one of the [CorsOrigins]. Without any, which is the
+//! default, browsers keep refusing cross-origin calls.
+//!
+//! An origin listed by name is allowed to send credentials, as every route of `/api` requires them, so only list the
+//! origins of apps trusted with the board. `*` allows any other origin without credentials, so that a page can only
+//! call the API with credentials it was given, in `Authorization` or `X-Api-Key`, and never with those the browser
+//! keeps for the board.
+
+use picoserve::{
+    extract::FromRef,
+    io::Read,
+    request::RequestParts,
+    response::{Body, Connection, HeadersIter, IntoResponse, Response, ResponseWriter, StatusCode},
+    routing::{
+        get, post, post_service, IntoPathParameterList, Layer, MethodHandler, Next,
+        RequestHandlerFunction, RequestHandlerService,
+    },
+    ResponseSent,
+};
+
+/// Request headers a cross-origin request may send.
+const ALLOWED_HEADERS: &str = "Authorization, Content-Type, X-Api-Key, X-Filename";
+
+/// Seconds a browser may reuse a preflight response.
+const PREFLIGHT_MAX_AGE: u32 = 600;
+
+/// Origins allowed to call `/api` from a browser, extracted from the application state.
+///
+/// A comma separated list, e.g. `http://dashboard.local:3000,https://example.com`, with `*` for any other origin, or
+/// empty for none.
+#[derive(Clone, Copy, Debug)]
+pub struct CorsOrigins(pub &'static str);
+
+impl CorsOrigins {
+    /// The origins set by the `SMOLWEB_CORS_ORIGINS` environment variable when building, none by default.
+    pub const fn from_build_env() -> Self {
+        match option_env!("SMOLWEB_CORS_ORIGINS") {
+            Some(origins) => Self(origins),
+            None => Self(""),
+        }
+    }
+
+    /// How `origin`, the value of an `Origin` header, may call `/api`, or `None` if it may not.
+    fn allow<'a>(&self, origin: &'a str) -> Option<AllowedOrigin<'a>> {
+        let mut origins = self.0.split(',').map(str::trim);
+
+        if origin.is_empty() {
+            None
+        } else if origins
+            .clone()
+            .any(|allowed| allowed.eq_ignore_ascii_case(origin))
+        {
+            Some(AllowedOrigin::Listed(origin))
+        } else if origins.any(|allowed| allowed == "*") {
+            Some(AllowedOrigin::Any)
+        } else {
+            None
+        }
+    }
+
+    /// How the `Origin` of the request may call `/api`, or `None` if it may not.
+    fn allow_request<'a>(&self, request_parts: &RequestParts<'a>) -> Option<AllowedOrigin<'a>> {
+        request_parts
+            .headers()
+            .get("Origin")
+            .and_then(|origin| core::str::from_utf8(origin.as_raw()).ok())
+            .and_then(|origin| self.allow(origin))
+    }
+}
+
+impl Default for CorsOrigins {
+    fn default() -> Self {
+        Self::from_build_env()
+    }
+}
+
+/// An origin of the [CorsOrigins].
+#[derive(Clone, Copy)]
+enum AllowedOrigin<'a> {
+    /// Listed by name, and sent back with its credentials allowed.
+    Listed(&'a str),
+    /// Allowed by `*`, without credentials.
+    Any,
+}
+
+/// Adds the headers letting an allowed origin read the response.
+struct AllowOrigin<'a, W> {
+    inner: W,
+    origin: AllowedOrigin<'a>,
+}
+
+impl<W: ResponseWriter> ResponseWriter for AllowOrigin<'_, W> {
+    type Error = W::Error;
+
+    async fn write_response<R: Read<Error = Self::Error>, H: HeadersIter, B: Body>(
+        self,
+        connection: Connection<'_, R>,
+        response: Response<H, B>,
+    ) -> Result<ResponseSent, Self::Error> {
+        match self.origin {
+            AllowedOrigin::Listed(origin) => {
+                let response = response
+                    .with_header("Access-Control-Allow-Origin", origin)
+                    .with_header("Access-Control-Allow-Credentials", "true")
+                    .with_header("Vary", "Origin");
+
+                self.inner.write_response(connection, response).await
+            }
+            AllowedOrigin::Any => {
+                let response = response.with_header("Access-Control-Allow-Origin", "*");
+
+                self.inner.write_response(connection, response).await
+            }
+        }
+    }
+}
+
+/// [Layer] letting the [CorsOrigins] read the responses of `/api`, other than those to `OPTIONS` answered by
+/// [Preflight].
+pub struct Cors;
+
+impl<State, PathParameters> Layer<State, PathParameters> for Cors
+where
+    CorsOrigins: FromRef<State>,
+{
+    type NextState = State;
+    type NextPathParameters = PathParameters;
+
+    async fn call_layer<
+        R: Read,
+        NextLayer: Next<R, Self::NextState, Self::NextPathParameters>,
+        W: ResponseWriter<Error = R::Error>,
+    >(
+        &self,
+        next: NextLayer,
+        state: &State,
+        path_parameters: PathParameters,
+        request_parts: RequestParts<'_>,
+        response_writer: W,
+    ) -> Result<ResponseSent, W::Error> {
+        let path = request_parts.path().encoded();
+        let is_api = path == "/api" || path.starts_with("/api/");
+
+        let origin = if is_api && request_parts.method() != "OPTIONS" {
+            CorsOrigins::from_ref(state).allow_request(&request_parts)
+        } else {
+            None
+        };
+
+        match origin {
+            Some(origin) => {
+                next.run(
+                    state,
+                    path_parameters,
+                    AllowOrigin {
+                        inner: response_writer,
+                        origin,
+                    },
+                )
+                .await
+            }
+            None => next.run(state, path_parameters, response_writer).await,
+        }
+    }
+}
+
+/// Answers a preflight in place of the `405 Method Not Allowed` of its route, which has no handler for `OPTIONS`.
+struct AnswerPreflight<'a, W> {
+    inner: W,
+    methods: &'static str,
+    origin: Option<AllowedOrigin<'a>>,
+}
+
+impl<W: ResponseWriter> ResponseWriter for AnswerPreflight<'_, W> {
+    type Error = W::Error;
+
+    async fn write_response<R: Read<Error = Self::Error>, H: HeadersIter, B: Body>(
+        self,
+        connection: Connection<'_, R>,
+        _response: Response<H, B>,
+    ) -> Result<ResponseSent, Self::Error> {
+        let response = Response::new(StatusCode::NO_CONTENT, "").with_header("Allow", self.methods);
+
+        match self.origin {
+            Some(origin) => {
+                response
+                    .with_header("Access-Control-Allow-Methods", self.methods)
+                    .with_header("Access-Control-Allow-Headers", ALLOWED_HEADERS)
+                    .with_header("Access-Control-Max-Age", PREFLIGHT_MAX_AGE)
+                    .write_to(
+                        connection,
+                        AllowOrigin {
+                            inner: self.inner,
+                            origin,
+                        },
+                    )
+                    .await
+            }
+            None => response.write_to(connection, self.inner).await,
+        }
+    }
+}
+
+/// [Layer] of a route of `/api` answering `OPTIONS` with `204 No Content` and its methods, and the preflight headers
+/// for the [CorsOrigins].
+///
+/// The preflight carries no credentials, and `OPTIONS` reaches no handler of the route, so it is answered without
+/// `RequireAuth` seeing it.
+struct Preflight(&'static str);
+
+impl<State, PathParameters> Layer<State, PathParameters> for Preflight
+where
+    CorsOrigins: FromRef<State>,
+{
+    type NextState = State;
+    type NextPathParameters = PathParameters;
+
+    async fn call_layer<
+        R: Read,
+        NextLayer: Next<R, Self::NextState, Self::NextPathParameters>,
+        W: ResponseWriter<Error = R::Error>,
+    >(
+        &self,
+        next: NextLayer,
+        state: &State,
+        path_parameters: PathParameters,
+        request_parts: RequestParts<'_>,
+        response_writer: W,
+    ) -> Result<ResponseSent, W::Error> {
+        if request_parts.method() != "OPTIONS" {
+            return next.run(state, path_parameters, response_writer).await;
+        }
+
+        next.run(
+            state,
+            path_parameters,
+            AnswerPreflight {
+                inner: response_writer,
+                methods: self.0,
+                origin: CorsOrigins::from_ref(state).allow_request(&request_parts),
+            },
+        )
+        .await
+    }
+}
+
+/// Route `GET` requests to `handler`, for a route of `/api`.
+pub fn api_get<State, PathParameters, T, Handler>(
+    handler: Handler,
+) -> impl MethodHandler<State, PathParameters>
+where
+    Handler: RequestHandlerFunction<State, PathParameters, T>,
+    CorsOrigins: FromRef<State>,
+{
+    get(handler).layer(Preflight("GET, HEAD, OPTIONS"))
+}
+
+/// Route `POST` requests to `handler`, for a route of `/api`.
+pub fn api_post<State, PathParameters, T, Handler>(
+    handler: Handler,
+) -> impl MethodHandler<State, PathParameters>
+where
+    Handler: RequestHandlerFunction<State, PathParameters, T>,
+    CorsOrigins: FromRef<State>,
+{
+    post(handler).layer(Preflight("POST, OPTIONS"))
+}
+
+/// Route `GET` requests to `get_handler` and `POST` requests to `post_handler`, for a route of `/api`.
+pub fn api_get_post<State, PathParameters, G, GetHandler, P, PostHandler>(
+    get_handler: GetHandler,
+    post_handler: PostHandler,
+) -> impl MethodHandler<State, PathParameters>
+where
+    GetHandler: RequestHandlerFunction<State, PathParameters, G>,
+    PostHandler: RequestHandlerFunction<State, PathParameters, P>,
+    CorsOrigins: FromRef<State>,
+{
+    get(get_handler)
+        .post(post_handler)
+        .layer(Preflight("GET, HEAD, POST, OPTIONS"))
+}
+
+/// Route `POST` requests to `service`, for a route of `/api`.
+pub fn api_post_service<State, PathParameters: IntoPathParameterList>(
+    service: impl RequestHandlerService<State, PathParameters::ParameterList>,
+) -> impl MethodHandler<State, PathParameters>
+where
+    CorsOrigins: FromRef<State>,
+{
+    post_service(service).layer(Preflight("POST, OPTIONS"))
+}
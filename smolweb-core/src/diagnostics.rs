@@ -0,0 +1,328 @@
+//! Health of the firmware shown by `GET /api/sysinfo`: stack headroom, heap, clock speed, network link
+//! and version, and the checks of `GET /readyz` in [health](crate::health).
+
+use core::sync::atomic::Ordering;
+
+use picoserve::{extract::State, response::Json as JsonResponse};
+use portable_atomic::{AtomicU32, AtomicU8, AtomicUsize};
+
+use crate::{auth::RequireAuth, metrics::Metrics, time::Clock};
+
+/// Tasks whose stack is followed, any others are left out.
+pub const MAX_TASKS: usize = 8;
+
+/// Word [Diagnostics::paint_stack] fills the stack with, which the stack overwrites as it grows.
+const STACK_PAINT: u32 = 0x5A5A_5A5A;
+
+const WORD: usize = core::mem::size_of::<u32>();
+
+/// Bytes below the stack pointer of [Diagnostics::paint_stack] left unpainted, for its own frame and interrupts.
+const PAINT_MARGIN: usize = 256;
+
+/// Short hash of the commit the firmware was built from, `unknown` outside of a git checkout.
+pub const GIT_HASH: &str = env!("SMOLWEB_GIT_HASH");
+
+/// Why the board last started, each matching a flag of its reset controller read at boot.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[serde(rename_all = "snake_case")]
+pub enum ResetCause {
+    PowerOn,
+    Brownout,
+    /// A watchdog expired, which means the firmware hung.
+    Watchdog,
+    /// Requested by the firmware itself.
+    Software,
+    /// Entering a low-power mode the firmware didn't allow.
+    LowPower,
+    /// The reset pin, e.g. the reset button or a debugger.
+    Pin,
+}
+
+impl ResetCause {
+    /// In the order the cause of a reset is picked from its flags: a power-on reset also sets the brownout and pin
+    /// flags, and most others the pin flag.
+    const ALL: [Self; 6] = [
+        Self::PowerOn,
+        Self::Brownout,
+        Self::Watchdog,
+        Self::Software,
+        Self::LowPower,
+        Self::Pin,
+    ];
+
+    fn bit(self) -> u8 {
+        1 << Self::ALL
+            .iter()
+            .position(|&cause| cause == self)
+            .unwrap_or(0)
+    }
+}
+
+/// Shared by every task as `&'static Diagnostics`, which report into it.
+pub struct Diagnostics {
+    version: &'static str,
+    tasks: &'static [&'static str],
+    /// Lowest stack pointer reported by each task of [Self::tasks], `usize::MAX` until it reports.
+    lowest_stack: [AtomicUsize; MAX_TASKS],
+    /// Lowest address the stack may grow down to, 0 if unknown.
+    stack_limit: AtomicUsize,
+    /// End of the words painted by [Self::paint_stack] from [Self::stack_limit], 0 until then.
+    painted_top: AtomicUsize,
+    /// 0 if unknown.
+    cpu_frequency_hz: AtomicU32,
+    /// `u32::MAX` without an allocator.
+    free_heap_bytes: AtomicU32,
+    /// [ResetCause::bit] of each flag set at boot, 0 if unknown.
+    reset_flags: AtomicU8,
+    /// [UP], [DOWN] or 0 if unknown.
+    link: AtomicU8,
+    /// Whether the network has an address, [UP], [DOWN] or 0 if unknown.
+    network: AtomicU8,
+    /// Tasks whose first [Self::heartbeat] `GET /readyz` waits for.
+    heartbeat_tasks: &'static [&'static str],
+    /// Bit of each task of [Self::heartbeat_tasks] which has beaten.
+    heartbeats: AtomicU8,
+}
+
+const DOWN: u8 = 1;
+const UP: u8 = 2;
+
+fn load_up(state: &AtomicU8) -> Option<bool> {
+    match state.load(Ordering::Relaxed) {
+        UP => Some(true),
+        DOWN => Some(false),
+        _ => None,
+    }
+}
+
+impl Diagnostics {
+    /// Diagnostics of the firmware `version` whose tasks report their stack under the names `tasks`.
+    pub const fn new(version: &'static str, tasks: &'static [&'static str]) -> Self {
+        #[allow(clippy::declare_interior_mutable_const)] // Only used to initialize the array
+        const NOT_REPORTED: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+        Self {
+            version,
+            tasks,
+            lowest_stack: [NOT_REPORTED; MAX_TASKS],
+            stack_limit: AtomicUsize::new(0),
+            painted_top: AtomicUsize::new(0),
+            cpu_frequency_hz: AtomicU32::new(0),
+            free_heap_bytes: AtomicU32::new(u32::MAX),
+            reset_flags: AtomicU8::new(0),
+            link: AtomicU8::new(0),
+            network: AtomicU8::new(0),
+            heartbeat_tasks: &[],
+            heartbeats: AtomicU8::new(0),
+        }
+    }
+
+    /// Make `GET /readyz` wait for a first [Self::heartbeat] of each of `tasks`, up to [MAX_TASKS].
+    pub const fn with_heartbeats(self, tasks: &'static [&'static str]) -> Self {
+        Self {
+            heartbeat_tasks: tasks,
+            ..self
+        }
+    }
+
+    /// Paint the stack with [STACK_PAINT] from `limit`, the lowest address it may grow down to, up to just below the
+    /// stack pointer, so that its headroom is the paint it never overwrote. The samples of the tasks are measured from
+    /// `limit` as well.
+    ///
+    /// Embassy tasks all run on the stack of `main`, so boards pass the end of their static data, first thing at boot
+    /// so that the whole stack is measured.
+    ///
+    /// # Safety
+    ///
+    /// Nothing but the stack may ever use the memory from `limit` up to the stack pointer, e.g. an allocator.
+    #[inline(never)]
+    pub unsafe fn paint_stack(&self, limit: usize) {
+        let marker = 0u8;
+        let bottom = limit.next_multiple_of(WORD);
+        let top = (core::ptr::addr_of!(marker) as usize).saturating_sub(PAINT_MARGIN) & !(WORD - 1);
+
+        for address in (bottom..top).step_by(WORD) {
+            core::ptr::write_volatile(address as *mut u32, STACK_PAINT);
+        }
+
+        self.stack_limit.store(bottom, Ordering::Relaxed);
+        self.painted_top.store(top.max(bottom), Ordering::Relaxed);
+    }
+
+    pub fn set_cpu_frequency(&self, hz: u32) {
+        self.cpu_frequency_hz.store(hz, Ordering::Relaxed);
+    }
+
+    /// Set by boards with an allocator, whenever they like.
+    pub fn set_free_heap(&self, bytes: usize) {
+        self.free_heap_bytes
+            .store(bytes.try_into().unwrap_or(u32::MAX - 1), Ordering::Relaxed);
+    }
+
+    /// Set the flags of the reset controller read at boot, from which the reset cause is picked.
+    pub fn set_reset_flags(&self, flags: &[ResetCause]) {
+        let bits = flags.iter().fold(0, |bits, &flag| bits | flag.bit());
+        self.reset_flags.store(bits, Ordering::Relaxed);
+    }
+
+    /// Set by boards watching their network link, whenever it goes up or down.
+    pub fn set_link_up(&self, up: bool) {
+        self.link
+            .store(if up { UP } else { DOWN }, Ordering::Relaxed);
+    }
+
+    pub(crate) fn link_up(&self) -> Option<bool> {
+        load_up(&self.link)
+    }
+
+    /// Set by boards whenever their network gets or loses its address, e.g. from DHCP.
+    pub fn set_network_up(&self, up: bool) {
+        self.network
+            .store(if up { UP } else { DOWN }, Ordering::Relaxed);
+    }
+
+    pub(crate) fn network_up(&self) -> Option<bool> {
+        load_up(&self.network)
+    }
+
+    /// Record that `task` is running, which tasks of [Self::with_heartbeats] do once started and may keep doing.
+    pub fn heartbeat(&self, task: &str) {
+        if let Some(index) = self
+            .heartbeat_tasks
+            .iter()
+            .take(MAX_TASKS)
+            .position(|&name| name == task)
+        {
+            self.heartbeats.fetch_or(1 << index, Ordering::Relaxed);
+        }
+    }
+
+    /// Each task of [Self::with_heartbeats], with whether it has beaten yet.
+    pub(crate) fn heartbeats(&self) -> impl Iterator<Item = (&'static str, bool)> {
+        let bits = self.heartbeats.load(Ordering::Relaxed);
+        self.heartbeat_tasks
+            .iter()
+            .take(MAX_TASKS)
+            .enumerate()
+            .map(move |(index, &name)| (name, bits & (1 << index) != 0))
+    }
+
+    pub(crate) fn version(&self) -> &'static str {
+        self.version
+    }
+
+    fn reset_flags(&self) -> impl Iterator<Item = ResetCause> {
+        let bits = self.reset_flags.load(Ordering::Relaxed);
+        ResetCause::ALL
+            .into_iter()
+            .filter(move |&cause| bits & cause.bit() != 0)
+    }
+
+    /// Record how deep the stack of `task` is at the point of the call, which tasks make where they nest deepest.
+    ///
+    /// Only a sample of the stack pointer where the call is made, so its headroom overestimates what is left, unlike
+    /// the high-water mark of the painted stack.
+    #[inline(always)]
+    pub fn report_stack(&self, task: &str) {
+        let marker = 0u8;
+        let stack_pointer = core::ptr::addr_of!(marker) as usize;
+
+        if let Some(lowest) = self
+            .tasks
+            .iter()
+            .position(|&name| name == task)
+            .and_then(|index| self.lowest_stack.get(index))
+        {
+            lowest.fetch_min(stack_pointer, Ordering::Relaxed);
+        }
+    }
+
+    fn sampled_stack_headroom(&self, index: usize) -> Option<usize> {
+        let stack_limit = self.stack_limit.load(Ordering::Relaxed);
+        let lowest = self.lowest_stack[index].load(Ordering::Relaxed);
+
+        (stack_limit != 0 && lowest != usize::MAX).then(|| lowest.saturating_sub(stack_limit))
+    }
+
+    /// Bytes of the stack never used since [Self::paint_stack], counted from its limit up to the first word that lost
+    /// its paint.
+    fn stack_headroom(&self) -> Option<usize> {
+        let bottom = self.stack_limit.load(Ordering::Relaxed);
+        let top = self.painted_top.load(Ordering::Relaxed);
+
+        (top != 0).then(|| {
+            let painted = (bottom..top).step_by(WORD).take_while(|&address| {
+                // SAFETY: Painted by `paint_stack`, whose caller leaves the memory to the stack
+                unsafe { core::ptr::read_volatile(address as *const u32) == STACK_PAINT }
+            });
+
+            painted.count() * WORD
+        })
+    }
+}
+
+/// Stack of a task in [SystemInfo], `sampled_stack_headroom_bytes` being `null` until it reports.
+#[derive(serde::Serialize)]
+pub struct TaskStack {
+    name: &'static str,
+    /// Headroom below the deepest stack pointer the task reported, more than is actually left.
+    sampled_stack_headroom_bytes: Option<usize>,
+}
+
+/// Body of `GET /api/sysinfo`, e.g.
+/// `{"uptime_seconds":120,"version":"0.1.0","git_hash":"1a2b3c4","reset_cause":"watchdog","reset_flags":["watchdog","pin"],"cpu_frequency_hz":400000000,"free_heap_bytes":null,"resident_memory_bytes":null,"link_up":true,"stack_headroom_bytes":3072,"tasks":[{"name":"web","sampled_stack_headroom_bytes":4096}]}`.
+///
+/// Values a platform doesn't know are `null`: boards have no resident memory, the tokio demo no stack to measure
+/// nor reset cause, whose flags are then empty, and only the Nucleo watches its Ethernet link.
+#[derive(serde::Serialize)]
+pub struct SystemInfo {
+    uptime_seconds: u64,
+    version: &'static str,
+    git_hash: &'static str,
+    reset_cause: Option<ResetCause>,
+    reset_flags: heapless::Vec<ResetCause, { ResetCause::ALL.len() }>,
+    cpu_frequency_hz: Option<u32>,
+    free_heap_bytes: Option<u32>,
+    resident_memory_bytes: Option<u32>,
+    /// Whether the network link is up, `false` e.g. while the cable is unplugged.
+    link_up: Option<bool>,
+    /// Stack the tasks share that was never used, from its paint.
+    stack_headroom_bytes: Option<usize>,
+    tasks: heapless::Vec<TaskStack, MAX_TASKS>,
+}
+
+/// `GET /api/sysinfo`
+pub(crate) async fn get_sysinfo<T: Clock>(
+    _: RequireAuth,
+    State(clock): State<T>,
+    State(metrics): State<&'static Metrics>,
+    State(diagnostics): State<&'static Diagnostics>,
+) -> JsonResponse<SystemInfo> {
+    let tasks = diagnostics
+        .tasks
+        .iter()
+        .take(MAX_TASKS)
+        .enumerate()
+        .map(|(index, &name)| TaskStack {
+            name,
+            sampled_stack_headroom_bytes: diagnostics.sampled_stack_headroom(index),
+        })
+        .collect();
+
+    JsonResponse(SystemInfo {
+        uptime_seconds: clock.uptime().as_secs(),
+        version: diagnostics.version,
+        git_hash: GIT_HASH,
+        reset_cause: diagnostics.reset_flags().next(),
+        reset_flags: diagnostics.reset_flags().collect(),
+        cpu_frequency_hz: Some(diagnostics.cpu_frequency_hz.load(Ordering::Relaxed))
+            .filter(|&hz| hz != 0),
+        free_heap_bytes: Some(diagnostics.free_heap_bytes.load(Ordering::Relaxed))
+            .filter(|&bytes| bytes != u32::MAX),
+        resident_memory_bytes: metrics.resident_memory(),
+        link_up: diagnostics.link_up(),
+        stack_headroom_bytes: diagnostics.stack_headroom(),
+        tasks,
+    })
+}
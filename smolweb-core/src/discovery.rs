@@ -0,0 +1,139 @@
+//! Discovery beacon broadcast by the boards, so that their address can be found without mDNS.
+//!
+//! Every [BEACON_INTERVAL], [run] broadcasts a [Beacon] as JSON to [DISCOVERY_PORT], which the `discover` binary of
+//! the tokio demo listens on. Sending it needs the `embassy` feature, reading it doesn't.
+
+use core::fmt::Write as _;
+use core::time::Duration;
+
+/// UDP port the beacons are broadcast to.
+pub const DISCOVERY_PORT: u16 = 48080;
+
+/// How often a board broadcasts its beacon.
+pub const BEACON_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Most bytes of a [Beacon] as JSON.
+pub const MAX_BEACON_SIZE: usize = 208;
+
+/// Datagram broadcast by a board, e.g. `{"name":"smolweb","ip":"192.168.1.50","port":8080,"version":"0.1.0"}`.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Beacon {
+    /// Hostname of the board, also answered over mDNS where it runs.
+    pub name: heapless::String<32>,
+    pub ip: heapless::String<40>,
+    /// TCP port of the web server.
+    pub port: u16,
+    /// Firmware version.
+    pub version: heapless::String<16>,
+    /// IPv6 address of the board, left out by boards without one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ipv6: Option<heapless::String<40>>,
+}
+
+/// `ip` as text, the longest IPv6 address taking 39 bytes.
+fn address_text(ip: impl core::fmt::Display) -> heapless::String<40> {
+    let mut address = heapless::String::new();
+    let _ = write!(address, "{ip}");
+    address
+}
+
+impl Beacon {
+    /// The beacon of a board named `name` serving HTTP on `ip:port`, names and versions too long being cut.
+    pub fn new(name: &str, ip: impl core::fmt::Display, port: u16, version: &str) -> Self {
+        fn truncated<const N: usize>(text: &str) -> heapless::String<N> {
+            let mut end = text.len().min(N);
+            while !text.is_char_boundary(end) {
+                end -= 1;
+            }
+            heapless::String::try_from(&text[..end]).unwrap_or_default()
+        }
+
+        Self {
+            name: truncated(name),
+            ip: address_text(ip),
+            port,
+            version: truncated(version),
+            ipv6: None,
+        }
+    }
+
+    /// The beacon also giving the IPv6 address `ip` of the board.
+    pub fn with_ipv6(self, ip: impl core::fmt::Display) -> Self {
+        Self {
+            ipv6: Some(address_text(ip)),
+            ..self
+        }
+    }
+
+    /// The beacon as JSON, to be broadcast.
+    pub fn to_json(&self) -> heapless::Vec<u8, MAX_BEACON_SIZE> {
+        let mut json = [0; MAX_BEACON_SIZE];
+        // Fields at their longest come to 194 bytes
+        let length = serde_json_core::to_slice(self, &mut json).unwrap_or(0);
+        heapless::Vec::from_slice(&json[..length]).unwrap_or_default()
+    }
+
+    /// Reads a beacon sent by [Self::to_json], returning `None` if it is something else.
+    pub fn from_json(json: &[u8]) -> Option<Self> {
+        serde_json_core::from_slice(json)
+            .ok()
+            .map(|(beacon, _)| beacon)
+    }
+}
+
+/// Broadcast the [Beacon] of the board, named `name` and serving HTTP on `port`, while the stack has an address.
+#[cfg(feature = "embassy")]
+pub async fn run<D: embassy_net::driver::Driver>(
+    stack: &embassy_net::Stack<D>,
+    name: &str,
+    port: u16,
+    version: &str,
+) -> ! {
+    use embassy_net::{
+        udp::{PacketMetadata, UdpSocket},
+        IpEndpoint, Ipv4Address,
+    };
+    use embassy_time::Timer;
+
+    let mut rx_meta = [PacketMetadata::EMPTY; 1];
+    let mut rx_buffer = [0; 0];
+    let mut tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut tx_buffer = [0; MAX_BEACON_SIZE];
+
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    socket
+        .bind(0)
+        .expect("A new socket can always bind to an ephemeral port");
+
+    log_info!("Broadcasting a discovery beacon to port {}", DISCOVERY_PORT);
+
+    loop {
+        // Without an address yet, there is nothing to tell
+        if let Some(config) = stack.config_v4() {
+            let beacon = Beacon::new(name, config.address.address(), port, version);
+            #[cfg(feature = "ipv6")]
+            let beacon = match stack.config_v6() {
+                Some(config) => beacon.with_ipv6(config.address.address()),
+                None => beacon,
+            };
+
+            if let Err(err) = socket
+                .send_to(
+                    &beacon.to_json(),
+                    IpEndpoint::new(Ipv4Address::BROADCAST.into(), DISCOVERY_PORT),
+                )
+                .await
+            {
+                log_warn!("Failed to broadcast the discovery beacon: {:?}", err);
+            }
+        }
+
+        Timer::after(embassy_time::Duration::from_secs(BEACON_INTERVAL.as_secs())).await;
+    }
+}
@@ -0,0 +1,131 @@
+//! Error responses of the JSON API, so that clients read every `/api` response as JSON, errors included.
+
+use core::time::Duration;
+
+use picoserve::{
+    io::{Read, Write},
+    request::RequestParts,
+    response::{
+        Connection, Content, IntoResponse, Json as JsonResponse, ResponseWriter, StatusCode,
+    },
+    ResponseSent,
+};
+
+/// Body of an [ApiError], e.g. `{"error":"No such endpoint"}`.
+#[derive(serde::Serialize)]
+struct ErrorBody {
+    error: &'static str,
+}
+
+/// Error of the JSON API, answered with `status` and `message` as `{"error":"<message>"}`.
+pub struct ApiError {
+    pub status: StatusCode,
+    pub message: &'static str,
+}
+
+impl ApiError {
+    pub const fn new(status: StatusCode, message: &'static str) -> Self {
+        Self { status, message }
+    }
+}
+
+impl IntoResponse for ApiError {
+    async fn write_to<R: Read, W: ResponseWriter<Error = R::Error>>(
+        self,
+        connection: Connection<'_, R>,
+        response_writer: W,
+    ) -> Result<ResponseSent, W::Error> {
+        JsonResponse(ErrorBody {
+            error: self.message,
+        })
+        .into_response()
+        .with_status_code(self.status)
+        .write_to(connection, response_writer)
+        .await
+    }
+}
+
+/// A request refused before its handler runs, e.g. one over the [ClientRateLimit](crate::rate_limit::ClientRateLimit),
+/// answered as an [ApiError] under `/api` and as text elsewhere.
+pub struct Refusal {
+    status: StatusCode,
+    message: &'static str,
+    /// Whole seconds until the request may be sent again, for `Retry-After`.
+    retry_after: Option<u64>,
+    api: bool,
+}
+
+impl Refusal {
+    /// Refuse the request of `request_parts` with `status` and `message`.
+    pub(crate) fn new(
+        status: StatusCode,
+        message: &'static str,
+        request_parts: &RequestParts<'_>,
+    ) -> Self {
+        let path = request_parts.path().encoded();
+
+        Self {
+            status,
+            message,
+            retry_after: None,
+            api: path == "/api" || path.starts_with("/api/"),
+        }
+    }
+
+    /// Tell the client to wait `retry_after` before sending the request again, rounded up to whole seconds.
+    pub(crate) fn with_retry_after(self, retry_after: Duration) -> Self {
+        Self {
+            retry_after: Some(retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0)),
+            ..self
+        }
+    }
+}
+
+impl IntoResponse for Refusal {
+    async fn write_to<R: Read, W: ResponseWriter<Error = R::Error>>(
+        self,
+        connection: Connection<'_, R>,
+        response_writer: W,
+    ) -> Result<ResponseSent, W::Error> {
+        let retry_after = self
+            .retry_after
+            .map(|retry_after| ("Retry-After", retry_after));
+
+        if self.api {
+            JsonResponse(ErrorBody {
+                error: self.message,
+            })
+            .into_response()
+            .with_status_code(self.status)
+            .with_headers(retry_after)
+            .write_to(connection, response_writer)
+            .await
+        } else {
+            (self.status, retry_after, TextLine(self.message))
+                .write_to(connection, response_writer)
+                .await
+        }
+    }
+}
+
+/// Text body of a [Refusal] outside `/api`, its message ended by a newline like the other text errors.
+struct TextLine(&'static str);
+
+impl Content for TextLine {
+    fn content_type(&self) -> &'static str {
+        "text/plain; charset=utf-8"
+    }
+
+    fn content_length(&self) -> usize {
+        self.0.len() + 1
+    }
+
+    async fn write_content<R: Read, W: Write>(
+        self,
+        _connection: Connection<'_, R>,
+        mut writer: W,
+    ) -> Result<(), W::Error> {
+        writer.write_all(self.0.as_bytes()).await?;
+        writer.write_all(b"\n").await
+    }
+}
@@ -0,0 +1,126 @@
+//! Events happening on the board, pushed to browsers by `GET /ws` and `GET /events`.
+
+use core::{fmt::Write as _, time::Duration};
+
+use picoserve::{
+    io::Write,
+    response::sse::{EventSource, EventWriter},
+};
+
+use crate::{
+    sensors::SensorReading,
+    time::{Clock, Iso8601},
+};
+
+/// How often the demos publish [BoardEvent::Uptime], which also keeps idle event streams open.
+pub const UPTIME_INTERVAL: Duration = Duration::from_secs(10);
+
+/// New state of an LED.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LedChange {
+    pub led: u8,
+    pub on: bool,
+}
+
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum BoardEvent {
+    /// An LED changed, whether over HTTP or from the board itself.
+    Led(LedChange),
+    /// The user button was pressed.
+    ButtonPressed,
+    /// Published every [UPTIME_INTERVAL], with the uptime in seconds.
+    Uptime(u64),
+    /// Published by the sensor task every [SENSOR_INTERVAL](crate::sensors::SENSOR_INTERVAL).
+    Sensor(SensorReading),
+}
+
+/// Queue of [BoardEvent]s, fed by the tasks of the demo.
+pub trait BoardEvents {
+    type Subscriber: EventSubscriber;
+
+    /// Start receiving events, or return `None` if no more subscribers can be added.
+    fn subscribe(&self) -> Option<Self::Subscriber>;
+}
+
+/// Receives the [BoardEvent]s published after it was subscribed, one per open WebSocket or event stream.
+#[allow(async_fn_in_trait)] // Only awaited with the concrete type known, where the future is `Send` if the implementation's is
+pub trait EventSubscriber {
+    /// Wait for the next event. Events missed because the subscriber lagged behind are skipped.
+    async fn next_event(&mut self) -> BoardEvent;
+}
+
+/// `change` as JSON, e.g. `{"led":2,"state":"on"}`.
+pub(crate) fn led_json(change: LedChange) -> heapless::String<32> {
+    let mut json = heapless::String::new();
+    // At most 25 bytes, with a three digit LED number
+    let _ = write!(
+        json,
+        "{{\"led\":{},\"state\":\"{}\"}}",
+        change.led,
+        if change.on { "on" } else { "off" },
+    );
+    json
+}
+
+/// The JSON object `json` with the time of `clock` added as `"time"`, if it is synchronized.
+fn with_time(json: &str, clock: &impl Clock) -> heapless::String<96> {
+    let mut data = heapless::String::new();
+
+    match (clock.unix_time(), json.strip_suffix('}')) {
+        (Some(unix_time), Some(fields)) => {
+            let separator = if fields.len() > 1 { "," } else { "" };
+            // At most 81 bytes, with the 50 bytes of a sensor reading
+            let _ = write!(
+                data,
+                "{fields}{separator}\"time\":\"{}\"}}",
+                Iso8601(unix_time)
+            );
+        }
+        _ => {
+            let _ = data.push_str(json);
+        }
+    }
+
+    data
+}
+
+/// [EventSource] writing every [BoardEvent] as a server-sent event named `led`, `button`, `uptime` or `sensor`, with
+/// JSON data.
+///
+/// Once the clock `T` is synchronized, the data of each event also has the time it was sent, e.g.
+/// `{"led":2,"state":"on","time":"2024-05-01T12:34:56Z"}`.
+pub(crate) struct BoardEventStream<S, T> {
+    pub(crate) subscriber: S,
+    pub(crate) clock: T,
+}
+
+impl<S: EventSubscriber, T: Clock> EventSource for BoardEventStream<S, T> {
+    async fn write_events<W: Write>(mut self, mut writer: EventWriter<W>) -> Result<(), W::Error> {
+        loop {
+            let (name, data) = match self.subscriber.next_event().await {
+                BoardEvent::Led(change) => ("led", with_time(&led_json(change), &self.clock)),
+                BoardEvent::ButtonPressed => ("button", with_time("{}", &self.clock)),
+                BoardEvent::Uptime(seconds) => {
+                    let mut data = heapless::String::<32>::new();
+                    // At most 32 bytes for the largest `u64`
+                    let _ = write!(data, "{{\"seconds\":{seconds}}}");
+                    ("uptime", with_time(&data, &self.clock))
+                }
+                BoardEvent::Sensor(reading) => {
+                    let mut data = heapless::String::<64>::new();
+                    // At most 50 bytes, as the SHT31 measures from -45 to 130 °C
+                    let _ = write!(
+                        data,
+                        "{{\"temperature_c\":{:.2},\"humidity_percent\":{:.2}}}",
+                        reading.temperature_c, reading.humidity_percent
+                    );
+                    ("sensor", with_time(&data, &self.clock))
+                }
+            };
+
+            writer.write_event(name, data.as_str()).await?
+        }
+    }
+}
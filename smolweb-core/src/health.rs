@@ -0,0 +1,84 @@
+//! `GET /healthz` and `GET /readyz`, polled without credentials by load balancers and test rigs.
+//!
+//! `/healthz` answers `200 OK` whenever a worker is there to serve it. `/readyz` answers `200 OK` once the board can
+//! take requests, its network having an address, its link up and each task of [Diagnostics::with_heartbeats] having
+//! beaten once, and `503 Service Unavailable` until then, with the result of each check either way. Checks the
+//! platform doesn't watch are `null` and pass.
+
+use picoserve::{
+    extract::State,
+    response::{IntoResponse, Json as JsonResponse, StatusCode},
+};
+
+use crate::{
+    diagnostics::{Diagnostics, MAX_TASKS},
+    time::Clock,
+};
+
+/// Body of `GET /healthz`, e.g. `{"status":"ok","uptime_seconds":120,"version":"0.1.0"}`.
+#[derive(serde::Serialize)]
+pub struct Health {
+    status: &'static str,
+    uptime_seconds: u64,
+    version: &'static str,
+}
+
+/// `GET /healthz`
+pub(crate) async fn get_healthz<T: Clock>(
+    State(clock): State<T>,
+    State(diagnostics): State<&'static Diagnostics>,
+) -> JsonResponse<Health> {
+    JsonResponse(Health {
+        status: "ok",
+        uptime_seconds: clock.uptime().as_secs(),
+        version: diagnostics.version(),
+    })
+}
+
+/// Heartbeat of a task in [Readiness].
+#[derive(serde::Serialize)]
+pub struct TaskHeartbeat {
+    name: &'static str,
+    heartbeat: bool,
+}
+
+/// Body of `GET /readyz`, e.g.
+/// `{"ready":false,"network_up":true,"link_up":null,"tasks":[{"name":"uptime","heartbeat":true},{"name":"schedules","heartbeat":false}]}`.
+#[derive(serde::Serialize)]
+pub struct Readiness {
+    ready: bool,
+    network_up: Option<bool>,
+    link_up: Option<bool>,
+    tasks: heapless::Vec<TaskHeartbeat, MAX_TASKS>,
+}
+
+/// `GET /readyz`
+pub(crate) async fn get_readyz(
+    State(diagnostics): State<&'static Diagnostics>,
+) -> impl IntoResponse {
+    let network_up = diagnostics.network_up();
+    let link_up = diagnostics.link_up();
+    let tasks: heapless::Vec<_, MAX_TASKS> = diagnostics
+        .heartbeats()
+        .map(|(name, heartbeat)| TaskHeartbeat { name, heartbeat })
+        .collect();
+
+    let ready = network_up != Some(false)
+        && link_up != Some(false)
+        && tasks.iter().all(|task| task.heartbeat);
+
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    JsonResponse(Readiness {
+        ready,
+        network_up,
+        link_up,
+        tasks,
+    })
+    .into_response()
+    .with_status_code(status)
+}
@@ -0,0 +1,69 @@
+use picoserve::{
+    extract::{FromRef, FromRequest},
+    io::Read,
+    request::{ReadAllBodyError, RequestBody, RequestParts},
+    response::{Connection, IntoResponse, ResponseWriter, StatusCode},
+    ResponseSent,
+};
+
+use crate::{
+    error::Refusal,
+    limits::{self, CutShort, Limits},
+};
+
+/// Extracts a request body deserialized from JSON.
+pub struct Json<T>(pub T);
+
+/// Why a [Json] body was not extracted.
+pub enum JsonRejection {
+    /// The request is over the [Limits], so its body was not read.
+    Refused(Refusal),
+    /// The body could not be read or deserialized.
+    Invalid(StatusCode, &'static str),
+}
+
+impl IntoResponse for JsonRejection {
+    async fn write_to<R: Read, W: ResponseWriter<Error = R::Error>>(
+        self,
+        connection: Connection<'_, R>,
+        response_writer: W,
+    ) -> Result<ResponseSent, W::Error> {
+        match self {
+            Self::Refused(refusal) => refusal.write_to(connection, response_writer).await,
+            Self::Invalid(status, message) => {
+                (status, message)
+                    .write_to(connection, response_writer)
+                    .await
+            }
+        }
+    }
+}
+
+impl<'r, State, T: serde::de::DeserializeOwned> FromRequest<'r, State> for Json<T>
+where
+    Limits: FromRef<State>,
+    CutShort: FromRef<State>,
+{
+    type Rejection = JsonRejection;
+
+    async fn from_request<R: Read>(
+        state: &'r State,
+        request_parts: RequestParts<'r>,
+        request_body: RequestBody<'r, R>,
+    ) -> Result<Self, Self::Rejection> {
+        limits::check(state, &request_parts).map_err(JsonRejection::Refused)?;
+
+        let body = request_body.read_all().await.map_err(|err| match err {
+            ReadAllBodyError::BufferIsTooSmall => {
+                JsonRejection::Invalid(StatusCode::PAYLOAD_TOO_LARGE, "Request body is too large\n")
+            }
+            ReadAllBodyError::UnexpectedEof | ReadAllBodyError::IO(_) => {
+                JsonRejection::Invalid(StatusCode::BAD_REQUEST, "Failed to read request body\n")
+            }
+        })?;
+
+        serde_json_core::from_slice(body)
+            .map(|(value, _)| Json(value))
+            .map_err(|_| JsonRejection::Invalid(StatusCode::BAD_REQUEST, "Invalid JSON body\n"))
+    }
+}
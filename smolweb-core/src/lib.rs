@@ -0,0 +1,484 @@
+#![no_std]
+
+//! Web application shared by the smolweb demos.
+//!
+//! Each demo implements [LedControl] and [Clock] for its own hardware (or simulation) and serves the router built by [make_app].
+
+#[macro_use]
+mod logging;
+
+pub mod access_log;
+pub mod analog;
+pub mod api;
+pub mod assets;
+pub mod auth;
+pub mod buffers;
+pub mod button;
+pub mod cached_file;
+pub mod chunked;
+pub mod coap;
+pub mod config_page;
+pub mod cors;
+pub mod diagnostics;
+pub mod discovery;
+pub mod error;
+pub mod events;
+pub mod health;
+pub mod json;
+pub mod limits;
+pub mod logs;
+#[cfg(feature = "embassy")]
+pub mod mdns;
+pub mod metrics;
+pub mod mime;
+#[cfg(feature = "embassy")]
+pub mod network;
+pub mod not_found;
+pub mod panic_record;
+pub mod patterns;
+#[cfg(feature = "embassy")]
+pub mod provisioning;
+pub mod rate_limit;
+pub mod redirect;
+pub mod schedules;
+pub mod sensors;
+pub mod session;
+pub mod settings;
+#[cfg(feature = "embassy")]
+pub mod sntp;
+pub mod static_files;
+pub mod status_page;
+pub mod strip;
+pub mod time;
+pub mod tone;
+pub mod upload;
+pub mod workers;
+pub mod ws;
+
+use core::fmt::Write;
+
+use picoserve::{
+    extract::{FromRef, Query, State},
+    io::Read,
+    response::{
+        ws::WebSocketUpgrade, Connection, EventStream, File, IntoResponse, Json as JsonResponse,
+        Response, ResponseWriter, StatusCode,
+    },
+    routing::{get, get_service, parse_path_segment, post, PathRouter},
+    ResponseSent,
+};
+
+use access_log::{ConnectionId, LogRequests};
+use assets::AssetStore;
+use auth::{Credentials, RequireAuth};
+use button::ButtonStats;
+use cors::{api_get, api_get_post, api_post, api_post_service, Cors, CorsOrigins};
+use diagnostics::Diagnostics;
+use events::{BoardEventStream, BoardEvents};
+use json::Json;
+use limits::{CutShort, EnforceLimits, Limits};
+use metrics::{CountRequests, Metrics};
+use patterns::Pattern;
+use rate_limit::{ClientAddress, ClientRateLimit, ToggleRateLimit};
+use sensors::SensorStats;
+use session::SessionContext;
+use settings::SettingsStore;
+use static_files::{StaticDir, StaticFiles};
+use status_page::LocalAddress;
+use strip::StripCommand;
+use time::{Clock, Iso8601};
+use tone::Tone;
+use ws::LedUpdates;
+
+/// TCP port the demos serve the application on, so the same URLs work on every platform.
+pub const DEFAULT_PORT: u16 = 8080;
+
+/// Access to the board LEDs, identified by their number on the board.
+pub trait LedControl {
+    /// Returns true if the board has an LED with the given number.
+    fn has_led(&self, led: u8) -> bool;
+
+    /// Invert the state of the LED.
+    fn toggle(&self, led: u8);
+
+    /// Turn the LED on or off.
+    fn set(&self, led: u8, on: bool);
+
+    /// Returns true if the LED is on.
+    fn state(&self, led: u8) -> bool;
+
+    /// Brightness of the LED in percent, or `None` if it can only be turned on and off.
+    fn brightness(&self, _led: u8) -> Option<u8> {
+        None
+    }
+
+    /// Set the brightness in percent of an LED whose [Self::brightness] isn't `None`, 0 turning it off.
+    fn set_brightness(&self, _led: u8, _percent: u8) {}
+
+    /// Play `pattern` on the LED, returning false if the board has no pattern engine.
+    fn set_pattern(&self, _led: u8, _pattern: Pattern) -> bool {
+        false
+    }
+
+    /// Pixels of the WS2812 strip of the board, 0 if it has none.
+    fn strip_length(&self) -> usize {
+        0
+    }
+
+    /// Show `command` on the strip, returning false if it can't be taken right now.
+    fn set_strip(&self, _command: StripCommand) -> bool {
+        false
+    }
+
+    /// Returns true if the board has a buzzer to play tones on.
+    fn has_buzzer(&self) -> bool {
+        false
+    }
+
+    /// Play `tone` on the buzzer, cutting short the one playing, returning false if it can't be taken right now.
+    fn play_tone(&self, _tone: Tone) -> bool {
+        false
+    }
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum LedState {
+    On,
+    Off,
+}
+
+/// Query of `/toggle_led/<n>`, e.g. `?state=on`, which sets the LED instead of toggling it.
+#[derive(serde::Deserialize)]
+struct ToggleQuery {
+    #[serde(default)]
+    state: Option<LedState>,
+}
+
+/// Response of `/toggle_led/<n>`: the new state as text after a toggle, or the [LedStatus](api::LedStatus) when set
+/// with `?state=`.
+enum ToggleResponse {
+    Toggled(&'static str),
+    Set(JsonResponse<api::LedStatus>),
+}
+
+impl IntoResponse for ToggleResponse {
+    async fn write_to<R: Read, W: ResponseWriter<Error = R::Error>>(
+        self,
+        connection: Connection<'_, R>,
+        response_writer: W,
+    ) -> Result<ResponseSent, W::Error> {
+        match self {
+            Self::Toggled(state) => state.write_to(connection, response_writer).await,
+            Self::Set(status) => status.write_to(connection, response_writer).await,
+        }
+    }
+}
+
+/// Body of `POST /led`, e.g. `{ "led": 2, "state": "on" }`.
+#[derive(serde::Deserialize)]
+struct LedRequest {
+    led: u8,
+    state: LedState,
+}
+
+fn on_off(on: bool) -> &'static str {
+    if on {
+        "ON"
+    } else {
+        "OFF"
+    }
+}
+
+/// Response to a refused toggle, with `Retry-After` in whole seconds when refused by the [ToggleRateLimit].
+type ToggleError = (StatusCode, Option<(&'static str, u64)>, &'static str);
+
+/// Record a toggle of `led` in `rate_limit`, or refuse it with `429 Too Many Requests`.
+fn check_rate_limit(
+    rate_limit: &ToggleRateLimit,
+    led: u8,
+    clock: &impl Clock,
+) -> Result<(), ToggleError> {
+    rate_limit.try_toggle(led, clock.uptime()).map_err(|retry_after| {
+        log_debug!("Not toggling LED{}, toggled too recently", led);
+        let retry_after_secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+        (
+            StatusCode::TOO_MANY_REQUESTS,
+            Some(("Retry-After", retry_after_secs)),
+            "Toggled too recently\n",
+        )
+    })
+}
+
+async fn toggle_led<C: LedControl, T: Clock>(
+    led_type: u8,
+    _: RequireAuth,
+    State(control): State<C>,
+    State(clock): State<T>,
+    State(metrics): State<&'static Metrics>,
+    State(rate_limit): State<&'static ToggleRateLimit>,
+    Query(query): Query<ToggleQuery>,
+) -> Result<ToggleResponse, ToggleError> {
+    metrics.count_toggle_led();
+
+    // The LED is part of the path, so like in the API an unknown one is not found
+    if !control.has_led(led_type) {
+        return Err((StatusCode::NOT_FOUND, None, "Unknown LED\n"));
+    }
+
+    // Setting an LED leaves it as asked whatever it was, so unlike a toggle it isn't rate limited
+    if let Some(state) = query.state {
+        log_debug!("Setting LED{}", led_type);
+        control.set(led_type, matches!(state, LedState::On));
+        return Ok(ToggleResponse::Set(JsonResponse(api::LedStatus::read(
+            &control, led_type,
+        ))));
+    }
+
+    check_rate_limit(rate_limit, led_type, &clock)?;
+
+    log_debug!("Toggling LED{}", led_type);
+    control.toggle(led_type);
+    let led_state = control.state(led_type);
+    log_debug!("LED value after toggle: {}", led_state);
+    Ok(ToggleResponse::Toggled(on_off(led_state)))
+}
+
+async fn set_led<C: LedControl>(
+    _: RequireAuth,
+    State(control): State<C>,
+    State(metrics): State<&'static Metrics>,
+    Json(request): Json<LedRequest>,
+) -> Result<&'static str, (StatusCode, &'static str)> {
+    metrics.count_led();
+
+    if !control.has_led(request.led) {
+        return Err((StatusCode::BAD_REQUEST, "Unknown LED\n"));
+    }
+
+    log_debug!("Setting LED{}", request.led);
+    control.set(request.led, matches!(request.state, LedState::On));
+    let led_state = control.state(request.led);
+    log_debug!("LED value after set: {}", led_state);
+    Ok(on_off(led_state))
+}
+
+async fn get_time<T: Clock>(
+    State(clock): State<T>,
+) -> Result<heapless::String<32>, (StatusCode, &'static str)> {
+    let unix_time = clock.unix_time().ok_or((
+        StatusCode::SERVICE_UNAVAILABLE,
+        "Time is not synchronized yet\n",
+    ))?;
+
+    let mut body = heapless::String::new();
+    // An ISO-8601 timestamp is at most 21 bytes for any year below 10000
+    let _ = writeln!(body, "{}", Iso8601(unix_time));
+    Ok(body)
+}
+
+async fn get_metrics<T: Clock>(
+    State(clock): State<T>,
+    State(metrics): State<&'static Metrics>,
+) -> impl IntoResponse {
+    Response::ok(metrics.snapshot(clock.uptime().as_secs(), clock.unix_time()))
+}
+
+async fn led_updates<C: LedControl + BoardEvents>(
+    State(control): State<C>,
+    upgrade: WebSocketUpgrade,
+) -> Result<impl IntoResponse, (StatusCode, &'static str)> {
+    let subscriber = control.subscribe().ok_or((
+        StatusCode::SERVICE_UNAVAILABLE,
+        "Too many WebSockets open\n",
+    ))?;
+
+    Ok(upgrade.on_upgrade(LedUpdates {
+        control,
+        subscriber,
+    }))
+}
+
+async fn board_events<C: BoardEvents, T: Clock>(
+    State(events): State<C>,
+    State(clock): State<T>,
+) -> Result<EventStream<BoardEventStream<C::Subscriber, T>>, (StatusCode, &'static str)> {
+    let subscriber = events.subscribe().ok_or((
+        StatusCode::SERVICE_UNAVAILABLE,
+        "Too many event streams open\n",
+    ))?;
+
+    Ok(EventStream(BoardEventStream { subscriber, clock }))
+}
+
+/// Build the application router, with `C`, `T`, the [Metrics], the [ToggleRateLimit], the [ButtonStats], the [SensorStats] and the [Credentials] extracted from the application state `S`.
+///
+/// `GET /` renders the control panel with the LEDs of `C`, the uptime of `T` and the [LocalAddress] of the connection.
+/// The files of `assets/`, like `index.css` and `index.js`, are served at the root by [StaticDir]. Requests which match
+/// no route nor file get the HTML 404 page from [NotFound](not_found::NotFound), or a JSON [ApiError](error::ApiError) under `/api`. Every request is counted and logged.
+/// `/toggle_led`, `/led` and everything under `/api` require the [Credentials], or the cookie of a session opened with
+/// them at `/login`, checked against the [SessionContext]. The page and its assets are public.
+/// Every route requiring them, and `POST /login`, is also limited per [ClientAddress] by the [ClientRateLimit].
+/// Files of the [AssetStore] `A` are served under `/static` in front of the embedded ones, and `POST /api/upload`
+/// writes them into stores which can, with [AssetUpload](upload::AssetUpload).
+/// `GET /logs` serves the [LOGS](logs::LOGS) buffer, following it with `?follow=true`, and also requires the [Credentials].
+/// `POST /api/leds/<n>/pattern` plays a blink [Pattern] on an LED, on boards whose [LedControl] has a pattern engine.
+/// `POST /api/strip` sets the pixels of the WS2812 strip of `C`, or plays an animation on it, and `POST /api/tone`
+/// plays a [Tone] on its buzzer.
+/// `GET /api/sensors` reads the latest sample of the [SensorStats], which the page also charts from `/events`.
+/// `GET /api/sysinfo` shows what the tasks reported into the [Diagnostics], and `GET /api/workers` the connections
+/// each web worker served, counted in the [Metrics].
+/// `GET /healthz` and `GET /readyz` are public, for load balancers and test rigs, and `/readyz` only answers `200 OK`
+/// once the checks of the [Diagnostics] pass, see [health].
+/// `GET` and `POST /api/settings` read and save the [Settings](settings::Settings) of the [SettingsStore] `P`, which
+/// `/config` also shows and saves as an HTML form, applying the LED states at once.
+/// `GET` and `POST /api/schedules` and `POST /api/schedules/<id>/delete` manage the [Schedule](schedules::Schedule)s
+/// kept with the settings, which the demos run with a [Scheduler](schedules::Scheduler).
+/// `GET /ws` opens a WebSocket pushing the LED states, and `GET /events` streams every event, from the [BoardEvents] of `C`.
+/// `OPTIONS` under `/api` answers with the methods of the route, and [CorsOrigins] lists the origins allowed to call it
+/// from a browser. Every route is wrapped in [EnforceLimits], and the router served over a
+/// [LimitedSocket](limits::LimitedSocket), for requests over the [Limits] to be refused with `413`, `414` or `431`.
+pub fn make_app<S, C, T, A, P>() -> picoserve::Router<impl PathRouter<S>, S>
+where
+    C: LedControl + BoardEvents + FromRef<S>,
+    T: Clock + FromRef<S>,
+    A: AssetStore + FromRef<S>,
+    P: SettingsStore + FromRef<S>,
+    &'static Metrics: FromRef<S>,
+    &'static ToggleRateLimit: FromRef<S>,
+    &'static ButtonStats: FromRef<S>,
+    &'static SensorStats: FromRef<S>,
+    &'static Diagnostics: FromRef<S>,
+    &'static ClientRateLimit: FromRef<S>,
+    Credentials: FromRef<S>,
+    SessionContext: FromRef<S>,
+    ConnectionId: FromRef<S>,
+    ClientAddress: FromRef<S>,
+    CorsOrigins: FromRef<S>,
+    Limits: FromRef<S>,
+    CutShort: FromRef<S>,
+    LocalAddress: FromRef<S>,
+{
+    add_middleware::<S, T, _>(make_routes::<S, C, T, A, P>())
+}
+
+/// The routes of [make_app] without the middleware, for demos adding routes specific to their board.
+///
+/// Pass the extended router to [add_middleware] so that the extra routes are counted and logged too.
+pub fn make_routes<S, C, T, A, P>() -> picoserve::Router<impl PathRouter<S>, S>
+where
+    C: LedControl + BoardEvents + FromRef<S>,
+    T: Clock + FromRef<S>,
+    A: AssetStore + FromRef<S>,
+    P: SettingsStore + FromRef<S>,
+    &'static Metrics: FromRef<S>,
+    &'static ToggleRateLimit: FromRef<S>,
+    &'static ButtonStats: FromRef<S>,
+    &'static SensorStats: FromRef<S>,
+    &'static Diagnostics: FromRef<S>,
+    &'static ClientRateLimit: FromRef<S>,
+    Credentials: FromRef<S>,
+    SessionContext: FromRef<S>,
+    ClientAddress: FromRef<S>,
+    CorsOrigins: FromRef<S>,
+    Limits: FromRef<S>,
+    CutShort: FromRef<S>,
+    LocalAddress: FromRef<S>,
+{
+    // Each `route` falls back to the router it was added to, so `StaticDir` only sees unmatched paths. Each handler is
+    // wrapped in `EnforceLimits`, as a picoserve layer can only replace the response of a handler, not skip it
+    picoserve::Router::from_service(EnforceLimits(StaticDir))
+        .route("/", get(EnforceLimits(status_page::get_index::<C, T>)))
+        .route(
+            "/login",
+            get_service(EnforceLimits(File::html(include_str!("login.html"))))
+                .post(EnforceLimits(session::login)),
+        )
+        .route("/logout", post(EnforceLimits(session::logout)))
+        .nest_service("/static", EnforceLimits(StaticFiles::<A>::new()))
+        // Static assets are public, control and API routes take a `RequireAuth` extractor
+        .route(
+            ("/toggle_led", parse_path_segment()),
+            get(EnforceLimits(toggle_led::<C, T>)),
+        )
+        .route("/led", post(EnforceLimits(set_led::<C>)))
+        .route("/time", get(EnforceLimits(get_time::<T>)))
+        .route("/metrics", get(EnforceLimits(get_metrics::<T>)))
+        .route("/ws", get(EnforceLimits(led_updates::<C>)))
+        .route("/events", get(EnforceLimits(board_events::<C, T>)))
+        .route("/logs", get(EnforceLimits(logs::get_logs)))
+        .route("/healthz", get(EnforceLimits(health::get_healthz::<T>)))
+        .route("/readyz", get(EnforceLimits(health::get_readyz)))
+        .route("/api/leds", api_get(EnforceLimits(api::list_leds::<C>)))
+        .route(
+            ("/api/leds", parse_path_segment()),
+            api_post(EnforceLimits(api::command_led::<C, T>)),
+        )
+        .route(
+            (("/api/leds", parse_path_segment()), "/brightness"),
+            api_post(EnforceLimits(api::set_brightness::<C>)),
+        )
+        .route(
+            (("/api/leds", parse_path_segment()), "/pattern"),
+            api_post(EnforceLimits(patterns::set_pattern::<C, P>)),
+        )
+        .route("/api/strip", api_post(EnforceLimits(strip::set_strip::<C>)))
+        .route("/api/tone", api_post(EnforceLimits(tone::play_tone::<C>)))
+        .route(
+            "/api/button",
+            api_get(EnforceLimits(button::get_button::<T>)),
+        )
+        .route("/api/sensors", api_get(EnforceLimits(sensors::get_sensors)))
+        .route("/api/time", api_get(EnforceLimits(api::get_time::<T>)))
+        .route(
+            "/api/sysinfo",
+            api_get(EnforceLimits(diagnostics::get_sysinfo::<T>)),
+        )
+        .route("/api/workers", api_get(EnforceLimits(workers::get_workers)))
+        .route(
+            "/api/upload",
+            api_post_service(EnforceLimits(upload::AssetUpload::<A>::new())),
+        )
+        .route(
+            "/api/settings",
+            api_get_post(
+                EnforceLimits(settings::get_settings::<P>),
+                EnforceLimits(settings::save_settings::<P>),
+            ),
+        )
+        .route(
+            "/api/schedules",
+            api_get_post(
+                EnforceLimits(schedules::list_schedules::<P>),
+                EnforceLimits(schedules::add_schedule::<C, T, P>),
+            ),
+        )
+        .route(
+            (("/api/schedules", parse_path_segment()), "/delete"),
+            api_post(EnforceLimits(schedules::delete_schedule::<P>)),
+        )
+        .route(
+            "/config",
+            get(EnforceLimits(config_page::get_config::<C, P>))
+                .post(EnforceLimits(config_page::post_config::<C, P>)),
+        )
+}
+
+/// Count and log every request handled by `router`, with the [ConnectionId] of the state `S`, and let the
+/// [CorsOrigins] call `/api` with [Cors].
+pub fn add_middleware<S, T, R>(
+    router: picoserve::Router<R, S>,
+) -> picoserve::Router<impl PathRouter<S>, S>
+where
+    T: Clock + FromRef<S>,
+    &'static Metrics: FromRef<S>,
+    ConnectionId: FromRef<S>,
+    CorsOrigins: FromRef<S>,
+    R: PathRouter<S>,
+{
+    // The last layer sees the request first, and every one sees those refused by `EnforceLimits`
+    router
+        .layer(Cors)
+        .layer(CountRequests)
+        .layer(LogRequests::<T>::new())
+}
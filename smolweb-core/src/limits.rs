@@ -0,0 +1,580 @@
+//! Bounds on the size of requests, so that those too large for the buffers of a worker are refused with a status
+//! instead of failing halfway.
+//!
+//! picoserve needs the whole head of a request in the `http` [buffer](crate::buffers::BufferConfig), so the
+//! [LimitedSocket] cuts short the heads over [Limits::max_request_line] or [Limits::max_header_bytes] while they still
+//! fit, and records it in the [HeadCut] of the connection, kept in its state. [EnforceLimits] wraps the handler of each
+//! route and answers those requests with `414 URI Too Long` or `431 Request Header Fields Too Large`, paths of more than
+//! [Limits::max_path_segments] segments with `414`, and bodies over [Limits::max_body_bytes] with
+//! `413 Payload Too Large`, in place of the handler, so that it never runs for them.
+//! [RequireAuth](crate::auth::RequireAuth), `POST /login` and the [Json](crate::json::Json) bodies check them again.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use embedded_io_async::ErrorType;
+use picoserve::{
+    extract::FromRef,
+    io::{Read, Socket},
+    request::{Path, Request, RequestParts},
+    response::{IntoResponse, ResponseWriter, StatusCode},
+    routing::{PathRouterService, RequestHandlerFunction, RequestHandlerService},
+    Error, ResponseSent, Timeouts, Timer,
+};
+
+use crate::{buffers::BufferConfig, error::Refusal, static_files::trim};
+
+/// Bytes of a header line kept to find `Content-Length`, whose name and value fit.
+const HEADER_LINE_LEN: usize = 40;
+
+/// Bytes of the head of a request cut short waiting to be read in place of those of the socket: the end of its last
+/// line, or its request line, up to [KEPT_REQUEST_LINE_LEN] bytes, with its end.
+const PENDING_LEN: usize = 64;
+
+/// Ends the request line of a head cut short, and the head.
+const END_OF_REQUEST_LINE: &[u8] = b" HTTP/1.1\r\n\r\n";
+
+/// Bytes of the request line kept when the head it starts is cut short after another request.
+const KEPT_REQUEST_LINE_LEN: usize = PENDING_LEN - END_OF_REQUEST_LINE.len();
+
+/// Largest requests served, extracted from the application state.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limits {
+    /// Bytes of the request line, e.g. `GET /api/leds?state=on HTTP/1.1`, with its line break.
+    pub max_request_line: usize,
+    /// Bytes of the headers after the request line, line breaks included.
+    pub max_header_bytes: usize,
+    /// `Content-Length` of a body. Handlers reading the body whole, like those of JSON, are also bound by the `http`
+    /// buffer, while uploads are streamed.
+    pub max_body_bytes: usize,
+    /// Segments of the path, e.g. 4 for `/api/leds/2/pattern`.
+    pub max_path_segments: usize,
+}
+
+impl Limits {
+    /// Limits whose heads fit in the `http` buffer of `buffers`: a quarter of it for the request line and most of the
+    /// rest for the headers, leaving room for the end of a head cut short.
+    pub const fn for_buffers(buffers: BufferConfig) -> Self {
+        Self {
+            max_request_line: buffers.http / 4,
+            max_header_bytes: buffers.http / 8 * 5,
+            // The largest firmware image taken by `/ota`, 768 KiB, with room to spare
+            max_body_bytes: 1024 * 1024,
+            max_path_segments: 8,
+        }
+    }
+
+    /// The limits of the web workers of every demo, for [BufferConfig::WEB].
+    pub const WEB: Self = Self::for_buffers(BufferConfig::WEB);
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self::WEB
+    }
+}
+
+/// The `Content-Length` of the request, 0 without a body.
+fn content_length(request_parts: &RequestParts<'_>) -> usize {
+    request_parts
+        .headers()
+        .get("Content-Length")
+        .and_then(|length| core::str::from_utf8(length.as_raw()).ok())
+        .and_then(|length| length.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+/// How the [LimitedSocket] of the connection cut short the head of the request being served, extracted from the state.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CutShort {
+    /// The head is whole.
+    #[default]
+    No,
+    /// The request line was over [Limits::max_request_line].
+    RequestLine,
+    /// The headers were over [Limits::max_header_bytes].
+    Headers,
+}
+
+/// Where the [LimitedSocket] of a connection records the [CutShort] of the request being served, kept in the state of
+/// the connection, outside of the request itself, so that a client can't set it.
+#[derive(Debug, Default)]
+pub struct HeadCut(AtomicU8);
+
+impl HeadCut {
+    pub const fn new() -> Self {
+        Self(AtomicU8::new(0))
+    }
+
+    /// How the head of the request being served was cut short.
+    pub fn get(&self) -> CutShort {
+        match self.0.load(Ordering::Relaxed) {
+            1 => CutShort::RequestLine,
+            2 => CutShort::Headers,
+            _ => CutShort::No,
+        }
+    }
+
+    fn set(&self, cut_short: CutShort) {
+        self.0.store(cut_short as u8, Ordering::Relaxed);
+    }
+}
+
+/// The refusal of the request of `request_parts` if it is over the [Limits] of `state`.
+pub(crate) fn check<State>(state: &State, request_parts: &RequestParts<'_>) -> Result<(), Refusal>
+where
+    Limits: FromRef<State>,
+    CutShort: FromRef<State>,
+{
+    let limits = Limits::from_ref(state);
+
+    let cut_short = CutShort::from_ref(state);
+
+    let (status, message) = if cut_short == CutShort::RequestLine {
+        (StatusCode::URI_TOO_LONG, "URI too long")
+    } else if cut_short == CutShort::Headers {
+        (
+            StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+            "Request headers too large",
+        )
+    } else if request_parts
+        .path()
+        .encoded()
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .count()
+        > limits.max_path_segments
+    {
+        (StatusCode::URI_TOO_LONG, "URI too long")
+    } else if content_length(request_parts) > limits.max_body_bytes {
+        (StatusCode::PAYLOAD_TOO_LARGE, "Request body is too large")
+    } else {
+        return Ok(());
+    };
+
+    log_debug!(
+        "Request over the limits, refusing {}",
+        request_parts.path().encoded()
+    );
+    Err(Refusal::new(status, message, request_parts))
+}
+
+/// Wraps the handler or service of a route, refusing the requests over the [Limits] of the state in its place, so
+/// that it never sees them.
+///
+/// Every route of [make_app](crate::make_app) is wrapped in one, and the layers of
+/// [add_middleware](crate::add_middleware) around them, so refused requests are still counted and logged.
+pub struct EnforceLimits<H>(pub H);
+
+impl<H> EnforceLimits<H> {
+    /// Answer `request` with `refusal`, skipping its body.
+    async fn refuse<R: Read, W: ResponseWriter<Error = R::Error>>(
+        refusal: Refusal,
+        request: Request<'_, R>,
+        response_writer: W,
+    ) -> Result<ResponseSent, W::Error> {
+        refusal
+            .write_to(request.body_connection.finalize().await?, response_writer)
+            .await
+    }
+}
+
+impl<State, PathParameters, T, H> RequestHandlerFunction<State, PathParameters, T>
+    for EnforceLimits<H>
+where
+    H: RequestHandlerFunction<State, PathParameters, T>,
+    Limits: FromRef<State>,
+    CutShort: FromRef<State>,
+{
+    async fn call_handler_func<R: Read, W: ResponseWriter<Error = R::Error>>(
+        &self,
+        state: &State,
+        path_parameters: PathParameters,
+        request: Request<'_, R>,
+        response_writer: W,
+    ) -> Result<ResponseSent, W::Error> {
+        match check(state, &request.parts) {
+            Ok(()) => {
+                self.0
+                    .call_handler_func(state, path_parameters, request, response_writer)
+                    .await
+            }
+            Err(refusal) => Self::refuse(refusal, request, response_writer).await,
+        }
+    }
+}
+
+impl<State, PathParameters, H> RequestHandlerService<State, PathParameters> for EnforceLimits<H>
+where
+    H: RequestHandlerService<State, PathParameters>,
+    Limits: FromRef<State>,
+    CutShort: FromRef<State>,
+{
+    async fn call_request_handler_service<R: Read, W: ResponseWriter<Error = R::Error>>(
+        &self,
+        state: &State,
+        path_parameters: PathParameters,
+        request: Request<'_, R>,
+        response_writer: W,
+    ) -> Result<ResponseSent, W::Error> {
+        match check(state, &request.parts) {
+            Ok(()) => {
+                self.0
+                    .call_request_handler_service(state, path_parameters, request, response_writer)
+                    .await
+            }
+            Err(refusal) => Self::refuse(refusal, request, response_writer).await,
+        }
+    }
+}
+
+impl<State, CurrentPathParameters, H> PathRouterService<State, CurrentPathParameters>
+    for EnforceLimits<H>
+where
+    H: PathRouterService<State, CurrentPathParameters>,
+    Limits: FromRef<State>,
+    CutShort: FromRef<State>,
+{
+    async fn call_request_handler_service<R: Read, W: ResponseWriter<Error = R::Error>>(
+        &self,
+        state: &State,
+        current_path_parameters: CurrentPathParameters,
+        path: Path<'_>,
+        request: Request<'_, R>,
+        response_writer: W,
+    ) -> Result<ResponseSent, W::Error> {
+        match check(state, &request.parts) {
+            Ok(()) => {
+                self.0
+                    .call_request_handler_service(
+                        state,
+                        current_path_parameters,
+                        path,
+                        request,
+                        response_writer,
+                    )
+                    .await
+            }
+            Err(refusal) => Self::refuse(refusal, request, response_writer).await,
+        }
+    }
+}
+
+/// Where the reader is in the requests of the connection.
+#[derive(Clone, Copy)]
+enum Position {
+    /// Before the method of the next request.
+    Start,
+    /// In the request line or the headers.
+    Head,
+    /// In the body, with this many bytes left.
+    Body(u64),
+    /// The head was over the [Limits] and cut short, so nothing more is read from the connection.
+    Refused,
+}
+
+/// State of the [LimitedSocket] reader between reads.
+struct RequestState {
+    position: Position,
+    /// Start of the header line being read.
+    line: heapless::Vec<u8, HEADER_LINE_LEN>,
+    /// Bytes of the line being read, of which [Self::line] only keeps the start.
+    line_len: usize,
+    /// Whether the line being read has a `:`.
+    line_has_colon: bool,
+    /// Whether the line being read is the request line.
+    in_request_line: bool,
+    /// Bytes of the headers read before the line being read.
+    header_bytes: usize,
+    content_length: u64,
+    /// Bytes waiting to be read, see [PENDING_LEN].
+    pending: heapless::Vec<u8, PENDING_LEN>,
+    /// How the head ending with [Self::pending] was cut short.
+    cut_short: CutShort,
+    limits: Limits,
+}
+
+impl RequestState {
+    /// Returns true if one more byte of the line being read would go over the [Limits].
+    fn is_full(&self) -> bool {
+        if self.in_request_line {
+            self.line_len >= self.limits.max_request_line
+        } else {
+            self.header_bytes + self.line_len >= self.limits.max_header_bytes
+        }
+    }
+
+    /// Start reading the head of the next request.
+    fn start_head(&mut self) {
+        self.position = Position::Head;
+        self.content_length = 0;
+        self.line.clear();
+        self.line_len = 0;
+        self.line_has_colon = false;
+        self.in_request_line = true;
+        self.header_bytes = 0;
+    }
+
+    /// Follow the requests through `bytes`.
+    ///
+    /// Returns how many of them fit in the [Limits] if a head goes over them, in which case the rest is dropped, with
+    /// where that head starts in `bytes`, or 0 if it started before them.
+    fn advance(&mut self, bytes: &[u8]) -> Option<(usize, usize)> {
+        let mut rest = bytes;
+        let mut head_start = 0;
+
+        while let Some((&byte, tail)) = rest.split_first() {
+            match self.position {
+                Position::Start => {
+                    head_start = bytes.len() - rest.len();
+                    self.start_head();
+                }
+                Position::Head => {
+                    if self.is_full() {
+                        return Some((bytes.len() - rest.len(), head_start));
+                    }
+                    rest = tail;
+                    self.line_len += 1;
+
+                    if byte != b'\n' {
+                        let _ = self.line.push(byte);
+                        self.line_has_colon |= byte == b':';
+                        continue;
+                    }
+
+                    let line = trim(&self.line);
+                    if line.is_empty() {
+                        self.position = match self.content_length {
+                            0 => Position::Start,
+                            length => Position::Body(length),
+                        };
+                    } else if let Some(length) = header_content_length(line) {
+                        self.content_length = length;
+                    }
+
+                    if !self.in_request_line {
+                        self.header_bytes += self.line_len;
+                    }
+                    self.in_request_line = false;
+                    self.line.clear();
+                    self.line_len = 0;
+                    self.line_has_colon = false;
+                }
+                Position::Body(remaining) => {
+                    let length = usize::try_from(remaining)
+                        .map_or(rest.len(), |remaining| rest.len().min(remaining));
+                    rest = &rest[length..];
+                    self.position = match remaining - length as u64 {
+                        0 => Position::Start,
+                        remaining => Position::Body(remaining),
+                    };
+                }
+                Position::Refused => return None,
+            }
+        }
+
+        None
+    }
+
+    /// How the head over the [Limits] found by [Self::advance] is cut short.
+    fn cut_short(&self) -> CutShort {
+        if self.in_request_line {
+            CutShort::RequestLine
+        } else {
+            CutShort::Headers
+        }
+    }
+
+    /// End the head cut short by [Self::advance], finishing the line being read so that picoserve can still parse it.
+    fn cut(&mut self) {
+        let end: &[u8] = if self.in_request_line {
+            // The request line is only this long with its path, so the version is what is left
+            END_OF_REQUEST_LINE
+        } else if self.line_len == 0 {
+            b"\r\n"
+        } else if self.line_has_colon {
+            b"\r\n\r\n"
+        } else {
+            b":\r\n\r\n"
+        };
+
+        self.cut_short = self.cut_short();
+        self.pending.clear();
+        let _ = self.pending.extend_from_slice(end);
+        self.position = Position::Refused;
+    }
+
+    /// Replace the head cut short by [Self::advance], of which `head` is the start, with its method and path, for it
+    /// started after other requests read along with it, still to be served before it is refused.
+    fn cut_after(&mut self, head: &[u8]) {
+        let request_line = head
+            .split(|&byte| byte == b'\r' || byte == b'\n')
+            .next()
+            .unwrap_or_default();
+        let method_and_path = request_line
+            .iter()
+            .enumerate()
+            .filter(|(_, &byte)| byte == b' ')
+            .nth(1)
+            .map_or(request_line.len(), |(index, _)| index);
+
+        self.cut_short = self.cut_short();
+        self.pending.clear();
+        let _ = self
+            .pending
+            .extend_from_slice(&request_line[..method_and_path.min(KEPT_REQUEST_LINE_LEN)]);
+        let _ = self.pending.extend_from_slice(END_OF_REQUEST_LINE);
+        self.position = Position::Refused;
+    }
+
+    /// Move the start of [Self::pending] into `buf`, returning its length.
+    fn take_pending(&mut self, buf: &mut [u8]) -> usize {
+        let length = self.pending.len().min(buf.len());
+        buf[..length].copy_from_slice(&self.pending[..length]);
+
+        let rest = heapless::Vec::from_slice(&self.pending[length..]).unwrap_or_default();
+        self.pending = rest;
+        length
+    }
+}
+
+/// The value of `line` if it is a `Content-Length` header.
+fn header_content_length(line: &[u8]) -> Option<u64> {
+    const NAME: &[u8] = b"content-length:";
+
+    if line.len() < NAME.len() || !line[..NAME.len()].eq_ignore_ascii_case(NAME) {
+        return None;
+    }
+
+    core::str::from_utf8(trim(&line[NAME.len()..]))
+        .ok()?
+        .parse()
+        .ok()
+}
+
+/// The reading half of a [LimitedSocket].
+pub struct LimitedReadHalf<'a, H> {
+    half: H,
+    state: &'a mut RequestState,
+    head_cut: &'a HeadCut,
+}
+
+impl<H: ErrorType> ErrorType for LimitedReadHalf<'_, H> {
+    type Error = H::Error;
+}
+
+impl<H: Read> Read for LimitedReadHalf<'_, H> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        // Only read once the requests before the one cut short are served
+        if !self.state.pending.is_empty() {
+            self.head_cut.set(self.state.cut_short);
+            return Ok(self.state.take_pending(buf));
+        }
+
+        // The end of the connection after a head cut short
+        if matches!(self.state.position, Position::Refused) {
+            return Ok(0);
+        }
+
+        let read = self.half.read(buf).await?;
+        match self.state.advance(&buf[..read]) {
+            None => Ok(read),
+            Some((kept, 0)) => {
+                log_debug!("Request head over the limits, cutting it short");
+                self.state.cut();
+                self.head_cut.set(self.state.cut_short);
+                // A read of 0 bytes would end the connection before the end of the head
+                Ok(match kept {
+                    0 => self.state.take_pending(buf),
+                    kept => kept,
+                })
+            }
+            Some((kept, head_start)) => {
+                log_debug!("Pipelined request head over the limits, cutting it short");
+                self.state.cut_after(&buf[head_start..kept]);
+                Ok(head_start)
+            }
+        }
+    }
+}
+
+/// Socket served by picoserve in place of `S`, cutting short the heads over the [Limits] so that they are refused
+/// instead of overflowing the buffer of picoserve.
+pub struct LimitedSocket<'a, S> {
+    socket: S,
+    request: RequestState,
+    head_cut: &'a HeadCut,
+}
+
+impl<'a, S> LimitedSocket<'a, S> {
+    /// Serve `socket` with the [Limits::WEB], recording the heads cut short in `head_cut`, that of the state served.
+    pub fn new(socket: S, head_cut: &'a HeadCut) -> Self {
+        Self::with_limits(socket, Limits::WEB, head_cut)
+    }
+
+    /// Serve `socket`, cutting short the heads over `limits`, which must fit in the `http` buffer.
+    pub fn with_limits(socket: S, limits: Limits, head_cut: &'a HeadCut) -> Self {
+        head_cut.set(CutShort::No);
+
+        Self {
+            head_cut,
+            socket,
+            request: RequestState {
+                position: Position::Start,
+                line: heapless::Vec::new(),
+                line_len: 0,
+                line_has_colon: false,
+                in_request_line: false,
+                header_bytes: 0,
+                content_length: 0,
+                pending: heapless::Vec::new(),
+                cut_short: CutShort::No,
+                limits,
+            },
+        }
+    }
+}
+
+impl<S: Socket> Socket for LimitedSocket<'_, S> {
+    type Error = S::Error;
+    type ReadHalf<'b>
+        = LimitedReadHalf<'b, S::ReadHalf<'b>>
+    where
+        Self: 'b;
+    type WriteHalf<'b>
+        = S::WriteHalf<'b>
+    where
+        Self: 'b;
+
+    fn split(&mut self) -> (Self::ReadHalf<'_>, Self::WriteHalf<'_>) {
+        let (read_half, write_half) = self.socket.split();
+
+        (
+            LimitedReadHalf {
+                half: read_half,
+                state: &mut self.request,
+                head_cut: self.head_cut,
+            },
+            write_half,
+        )
+    }
+
+    async fn abort<T: Timer>(
+        self,
+        timeouts: &Timeouts<T::Duration>,
+        timer: &mut T,
+    ) -> Result<(), Error<Self::Error>> {
+        self.socket.abort(timeouts, timer).await
+    }
+
+    async fn shutdown<T: Timer>(
+        self,
+        timeouts: &Timeouts<T::Duration>,
+        timer: &mut T,
+    ) -> Result<(), Error<Self::Error>> {
+        self.socket.shutdown(timeouts, timer).await
+    }
+}
@@ -0,0 +1,60 @@
+#![allow(unused_macros)]
+
+// `defmt` only formats messages on the host, so those of this crate are also written to `/logs` as text
+
+macro_rules! log_info {
+    ($f:literal $(,$arg:expr)* $(,)?) => {
+        {
+            #[cfg(feature = "log")]
+            log::info!($f $(,$arg)*);
+
+            #[cfg(feature = "defmt")]
+            defmt::info!($f $(,$arg)*);
+
+            #[cfg(all(feature = "defmt", not(feature = "log")))]
+            crate::logs::LOGS.push_line("INFO", format_args!($f $(,$arg)*));
+
+            $(
+                let _ = &$arg;
+            )*
+        }
+    };
+}
+
+macro_rules! log_debug {
+    ($f:literal $(,$arg:expr)* $(,)?) => {
+        {
+            #[cfg(feature = "log")]
+            log::debug!($f $(,$arg)*);
+
+            #[cfg(feature = "defmt")]
+            defmt::debug!($f $(,$arg)*);
+
+            #[cfg(all(feature = "defmt", not(feature = "log")))]
+            crate::logs::LOGS.push_line("DEBUG", format_args!($f $(,$arg)*));
+
+            $(
+                let _ = &$arg;
+            )*
+        }
+    };
+}
+
+macro_rules! log_warn {
+    ($f:literal $(,$arg:expr)* $(,)?) => {
+        {
+            #[cfg(feature = "log")]
+            log::warn!($f $(,$arg)*);
+
+            #[cfg(feature = "defmt")]
+            defmt::warn!($f $(,$arg)*);
+
+            #[cfg(all(feature = "defmt", not(feature = "log")))]
+            crate::logs::LOGS.push_line("WARN", format_args!($f $(,$arg)*));
+
+            $(
+                let _ = &$arg;
+            )*
+        }
+    };
+}
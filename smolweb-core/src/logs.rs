@@ -0,0 +1,349 @@
+//! Recent log lines kept in RAM, served by `GET /logs` so that a board can be followed without a debug probe.
+//!
+//! With the `log` feature, demos install [TeeLogger] to copy every record into [LOGS]. With `defmt`, whose messages
+//! are only formatted on the host, the log macros of this crate copy its own messages instead.
+
+use core::{
+    cell::UnsafeCell,
+    fmt::{self, Write as _},
+    future::poll_fn,
+    ops::{Deref, DerefMut},
+    sync::atomic::Ordering,
+    task::{Poll, Waker},
+};
+
+use picoserve::{
+    extract::Query,
+    io::{Read, Write},
+    response::{
+        sse::{EventSource, EventWriter},
+        Connection, EventStream, IntoResponse, ResponseWriter,
+    },
+    ResponseSent,
+};
+use portable_atomic::AtomicBool;
+
+use crate::{
+    auth::RequireAuth,
+    chunked::{ChunkSource, Chunked, CHUNK_SIZE},
+};
+
+/// Bytes of log kept, the oldest lines being dropped to make room for new ones.
+pub const LOG_BUFFER_SIZE: usize = 4096;
+
+/// Longest line kept, longer ones are cut.
+const MAX_LINE_LENGTH: usize = 160;
+
+/// `GET /logs?follow=true` streams open at the same time, any more are polled until one closes.
+const MAX_FOLLOWERS: usize = 4;
+
+/// The log buffer shared by the whole firmware.
+pub static LOGS: LogBuffer = LogBuffer::new();
+
+struct Ring {
+    bytes: [u8; LOG_BUFFER_SIZE],
+    /// Bytes written since boot, wrapping around, so that readers can tell what they missed.
+    end: usize,
+    /// Whether the oldest bytes were written over, so that the oldest line may have lost its start.
+    wrapped: bool,
+    /// Woken by the next write.
+    followers: [Option<Waker>; MAX_FOLLOWERS],
+}
+
+/// Ring buffer of log lines, written from any task or thread.
+///
+/// Rather than waiting, a line written while another is being written or read is dropped, which can only happen
+/// from an interrupt or another thread.
+pub struct LogBuffer {
+    locked: AtomicBool,
+    ring: UnsafeCell<Ring>,
+}
+
+// Only accessed through the `Guard` of `try_lock`
+unsafe impl Sync for LogBuffer {}
+
+struct Guard<'a>(&'a LogBuffer);
+
+impl Deref for Guard<'_> {
+    type Target = Ring;
+
+    fn deref(&self) -> &Ring {
+        // Holding the guard means `locked` was set by us
+        unsafe { &*self.0.ring.get() }
+    }
+}
+
+impl DerefMut for Guard<'_> {
+    fn deref_mut(&mut self) -> &mut Ring {
+        unsafe { &mut *self.0.ring.get() }
+    }
+}
+
+impl Drop for Guard<'_> {
+    fn drop(&mut self) {
+        self.0.locked.store(false, Ordering::Release);
+    }
+}
+
+const NO_FOLLOWER: Option<Waker> = None;
+
+impl LogBuffer {
+    pub const fn new() -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            ring: UnsafeCell::new(Ring {
+                bytes: [0; LOG_BUFFER_SIZE],
+                end: 0,
+                wrapped: false,
+                followers: [NO_FOLLOWER; MAX_FOLLOWERS],
+            }),
+        }
+    }
+
+    fn try_lock(&self) -> Option<Guard<'_>> {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| Guard(self))
+    }
+
+    /// Append the line `<level> <message>`.
+    pub fn push_line(&self, level: &str, message: fmt::Arguments) {
+        let mut line = heapless::String::<MAX_LINE_LENGTH>::new();
+        // Cut at the capacity, then ended by a newline whatever happened
+        let _ = write!(line, "{level} {message}");
+        if line.push('\n').is_err() {
+            line.pop();
+            let _ = line.push('\n');
+        }
+
+        let Some(mut ring) = self.try_lock() else {
+            return;
+        };
+
+        for &byte in line.as_bytes() {
+            let index = ring.end % LOG_BUFFER_SIZE;
+            ring.bytes[index] = byte;
+            ring.end = ring.end.wrapping_add(1);
+        }
+        ring.wrapped |= ring.end >= LOG_BUFFER_SIZE;
+
+        for follower in &mut ring.followers {
+            if let Some(waker) = follower.take() {
+                waker.wake();
+            }
+        }
+    }
+
+    /// Where the oldest whole line in the ring starts and where the ring ends, or `None` while a line is being written.
+    fn bounds(&self) -> Option<(usize, usize)> {
+        self.try_lock()
+            .map(|ring| (Self::oldest_line(&ring), ring.end))
+    }
+
+    fn oldest_line(ring: &Ring) -> usize {
+        if !ring.wrapped {
+            return 0;
+        }
+
+        let mut position = ring.end.wrapping_sub(LOG_BUFFER_SIZE);
+
+        // Drop the rest of the line the oldest byte is part of
+        while position != ring.end {
+            let byte = ring.bytes[position % LOG_BUFFER_SIZE];
+            position = position.wrapping_add(1);
+            if byte == b'\n' {
+                break;
+            }
+        }
+
+        position
+    }
+
+    /// Copy the bytes from `position` up to `end` into `out`, returning how many, or `None` while a line is being
+    /// written.
+    ///
+    /// Bytes the ring has written over since are lost, and none are copied.
+    fn read(&self, position: usize, end: usize, out: &mut [u8]) -> Option<usize> {
+        let ring = self.try_lock()?;
+
+        if ring.end.wrapping_sub(position) > LOG_BUFFER_SIZE {
+            return Some(0);
+        }
+
+        let length = end.wrapping_sub(position).min(out.len());
+        for (offset, byte) in out[..length].iter_mut().enumerate() {
+            *byte = ring.bytes[position.wrapping_add(offset) % LOG_BUFFER_SIZE];
+        }
+
+        Some(length)
+    }
+
+    /// Wait until something is written after `cursor`.
+    async fn wait_beyond(&self, cursor: usize) {
+        poll_fn(|cx| {
+            let Some(mut ring) = self.try_lock() else {
+                // Released by whoever holds it without awaiting
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            };
+
+            if ring.end != cursor {
+                return Poll::Ready(());
+            }
+
+            let free_slot = ring.followers.iter_mut().find(|follower| {
+                follower
+                    .as_ref()
+                    .is_none_or(|waker| waker.will_wake(cx.waker()))
+            });
+
+            match free_slot {
+                Some(slot) => *slot = Some(cx.waker().clone()),
+                None => cx.waker().wake_by_ref(),
+            }
+
+            Poll::Pending
+        })
+        .await
+    }
+}
+
+impl Default for LogBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// [log::Log] copying the records enabled by `L` into [LOGS] before passing them on to it.
+#[cfg(feature = "log")]
+pub struct TeeLogger<L>(pub L);
+
+#[cfg(feature = "log")]
+impl<L: log::Log> log::Log for TeeLogger<L> {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.0.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.0.enabled(record.metadata()) {
+            LOGS.push_line(record.level().as_str(), *record.args());
+        }
+
+        self.0.log(record);
+    }
+
+    fn flush(&self) {
+        self.0.flush();
+    }
+}
+
+/// Query of `GET /logs`.
+#[derive(serde::Deserialize)]
+pub(crate) struct LogsQuery {
+    /// Keep the response open and send new lines as they are written.
+    #[serde(default)]
+    follow: bool,
+}
+
+/// Wait until no line is being written into [LOGS], then apply `read` to it.
+async fn read_logs<T>(mut read: impl FnMut(&LogBuffer) -> Option<T>) -> T {
+    loop {
+        match read(&LOGS) {
+            Some(value) => return value,
+            // Taken by a line being written, which is done without awaiting
+            None => embassy_futures::yield_now().await,
+        }
+    }
+}
+
+/// The lines in [LOGS] when the request came, as a [ChunkSource].
+///
+/// Should the ring write over the lines not sent yet, by a burst of logs on a slow connection, the rest of the body is
+/// sent as spaces.
+pub(crate) struct LogLines {
+    position: usize,
+    end: usize,
+}
+
+impl ChunkSource for LogLines {
+    fn content_type(&self) -> &'static str {
+        "text/plain; charset=utf-8"
+    }
+
+    fn content_length(&self) -> usize {
+        self.end.wrapping_sub(self.position)
+    }
+
+    async fn fill(&mut self, buffer: &mut [u8]) -> usize {
+        let length = read_logs(|logs| logs.read(self.position, self.end, buffer)).await;
+        self.position = self.position.wrapping_add(length);
+        length
+    }
+}
+
+/// [EventSource] sending the lines in [LOGS], then the next as they are written, as events named `log` whose data is
+/// the lines read at once.
+pub(crate) struct FollowedLogs;
+
+impl EventSource for FollowedLogs {
+    async fn write_events<W: Write>(self, mut writer: EventWriter<W>) -> Result<(), W::Error> {
+        let mut buffer = [0; CHUNK_SIZE];
+        let (mut position, _) = read_logs(LogBuffer::bounds).await;
+
+        loop {
+            let (oldest, end) = read_logs(LogBuffer::bounds).await;
+            if end.wrapping_sub(position) > LOG_BUFFER_SIZE {
+                // Written over while the client was slow to read
+                position = oldest;
+            }
+
+            if end == position {
+                LOGS.wait_beyond(position).await;
+                continue;
+            }
+
+            let length = read_logs(|logs| logs.read(position, end, &mut buffer)).await;
+            // Whole lines only, which are shorter than the buffer
+            let Some(lines) = buffer[..length].iter().rposition(|&byte| byte == b'\n') else {
+                position = oldest;
+                continue;
+            };
+            position = position.wrapping_add(lines + 1);
+
+            if let Ok(lines) = core::str::from_utf8(&buffer[..lines]) {
+                writer.write_event("log", lines).await?;
+            }
+        }
+    }
+}
+
+/// Response of `GET /logs`, depending on its query.
+pub(crate) enum Logs {
+    Lines(Chunked<LogLines>),
+    Followed(EventStream<FollowedLogs>),
+}
+
+impl IntoResponse for Logs {
+    async fn write_to<R: Read, W: ResponseWriter<Error = R::Error>>(
+        self,
+        connection: Connection<'_, R>,
+        response_writer: W,
+    ) -> Result<ResponseSent, W::Error> {
+        match self {
+            Self::Lines(lines) => lines.write_to(connection, response_writer).await,
+            Self::Followed(stream) => stream.write_to(connection, response_writer).await,
+        }
+    }
+}
+
+/// `GET /logs`: the lines in [LOGS] as plain text, or with `?follow=true` those and the next as they come, as an event
+/// stream.
+pub(crate) async fn get_logs(_: RequireAuth, Query(query): Query<LogsQuery>) -> Logs {
+    if query.follow {
+        return Logs::Followed(EventStream(FollowedLogs));
+    }
+
+    let (position, end) = read_logs(LogBuffer::bounds).await;
+    Logs::Lines(Chunked(LogLines { position, end }))
+}
@@ -0,0 +1,462 @@
+//! mDNS responder for targets running `embassy-net`, enabled by the `embassy` feature.
+//!
+//! Answers `<hostname>.local` with the IPv4 address of the stack and advertises the web server
+//! as the DNS-SD service `<hostname>._http._tcp.local`, so browsers and service browsers find it without knowing its address.
+//!
+//! With the `ipv6` feature, the IPv6 address of the stack is answered too, as an AAAA record. Queries are still only
+//! taken over IPv4, as `embassy-net` can't join IPv6 multicast groups.
+
+use embassy_net::{
+    driver::Driver,
+    udp::{PacketMetadata, UdpSocket},
+    IpEndpoint, Ipv4Address, Stack,
+};
+
+const MDNS_PORT: u16 = 5353;
+const MDNS_GROUP: Ipv4Address = Ipv4Address::new(224, 0, 0, 251);
+
+/// How long clients may cache the records, in seconds, as recommended by RFC 6762 for records with a host name.
+const TTL: u32 = 120;
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+
+const CLASS_IN: u16 = 1;
+const CLASS_ANY: u16 = 255;
+/// Set on records which only this host answers, so that caches replace rather than merge them.
+const CACHE_FLUSH: u16 = 0x8000;
+/// Set in a question asking for a unicast response.
+const UNICAST_RESPONSE: u16 = 0x8000;
+
+/// Largest DNS message handled, which is all a plain DNS packet may carry.
+const PACKET_SIZE: usize = 512;
+
+/// Most labels in a name the responder compares, `<hostname>._http._tcp.local` has four.
+const MAX_LABELS: usize = 8;
+
+const LOCAL: &[u8] = b"local";
+const HTTP_SERVICE: [&[u8]; 3] = [b"_http", b"_tcp", LOCAL];
+const SERVICES: [&[u8]; 4] = [b"_services", b"_dns-sd", b"_udp", LOCAL];
+
+/// The records the responder knows, as a bit set.
+#[derive(Clone, Copy, Default, PartialEq)]
+struct Records(u8);
+
+impl Records {
+    const ADDRESS: Self = Self(1 << 0);
+    const SERVICE_POINTER: Self = Self(1 << 1);
+    const SERVICE: Self = Self(1 << 2);
+    const TEXT: Self = Self(1 << 3);
+    const SERVICE_TYPE: Self = Self(1 << 4);
+    const ADDRESS_V6: Self = Self(1 << 5);
+    /// Both addresses, for the additional records.
+    const ADDRESSES: Self = Self(Self::ADDRESS.0 | Self::ADDRESS_V6.0);
+
+    fn add(&mut self, records: Self) {
+        self.0 |= records.0;
+    }
+
+    fn contains(self, record: Self) -> bool {
+        self.0 & record.0 != 0
+    }
+
+    fn without(self, records: Self) -> Self {
+        Self(self.0 & !records.0)
+    }
+
+    fn count(self) -> u16 {
+        self.0.count_ones() as u16
+    }
+}
+
+/// The names of the records of a host.
+struct Names<'a> {
+    host: [&'a [u8]; 2],
+    instance: [&'a [u8]; 4],
+}
+
+impl<'a> Names<'a> {
+    fn new(hostname: &'a str) -> Self {
+        let [service, protocol, local] = HTTP_SERVICE;
+
+        Self {
+            host: [hostname.as_bytes(), LOCAL],
+            instance: [hostname.as_bytes(), service, protocol, local],
+        }
+    }
+
+    /// The records answering a question for `name` and `qtype`, with the ones worth adding to the answer.
+    fn answers(&self, name: &[&[u8]], qtype: u16) -> (Records, Records) {
+        let is = |expected: &[&[u8]]| {
+            name.len() == expected.len()
+                && name
+                    .iter()
+                    .zip(expected)
+                    .all(|(label, expected)| label.eq_ignore_ascii_case(expected))
+        };
+        let asks = |record_type: u16| qtype == record_type || qtype == TYPE_ANY;
+
+        let mut answers = Records::default();
+        let mut additional = Records::default();
+
+        if is(&self.host) && asks(TYPE_A) {
+            answers.add(Records::ADDRESS);
+        }
+
+        if is(&self.host) && asks(TYPE_AAAA) {
+            answers.add(Records::ADDRESS_V6);
+        }
+
+        if is(&HTTP_SERVICE) && asks(TYPE_PTR) {
+            answers.add(Records::SERVICE_POINTER);
+            // Saves the browser asking for the instance right after
+            additional.add(Records::SERVICE);
+            additional.add(Records::TEXT);
+            additional.add(Records::ADDRESSES);
+        }
+
+        if is(&self.instance) {
+            if asks(TYPE_SRV) {
+                answers.add(Records::SERVICE);
+                additional.add(Records::ADDRESSES);
+            }
+            if asks(TYPE_TXT) {
+                answers.add(Records::TEXT);
+            }
+        }
+
+        if is(&SERVICES) && asks(TYPE_PTR) {
+            answers.add(Records::SERVICE_TYPE);
+        }
+
+        (answers, additional)
+    }
+}
+
+/// Reads the name at `offset` of `packet`, following compression pointers.
+///
+/// Returns the labels and the offset following the name, or `None` if it is malformed or too long.
+pub(crate) fn read_name(
+    packet: &[u8],
+    offset: usize,
+) -> Option<(heapless::Vec<&[u8], MAX_LABELS>, usize)> {
+    let mut labels = heapless::Vec::new();
+    let mut position = offset;
+    let mut end = None;
+
+    // Bounds the number of pointers followed, so that a pointer loop can't stall the responder
+    for _ in 0..PACKET_SIZE {
+        let length = usize::from(*packet.get(position)?);
+
+        match length {
+            0 => return Some((labels, end.unwrap_or(position + 1))),
+            length if length & 0xC0 == 0xC0 => {
+                let pointer = usize::from(u16::from_be_bytes([
+                    packet[position] & 0x3F,
+                    *packet.get(position + 1)?,
+                ]));
+                end.get_or_insert(position + 2);
+                position = pointer;
+            }
+            length if length <= 63 => {
+                let label = packet.get((position + 1)..(position + 1 + length))?;
+                labels.push(label).ok()?;
+                position += 1 + length;
+            }
+            _ => return None,
+        }
+    }
+
+    None
+}
+
+/// Writes a DNS message into a fixed buffer, failing once it is full.
+struct Writer<'a> {
+    buffer: &'a mut [u8],
+    length: usize,
+}
+
+impl Writer<'_> {
+    fn bytes(&mut self, bytes: &[u8]) -> Option<()> {
+        self.buffer
+            .get_mut(self.length..(self.length + bytes.len()))?
+            .copy_from_slice(bytes);
+        self.length += bytes.len();
+        Some(())
+    }
+
+    fn u16(&mut self, value: u16) -> Option<()> {
+        self.bytes(&value.to_be_bytes())
+    }
+
+    fn u32(&mut self, value: u32) -> Option<()> {
+        self.bytes(&value.to_be_bytes())
+    }
+
+    fn name(&mut self, labels: &[&[u8]]) -> Option<()> {
+        for label in labels {
+            self.bytes(&[label.len() as u8])?;
+            self.bytes(label)?;
+        }
+        self.bytes(&[0])
+    }
+
+    fn record_header(
+        &mut self,
+        name: &[&[u8]],
+        record_type: u16,
+        class: u16,
+        data_length: usize,
+    ) -> Option<()> {
+        self.name(name)?;
+        self.u16(record_type)?;
+        self.u16(class)?;
+        self.u32(TTL)?;
+        self.u16(data_length as u16)
+    }
+}
+
+fn name_length(labels: &[&[u8]]) -> usize {
+    labels.iter().map(|label| 1 + label.len()).sum::<usize>() + 1
+}
+
+/// The addresses the host is answered with.
+struct Addresses {
+    v4: Ipv4Address,
+    /// Only with the `ipv6` feature.
+    v6: Option<[u8; 16]>,
+}
+
+impl Addresses {
+    /// `records` without those of the addresses the host doesn't have.
+    fn filter(&self, records: Records) -> Records {
+        match self.v6 {
+            Some(_) => records,
+            None => records.without(Records::ADDRESS_V6),
+        }
+    }
+}
+
+/// Writes `records` in a fixed order.
+fn write_records(
+    writer: &mut Writer,
+    names: &Names,
+    addresses: &Addresses,
+    port: u16,
+    records: Records,
+) -> Option<()> {
+    if records.contains(Records::SERVICE_TYPE) {
+        writer.record_header(&SERVICES, TYPE_PTR, CLASS_IN, name_length(&HTTP_SERVICE))?;
+        writer.name(&HTTP_SERVICE)?;
+    }
+
+    if records.contains(Records::SERVICE_POINTER) {
+        // Other hosts may offer the same service type, so this record is shared and not flushed
+        writer.record_header(
+            &HTTP_SERVICE,
+            TYPE_PTR,
+            CLASS_IN,
+            name_length(&names.instance),
+        )?;
+        writer.name(&names.instance)?;
+    }
+
+    if records.contains(Records::SERVICE) {
+        writer.record_header(
+            &names.instance,
+            TYPE_SRV,
+            CLASS_IN | CACHE_FLUSH,
+            6 + name_length(&names.host),
+        )?;
+        writer.u16(0)?; // Priority
+        writer.u16(0)?; // Weight
+        writer.u16(port)?;
+        writer.name(&names.host)?;
+    }
+
+    if records.contains(Records::TEXT) {
+        // DNS-SD requires a TXT record, a single empty string when there is nothing to say
+        writer.record_header(&names.instance, TYPE_TXT, CLASS_IN | CACHE_FLUSH, 1)?;
+        writer.bytes(&[0])?;
+    }
+
+    if records.contains(Records::ADDRESS) {
+        writer.record_header(&names.host, TYPE_A, CLASS_IN | CACHE_FLUSH, 4)?;
+        writer.bytes(addresses.v4.as_bytes())?;
+    }
+
+    if let (true, Some(address)) = (records.contains(Records::ADDRESS_V6), &addresses.v6) {
+        writer.record_header(&names.host, TYPE_AAAA, CLASS_IN | CACHE_FLUSH, 16)?;
+        writer.bytes(address)?;
+    }
+
+    Some(())
+}
+
+/// How to send the response to a query.
+enum Destination {
+    /// To the multicast group, as most queries expect.
+    Multicast,
+    /// Back to the sender, which asked for it or is a plain DNS resolver not listening on the mDNS port.
+    Unicast,
+}
+
+/// Builds the response to `query` into `response`, returning its length and destination, or `None` if nothing matches.
+fn respond(
+    query: &[u8],
+    from_mdns_port: bool,
+    names: &Names,
+    addresses: &Addresses,
+    port: u16,
+    response: &mut [u8],
+) -> Option<(usize, Destination)> {
+    let header = query.get(..12)?;
+
+    // Ignore responses, including our own, and anything but standard queries
+    if header[2] & 0xF8 != 0 {
+        return None;
+    }
+
+    let question_count = u16::from_be_bytes([header[4], header[5]]);
+    let mut offset = 12;
+    let mut answers = Records::default();
+    let mut additional = Records::default();
+    let mut unicast = !from_mdns_port;
+
+    for _ in 0..question_count {
+        let (name, next) = read_name(query, offset)?;
+        let question = query.get(next..(next + 4))?;
+        let qtype = u16::from_be_bytes([question[0], question[1]]);
+        let qclass = u16::from_be_bytes([question[2], question[3]]);
+        offset = next + 4;
+
+        if !matches!(qclass & !UNICAST_RESPONSE, CLASS_IN | CLASS_ANY) {
+            continue;
+        }
+
+        let (question_answers, question_additional) = names.answers(&name, qtype);
+        if question_answers != Records::default() && qclass & UNICAST_RESPONSE != 0 {
+            unicast = true;
+        }
+
+        answers.add(question_answers);
+        additional.add(question_additional);
+    }
+
+    let answers = addresses.filter(answers);
+    if answers == Records::default() {
+        return None;
+    }
+
+    let additional = addresses.filter(additional.without(answers));
+
+    let mut writer = Writer {
+        buffer: response,
+        length: 0,
+    };
+
+    // A plain DNS resolver expects its ID and questions back, which mDNS clients ignore
+    let (id, questions) = if from_mdns_port {
+        (0, &query[12..12])
+    } else {
+        (
+            u16::from_be_bytes([header[0], header[1]]),
+            &query[12..offset],
+        )
+    };
+
+    writer.u16(id)?;
+    writer.u16(0x8400)?; // Response, authoritative
+    writer.u16(if from_mdns_port { 0 } else { question_count })?;
+    writer.u16(answers.count())?;
+    writer.u16(0)?;
+    writer.u16(additional.count())?;
+    // The questions start at the same offset as in the query, so compression pointers into them stay valid
+    writer.bytes(questions)?;
+    write_records(&mut writer, names, addresses, port, answers)?;
+    write_records(&mut writer, names, addresses, port, additional)?;
+
+    let destination = if unicast {
+        Destination::Unicast
+    } else {
+        Destination::Multicast
+    };
+
+    Some((writer.length, destination))
+}
+
+/// Answer mDNS queries for `hostname`, a single label such as `smolweb`, and its web server on `port`.
+pub async fn run<D: Driver>(stack: &Stack<D>, hostname: &str, port: u16) -> ! {
+    let mut rx_meta = [PacketMetadata::EMPTY; 2];
+    let mut rx_buffer = [0; PACKET_SIZE];
+    let mut tx_meta = [PacketMetadata::EMPTY; 2];
+    let mut tx_buffer = [0; PACKET_SIZE];
+
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    socket
+        .bind(MDNS_PORT)
+        .expect("Only the responder binds the mDNS port");
+
+    if let Err(err) = stack.join_multicast_group(MDNS_GROUP) {
+        log_warn!("Failed to join the mDNS group: {:?}", err);
+    }
+
+    let names = Names::new(hostname);
+    let mut query = [0; PACKET_SIZE];
+    let mut response = [0; PACKET_SIZE];
+
+    log_info!("Answering mDNS queries for {}.local", hostname);
+
+    loop {
+        let (length, sender) = match socket.recv_from(&mut query).await {
+            Ok(received) => received,
+            Err(err) => {
+                log_warn!("Failed to receive mDNS query: {:?}", err);
+                continue;
+            }
+        };
+
+        // Nothing to answer with until DHCP has given us an address
+        let Some(config) = stack.config_v4() else {
+            continue;
+        };
+
+        let addresses = Addresses {
+            v4: config.address.address(),
+            #[cfg(feature = "ipv6")]
+            v6: stack.config_v6().map(|config| config.address.address().0),
+            #[cfg(not(feature = "ipv6"))]
+            v6: None,
+        };
+
+        let Some((response_length, destination)) = respond(
+            &query[..length],
+            sender.port == MDNS_PORT,
+            &names,
+            &addresses,
+            port,
+            &mut response,
+        ) else {
+            continue;
+        };
+
+        let endpoint = match destination {
+            Destination::Multicast => IpEndpoint::new(MDNS_GROUP.into(), MDNS_PORT),
+            Destination::Unicast => sender,
+        };
+
+        if let Err(err) = socket.send_to(&response[..response_length], endpoint).await {
+            log_warn!("Failed to send mDNS response: {:?}", err);
+        }
+    }
+}
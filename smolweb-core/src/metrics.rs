@@ -0,0 +1,333 @@
+use core::{fmt::Write as _, sync::atomic::Ordering};
+
+use picoserve::{
+    extract::FromRef,
+    io::{Read, Write},
+    request::RequestParts,
+    response::{Connection, Content, ResponseWriter},
+    routing::{Layer, Next},
+    ResponseSent,
+};
+// Cortex-M0+ has no atomic read-modify-write instructions, `portable-atomic` falls back to critical sections there
+use portable_atomic::AtomicU32;
+
+use crate::workers::{CountBytes, WorkerCounters};
+
+/// Routes counted on their own by `GET /metrics`, matched on the whole path or on the segments before a `/`.
+///
+/// Requests to any other path, including the ones a demo adds, are counted as `other`.
+const ROUTES: [&str; 16] = [
+    "/",
+    "/index.css",
+    "/index.js",
+    "/static",
+    "/toggle_led",
+    "/led",
+    "/time",
+    "/metrics",
+    "/ws",
+    "/events",
+    "/logs",
+    "/api/leds",
+    "/api/button",
+    "/api/time",
+    "/api/sysinfo",
+    "other",
+];
+
+/// Web workers whose open connections and [statistics](crate::workers) are shown, any others are left out.
+pub const MAX_WORKERS: usize = 8;
+
+fn route_index(path: &str) -> usize {
+    ROUTES[..ROUTES.len() - 1]
+        .iter()
+        .position(|&route| {
+            path == route
+                || (route != "/"
+                    && path
+                        .strip_prefix(route)
+                        .is_some_and(|rest| rest.starts_with('/')))
+        })
+        .unwrap_or(ROUTES.len() - 1)
+}
+
+/// Counters shown by `GET /metrics`, shared by every connection as `&'static Metrics`.
+pub struct Metrics {
+    requests: AtomicU32,
+    toggle_led_requests: AtomicU32,
+    led_requests: AtomicU32,
+    route_requests: [AtomicU32; ROUTES.len()],
+    /// Connections of each web worker, see [Self::open_connection].
+    worker_counters: [WorkerCounters; MAX_WORKERS],
+    /// One more than the highest worker which started or opened a connection.
+    workers: AtomicU32,
+    /// Set by demos running as a process, 0 if unknown. 64 bit atomics aren't available on every target.
+    resident_memory_bytes: AtomicU32,
+}
+
+impl Metrics {
+    pub const fn new() -> Self {
+        #[allow(clippy::declare_interior_mutable_const)] // Only used to initialize the arrays
+        const ZERO: AtomicU32 = AtomicU32::new(0);
+        #[allow(clippy::declare_interior_mutable_const)]
+        const NO_CONNECTIONS: WorkerCounters = WorkerCounters::new();
+
+        Self {
+            requests: AtomicU32::new(0),
+            toggle_led_requests: AtomicU32::new(0),
+            led_requests: AtomicU32::new(0),
+            route_requests: [ZERO; ROUTES.len()],
+            worker_counters: [NO_CONNECTIONS; MAX_WORKERS],
+            workers: AtomicU32::new(0),
+            resident_memory_bytes: AtomicU32::new(0),
+        }
+    }
+
+    pub(crate) fn count_toggle_led(&self) {
+        self.toggle_led_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn count_led(&self) {
+        self.led_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Show `worker` in `GET /api/workers` and `GET /metrics` before its first connection.
+    pub fn start_worker(&self, worker: usize) {
+        if worker < MAX_WORKERS {
+            self.workers.fetch_max(worker as u32 + 1, Ordering::Relaxed);
+        }
+    }
+
+    /// Count a connection accepted by `worker`, open until the returned guard is dropped.
+    pub fn open_connection(&self, worker: usize) -> OpenConnection<'_> {
+        self.start_worker(worker);
+
+        let counters = self.worker_counters.get(worker);
+        if let Some(counters) = counters {
+            counters.open.fetch_add(1, Ordering::Relaxed);
+            counters.accepted.fetch_add(1, Ordering::Relaxed);
+        }
+
+        OpenConnection { counters }
+    }
+
+    /// The counters of the workers started so far.
+    pub(crate) fn worker_counters(&self) -> &[WorkerCounters] {
+        let workers = self.workers.load(Ordering::Relaxed) as usize;
+        &self.worker_counters[..workers.min(MAX_WORKERS)]
+    }
+
+    /// Set the memory used by the process, shown as `process_resident_memory_bytes` up to 4 GiB.
+    pub fn set_resident_memory(&self, bytes: u64) {
+        self.resident_memory_bytes
+            .store(bytes.try_into().unwrap_or(u32::MAX), Ordering::Relaxed);
+    }
+
+    /// The memory set by [Self::set_resident_memory], if any.
+    pub(crate) fn resident_memory(&self) -> Option<u32> {
+        Some(self.resident_memory_bytes.load(Ordering::Relaxed)).filter(|&bytes| bytes != 0)
+    }
+
+    /// Read all the counters at once, so that the body has the length it was sent with.
+    /// `unix_time` is shown as `time_seconds` once the clock is synchronized.
+    pub(crate) fn snapshot(&self, uptime: u64, unix_time: Option<u64>) -> MetricsSnapshot {
+        let load = |counter: &AtomicU32| counter.load(Ordering::Relaxed);
+
+        MetricsSnapshot {
+            uptime,
+            unix_time,
+            requests: load(&self.requests),
+            toggle_led_requests: load(&self.toggle_led_requests),
+            led_requests: load(&self.led_requests),
+            route_requests: self.route_requests.each_ref().map(load),
+            connections: self
+                .worker_counters
+                .each_ref()
+                .map(|counters| load(&counters.open)),
+            workers: (load(&self.workers) as usize).min(MAX_WORKERS),
+            resident_memory_bytes: load(&self.resident_memory_bytes),
+        }
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A connection counted by [Metrics::open_connection].
+pub struct OpenConnection<'a> {
+    /// `None` for workers past [MAX_WORKERS].
+    counters: Option<&'a WorkerCounters>,
+}
+
+impl<'a> OpenConnection<'a> {
+    /// Serve `socket` through this, so that the bytes read and written are added to the worker.
+    pub fn count_bytes<S>(&self, socket: S) -> CountBytes<'a, S> {
+        CountBytes::new(socket, self.counters)
+    }
+
+    /// Add the requests picoserve handled on the connection once it is closed.
+    pub fn served(&self, requests: u64) {
+        if let Some(counters) = self.counters {
+            counters
+                .requests
+                .fetch_add(requests.try_into().unwrap_or(u32::MAX), Ordering::Relaxed);
+        }
+    }
+}
+
+impl Drop for OpenConnection<'_> {
+    fn drop(&mut self) {
+        if let Some(counters) = self.counters {
+            counters.open.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Body of `GET /metrics` in the Prometheus text format, rendered a line at a time.
+pub(crate) struct MetricsSnapshot {
+    uptime: u64,
+    unix_time: Option<u64>,
+    requests: u32,
+    toggle_led_requests: u32,
+    led_requests: u32,
+    route_requests: [u32; ROUTES.len()],
+    connections: [u32; MAX_WORKERS],
+    workers: usize,
+    resident_memory_bytes: u32,
+}
+
+/// One line of [MetricsSnapshot], the longest being the `# TYPE` and `# HELP` comments.
+type Line = heapless::String<96>;
+
+fn line(args: core::fmt::Arguments) -> Line {
+    let mut line = Line::new();
+    let _ = line.write_fmt(args);
+    line
+}
+
+impl MetricsSnapshot {
+    fn lines(&self) -> impl Iterator<Item = Line> + '_ {
+        let totals = [
+            line(format_args!("# TYPE uptime_seconds gauge\n")),
+            line(format_args!("uptime_seconds {}\n", self.uptime)),
+            line(format_args!("# TYPE http_requests_total counter\n")),
+            line(format_args!("http_requests_total {}\n", self.requests)),
+            line(format_args!("# TYPE toggle_led_requests_total counter\n")),
+            line(format_args!(
+                "toggle_led_requests_total {}\n",
+                self.toggle_led_requests
+            )),
+            line(format_args!("# TYPE led_requests_total counter\n")),
+            line(format_args!("led_requests_total {}\n", self.led_requests)),
+            line(format_args!("# TYPE http_route_requests_total counter\n")),
+        ];
+
+        let time = self
+            .unix_time
+            .map(|unix_time| {
+                [
+                    line(format_args!("# TYPE time_seconds gauge\n")),
+                    line(format_args!("time_seconds {unix_time}\n")),
+                ]
+            })
+            .into_iter()
+            .flatten();
+
+        let routes = ROUTES
+            .iter()
+            .zip(self.route_requests)
+            .map(|(route, requests)| {
+                line(format_args!(
+                    "http_route_requests_total{{route=\"{route}\"}} {requests}\n"
+                ))
+            });
+
+        let connections =
+            core::iter::once(line(format_args!("# TYPE http_active_connections gauge\n"))).chain(
+                self.connections[..self.workers]
+                    .iter()
+                    .enumerate()
+                    .map(|(worker, connections)| {
+                        line(format_args!(
+                            "http_active_connections{{worker=\"{worker}\"}} {connections}\n"
+                        ))
+                    }),
+            );
+
+        let memory = (self.resident_memory_bytes > 0)
+            .then(|| {
+                [
+                    line(format_args!("# TYPE process_resident_memory_bytes gauge\n")),
+                    line(format_args!(
+                        "process_resident_memory_bytes {}\n",
+                        self.resident_memory_bytes
+                    )),
+                ]
+            })
+            .into_iter()
+            .flatten();
+
+        totals
+            .into_iter()
+            .chain(time)
+            .chain(routes)
+            .chain(connections)
+            .chain(memory)
+    }
+}
+
+impl Content for MetricsSnapshot {
+    fn content_type(&self) -> &'static str {
+        "text/plain; version=0.0.4; charset=utf-8"
+    }
+
+    fn content_length(&self) -> usize {
+        self.lines().map(|line| line.len()).sum()
+    }
+
+    async fn write_content<R: Read, W: Write>(
+        self,
+        _connection: Connection<'_, R>,
+        mut writer: W,
+    ) -> Result<(), W::Error> {
+        for line in self.lines() {
+            writer.write_all(line.as_bytes()).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// [Layer] counting every request in [Metrics], including the ones no route matches.
+pub struct CountRequests;
+
+impl<State, PathParameters> Layer<State, PathParameters> for CountRequests
+where
+    &'static Metrics: FromRef<State>,
+{
+    type NextState = State;
+    type NextPathParameters = PathParameters;
+
+    async fn call_layer<
+        R: Read,
+        NextLayer: Next<R, Self::NextState, Self::NextPathParameters>,
+        W: ResponseWriter<Error = R::Error>,
+    >(
+        &self,
+        next: NextLayer,
+        state: &State,
+        path_parameters: PathParameters,
+        request_parts: RequestParts<'_>,
+        response_writer: W,
+    ) -> Result<ResponseSent, W::Error> {
+        let metrics = <&'static Metrics>::from_ref(state);
+        metrics.requests.fetch_add(1, Ordering::Relaxed);
+        metrics.route_requests[route_index(request_parts.path().encoded())]
+            .fetch_add(1, Ordering::Relaxed);
+
+        next.run(state, path_parameters, response_writer).await
+    }
+}
@@ -0,0 +1,124 @@
+//! IPv4 configuration with a static fallback for targets running `embassy-net`, enabled by the `embassy` feature.
+//!
+//! With the `ipv6` feature, the stack also gets an IPv6 link-local address derived from its MAC address, next to
+//! the IPv4 one. `embassy-net` doesn't do SLAAC, so there is no global IPv6 address.
+
+use embassy_net::{driver::Driver, ConfigV4, DhcpConfig, Stack, StaticConfigV4};
+use embassy_time::{with_timeout, Duration};
+
+/// How a stack got its IPv4 address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Ipv4Mode {
+    Dhcp,
+    Static,
+}
+
+/// Where the IPv4 address of a stack comes from: DHCP, a static address, or DHCP falling back to a static address.
+pub struct NetworkConfig {
+    /// How long to wait for a DHCP lease, or `None` to use [Self::static_config] straight away.
+    pub dhcp_timeout: Option<Duration>,
+    /// Address used when DHCP is skipped or times out. Without one, DHCP is waited for indefinitely.
+    pub static_config: Option<StaticConfigV4>,
+    /// Sent to the DHCP server as option 12, so that the board is listed by name in the leases of the router.
+    pub hostname: Option<heapless::String<32>>,
+    /// IPv6 address of the stack, usually [ipv6_link_local].
+    #[cfg(feature = "ipv6")]
+    pub ipv6: Option<embassy_net::StaticConfigV6>,
+}
+
+/// The `fe80::/64` address of the interface with hardware address `mac`, its modified EUI-64 identifier as in RFC 4291.
+#[cfg(feature = "ipv6")]
+pub fn ipv6_link_local(mac: [u8; 6]) -> embassy_net::StaticConfigV6 {
+    use embassy_net::{Ipv6Address, Ipv6Cidr};
+
+    let mut address = [0; 16];
+    address[..2].copy_from_slice(&[0xfe, 0x80]);
+    address[8..11].copy_from_slice(&mac[..3]);
+    // Flips the universal/local bit
+    address[8] ^= 0x02;
+    address[11..13].copy_from_slice(&[0xff, 0xfe]);
+    address[13..].copy_from_slice(&mac[3..]);
+
+    embassy_net::StaticConfigV6 {
+        address: Ipv6Cidr::new(Ipv6Address::from_bytes(&address), 64),
+        gateway: None,
+        dns_servers: heapless::Vec::new(),
+    }
+}
+
+impl NetworkConfig {
+    fn dhcp_config(&self) -> DhcpConfig {
+        let mut config = DhcpConfig::default();
+        config.hostname = self.hostname.clone();
+        config
+    }
+
+    /// The configuration to create the stack with.
+    pub fn initial(&self) -> embassy_net::Config {
+        #[allow(unused_mut)]
+        let mut config = match (&self.dhcp_timeout, &self.static_config) {
+            (None, Some(static_config)) => embassy_net::Config::ipv4_static(static_config.clone()),
+            _ => embassy_net::Config::dhcpv4(self.dhcp_config()),
+        };
+
+        #[cfg(feature = "ipv6")]
+        if let Some(ipv6) = &self.ipv6 {
+            config.ipv6 = embassy_net::ConfigV6::Static(ipv6.clone());
+        }
+
+        config
+    }
+
+    /// Start over from DHCP after the link came back, e.g. once the cable is plugged in again, and wait for an address
+    /// as [Self::wait_config_up] does. A stack without DHCP keeps its static address.
+    pub async fn restart_dhcp<D: Driver>(&self, stack: &Stack<D>) -> Ipv4Mode {
+        if self.dhcp_timeout.is_none() && self.static_config.is_some() {
+            return Ipv4Mode::Static;
+        }
+
+        // A new DHCP client, which discovers straight away instead of waiting for its lease to run out
+        stack.set_config_v4(ConfigV4::Dhcp(self.dhcp_config()));
+        self.wait_config_up(stack).await
+    }
+
+    /// Wait until the stack created with [Self::initial] has an address, switching to the static one if DHCP times out.
+    ///
+    /// Once the stack has fallen back, it keeps the static address until the next boot.
+    pub async fn wait_config_up<D: Driver>(&self, stack: &Stack<D>) -> Ipv4Mode {
+        let mode = match (self.dhcp_timeout, &self.static_config) {
+            (None, Some(_)) => {
+                stack.wait_config_up().await;
+                Ipv4Mode::Static
+            }
+            (Some(timeout), Some(static_config)) => {
+                if with_timeout(timeout, stack.wait_config_up()).await.is_ok() {
+                    Ipv4Mode::Dhcp
+                } else {
+                    log_warn!(
+                        "No DHCP lease after {} s, falling back to the static address",
+                        timeout.as_secs()
+                    );
+                    stack.set_config_v4(ConfigV4::Static(static_config.clone()));
+                    stack.wait_config_up().await;
+                    Ipv4Mode::Static
+                }
+            }
+            (_, None) => {
+                stack.wait_config_up().await;
+                Ipv4Mode::Dhcp
+            }
+        };
+
+        if let Some(config) = stack.config_v4() {
+            log_info!("IP address {} from {:?}", config.address, mode);
+        }
+
+        #[cfg(feature = "ipv6")]
+        if let Some(config) = stack.config_v6() {
+            log_info!("IPv6 address {}", config.address);
+        }
+
+        mode
+    }
+}
@@ -0,0 +1,39 @@
+use picoserve::{
+    io::Read,
+    request::{Path, Request},
+    response::{File, IntoResponse, ResponseWriter, StatusCode},
+    routing::PathRouterService,
+    ResponseSent,
+};
+
+use crate::error::ApiError;
+
+/// Fallback service which answers every request with the HTML 404 page, or an [ApiError] under `/api`.
+///
+/// Used as the base of the router, so it only sees requests no route matched.
+pub struct NotFound;
+
+impl<State, CurrentPathParameters> PathRouterService<State, CurrentPathParameters> for NotFound {
+    async fn call_request_handler_service<R: Read, W: ResponseWriter<Error = R::Error>>(
+        &self,
+        _state: &State,
+        _current_path_parameters: CurrentPathParameters,
+        _path: Path<'_>,
+        request: Request<'_, R>,
+        response_writer: W,
+    ) -> Result<ResponseSent, W::Error> {
+        let path = request.parts.path().encoded();
+        let is_api = path == "/api" || path.starts_with("/api/");
+        let connection = request.body_connection.finalize().await?;
+
+        if is_api {
+            ApiError::new(StatusCode::NOT_FOUND, "No such endpoint")
+                .write_to(connection, response_writer)
+                .await
+        } else {
+            (StatusCode::NOT_FOUND, File::html(include_str!("404.html")))
+                .write_to(connection, response_writer)
+                .await
+        }
+    }
+}
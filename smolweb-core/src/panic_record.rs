@@ -0,0 +1,90 @@
+//! The last panic of a board, kept in RAM across the reset that follows it, so that `GET /api/panic` can show it.
+//!
+//! The panic handler of the board writes a [PanicRecord] into a variable of the `.uninit` section, which neither the
+//! reset nor the startup code clear, and the next boot reads it back with [PanicRecord::take]. RAM left random by a
+//! power-on is told apart from a record by its magic number.
+
+use core::fmt::Write;
+
+/// Most bytes of a panic message kept, which includes its location.
+pub const MESSAGE_LEN: usize = 200;
+
+/// Marks a record written by [PanicRecord::write], "PNIC".
+const MAGIC: u32 = 0x504E_4943;
+
+/// A panic as laid out in RAM.
+#[repr(C)]
+pub struct PanicRecord {
+    magic: u32,
+    /// Address of the faulting instruction, 0 if unknown.
+    program_counter: u32,
+    length: u32,
+    message: [u8; MESSAGE_LEN],
+}
+
+/// Writes into [PanicRecord::message], dropping what doesn't fit.
+struct MessageWriter<'a> {
+    message: &'a mut [u8; MESSAGE_LEN],
+    length: usize,
+}
+
+impl Write for MessageWriter<'_> {
+    fn write_str(&mut self, text: &str) -> core::fmt::Result {
+        let length = text.len().min(MESSAGE_LEN - self.length);
+        self.message[self.length..][..length].copy_from_slice(&text.as_bytes()[..length]);
+        self.length += length;
+        Ok(())
+    }
+}
+
+impl PanicRecord {
+    /// Record `message`, e.g. the `PanicInfo` of a panic with its location, and the `program_counter` it happened at
+    /// if known.
+    pub fn write(&mut self, message: core::fmt::Arguments<'_>, program_counter: Option<u32>) {
+        let mut writer = MessageWriter {
+            message: &mut self.message,
+            length: 0,
+        };
+        let _ = writer.write_fmt(message);
+
+        self.length = writer.length as u32;
+        self.program_counter = program_counter.unwrap_or(0);
+        self.magic = MAGIC;
+    }
+
+    /// The panic recorded before the last reset, if any, which is cleared so that the next reset doesn't report it
+    /// again.
+    pub fn take(&mut self) -> Option<PanicReport> {
+        if core::mem::replace(&mut self.magic, 0) != MAGIC {
+            return None;
+        }
+
+        let message = self.message.get(..self.length as usize)?;
+        // A message cut in the middle of a character keeps what comes before it
+        let message = match core::str::from_utf8(message) {
+            Ok(message) => message,
+            Err(err) => core::str::from_utf8(&message[..err.valid_up_to()]).ok()?,
+        };
+
+        let mut program_counter = None;
+        if self.program_counter != 0 {
+            let mut hex = heapless::String::new();
+            write!(hex, "{:#010x}", self.program_counter).ok()?;
+            program_counter = Some(hex);
+        }
+
+        Some(PanicReport {
+            message: heapless::String::try_from(message).ok()?,
+            program_counter,
+        })
+    }
+}
+
+/// Body of `GET /api/panic`, e.g. `{"message":"panicked at src/main.rs:512:9:\nindex out of bounds","program_counter":null}`.
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct PanicReport {
+    pub message: heapless::String<MESSAGE_LEN>,
+    /// Address of the instruction which faulted, e.g. `"0x08012a4c"`, known for hard faults only, as a panic message
+    /// already holds its location.
+    pub program_counter: Option<heapless::String<10>>,
+}
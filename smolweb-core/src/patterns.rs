@@ -0,0 +1,236 @@
+//! Blink patterns played on the LEDs, selected with `POST /api/leds/<n>/pattern`.
+//!
+//! The handler passes the [Pattern] to [LedControl::set_pattern], which boards with a pattern engine forward to the
+//! task playing them with a [PatternPlayer].
+
+use core::time::Duration;
+
+use picoserve::{
+    extract::State,
+    response::{Json as JsonResponse, StatusCode},
+};
+
+use crate::{
+    auth::RequireAuth, error::ApiError, json::Json, metrics::Metrics, rate_limit::MAX_LEDS,
+    settings::SettingsStore, LedControl,
+};
+
+/// Shortest and longest cycle of a pattern, so that LEDs neither flicker nor look stuck.
+const MIN_PERIOD: Duration = Duration::from_millis(100);
+const MAX_PERIOD: Duration = Duration::from_secs(60);
+
+/// Returns true if a pattern can cycle every `period_ms` milliseconds.
+pub(crate) fn is_valid_period_ms(period_ms: u32) -> bool {
+    (MIN_PERIOD..=MAX_PERIOD).contains(&Duration::from_millis(period_ms.into()))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[serde(rename_all = "snake_case")]
+pub enum PatternKind {
+    /// No pattern, the LED stays on and is controlled by hand again.
+    Steady,
+    /// On for half of the period, off for the other half.
+    Blink,
+    /// Two short beats at the start of the period.
+    Heartbeat,
+    /// `... --- ...` in Morse code, each period.
+    Sos,
+}
+
+impl PatternKind {
+    /// The steps of one cycle, each lit or not for a number of units.
+    fn steps(self) -> &'static [(bool, u32)] {
+        match self {
+            Self::Steady => &[(true, 1)],
+            Self::Blink => &[(true, 1), (false, 1)],
+            Self::Heartbeat => &[(true, 1), (false, 1), (true, 1), (false, 7)],
+            // A dot is one unit and a dash three, with a unit between them, three between letters and seven between
+            // words
+            Self::Sos => &[
+                (true, 1),
+                (false, 1),
+                (true, 1),
+                (false, 1),
+                (true, 1),
+                (false, 3),
+                (true, 3),
+                (false, 1),
+                (true, 3),
+                (false, 1),
+                (true, 3),
+                (false, 3),
+                (true, 1),
+                (false, 1),
+                (true, 1),
+                (false, 1),
+                (true, 1),
+                (false, 7),
+            ],
+        }
+    }
+
+    /// The period used when none is given, in milliseconds.
+    fn default_period_ms(self) -> u32 {
+        match self {
+            Self::Steady | Self::Blink => 1000,
+            Self::Heartbeat => 1200,
+            // 200 ms per unit
+            Self::Sos => 6800,
+        }
+    }
+}
+
+/// A pattern and the length of one of its cycles.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Pattern {
+    pub kind: PatternKind,
+    pub period: Duration,
+}
+
+impl Pattern {
+    /// Whether the LED is lit `elapsed` after the pattern started, and for how much longer it stays so.
+    fn state_at(&self, elapsed: Duration) -> (bool, Duration) {
+        let steps = self.kind.steps();
+        let units: u32 = steps.iter().map(|&(_, units)| units).sum();
+        let period_us = (self.period.as_micros() as u64).max(1);
+        let position_us = elapsed.as_micros() as u64 % period_us;
+
+        let mut step_end_us = 0;
+        for &(on, step_units) in steps {
+            step_end_us += period_us * u64::from(step_units) / u64::from(units);
+            if position_us < step_end_us {
+                return (on, Duration::from_micros(step_end_us - position_us));
+            }
+        }
+
+        // Only reached through rounding, at the very end of the cycle
+        (steps[0].0, Duration::from_micros(period_us - position_us))
+    }
+}
+
+/// Plays the [Pattern] of each LED, driven by the pattern engine of a board.
+///
+/// The engine calls [Self::update] whenever the time it returned has passed, and [Self::set] for each pattern
+/// selected in between.
+pub struct PatternPlayer {
+    /// The pattern of each LED and the uptime it started at, `None` when steady.
+    playing: [Option<(Pattern, Duration)>; MAX_LEDS],
+}
+
+impl PatternPlayer {
+    pub const fn new() -> Self {
+        Self {
+            playing: [None; MAX_LEDS],
+        }
+    }
+
+    /// Start playing `pattern` on LED `led` at `uptime`, or stop with the LED on if it is [PatternKind::Steady].
+    pub fn set(&mut self, control: &impl LedControl, led: u8, pattern: Pattern, uptime: Duration) {
+        let Some(playing) = self.playing.get_mut(usize::from(led)) else {
+            return;
+        };
+
+        if pattern.kind == PatternKind::Steady {
+            *playing = None;
+            control.set(led, true);
+        } else {
+            *playing = Some((pattern, uptime));
+        }
+    }
+
+    /// Light the LEDs as their patterns want them at `uptime`, returning how long until the next change, or `None`
+    /// if no pattern is playing.
+    pub fn update(&self, control: &impl LedControl, uptime: Duration) -> Option<Duration> {
+        let mut next_change: Option<Duration> = None;
+
+        for (led, playing) in (0..).zip(&self.playing) {
+            let Some((pattern, started)) = playing else {
+                continue;
+            };
+
+            let (on, remaining) = pattern.state_at(uptime.saturating_sub(*started));
+            if control.state(led) != on {
+                control.set(led, on);
+            }
+
+            next_change = Some(next_change.map_or(remaining, |next| next.min(remaining)));
+        }
+
+        next_change
+    }
+}
+
+impl Default for PatternPlayer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Body of `POST /api/leds/<n>/pattern`, e.g. `{"pattern":"blink","period_ms":500}`.
+///
+/// Without `period_ms`, each pattern has its own: the `blink_period_ms` of the [Settings](crate::settings::Settings)
+/// to blink, 1.2 s for a heartbeat and 6.8 s for SOS.
+#[derive(serde::Deserialize)]
+pub(crate) struct PatternRequest {
+    pattern: PatternKind,
+    #[serde(default)]
+    period_ms: Option<u32>,
+}
+
+/// Answer to `POST /api/leds/<n>/pattern`, e.g. `{"led":2,"pattern":"blink","period_ms":500}`.
+#[derive(serde::Serialize)]
+pub struct PatternStatus {
+    led: u8,
+    pattern: PatternKind,
+    period_ms: u32,
+}
+
+/// `POST /api/leds/<n>/pattern`: play a pattern on LED `n`, or stop it with `steady`.
+pub(crate) async fn set_pattern<C: LedControl, P: SettingsStore>(
+    led: u8,
+    _: RequireAuth,
+    State(control): State<C>,
+    State(store): State<P>,
+    State(metrics): State<&'static Metrics>,
+    Json(request): Json<PatternRequest>,
+) -> Result<JsonResponse<PatternStatus>, ApiError> {
+    metrics.count_led();
+
+    if !control.has_led(led) {
+        return Err(ApiError::new(StatusCode::NOT_FOUND, "Unknown LED"));
+    }
+
+    let period_ms = request.period_ms.unwrap_or_else(|| match request.pattern {
+        PatternKind::Blink => store.settings().blink_period_ms,
+        kind => kind.default_period_ms(),
+    });
+    let period = Duration::from_millis(period_ms.into());
+
+    if !is_valid_period_ms(period_ms) {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "period_ms must be between 100 and 60000",
+        ));
+    }
+
+    let pattern = Pattern {
+        kind: request.pattern,
+        period,
+    };
+
+    if !control.set_pattern(led, pattern) {
+        return Err(ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Patterns are not available on this board",
+        ));
+    }
+
+    log_debug!("LED{} pattern set to {:?}", led, pattern);
+    Ok(JsonResponse(PatternStatus {
+        led,
+        pattern: request.pattern,
+        period_ms,
+    }))
+}
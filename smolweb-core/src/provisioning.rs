@@ -0,0 +1,414 @@
+//! WiFi provisioning through a captive portal for boards without stored credentials, enabled by the `embassy` feature.
+//!
+//! The board opens an access point on [AP_ADDRESS] and runs [run_dhcp] so that clients get an address,
+//! [run_dns] so that every name they look up leads to the board, and the router of [make_setup_app] on port 80.
+//! Phones and laptops probe a known URL when joining a network, get redirected to `/setup` and show the form on their own.
+
+use embassy_net::{
+    driver::Driver,
+    udp::{PacketMetadata, UdpSocket},
+    IpEndpoint, Ipv4Address, Ipv4Cidr, Stack, StaticConfigV4,
+};
+use picoserve::{
+    extract::{Form, FromRef, State},
+    io::Read,
+    request::{Path, Request},
+    response::{File, IntoResponse, Redirect, ResponseWriter, StatusCode},
+    routing::{get_service, PathRouter, PathRouterService},
+    ResponseSent,
+};
+
+/// Address of the board on its access point, which is also the gateway and DNS server of the clients.
+pub const AP_ADDRESS: Ipv4Address = Ipv4Address::new(192, 168, 4, 1);
+
+/// The form, by the address of the board so that clients which asked for another host end up on it.
+const SETUP_URL: &str = "http://192.168.4.1/setup";
+
+/// Port of the setup form. Captive portal probes are plain HTTP on port 80.
+pub const SETUP_PORT: u16 = 80;
+
+/// The configuration of the stack while the access point is open.
+pub fn ap_config() -> embassy_net::Config {
+    embassy_net::Config::ipv4_static(StaticConfigV4 {
+        address: Ipv4Cidr::new(AP_ADDRESS, 24),
+        gateway: None,
+        dns_servers: heapless::Vec::new(),
+    })
+}
+
+/// Network to join, as entered in the setup form.
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct WifiCredentials {
+    pub ssid: heapless::String<32>,
+    /// Empty for an open network.
+    pub password: heapless::String<64>,
+}
+
+/// First bytes of a stored record, erased flash reads as `0xFF`.
+const MAGIC: [u8; 4] = *b"SWC1";
+
+impl WifiCredentials {
+    /// Size of the record written by [Self::to_record].
+    pub const RECORD_SIZE: usize = MAGIC.len() + 1 + 32 + 1 + 64;
+
+    /// Returns true if the SSID is not empty and the password is empty or a valid WPA2 passphrase.
+    pub fn is_valid(&self) -> bool {
+        !self.ssid.is_empty()
+            && (self.password.is_empty() || (8..=63).contains(&self.password.len()))
+    }
+
+    /// The credentials as a fixed size record, to be stored in flash.
+    pub fn to_record(&self) -> [u8; Self::RECORD_SIZE] {
+        let mut record = [0; Self::RECORD_SIZE];
+        let (magic, rest) = record.split_at_mut(MAGIC.len());
+        let (ssid, password) = rest.split_at_mut(1 + 32);
+
+        magic.copy_from_slice(&MAGIC);
+        ssid[0] = self.ssid.len() as u8;
+        ssid[1..][..self.ssid.len()].copy_from_slice(self.ssid.as_bytes());
+        password[0] = self.password.len() as u8;
+        password[1..][..self.password.len()].copy_from_slice(self.password.as_bytes());
+
+        record
+    }
+
+    /// Reads a record written by [Self::to_record], returning `None` for erased flash or any other data.
+    pub fn from_record(record: &[u8; Self::RECORD_SIZE]) -> Option<Self> {
+        let rest = record.strip_prefix(&MAGIC)?;
+        let (ssid, password) = rest.split_at(1 + 32);
+
+        let field = |field: &[u8]| {
+            let value = field.get(1..=usize::from(field[0]))?;
+            heapless::String::try_from(core::str::from_utf8(value).ok()?).ok()
+        };
+
+        Some(Self {
+            ssid: field(ssid)?,
+            password: field(password)?,
+        })
+    }
+}
+
+/// Where the board keeps the credentials entered in the setup form.
+pub trait Provisioner {
+    /// Store `credentials`, returning false if they could not be written.
+    fn save(&self, credentials: &WifiCredentials) -> bool;
+
+    /// Reboot into station mode, once the response to the form has had time to be sent.
+    fn restart_soon(&self);
+}
+
+async fn save_credentials<P: Provisioner>(
+    State(provisioner): State<P>,
+    Form(credentials): Form<WifiCredentials>,
+) -> Result<File, (StatusCode, &'static str)> {
+    if !credentials.is_valid() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "The password must be empty or 8 to 63 characters long\n",
+        ));
+    }
+
+    if !provisioner.save(&credentials) {
+        return Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to save the credentials\n",
+        ));
+    }
+
+    log_info!(
+        "Saved credentials for {}, restarting",
+        credentials.ssid.as_str()
+    );
+    provisioner.restart_soon();
+
+    Ok(File::html(include_str!("setup_saved.html")))
+}
+
+/// Fallback service redirecting every other request to the form, which is what makes clients show it.
+struct RedirectToSetup;
+
+impl<State, CurrentPathParameters> PathRouterService<State, CurrentPathParameters>
+    for RedirectToSetup
+{
+    async fn call_request_handler_service<R: Read, W: ResponseWriter<Error = R::Error>>(
+        &self,
+        _state: &State,
+        _current_path_parameters: CurrentPathParameters,
+        _path: Path<'_>,
+        request: Request<'_, R>,
+        response_writer: W,
+    ) -> Result<ResponseSent, W::Error> {
+        Redirect::to(SETUP_URL)
+            .write_to(request.body_connection.finalize().await?, response_writer)
+            .await
+    }
+}
+
+/// The router served on [SETUP_PORT] while the access point is open: the form at `/setup`, and redirects to it.
+pub fn make_setup_app<S, P>() -> picoserve::Router<impl PathRouter<S>, S>
+where
+    P: Provisioner + FromRef<S>,
+{
+    picoserve::Router::from_service(RedirectToSetup).route(
+        "/setup",
+        get_service(File::html(include_str!("setup.html"))).post(save_credentials::<P>),
+    )
+}
+
+/// Most bytes of a DNS query or DHCP message handled.
+const PACKET_SIZE: usize = 576;
+
+const DNS_PORT: u16 = 53;
+
+/// How long clients may cache the answers, kept short as the board is only briefly their DNS server.
+const DNS_TTL: u32 = 60;
+
+/// Builds the response to `query`, answering an A question for any name with `address`.
+///
+/// Other questions get an empty answer, so that clients fall back to IPv4.
+fn dns_response(query: &[u8], address: Ipv4Address, response: &mut [u8]) -> Option<usize> {
+    let header = query.get(..12)?;
+
+    // Only standard queries with a single question, which is all resolvers send
+    if header[2] & 0xF8 != 0 || header[4..6] != [0, 1] {
+        return None;
+    }
+
+    let (_, end) = crate::mdns::read_name(query, 12)?;
+    let question = query.get(end..(end + 4))?;
+    let is_address = question == [0, 1, 0, 1]; // Type A, class IN
+    let question_end = end + 4;
+
+    let answer_length = if is_address { 16 } else { 0 };
+    let length = question_end + answer_length;
+    let response = response.get_mut(..length)?;
+
+    response[..question_end].copy_from_slice(&query[..question_end]);
+    // Response, authoritative, keeping the recursion desired bit, and recursion available
+    response[2] = 0x84 | (query[2] & 0x01);
+    response[3] = 0x80;
+    response[6..8].copy_from_slice(&u16::from(is_address).to_be_bytes());
+    response[8..12].fill(0);
+
+    if is_address {
+        let answer = &mut response[question_end..];
+        answer[..2].copy_from_slice(&[0xC0, 12]); // Pointer to the name of the question
+        answer[2..6].copy_from_slice(&[0, 1, 0, 1]);
+        answer[6..10].copy_from_slice(&DNS_TTL.to_be_bytes());
+        answer[10..12].copy_from_slice(&4u16.to_be_bytes());
+        answer[12..16].copy_from_slice(address.as_bytes());
+    }
+
+    Some(length)
+}
+
+/// Answer every DNS query on the access point with [AP_ADDRESS].
+pub async fn run_dns<D: Driver>(stack: &Stack<D>) -> ! {
+    let mut rx_meta = [PacketMetadata::EMPTY; 2];
+    let mut rx_buffer = [0; PACKET_SIZE];
+    let mut tx_meta = [PacketMetadata::EMPTY; 2];
+    let mut tx_buffer = [0; PACKET_SIZE];
+
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    socket
+        .bind(DNS_PORT)
+        .expect("Only the captive DNS server binds the DNS port");
+
+    let mut query = [0; PACKET_SIZE];
+    let mut response = [0; PACKET_SIZE];
+
+    loop {
+        let (length, sender) = match socket.recv_from(&mut query).await {
+            Ok(received) => received,
+            Err(err) => {
+                log_warn!("Failed to receive DNS query: {:?}", err);
+                continue;
+            }
+        };
+
+        let Some(response_length) = dns_response(&query[..length], AP_ADDRESS, &mut response)
+        else {
+            continue;
+        };
+
+        if let Err(err) = socket.send_to(&response[..response_length], sender).await {
+            log_warn!("Failed to send DNS response: {:?}", err);
+        }
+    }
+}
+
+const DHCP_SERVER_PORT: u16 = 67;
+const DHCP_CLIENT_PORT: u16 = 68;
+
+/// Clients given an address at the same time, from `192.168.4.2` on.
+const MAX_LEASES: usize = 8;
+const FIRST_LEASE: u8 = 2;
+
+/// Lease time in seconds. Clients only stay until the credentials are saved.
+const LEASE_TIME: u32 = 3600;
+
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_DNS_SERVER: u8 = 6;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_ID: u8 = 54;
+const OPTION_END: u8 = 255;
+
+const DHCP_DISCOVER: u8 = 1;
+const DHCP_OFFER: u8 = 2;
+const DHCP_REQUEST: u8 = 3;
+const DHCP_ACK: u8 = 5;
+
+/// Hardware addresses of the clients, the lease of the one at index `i` being `FIRST_LEASE + i`.
+struct Leases {
+    clients: [Option<[u8; 6]>; MAX_LEASES],
+    /// Slot reused when all are taken, cycling so that the oldest client loses its lease first.
+    next_reused: usize,
+}
+
+impl Leases {
+    fn address(&mut self, client: [u8; 6]) -> Ipv4Address {
+        let index = self
+            .clients
+            .iter()
+            .position(|&leased| leased == Some(client))
+            .or_else(|| self.clients.iter().position(Option::is_none))
+            .unwrap_or_else(|| {
+                let index = self.next_reused;
+                self.next_reused = (index + 1) % MAX_LEASES;
+                index
+            });
+
+        self.clients[index] = Some(client);
+        Ipv4Address::new(192, 168, 4, FIRST_LEASE + index as u8)
+    }
+}
+
+/// Returns the value of the DHCP option `code` in `options`.
+fn dhcp_option(options: &[u8], code: u8) -> Option<&[u8]> {
+    let mut offset = 0;
+
+    loop {
+        match *options.get(offset)? {
+            0 => offset += 1, // Padding
+            OPTION_END => return None,
+            option => {
+                let length = usize::from(*options.get(offset + 1)?);
+                let value = options.get((offset + 2)..(offset + 2 + length))?;
+                if option == code {
+                    return Some(value);
+                }
+                offset += 2 + length;
+            }
+        }
+    }
+}
+
+/// Builds the offer or acknowledgment for the discover or request in `request`, returning its length.
+fn dhcp_response(request: &[u8], leases: &mut Leases, response: &mut [u8]) -> Option<usize> {
+    // A boot request over Ethernet, with the magic cookie in front of the options
+    if request.get(..3)? != [1, 1, 6] || request.get(236..240)? != MAGIC_COOKIE {
+        return None;
+    }
+
+    let message_type = match dhcp_option(&request[240..], OPTION_MESSAGE_TYPE)? {
+        [DHCP_DISCOVER] => DHCP_OFFER,
+        [DHCP_REQUEST] => DHCP_ACK,
+        _ => return None,
+    };
+
+    let client: [u8; 6] = request[28..34].try_into().ok()?;
+    let address = leases.address(client);
+
+    // BOOTP requires at least 300 bytes
+    let response = response.get_mut(..300)?;
+    response.fill(0);
+    response[..4].copy_from_slice(&[2, 1, 6, 0]); // Boot reply over Ethernet
+    response[4..8].copy_from_slice(&request[4..8]); // Transaction ID
+    response[10..12].copy_from_slice(&request[10..12]); // Flags
+    response[16..20].copy_from_slice(address.as_bytes()); // Client address
+    response[20..24].copy_from_slice(AP_ADDRESS.as_bytes()); // Server address
+    response[28..44].copy_from_slice(&request[28..44]); // Client hardware address
+    response[236..240].copy_from_slice(&MAGIC_COOKIE);
+
+    let options: [(u8, &[u8]); 6] = [
+        (OPTION_MESSAGE_TYPE, &[message_type]),
+        (OPTION_SERVER_ID, AP_ADDRESS.as_bytes()),
+        (OPTION_LEASE_TIME, &LEASE_TIME.to_be_bytes()),
+        (OPTION_SUBNET_MASK, &[255, 255, 255, 0]),
+        (OPTION_ROUTER, AP_ADDRESS.as_bytes()),
+        (OPTION_DNS_SERVER, AP_ADDRESS.as_bytes()),
+    ];
+
+    let mut offset = 240;
+    for (code, value) in options {
+        response[offset] = code;
+        response[offset + 1] = value.len() as u8;
+        response[(offset + 2)..(offset + 2 + value.len())].copy_from_slice(value);
+        offset += 2 + value.len();
+    }
+    response[offset] = OPTION_END;
+
+    Some(response.len())
+}
+
+/// Give the clients of the access point an address, with the board as their gateway and DNS server.
+///
+/// Only offers and acknowledges, which is all a client joining the access point needs.
+pub async fn run_dhcp<D: Driver>(stack: &Stack<D>) -> ! {
+    let mut rx_meta = [PacketMetadata::EMPTY; 2];
+    let mut rx_buffer = [0; PACKET_SIZE];
+    let mut tx_meta = [PacketMetadata::EMPTY; 2];
+    let mut tx_buffer = [0; PACKET_SIZE];
+
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    socket
+        .bind(DHCP_SERVER_PORT)
+        .expect("Only the DHCP server binds its port");
+
+    let mut leases = Leases {
+        clients: [None; MAX_LEASES],
+        next_reused: 0,
+    };
+    let mut request = [0; PACKET_SIZE];
+    let mut response = [0; PACKET_SIZE];
+
+    log_info!("Serving DHCP on {}", AP_ADDRESS);
+
+    loop {
+        let length = match socket.recv_from(&mut request).await {
+            Ok((length, _)) => length,
+            Err(err) => {
+                log_warn!("Failed to receive DHCP request: {:?}", err);
+                continue;
+            }
+        };
+
+        let Some(response_length) = dhcp_response(&request[..length], &mut leases, &mut response)
+        else {
+            continue;
+        };
+
+        // The client has no address yet, so the reply is broadcast
+        let endpoint = IpEndpoint::new(Ipv4Address::BROADCAST.into(), DHCP_CLIENT_PORT);
+        if let Err(err) = socket.send_to(&response[..response_length], endpoint).await {
+            log_warn!("Failed to send DHCP response: {:?}", err);
+        }
+    }
+}
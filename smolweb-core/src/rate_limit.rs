@@ -0,0 +1,254 @@
+use core::{net::IpAddr, sync::atomic::Ordering, time::Duration};
+
+use picoserve::{request::RequestParts, response::StatusCode};
+use portable_atomic::AtomicU32;
+
+use crate::error::Refusal;
+
+/// LEDs numbered from this are not rate limited.
+pub const MAX_LEDS: usize = 8;
+
+/// Clients tracked at once by a [ClientRateLimit].
+pub const MAX_CLIENTS: usize = 8;
+
+/// Requests a client of the boards may make to the control routes in a row, enough for the page to load and a few
+/// quick clicks.
+pub const CLIENT_BURST: u32 = 20;
+
+/// How often a client of the boards gets a request back, 10 per second once its burst is used up.
+pub const CLIENT_REFILL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Minimum interval between two toggles of the same LED, shared by every connection as `&'static ToggleRateLimit`.
+///
+/// Protects a relay wired in place of an LED from being switched too fast. An interval of zero disables the limit.
+pub struct ToggleRateLimit {
+    min_interval: Duration,
+    /// Uptime in milliseconds of the last toggle of each LED, plus one so that 0 means never.
+    ///
+    /// This wraps after 49 days, an earlier toggle is then only mistaken for a recent one if it lands inside the interval.
+    last_toggles: [AtomicU32; MAX_LEDS],
+}
+
+impl ToggleRateLimit {
+    pub const fn new(min_interval: Duration) -> Self {
+        #[allow(clippy::declare_interior_mutable_const)] // Only used to initialize the array
+        const NEVER: AtomicU32 = AtomicU32::new(0);
+
+        Self {
+            min_interval,
+            last_toggles: [NEVER; MAX_LEDS],
+        }
+    }
+
+    /// Record a toggle of `led` at `uptime`, or return how long until it may be toggled again.
+    pub(crate) fn try_toggle(&self, led: u8, uptime: Duration) -> Result<(), Duration> {
+        let Some(last_toggle) = self.last_toggles.get(usize::from(led)) else {
+            return Ok(());
+        };
+
+        if self.min_interval.is_zero() {
+            return Ok(());
+        }
+
+        let now = (uptime.as_millis() as u32).wrapping_add(1);
+        let min_interval = self.min_interval.as_millis() as u32;
+
+        // Retry if another connection toggled the same LED in between, so that only one of them succeeds
+        let mut previous = last_toggle.load(Ordering::Relaxed);
+        loop {
+            if previous != 0 {
+                let elapsed = now.wrapping_sub(previous);
+                if elapsed < min_interval {
+                    return Err(Duration::from_millis(u64::from(min_interval - elapsed)));
+                }
+            }
+
+            match last_toggle.compare_exchange_weak(
+                previous,
+                now,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return Ok(()),
+                Err(current) => previous = current,
+            }
+        }
+    }
+}
+
+/// Address of the client of the connection, part of the application state like
+/// [LocalAddress](crate::status_page::LocalAddress), or `None` if unknown.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ClientAddress(pub Option<IpAddr>);
+
+#[cfg(feature = "embassy")]
+impl ClientAddress {
+    /// The address `socket` accepted its connection from.
+    pub fn of_socket(socket: &embassy_net::tcp::TcpSocket<'_>) -> Self {
+        socket
+            .remote_endpoint()
+            .map_or(Self(None), Self::of_endpoint)
+    }
+
+    /// The address of `endpoint`, e.g. the sender of a datagram.
+    pub fn of_endpoint(endpoint: embassy_net::IpEndpoint) -> Self {
+        use core::fmt::Write;
+
+        // Going through text, as the variants of the address depend on the features of `embassy-net`
+        let mut address = heapless::String::<40>::new();
+        Self(
+            write!(address, "{}", endpoint.addr)
+                .ok()
+                .and_then(|()| address.parse().ok()),
+        )
+    }
+}
+
+/// Token bucket of each client IP for the routes taking credentials, checked by [RequireAuth](crate::auth::RequireAuth)
+/// before them and by `POST /login`, and shared by every connection as `&'static ClientRateLimit`.
+///
+/// A bucket holds up to `burst` requests and gains one back every `interval`. Only [MAX_CLIENTS] buckets are kept, so
+/// a new client takes over the fullest one with the requests left in it, and a client cycling through addresses gets
+/// no more requests than the buckets hold together. An interval of zero disables the limit.
+pub struct ClientRateLimit {
+    burst: u32,
+    interval: Duration,
+    /// IPv4 address of the client of each bucket, or its IPv6 address folded to 32 bits, 0 for a free bucket.
+    clients: [AtomicU32; MAX_CLIENTS],
+    /// Uptime in milliseconds at which each bucket is full again, which is all a token bucket needs to be kept.
+    ///
+    /// Like [ToggleRateLimit] this wraps after 49 days, which only matters to a client idle for that long.
+    full_at: [AtomicU32; MAX_CLIENTS],
+}
+
+impl ClientRateLimit {
+    pub const fn new(burst: u32, interval: Duration) -> Self {
+        #[allow(clippy::declare_interior_mutable_const)] // Only used to initialize the arrays
+        const ZERO: AtomicU32 = AtomicU32::new(0);
+
+        Self {
+            burst,
+            interval,
+            clients: [ZERO; MAX_CLIENTS],
+            full_at: [ZERO; MAX_CLIENTS],
+        }
+    }
+
+    fn key(client: IpAddr) -> u32 {
+        let key = match client {
+            IpAddr::V4(address) => u32::from(address),
+            IpAddr::V6(address) => match address.to_ipv4_mapped() {
+                Some(address) => u32::from(address),
+                None => {
+                    let address = u128::from(address);
+                    (address as u32)
+                        ^ ((address >> 32) as u32)
+                        ^ ((address >> 64) as u32)
+                        ^ ((address >> 96) as u32)
+                }
+            },
+        };
+
+        // 0 marks a free bucket
+        key.max(1)
+    }
+
+    /// The bucket of `key`, taking over the fullest one at `now`, as it is, if it has none.
+    fn bucket(&self, key: u32, now: u32) -> usize {
+        loop {
+            if let Some(index) = self
+                .clients
+                .iter()
+                .position(|client| client.load(Ordering::Relaxed) == key)
+            {
+                return index;
+            }
+
+            // A free bucket is as good as a full one, whose refill time has passed
+            let (index, previous) = (0..MAX_CLIENTS)
+                .map(|index| (index, self.clients[index].load(Ordering::Relaxed)))
+                .min_by_key(|&(index, client)| {
+                    let pending = self.full_at[index]
+                        .load(Ordering::Relaxed)
+                        .wrapping_sub(now);
+                    if client == 0 {
+                        0
+                    } else {
+                        (pending as i32).max(0)
+                    }
+                })
+                .unwrap_or((0, 0));
+
+            // Another connection may take the same bucket in between, then the search starts again
+            if self.clients[index]
+                .compare_exchange(previous, key, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+            {
+                // A bucket already full is full from now on, so that its refill time doesn't wrap around for long
+                let full_at = self.full_at[index].load(Ordering::Relaxed);
+                if previous == 0 || full_at.wrapping_sub(now) as i32 <= 0 {
+                    self.full_at[index].store(now, Ordering::Relaxed);
+                }
+                return index;
+            }
+        }
+    }
+
+    /// Take a request from the bucket of `client` at `uptime`, or return how long until it has one again.
+    pub(crate) fn try_request(&self, client: IpAddr, uptime: Duration) -> Result<(), Duration> {
+        if self.interval.is_zero() {
+            return Ok(());
+        }
+
+        let now = uptime.as_millis() as u32;
+        let interval = self.interval.as_millis() as u32;
+        // How far the refill time may be ahead of now, with at least one request left
+        let tolerance = interval.saturating_mul(self.burst.saturating_sub(1));
+
+        let full_at = &self.full_at[self.bucket(Self::key(client), now)];
+
+        let mut previous = full_at.load(Ordering::Relaxed);
+        loop {
+            let pending = (previous.wrapping_sub(now) as i32).max(0) as u32;
+            if pending > tolerance {
+                return Err(Duration::from_millis(u64::from(pending - tolerance)));
+            }
+
+            match full_at.compare_exchange_weak(
+                previous,
+                now.wrapping_add(pending).wrapping_add(interval),
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return Ok(()),
+                Err(current) => previous = current,
+            }
+        }
+    }
+
+    /// Take a request of `client` at `uptime`, or refuse it with `429 Too Many Requests` and a `Retry-After`. Requests
+    /// of an unknown client aren't limited.
+    pub(crate) fn admit(
+        &self,
+        client: ClientAddress,
+        uptime: Duration,
+        request_parts: &RequestParts<'_>,
+    ) -> Result<(), Refusal> {
+        let Some(client) = client.0 else {
+            return Ok(());
+        };
+
+        self.try_request(client, uptime).map_err(|retry_after| {
+            log_debug!(
+                "Too many requests from this client, refusing {}",
+                request_parts.path().encoded()
+            );
+            Refusal::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                "Too many requests",
+                request_parts,
+            )
+            .with_retry_after(retry_after)
+        })
+    }
+}
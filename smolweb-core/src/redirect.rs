@@ -0,0 +1,131 @@
+//! Redirect from port 80 to the application port, so that typing the bare address of a board in a browser works.
+//!
+//! [RedirectToPort] answers every request with `301 Moved Permanently` to the same path on the other port, at the
+//! host the browser asked for. With the `embassy` feature, [run] serves it on [HTTP_PORT] next to the web tasks.
+
+use core::fmt::Write as _;
+
+use picoserve::{
+    extract::FromRef,
+    io::Read,
+    request::{Path, Request},
+    response::{IntoResponse, ResponseWriter, StatusCode},
+    routing::PathRouterService,
+    ResponseSent,
+};
+
+use crate::status_page::LocalAddress;
+
+/// The default port of HTTP, which browsers connect to when the URL has none.
+pub const HTTP_PORT: u16 = 80;
+
+/// Service redirecting every request to the same path on port `.0`.
+///
+/// The host comes from the `Host` header, or the [LocalAddress] of the state for clients which send none.
+pub struct RedirectToPort(pub u16);
+
+/// The host of a `Host` header, without its port.
+fn host_without_port(host: &str) -> &str {
+    match host.rfind(':') {
+        // An IPv6 address is in brackets, and has colons of its own
+        Some(colon) if !host[colon..].contains(']') => &host[..colon],
+        _ => host,
+    }
+}
+
+impl<State, CurrentPathParameters> PathRouterService<State, CurrentPathParameters>
+    for RedirectToPort
+where
+    LocalAddress: FromRef<State>,
+{
+    async fn call_request_handler_service<R: Read, W: ResponseWriter<Error = R::Error>>(
+        &self,
+        state: &State,
+        _current_path_parameters: CurrentPathParameters,
+        _path: Path<'_>,
+        request: Request<'_, R>,
+        response_writer: W,
+    ) -> Result<ResponseSent, W::Error> {
+        let host = request
+            .parts
+            .headers()
+            .get("Host")
+            .and_then(|host| core::str::from_utf8(host.as_raw()).ok())
+            .map(host_without_port);
+
+        let mut location = heapless::String::<160>::new();
+        let origin = match (host, LocalAddress::from_ref(state).0) {
+            (Some(host), _) => write!(location, "http://{host}:{}", self.0),
+            (None, Some(core::net::IpAddr::V6(address))) => {
+                write!(location, "http://[{address}]:{}", self.0)
+            }
+            (None, Some(address)) => write!(location, "http://{address}:{}", self.0),
+            (None, None) => Err(core::fmt::Error),
+        };
+
+        if origin.is_err() {
+            let connection = request.body_connection.finalize().await?;
+            return (StatusCode::BAD_REQUEST, "Missing Host header\n")
+                .write_to(connection, response_writer)
+                .await;
+        }
+
+        // A path too long for the buffer goes to the page instead
+        let origin_len = location.len();
+        if location.push_str(request.parts.path().encoded()).is_err() {
+            location.truncate(origin_len);
+            let _ = location.push('/');
+        }
+
+        let connection = request.body_connection.finalize().await?;
+        (
+            StatusCode::MOVED_PERMANENTLY,
+            ("Location", location.as_str()),
+            "Moved to the application port\n",
+        )
+            .write_to(connection, response_writer)
+            .await
+    }
+}
+
+/// Serve [RedirectToPort] to `to_port` on [HTTP_PORT], one connection at a time, while the stack has an address.
+#[cfg(feature = "embassy")]
+pub async fn run<D: embassy_net::driver::Driver>(stack: &embassy_net::Stack<D>, to_port: u16) -> ! {
+    use embassy_net::tcp::TcpSocket;
+    use embassy_time::Duration;
+
+    let app = picoserve::Router::from_service(RedirectToPort(to_port));
+    let config = picoserve::Config::new(picoserve::Timeouts {
+        start_read_request: Some(Duration::from_secs(5)),
+        read_request: Some(Duration::from_secs(1)),
+        write: Some(Duration::from_secs(1)),
+    })
+    .close_connection_after_response();
+
+    const BUFFERS: crate::buffers::BufferConfig = crate::buffers::BufferConfig::REDIRECT;
+    let mut tcp_rx_buffer = [0; BUFFERS.tcp_rx];
+    let mut tcp_tx_buffer = [0; BUFFERS.tcp_tx];
+    let mut http_buffer = [0; BUFFERS.http];
+
+    log_info!("Redirecting port {} to port {}", HTTP_PORT, to_port);
+
+    loop {
+        if !stack.is_config_up() {
+            stack.wait_config_up().await;
+        }
+
+        let mut socket = TcpSocket::new(stack, &mut tcp_rx_buffer, &mut tcp_tx_buffer);
+
+        if let Err(err) = socket.accept(HTTP_PORT).await {
+            log_warn!("Redirect accept error: {:?}", err);
+            continue;
+        }
+
+        let state = LocalAddress::of_socket(&socket);
+        if let Err(err) =
+            picoserve::serve_with_state(&app, &config, &mut http_buffer, socket, &state).await
+        {
+            log_debug!("Redirect connection failed: {:?}", err);
+        }
+    }
+}
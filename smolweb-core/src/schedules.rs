@@ -0,0 +1,286 @@
+//! Actions run at set times, listed and added with `GET` and `POST /api/schedules` and removed with
+//! `POST /api/schedules/<id>/delete`: an LED set every day at the same time, or set once, e.g. the strip turned off in
+//! ten minutes.
+//!
+//! The schedules are kept in the [Settings](crate::settings::Settings), so that they survive a reboot, and the demos
+//! call [Scheduler::run_due] every [CHECK_INTERVAL]. Times are UTC and read from the [Clock], so nothing runs until
+//! it is set, by SNTP on the boards.
+
+use core::time::Duration;
+
+use picoserve::{
+    extract::State,
+    response::{Json as JsonResponse, StatusCode},
+};
+
+use crate::{
+    api::LedCommand, auth::RequireAuth, error::ApiError, json::Json, settings::SettingsStore,
+    strip::StripCommand, time::Clock, LedControl,
+};
+
+/// Most schedules kept, whose JSON takes about 600 bytes of the settings.
+pub const MAX_SCHEDULES: usize = 8;
+
+/// How often the demos call [Scheduler::run_due], often enough for a schedule to run in the second it is due.
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Seconds a daily schedule may still run late, e.g. when the clock was set forward past it.
+const MAX_DELAY: u64 = 60;
+
+const SECONDS_PER_DAY: u64 = 86400;
+
+/// An action run at a set time, e.g. `{"id":1,"led":2,"action":"toggle","daily_at":"18:00"}`.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Schedule {
+    /// Number of the schedule, for `POST /api/schedules/<id>/delete`.
+    pub id: u8,
+    /// LED the action is run on, or `null` for the WS2812 strip, which can only be turned `off`.
+    pub led: Option<u8>,
+    pub action: LedCommand,
+    /// Time of a schedule run every day, `"HH:MM"` in UTC.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub daily_at: Option<heapless::String<5>>,
+    /// Unix time of a schedule run once, which is then removed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub at: Option<u64>,
+}
+
+/// Seconds since midnight of `"HH:MM"`.
+fn parse_time_of_day(time: &str) -> Option<u64> {
+    let (hours, minutes) = time.split_once(':')?;
+    if hours.len() != 2 || minutes.len() != 2 {
+        return None;
+    }
+
+    let hours: u64 = hours.parse().ok()?;
+    let minutes: u64 = minutes.parse().ok()?;
+    (hours < 24 && minutes < 60).then_some(hours * 3600 + minutes * 60)
+}
+
+impl Schedule {
+    /// Why the schedule can't run on the board of `control`, as the body of a `400 Bad Request`, or `None` if it can.
+    pub fn error(&self, control: &impl LedControl) -> Option<&'static str> {
+        match (&self.daily_at, self.at) {
+            (Some(daily_at), None) if parse_time_of_day(daily_at).is_none() => {
+                return Some("daily_at must be a time of day, HH:MM");
+            }
+            (Some(_), None) | (None, Some(_)) => {}
+            _ => {
+                return Some("A schedule runs either daily_at a time, at a time or after_s seconds")
+            }
+        }
+
+        match self.led {
+            Some(led) if !control.has_led(led) => Some("Unknown LED"),
+            None if control.strip_length() == 0 => Some("This board has no LED strip"),
+            None if self.action != LedCommand::Off => Some("The LED strip can only be turned off"),
+            _ => None,
+        }
+    }
+
+    /// Returns true if the schedule is due at a time after `since` and up to `now`, or before for one run once.
+    fn is_due(&self, since: u64, now: u64) -> bool {
+        if let Some(at) = self.at {
+            return at <= now;
+        }
+
+        // The last time the daily schedule came, today or yesterday
+        self.daily_at
+            .as_deref()
+            .and_then(parse_time_of_day)
+            .is_some_and(|time_of_day| {
+                let last = now - (now + SECONDS_PER_DAY - time_of_day) % SECONDS_PER_DAY;
+                last > since
+            })
+    }
+
+    /// Run the action.
+    fn run(&self, control: &impl LedControl) {
+        match (self.led, self.action) {
+            (Some(led), LedCommand::On) => control.set(led, true),
+            (Some(led), LedCommand::Off) => control.set(led, false),
+            (Some(led), LedCommand::Toggle) => control.toggle(led),
+            (None, _) => {
+                if !control.set_strip(StripCommand::Pixels(heapless::Vec::new())) {
+                    log_warn!("Schedule {} could not turn the strip off", self.id);
+                }
+            }
+        }
+    }
+}
+
+/// Runs the schedules of a [SettingsStore] as they come due.
+pub struct Scheduler {
+    /// Unix time of the last check, `None` until the clock is set.
+    last_check: Option<u64>,
+}
+
+impl Scheduler {
+    pub const fn new() -> Self {
+        Self { last_check: None }
+    }
+
+    /// Run the schedules due since the last call, and remove those run once from the store.
+    ///
+    /// Daily schedules which came while the clock was not set, or the board was off, are not run late.
+    pub fn run_due(
+        &mut self,
+        control: &impl LedControl,
+        clock: &impl Clock,
+        store: &impl SettingsStore,
+    ) {
+        let Some(now) = clock.unix_time() else {
+            return;
+        };
+
+        // Nothing is run for the time before the first check, nor twice when the clock goes back
+        let since = match self.last_check {
+            Some(last_check) if last_check <= now => last_check.max(now.saturating_sub(MAX_DELAY)),
+            _ => now,
+        };
+        self.last_check = Some(now);
+
+        let mut settings = store.settings();
+        if !settings
+            .schedules
+            .iter()
+            .any(|schedule| schedule.is_due(since, now))
+        {
+            return;
+        }
+
+        for schedule in settings
+            .schedules
+            .iter()
+            .filter(|schedule| schedule.is_due(since, now))
+        {
+            log_info!("Running schedule {}", schedule.id);
+            schedule.run(control);
+        }
+
+        let scheduled = settings.schedules.len();
+        settings
+            .schedules
+            .retain(|schedule| schedule.at.is_none() || !schedule.is_due(since, now));
+
+        if settings.schedules.len() != scheduled && !store.save(&settings) {
+            log_warn!("Failed to remove the schedules run once");
+        }
+    }
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Body of `POST /api/schedules`, e.g. `{"led":2,"action":"toggle","daily_at":"18:00"}` or
+/// `{"led":null,"action":"off","after_s":600}`.
+#[derive(serde::Deserialize)]
+pub(crate) struct ScheduleRequest {
+    #[serde(default)]
+    led: Option<u8>,
+    action: LedCommand,
+    #[serde(default)]
+    daily_at: Option<heapless::String<5>>,
+    #[serde(default)]
+    at: Option<u64>,
+    /// Seconds from now of a schedule run once, in place of `at`.
+    #[serde(default)]
+    after_s: Option<u32>,
+}
+
+/// `GET /api/schedules`: the schedules, e.g. `[{"id":1,"led":2,"action":"toggle","daily_at":"18:00"}]`.
+pub(crate) async fn list_schedules<P: SettingsStore>(
+    _: RequireAuth,
+    State(store): State<P>,
+) -> JsonResponse<heapless::Vec<Schedule, MAX_SCHEDULES>> {
+    JsonResponse(store.settings().schedules)
+}
+
+/// `POST /api/schedules`: add a schedule, answered with its `id`.
+pub(crate) async fn add_schedule<C: LedControl, T: Clock, P: SettingsStore>(
+    _: RequireAuth,
+    State(control): State<C>,
+    State(clock): State<T>,
+    State(store): State<P>,
+    Json(request): Json<ScheduleRequest>,
+) -> Result<JsonResponse<Schedule>, ApiError> {
+    let at = match (request.at, request.after_s) {
+        (at, None) => at,
+        (None, Some(after_s)) => {
+            let now = clock.unix_time().ok_or(ApiError::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "The clock is not set yet",
+            ))?;
+            Some(now + u64::from(after_s))
+        }
+        (Some(_), Some(_)) => {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "A schedule runs either daily_at a time, at a time or after_s seconds",
+            ));
+        }
+    };
+
+    let mut settings = store.settings();
+
+    // The lowest free id, so that ids stay small
+    let Some(id) =
+        (1..=u8::MAX).find(|&id| settings.schedules.iter().all(|schedule| schedule.id != id))
+    else {
+        return Err(ApiError::new(StatusCode::CONFLICT, "Too many schedules"));
+    };
+
+    let schedule = Schedule {
+        id,
+        led: request.led,
+        action: request.action,
+        daily_at: request.daily_at,
+        at,
+    };
+
+    if let Some(error) = schedule.error(&control) {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, error));
+    }
+
+    if settings.schedules.push(schedule.clone()).is_err() {
+        return Err(ApiError::new(StatusCode::CONFLICT, "Too many schedules"));
+    }
+
+    if !store.save(&settings) {
+        return Err(ApiError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to save the schedules",
+        ));
+    }
+
+    log_debug!("Added schedule {}", id);
+    Ok(JsonResponse(schedule))
+}
+
+/// `POST /api/schedules/<id>/delete`: remove a schedule, answered with the remaining ones.
+pub(crate) async fn delete_schedule<P: SettingsStore>(
+    id: u8,
+    _: RequireAuth,
+    State(store): State<P>,
+) -> Result<JsonResponse<heapless::Vec<Schedule, MAX_SCHEDULES>>, ApiError> {
+    let mut settings = store.settings();
+
+    let scheduled = settings.schedules.len();
+    settings.schedules.retain(|schedule| schedule.id != id);
+    if settings.schedules.len() == scheduled {
+        return Err(ApiError::new(StatusCode::NOT_FOUND, "Unknown schedule"));
+    }
+
+    if !store.save(&settings) {
+        return Err(ApiError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to save the schedules",
+        ));
+    }
+
+    log_debug!("Removed schedule {}", id);
+    Ok(JsonResponse(settings.schedules))
+}
@@ -0,0 +1,190 @@
+//! Temperature and humidity of the board, sampled by a sensor task into [SensorStats] and read with `GET /api/sensors`.
+//!
+//! Each sample is also published as a [BoardEvent::Sensor](crate::events::BoardEvent::Sensor), which the page charts.
+//! With the `sht31` feature, `Sht31` reads them from a Sensirion SHT31 over any `embedded-hal-async` I2C bus.
+
+use core::{sync::atomic::Ordering, time::Duration};
+
+use picoserve::{
+    extract::State,
+    response::{Json, StatusCode},
+};
+use portable_atomic::AtomicU32;
+
+use crate::{auth::RequireAuth, error::ApiError};
+
+/// How often the sensor tasks sample, which is also how often the page chart moves.
+pub const SENSOR_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A sample of the environment of the board.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SensorReading {
+    pub temperature_c: f32,
+    pub humidity_percent: f32,
+}
+
+/// Latest [SensorReading], shared as `&'static SensorStats` like [ButtonStats](crate::button::ButtonStats).
+///
+/// Boards without a sensor keep one which is never sampled, and `GET /api/sensors` answers them with
+/// `503 Service Unavailable`.
+pub struct SensorStats {
+    /// Bits of the `f32`s of the latest reading.
+    temperature: AtomicU32,
+    humidity: AtomicU32,
+    /// Uptime in milliseconds of the latest reading, plus one so that 0 means never.
+    ///
+    /// This wraps after 49 days, like the last press of [ButtonStats](crate::button::ButtonStats).
+    sampled_at: AtomicU32,
+}
+
+impl SensorStats {
+    pub const fn new() -> Self {
+        Self {
+            temperature: AtomicU32::new(0),
+            humidity: AtomicU32::new(0),
+            sampled_at: AtomicU32::new(0),
+        }
+    }
+
+    /// Record `reading`, sampled at `uptime`, called by the sensor task.
+    pub fn record(&self, reading: SensorReading, uptime: Duration) {
+        self.temperature
+            .store(reading.temperature_c.to_bits(), Ordering::Relaxed);
+        self.humidity
+            .store(reading.humidity_percent.to_bits(), Ordering::Relaxed);
+        self.sampled_at.store(
+            (uptime.as_millis() as u32).wrapping_add(1),
+            Ordering::Relaxed,
+        );
+    }
+
+    /// The latest reading and the uptime in milliseconds it was sampled at, or `None` before the first one.
+    pub fn latest(&self) -> Option<(SensorReading, u32)> {
+        let sampled_at = self.sampled_at.load(Ordering::Relaxed).checked_sub(1)?;
+
+        Some((
+            SensorReading {
+                temperature_c: f32::from_bits(self.temperature.load(Ordering::Relaxed)),
+                humidity_percent: f32::from_bits(self.humidity.load(Ordering::Relaxed)),
+            },
+            sampled_at,
+        ))
+    }
+}
+
+impl Default for SensorStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Body of `GET /api/sensors`, e.g. `{"temperature_c":21.5,"humidity_percent":40.25,"sampled_uptime_ms":5120}`.
+#[derive(serde::Serialize)]
+pub(crate) struct SensorStatus {
+    temperature_c: f32,
+    humidity_percent: f32,
+    sampled_uptime_ms: u32,
+}
+
+pub(crate) async fn get_sensors(
+    _: RequireAuth,
+    State(sensors): State<&'static SensorStats>,
+) -> Result<Json<SensorStatus>, ApiError> {
+    let (reading, sampled_uptime_ms) = sensors.latest().ok_or(ApiError::new(
+        StatusCode::SERVICE_UNAVAILABLE,
+        "No sensor reading yet",
+    ))?;
+
+    Ok(Json(SensorStatus {
+        temperature_c: reading.temperature_c,
+        humidity_percent: reading.humidity_percent,
+        sampled_uptime_ms,
+    }))
+}
+
+#[cfg(feature = "sht31")]
+pub use sht31::{Sht31, Sht31Error, SHT31_DEFAULT_ADDRESS};
+
+#[cfg(feature = "sht31")]
+mod sht31 {
+    use embedded_hal_async::{delay::DelayNs, i2c::I2c};
+
+    use super::SensorReading;
+
+    /// Address of the sensor with its ADDR pin low, as on most breakout boards.
+    pub const SHT31_DEFAULT_ADDRESS: u8 = 0x44;
+
+    /// Single shot measurement with high repeatability, without clock stretching.
+    const MEASURE: [u8; 2] = [0x24, 0x00];
+
+    /// Longest duration of a [MEASURE], from the datasheet.
+    const MEASURE_TIME_MS: u32 = 16;
+
+    #[derive(Debug)]
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    pub enum Sht31Error<E> {
+        I2c(E),
+        /// A word read back didn't match its checksum.
+        Crc,
+    }
+
+    /// Sensirion SHT31 temperature and humidity sensor.
+    pub struct Sht31<I> {
+        i2c: I,
+        address: u8,
+    }
+
+    impl<I: I2c> Sht31<I> {
+        pub fn new(i2c: I, address: u8) -> Self {
+            Self { i2c, address }
+        }
+
+        /// Measure the temperature and humidity, which takes up to 16 ms.
+        pub async fn measure(
+            &mut self,
+            delay: &mut impl DelayNs,
+        ) -> Result<SensorReading, Sht31Error<I::Error>> {
+            self.i2c
+                .write(self.address, &MEASURE)
+                .await
+                .map_err(Sht31Error::I2c)?;
+            delay.delay_ms(MEASURE_TIME_MS).await;
+
+            let mut data = [0; 6];
+            self.i2c
+                .read(self.address, &mut data)
+                .await
+                .map_err(Sht31Error::I2c)?;
+
+            let temperature = word(&data[0..3])?;
+            let humidity = word(&data[3..6])?;
+
+            Ok(SensorReading {
+                temperature_c: -45.0 + 175.0 * f32::from(temperature) / 65535.0,
+                humidity_percent: 100.0 * f32::from(humidity) / 65535.0,
+            })
+        }
+    }
+
+    /// The word of two bytes followed by their CRC-8, checked.
+    fn word<E>(bytes: &[u8]) -> Result<u16, Sht31Error<E>> {
+        let crc = bytes[..2].iter().fold(0xff_u8, |mut crc, &byte| {
+            crc ^= byte;
+            for _ in 0..8 {
+                crc = if crc & 0x80 != 0 {
+                    (crc << 1) ^ 0x31
+                } else {
+                    crc << 1
+                };
+            }
+            crc
+        });
+
+        if crc != bytes[2] {
+            return Err(Sht31Error::Crc);
+        }
+
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+}
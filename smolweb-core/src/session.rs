@@ -0,0 +1,323 @@
+//! Cookie sessions, so that a browser logs in once at `/login` instead of sending Basic credentials with each request.
+//!
+//! `POST /login` checks the [Credentials] and opens a session in the [Sessions] table, whose cookie
+//! [RequireAuth](crate::auth::RequireAuth) then accepts like the credentials themselves until it expires or
+//! `POST /logout` closes it. Login attempts count against the [ClientRateLimit] of the client like requests to the
+//! routes taking credentials. The cookie is the id and expiry of the session signed with HMAC-SHA1 under a key drawn
+//! from the RNG of the board at boot, so it can't be made up, and the table only keeps [MAX_SESSIONS] of them, the one
+//! closest to expiring making room for a new one.
+
+use core::{fmt, sync::atomic::Ordering, time::Duration};
+
+use picoserve::{
+    extract::{Form, FromRef, FromRequestParts, State},
+    request::RequestParts,
+    response::{IntoResponse, Response, StatusCode},
+};
+use portable_atomic::AtomicU32;
+
+use crate::{
+    auth::{constant_time_eq, Credentials},
+    error::Refusal,
+    limits::{self, CutShort, Limits},
+    rate_limit::{ClientAddress, ClientRateLimit},
+};
+
+/// Sessions open at once.
+pub const MAX_SESSIONS: usize = 4;
+
+/// How long a session lasts after logging in.
+pub const SESSION_LIFETIME: Duration = Duration::from_secs(3600);
+
+/// Bytes of the key signing the cookies.
+pub const KEY_LEN: usize = 32;
+
+/// Name of the cookie holding the session.
+const COOKIE_NAME: &str = "smolweb_session";
+
+/// Bytes of a SHA-1 block, which HMAC pads the key to.
+const BLOCK_LEN: usize = 64;
+const DIGEST_LEN: usize = 20;
+
+/// Bytes of a token: the id and expiry of the session, then their signature.
+const TOKEN_LEN: usize = 4 + 4 + DIGEST_LEN;
+
+/// HMAC-SHA1 of `message` under `key`, from RFC 2104.
+fn hmac_sha1(key: &[u8; KEY_LEN], message: &[u8; 8]) -> [u8; DIGEST_LEN] {
+    let mut inner = [0x36; BLOCK_LEN + 8];
+    let mut outer = [0x5c; BLOCK_LEN + DIGEST_LEN];
+
+    for (index, &byte) in key.iter().enumerate() {
+        inner[index] ^= byte;
+        outer[index] ^= byte;
+    }
+
+    inner[BLOCK_LEN..].copy_from_slice(message);
+    outer[BLOCK_LEN..].copy_from_slice(&const_sha1::sha1(&inner).as_bytes());
+    const_sha1::sha1(&outer).as_bytes()
+}
+
+/// A session as carried by its cookie.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Token {
+    id: u32,
+    /// Uptime in seconds the session expires at.
+    expires_at: u32,
+}
+
+impl Token {
+    fn message(&self) -> [u8; 8] {
+        let mut message = [0; 8];
+        message[..4].copy_from_slice(&self.id.to_be_bytes());
+        message[4..].copy_from_slice(&self.expires_at.to_be_bytes());
+        message
+    }
+
+    /// The value of the cookie, in hexadecimal.
+    fn encode(&self, key: &[u8; KEY_LEN]) -> [u8; 2 * TOKEN_LEN] {
+        let mut token = [0; TOKEN_LEN];
+        token[..8].copy_from_slice(&self.message());
+        token[8..].copy_from_slice(&hmac_sha1(key, &self.message()));
+
+        let mut encoded = [0; 2 * TOKEN_LEN];
+        data_encoding::HEXLOWER.encode_mut(&token, &mut encoded);
+        encoded
+    }
+
+    /// Reads the value of a cookie written by [Self::encode], returning `None` if it wasn't signed with `key`.
+    fn decode(encoded: &[u8], key: &[u8; KEY_LEN]) -> Option<Self> {
+        let mut token = [0; TOKEN_LEN];
+        if encoded.len() != 2 * TOKEN_LEN {
+            return None;
+        }
+        data_encoding::HEXLOWER
+            .decode_mut(encoded, &mut token)
+            .ok()?;
+
+        let (message, signature) = token.split_at(8);
+        let decoded = Self {
+            id: u32::from_be_bytes(message[..4].try_into().ok()?),
+            expires_at: u32::from_be_bytes(message[4..].try_into().ok()?),
+        };
+
+        constant_time_eq(signature, &hmac_sha1(key, &decoded.message())).then_some(decoded)
+    }
+}
+
+/// The open sessions, shared by every connection as `&'static Sessions`.
+pub struct Sessions {
+    key: [u8; KEY_LEN],
+    /// Id of the last session opened, so that a closed session is never opened again.
+    last_id: AtomicU32,
+    /// Id of the session in each slot, 0 for a free slot.
+    ids: [AtomicU32; MAX_SESSIONS],
+    /// Uptime in seconds the session in each slot expires at.
+    expires_at: [AtomicU32; MAX_SESSIONS],
+}
+
+impl Sessions {
+    /// The table of sessions whose cookies are signed with `key`, which must be random for them not to be forged.
+    pub const fn new(key: [u8; KEY_LEN]) -> Self {
+        #[allow(clippy::declare_interior_mutable_const)] // Only used to initialize the arrays
+        const ZERO: AtomicU32 = AtomicU32::new(0);
+
+        Self {
+            key,
+            last_id: ZERO,
+            ids: [ZERO; MAX_SESSIONS],
+            expires_at: [ZERO; MAX_SESSIONS],
+        }
+    }
+
+    /// Open a session at `uptime`, closing the one closest to expiring if all slots are taken, and return its cookie.
+    fn open(&self, uptime: Duration) -> [u8; 2 * TOKEN_LEN] {
+        // 0 marks a free slot
+        let id = self
+            .last_id
+            .fetch_add(1, Ordering::Relaxed)
+            .wrapping_add(1)
+            .max(1);
+        let expires_at = (uptime + SESSION_LIFETIME).as_secs() as u32;
+
+        loop {
+            let (index, previous) = (0..MAX_SESSIONS)
+                .map(|index| (index, self.ids[index].load(Ordering::Relaxed)))
+                .min_by_key(|&(index, id)| match id {
+                    0 => 0,
+                    _ => self.expires_at[index].load(Ordering::Relaxed),
+                })
+                .unwrap_or((0, 0));
+
+            // Another connection may take the same slot in between, then the search starts again
+            if self.ids[index]
+                .compare_exchange(previous, id, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+            {
+                self.expires_at[index].store(expires_at, Ordering::Relaxed);
+                return Token { id, expires_at }.encode(&self.key);
+            }
+        }
+    }
+
+    /// The slot of the session of `cookie` if it is open at `uptime`.
+    fn find(&self, cookie: &[u8], uptime: Duration) -> Option<usize> {
+        let token = Token::decode(cookie, &self.key)?;
+
+        if u64::from(token.expires_at) <= uptime.as_secs() {
+            return None;
+        }
+
+        self.ids
+            .iter()
+            .position(|id| id.load(Ordering::Relaxed) == token.id)
+    }
+
+    /// Returns true if `cookie` is that of a session open at `uptime`.
+    fn is_open(&self, cookie: &[u8], uptime: Duration) -> bool {
+        self.find(cookie, uptime).is_some()
+    }
+
+    /// Close the session of `cookie`, if open.
+    fn close(&self, cookie: &[u8], uptime: Duration) {
+        if let Some(index) = self.find(cookie, uptime) {
+            self.ids[index].store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+/// The [Sessions] and the uptime of the request, part of the application state so that
+/// [RequireAuth](crate::auth::RequireAuth) can check session cookies without knowing the [Clock](crate::time::Clock)
+/// of the board.
+#[derive(Clone, Copy)]
+pub struct SessionContext {
+    pub sessions: &'static Sessions,
+    pub uptime: Duration,
+}
+
+impl SessionContext {
+    /// Returns true if the `Cookie` header of the request holds an open session.
+    pub(crate) fn request_has_session(&self, request_parts: &RequestParts<'_>) -> bool {
+        session_cookie(request_parts)
+            .is_some_and(|cookie| self.sessions.is_open(cookie, self.uptime))
+    }
+}
+
+/// The value of the session cookie of the request, if any.
+fn session_cookie<'a>(request_parts: &'a RequestParts<'_>) -> Option<&'a [u8]> {
+    let header = request_parts.headers().get("cookie")?.as_raw();
+
+    header.split(|&b| b == b';').find_map(|cookie| {
+        let cookie = crate::static_files::trim(cookie);
+        let value = cookie.strip_prefix(COOKIE_NAME.as_bytes())?;
+        value.strip_prefix(b"=")
+    })
+}
+
+/// `Set-Cookie` value opening the session of `token`, or removing the cookie without one.
+struct SetCookie(Option<[u8; 2 * TOKEN_LEN]>);
+
+impl fmt::Display for SetCookie {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // `SameSite=Strict` keeps other sites from posting to the board on behalf of a logged in browser
+        match &self.0 {
+            Some(token) => write!(
+                f,
+                "{COOKIE_NAME}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Strict",
+                core::str::from_utf8(token).unwrap_or_default(),
+                SESSION_LIFETIME.as_secs()
+            ),
+            None => write!(
+                f,
+                "{COOKIE_NAME}=; Path=/; Max-Age=0; HttpOnly; SameSite=Strict"
+            ),
+        }
+    }
+}
+
+/// Body of `POST /login`, as sent by the form of `GET /login`.
+#[derive(serde::Deserialize)]
+pub(crate) struct LoginForm {
+    username: heapless::String<32>,
+    password: heapless::String<64>,
+}
+
+/// Extractor taking a login attempt from the bucket of the client in the [ClientRateLimit], so that passwords are
+/// guessed no faster than the routes taking credentials are called, after refusing the attempts over the [Limits],
+/// whose form is not read.
+pub(crate) struct LoginAttempt;
+
+impl<'r, State> FromRequestParts<'r, State> for LoginAttempt
+where
+    SessionContext: FromRef<State>,
+    &'static ClientRateLimit: FromRef<State>,
+    ClientAddress: FromRef<State>,
+    Limits: FromRef<State>,
+    CutShort: FromRef<State>,
+{
+    type Rejection = Refusal;
+
+    async fn from_request_parts(
+        state: &'r State,
+        request_parts: &RequestParts<'r>,
+    ) -> Result<Self, Self::Rejection> {
+        limits::check(state, request_parts)?;
+        <&'static ClientRateLimit>::from_ref(state)
+            .admit(
+                ClientAddress::from_ref(state),
+                SessionContext::from_ref(state).uptime,
+                request_parts,
+            )
+            .map(|()| Self)
+    }
+}
+
+/// `POST /login`: open a session if the credentials are right, and go to the control panel with its cookie.
+pub(crate) async fn login(
+    _: LoginAttempt,
+    State(credentials): State<Credentials>,
+    State(context): State<SessionContext>,
+    Form(form): Form<LoginForm>,
+) -> Result<impl IntoResponse, (StatusCode, &'static str)> {
+    if !credentials.are_valid(form.username.as_bytes(), form.password.as_bytes()) {
+        // The username is whatever the client sent, so it is left out of the logs
+        log_warn!("Failed login");
+        return Err((StatusCode::UNAUTHORIZED, "Wrong username or password\n"));
+    }
+
+    let cookie = context.sessions.open(context.uptime);
+
+    log_info!("Session opened for {}", form.username.as_str());
+    Ok(Response::new(StatusCode::SEE_OTHER, "Logged in\n")
+        .with_header("Location", "/")
+        .with_header("Set-Cookie", SetCookie(Some(cookie))))
+}
+
+/// Extractor of the session cookie of the request, `None` without one or with one of the wrong length.
+pub(crate) struct SessionCookie(Option<[u8; 2 * TOKEN_LEN]>);
+
+impl<'r, State> FromRequestParts<'r, State> for SessionCookie {
+    type Rejection = core::convert::Infallible;
+
+    async fn from_request_parts(
+        _state: &'r State,
+        request_parts: &RequestParts<'r>,
+    ) -> Result<Self, Self::Rejection> {
+        Ok(Self(
+            session_cookie(request_parts).and_then(|cookie| cookie.try_into().ok()),
+        ))
+    }
+}
+
+/// `POST /logout`: close the session of the request, if any, and remove its cookie.
+pub(crate) async fn logout(
+    State(context): State<SessionContext>,
+    SessionCookie(cookie): SessionCookie,
+) -> impl IntoResponse {
+    if let Some(cookie) = cookie {
+        context.sessions.close(&cookie, context.uptime);
+    }
+
+    Response::new(StatusCode::SEE_OTHER, "Logged out\n")
+        .with_header("Location", "/login")
+        .with_header("Set-Cookie", SetCookie(None))
+}
@@ -0,0 +1,155 @@
+//! Settings of the board kept across reboots, read and changed with `GET` and `POST /api/settings`, or the form of
+//! [`/config`](crate::config_page).
+//!
+//! The demos load them at boot and apply them before starting the web tasks, so changes take effect at the next boot,
+//! except for the blink period, which is read by each `blink` pattern started. The [Schedule]s are kept with them, but
+//! managed with [`/api/schedules`](crate::schedules), so replacing the settings keeps them.
+
+use picoserve::{
+    extract::State,
+    response::{Json as JsonResponse, StatusCode},
+};
+
+use crate::{
+    auth::RequireAuth,
+    json::Json,
+    patterns,
+    schedules::{Schedule, MAX_SCHEDULES},
+};
+
+/// Most bytes of [Settings] as JSON, which boards reserve to store them.
+pub const MAX_JSON_SIZE: usize = 768;
+
+/// Body of `GET` and `POST /api/settings`, e.g. `{"hostname":"smolweb","leds_on_at_boot":[1,2],"blink_period_ms":1000}`.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Settings {
+    /// Name of the board, answered over mDNS as `<hostname>.local` and sent to the DHCP server. A single DNS label.
+    pub hostname: heapless::String<32>,
+    /// LEDs turned on at boot, the others start off.
+    pub leds_on_at_boot: heapless::Vec<u8, 8>,
+    /// Period of the `blink` pattern when `POST /api/leds/<n>/pattern` gives none, 1 s if stored without it.
+    #[serde(default = "default_blink_period_ms")]
+    pub blink_period_ms: u32,
+    /// Left out while there are none, and when read from a `POST`, which keeps those of the store.
+    #[serde(default, skip_serializing_if = "heapless::Vec::is_empty")]
+    pub schedules: heapless::Vec<Schedule, MAX_SCHEDULES>,
+}
+
+fn default_blink_period_ms() -> u32 {
+    1000
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            hostname: heapless::String::try_from("smolweb").unwrap(),
+            leds_on_at_boot: heapless::Vec::from_slice(&[1, 2, 3]).unwrap(),
+            blink_period_ms: default_blink_period_ms(),
+            schedules: heapless::Vec::new(),
+        }
+    }
+}
+
+impl Settings {
+    /// Returns true if the hostname is a valid DNS label, letters, digits and inner `-`, and the blink period one of
+    /// a pattern.
+    pub fn is_valid(&self) -> bool {
+        self.error().is_none()
+    }
+
+    /// Why the settings are not valid, as the body of a `400 Bad Request`, or `None` if they are.
+    pub fn error(&self) -> Option<&'static str> {
+        let hostname = self.hostname.as_bytes();
+
+        let hostname_is_valid = !hostname.is_empty()
+            && hostname
+                .iter()
+                .all(|&b| b.is_ascii_alphanumeric() || b == b'-')
+            && hostname.first() != Some(&b'-')
+            && hostname.last() != Some(&b'-');
+
+        if !hostname_is_valid {
+            Some("The hostname must be letters, digits and inner '-'\n")
+        } else if !patterns::is_valid_period_ms(self.blink_period_ms) {
+            Some("blink_period_ms must be between 100 and 60000\n")
+        } else {
+            None
+        }
+    }
+
+    /// Returns true if LED `led` is turned on at boot.
+    pub fn led_on_at_boot(&self, led: u8) -> bool {
+        self.leds_on_at_boot.contains(&led)
+    }
+
+    /// The settings as JSON, to be stored.
+    pub fn to_json(&self) -> heapless::Vec<u8, MAX_JSON_SIZE> {
+        let mut json = [0; MAX_JSON_SIZE];
+        // The longest hostname, 8 three digit LEDs and the longest blink period come to 125 bytes, and each schedule
+        // to at most 65 more with its comma
+        let length = serde_json_core::to_slice(self, &mut json).unwrap_or(0);
+        heapless::Vec::from_slice(&json[..length]).unwrap_or_default()
+    }
+
+    /// Reads settings stored by [Self::to_json], returning `None` if they are not valid.
+    pub fn from_json(json: &[u8]) -> Option<Self> {
+        serde_json_core::from_slice(json)
+            .ok()
+            .map(|(settings, _)| settings)
+            .filter(Self::is_valid)
+    }
+}
+
+/// Where the board keeps its [Settings].
+pub trait SettingsStore {
+    /// The settings loaded at boot, or saved since.
+    fn settings(&self) -> Settings;
+
+    /// Store `settings`, returning false if they could not be written.
+    fn save(&self, settings: &Settings) -> bool;
+}
+
+/// [SettingsStore] of demos without storage, which always use the default settings.
+#[derive(Clone, Copy)]
+pub struct NoSettingsStore;
+
+impl SettingsStore for NoSettingsStore {
+    fn settings(&self) -> Settings {
+        Settings::default()
+    }
+
+    fn save(&self, _settings: &Settings) -> bool {
+        false
+    }
+}
+
+/// `GET /api/settings`
+pub(crate) async fn get_settings<P: SettingsStore>(
+    _: RequireAuth,
+    State(store): State<P>,
+) -> JsonResponse<Settings> {
+    JsonResponse(store.settings())
+}
+
+/// `POST /api/settings`: replace the settings, which take effect at the next boot.
+pub(crate) async fn save_settings<P: SettingsStore>(
+    _: RequireAuth,
+    State(store): State<P>,
+    Json(mut settings): Json<Settings>,
+) -> Result<JsonResponse<Settings>, (StatusCode, &'static str)> {
+    settings.schedules = store.settings().schedules;
+
+    if let Some(error) = settings.error() {
+        return Err((StatusCode::BAD_REQUEST, error));
+    }
+
+    if !store.save(&settings) {
+        return Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to save the settings\n",
+        ));
+    }
+
+    log_debug!("Saved settings");
+    Ok(JsonResponse(settings))
+}
@@ -0,0 +1,136 @@
+//! SNTP client for targets running `embassy-net`, enabled by the `embassy` feature.
+
+use core::sync::atomic::Ordering;
+
+use embassy_net::{
+    dns::DnsQueryType,
+    driver::Driver,
+    udp::{PacketMetadata, UdpSocket},
+    IpEndpoint, Stack,
+};
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use portable_atomic::AtomicU32;
+
+use crate::time::Clock;
+
+const NTP_PORT: u16 = 123;
+
+/// How long to wait for the server to answer a request.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Delay before trying again after a failed sync.
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Delay between two successful syncs, to correct the drift of the local clock.
+const RESYNC_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Seconds between the NTP epoch (1900) and the Unix epoch (1970).
+const NTP_TO_UNIX_SECONDS: u32 = 2_208_988_800;
+
+/// Unix time at boot in seconds, or 0 before the first sync.
+///
+/// Cortex-M has no 64-bit atomics, and seconds since 1970 fit in a `u32` until 2106.
+static BOOT_UNIX_TIME: AtomicU32 = AtomicU32::new(0);
+
+/// [Clock] reading the time synchronized by [run].
+#[derive(Clone, Copy)]
+pub struct SntpClock;
+
+impl Clock for SntpClock {
+    fn unix_time(&self) -> Option<u64> {
+        match BOOT_UNIX_TIME.load(Ordering::Relaxed) {
+            0 => None,
+            boot_unix_time => Some(u64::from(boot_unix_time) + Instant::now().as_secs()),
+        }
+    }
+
+    fn uptime(&self) -> core::time::Duration {
+        core::time::Duration::from_micros(Instant::now().as_micros())
+    }
+}
+
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+enum SyncError {
+    Dns(embassy_net::dns::Error),
+    NoAddress,
+    Send(embassy_net::udp::SendError),
+    Receive(embassy_net::udp::RecvError),
+    Timeout,
+    InvalidResponse,
+}
+
+/// Send a single SNTP request and return the server time as seconds since the Unix epoch.
+async fn query_server<D: Driver>(
+    stack: &Stack<D>,
+    socket: &UdpSocket<'_>,
+    server: &str,
+) -> Result<u32, SyncError> {
+    let address = *stack
+        .dns_query(server, DnsQueryType::A)
+        .await
+        .map_err(SyncError::Dns)?
+        .first()
+        .ok_or(SyncError::NoAddress)?;
+
+    // Leap indicator 0, version 3, mode 3 (client), everything else zero
+    let mut packet = [0; 48];
+    packet[0] = 0x1b;
+
+    socket
+        .send_to(&packet, IpEndpoint::new(address, NTP_PORT))
+        .await
+        .map_err(SyncError::Send)?;
+
+    let (length, _) = with_timeout(RESPONSE_TIMEOUT, socket.recv_from(&mut packet))
+        .await
+        .map_err(|_| SyncError::Timeout)?
+        .map_err(SyncError::Receive)?;
+
+    if length < packet.len() {
+        return Err(SyncError::InvalidResponse);
+    }
+
+    // The seconds of the transmit timestamp are at bytes 40..44
+    let ntp_seconds = u32::from_be_bytes([packet[40], packet[41], packet[42], packet[43]]);
+
+    if ntp_seconds < NTP_TO_UNIX_SECONDS {
+        return Err(SyncError::InvalidResponse);
+    }
+
+    Ok(ntp_seconds - NTP_TO_UNIX_SECONDS)
+}
+
+/// Keep [SntpClock] synchronized with `server`, resolved through DNS.
+pub async fn run<D: Driver>(stack: &Stack<D>, server: &str) -> ! {
+    let mut rx_meta = [PacketMetadata::EMPTY; 1];
+    let mut rx_buffer = [0; 64];
+    let mut tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut tx_buffer = [0; 64];
+
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    socket
+        .bind(0)
+        .expect("A new socket can always bind to an ephemeral port");
+
+    loop {
+        match query_server(stack, &socket, server).await {
+            Ok(unix_time) => {
+                let boot_unix_time = unix_time.saturating_sub(Instant::now().as_secs() as u32);
+                BOOT_UNIX_TIME.store(boot_unix_time.max(1), Ordering::Relaxed);
+                log_info!("Time synchronized with {}: {}", server, unix_time);
+                Timer::after(RESYNC_INTERVAL).await;
+            }
+            Err(err) => {
+                log_warn!("Failed to synchronize time with {}: {:?}", server, err);
+                Timer::after(RETRY_INTERVAL).await;
+            }
+        }
+    }
+}
@@ -0,0 +1,222 @@
+use core::marker::PhantomData;
+
+use picoserve::{
+    extract::FromRef,
+    io::Read,
+    request::{Path, Request},
+    response::{IntoResponse, Response, ResponseWriter},
+    routing::{
+        get_service, MethodHandler, NoPathParameters, PathRouterService, RequestHandlerService,
+    },
+    ResponseSent,
+};
+
+use crate::{
+    assets::{is_safe_path, AssetStore, StoredFile},
+    cached_file::CachedFile,
+    mime,
+    not_found::NotFound,
+};
+
+/// An embedded file, with an optional pre-compressed variant sent to clients accepting gzip.
+pub(crate) struct StaticFile {
+    pub(crate) plain: CachedFile,
+    pub(crate) gzip: Option<CachedFile>,
+}
+
+impl<State, PathParameters> RequestHandlerService<State, PathParameters> for &StaticFile {
+    async fn call_request_handler_service<R: Read, W: ResponseWriter<Error = R::Error>>(
+        &self,
+        state: &State,
+        path_parameters: PathParameters,
+        request: Request<'_, R>,
+        response_writer: W,
+    ) -> Result<ResponseSent, W::Error> {
+        let client_accepts_gzip = request
+            .parts
+            .headers()
+            .get("Accept-Encoding")
+            .is_some_and(|accept_encoding| accepts_gzip(accept_encoding.as_raw()));
+
+        let file = match &self.gzip {
+            Some(gzip) if client_accepts_gzip => gzip,
+            _ => &self.plain,
+        };
+
+        file.call_request_handler_service(state, path_parameters, request, response_writer)
+            .await
+    }
+}
+
+/// Files embedded from the `static/` directory by `build.rs`, keyed by their path below it.
+///
+/// The contents stay in flash as `&'static [u8]`, nothing is copied into RAM.
+static FILES: &[(&str, StaticFile)] = include!(concat!(env!("OUT_DIR"), "/static_files.rs"));
+
+/// Files embedded from the `assets/` directory by `build.rs`, the stylesheet and script of the page among them, keyed
+/// by their path below it, with a gzip variant for the text files.
+static ASSETS: &[(&str, StaticFile)] = include!(concat!(env!("OUT_DIR"), "/assets.rs"));
+
+pub(crate) fn trim(bytes: &[u8]) -> &[u8] {
+    let start = bytes
+        .iter()
+        .position(|b| !b.is_ascii_whitespace())
+        .unwrap_or(bytes.len());
+    let end = bytes
+        .iter()
+        .rposition(|b| !b.is_ascii_whitespace())
+        .map_or(start, |end| end + 1);
+    &bytes[start..end]
+}
+
+/// Returns true if an `Accept-Encoding` header allows a gzip response.
+fn accepts_gzip(accept_encoding: &[u8]) -> bool {
+    accept_encoding.split(|&b| b == b',').any(|coding| {
+        let mut parameters = coding.split(|&b| b == b';').map(trim);
+
+        let is_gzip = parameters
+            .next()
+            .is_some_and(|name| name.eq_ignore_ascii_case(b"gzip"));
+
+        // `gzip;q=0` explicitly refuses gzip
+        let is_refused = parameters.any(|parameter| {
+            parameter
+                .strip_prefix(b"q=")
+                .is_some_and(|quality| quality.iter().all(|&b| b == b'0' || b == b'.'))
+        });
+
+        is_gzip && !is_refused
+    })
+}
+
+/// The file of `files` at `path`, if any.
+fn embedded_file(files: &'static [(&str, StaticFile)], path: Path) -> Option<&'static StaticFile> {
+    files
+        .iter()
+        .find_map(|(name, file)| (path == *name).then_some(file))
+}
+
+/// Service serving the files of [ASSETS] at the root of the site, and [NotFound] for any other path.
+///
+/// Used as the base of the router in place of [NotFound], so that a file added to `assets/` is served without a
+/// route of its own, and routes always take precedence over it. Files are served through [get_service], which answers
+/// `HEAD` without the body and other methods with `405 Method Not Allowed`, as for the `get` routes.
+pub struct StaticDir;
+
+impl<State, CurrentPathParameters> PathRouterService<State, CurrentPathParameters> for StaticDir {
+    async fn call_request_handler_service<R: Read, W: ResponseWriter<Error = R::Error>>(
+        &self,
+        state: &State,
+        current_path_parameters: CurrentPathParameters,
+        path: Path<'_>,
+        request: Request<'_, R>,
+        response_writer: W,
+    ) -> Result<ResponseSent, W::Error> {
+        let Some(file) = embedded_file(ASSETS, path) else {
+            return NotFound
+                .call_request_handler_service(
+                    state,
+                    current_path_parameters,
+                    path,
+                    request,
+                    response_writer,
+                )
+                .await;
+        };
+
+        get_service(file)
+            .call_method_handler(state, NoPathParameters, request, response_writer)
+            .await
+    }
+}
+
+/// Service serving the files of the [AssetStore] `A`, then [FILES], meant to be nested under `/static`.
+///
+/// Like [StaticDir], files are served through [get_service], so `HEAD` is answered without the body.
+pub struct StaticFiles<A>(PhantomData<fn() -> A>);
+
+impl<A> StaticFiles<A> {
+    pub const fn new() -> Self {
+        Self(PhantomData)
+    }
+}
+
+impl<A> Default for StaticFiles<A> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<State, CurrentPathParameters, A> PathRouterService<State, CurrentPathParameters>
+    for StaticFiles<A>
+where
+    A: AssetStore + FromRef<State>,
+{
+    async fn call_request_handler_service<R: Read, W: ResponseWriter<Error = R::Error>>(
+        &self,
+        state: &State,
+        _current_path_parameters: CurrentPathParameters,
+        path: Path<'_>,
+        request: Request<'_, R>,
+        response_writer: W,
+    ) -> Result<ResponseSent, W::Error> {
+        get_service(StaticFileAt::<A> {
+            path,
+            _assets: PhantomData,
+        })
+        .call_method_handler(state, NoPathParameters, request, response_writer)
+        .await
+    }
+}
+
+/// The file at `path` below `/static`, served by [StaticFiles] for `GET` and `HEAD`.
+struct StaticFileAt<'p, A> {
+    path: Path<'p>,
+    _assets: PhantomData<fn() -> A>,
+}
+
+impl<State, PathParameters, A> RequestHandlerService<State, PathParameters> for StaticFileAt<'_, A>
+where
+    A: AssetStore + FromRef<State>,
+{
+    async fn call_request_handler_service<R: Read, W: ResponseWriter<Error = R::Error>>(
+        &self,
+        state: &State,
+        path_parameters: PathParameters,
+        request: Request<'_, R>,
+        response_writer: W,
+    ) -> Result<ResponseSent, W::Error> {
+        let path = self.path;
+
+        let stored_file = if is_safe_path(path.encoded()) {
+            A::from_ref(state).open(path.encoded()).await
+        } else {
+            None
+        };
+
+        if let Some(file) = stored_file {
+            let name = path.encoded().rsplit('/').next().unwrap_or_default();
+            let extension = name.rsplit_once('.').map_or("", |(_, extension)| extension);
+            let content_type = mime::content_type(extension);
+
+            return Response::ok(StoredFile { file, content_type })
+                .write_to(request.body_connection.finalize().await?, response_writer)
+                .await;
+        }
+
+        let Some(file) = embedded_file(FILES, path) else {
+            return NotFound
+                .call_request_handler_service(
+                    state,
+                    path_parameters,
+                    path,
+                    request,
+                    response_writer,
+                )
+                .await;
+        };
+
+        file.call_request_handler_service(state, path_parameters, request, response_writer)
+            .await
+    }
+}
@@ -0,0 +1,279 @@
+//! The control panel at `/`, rendered from the live state of the board so that it is right before its script runs.
+//!
+//! `index.html` is the template: each `{{name}}` in it is replaced with a value of the board when the page is
+//! requested. The page is written piece by piece, once to count its length and once to send it, so that no buffer
+//! holds it whole.
+
+use core::{fmt::Write as _, time::Duration};
+
+use picoserve::{
+    extract::State,
+    io::{Read, Write},
+    response::{Connection, Content, IntoResponse, Response, StatusCode},
+};
+
+use crate::{
+    button::ButtonStats,
+    rate_limit::MAX_LEDS,
+    sensors::{SensorReading, SensorStats},
+    time::Clock,
+    LedControl,
+};
+
+const TEMPLATE: &str = include_str!("index.html");
+
+/// Address of the board the connection was accepted on, shown by the page.
+///
+/// Part of the application state, and set by each web task for the connection it serves like
+/// [ConnectionId](crate::access_log::ConnectionId).
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LocalAddress(pub Option<core::net::IpAddr>);
+
+#[cfg(feature = "embassy")]
+impl LocalAddress {
+    /// The address `socket` accepted its connection on.
+    pub fn of_socket(socket: &embassy_net::tcp::TcpSocket<'_>) -> Self {
+        // Going through text, as the variants of the address depend on the features of `embassy-net`
+        Self(socket.local_endpoint().and_then(|endpoint| {
+            let mut address = heapless::String::<40>::new();
+            write!(address, "{}", endpoint.addr).ok()?;
+            address.parse().ok()
+        }))
+    }
+}
+
+/// Uptime as `[<days> d ]HH:MM:SS`, the same as `format_uptime` in `index.js`.
+struct Uptime(Duration);
+
+impl core::fmt::Display for Uptime {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let seconds = self.0.as_secs();
+        let days = seconds / 86_400;
+
+        if days > 0 {
+            write!(f, "{days} d ")?;
+        }
+
+        write!(
+            f,
+            "{:02}:{:02}:{:02}",
+            seconds / 3600 % 24,
+            seconds / 60 % 60,
+            seconds % 60
+        )
+    }
+}
+
+/// A piece of the page, either from the template or rendered.
+#[allow(clippy::large_enum_variant)] // Only one is held at a time, on the stack of the worker
+pub(crate) enum Piece {
+    Text(&'static str),
+    /// The longest is the widget of the strip, well below the capacity.
+    Value(heapless::String<512>),
+}
+
+impl Piece {
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        match self {
+            Self::Text(text) => text.as_bytes(),
+            Self::Value(value) => value.as_bytes(),
+        }
+    }
+}
+
+/// An LED as shown by the page.
+struct LedRow {
+    led: u8,
+    on: bool,
+    brightness: Option<u8>,
+}
+
+impl LedRow {
+    /// The row of the LED: its button, its state, and its slider if it can be dimmed.
+    fn render(&self) -> heapless::String<512> {
+        let mut row = heapless::String::new();
+        let (led, state) = (self.led, if self.on { "ON" } else { "OFF" });
+
+        let _ = write!(
+            row,
+            "<p><input type=\"button\" value=\"LED{led}\" onclick=\"toggle_led({led})\" /> \
+             <label id=\"led{led}Label\">{state}</label>",
+        );
+
+        if let Some(brightness) = self.brightness {
+            let _ = write!(
+                row,
+                "<br /><label>LED{led} brightness <input type=\"range\" min=\"0\" max=\"100\" \
+                 value=\"{brightness}\" onchange=\"set_brightness({led}, this.value)\" /></label>",
+            );
+        }
+
+        let _ = row.push_str("</p>\n");
+        row
+    }
+}
+
+/// Everything shown by the page, read when it was requested so that both passes write the same.
+pub(crate) struct StatusPage {
+    leds: heapless::Vec<LedRow, MAX_LEDS>,
+    uptime: Duration,
+    address: LocalAddress,
+    button_presses: u32,
+    sensor: Option<SensorReading>,
+    strip_length: usize,
+    has_buzzer: bool,
+}
+
+impl StatusPage {
+    fn pieces(&self) -> Pieces<'_> {
+        Pieces {
+            page: self,
+            rest: TEMPLATE,
+            next_led: None,
+        }
+    }
+
+    /// The value of `{{name}}`, empty for unknown names.
+    fn value(&self, name: &str) -> heapless::String<512> {
+        let mut value = heapless::String::new();
+
+        let _ = match name {
+            "uptime" => write!(value, "{}", Uptime(self.uptime)),
+            "address" => match self.address.0 {
+                Some(address) => write!(value, "{address}"),
+                None => write!(value, "unknown"),
+            },
+            "button_presses" => write!(value, "{}", self.button_presses),
+            // The same as `format_sensor` in `index.js`
+            "sensor" => match self.sensor {
+                Some(reading) => write!(
+                    value,
+                    "{:.1} °C, {:.1} % humidity",
+                    reading.temperature_c, reading.humidity_percent
+                ),
+                None => write!(value, "No sensor reading yet"),
+            },
+            // Only on boards with a strip
+            "strip" if self.strip_length > 0 => writeln!(
+                value,
+                "<p><label>LED strip ({} pixels) <input type=\"color\" value=\"#ff8000\" \
+                 onchange=\"fill_strip(this.value)\" /></label> \
+                 <select onchange=\"animate_strip(this.value)\"><option value=\"\">No animation</option>\
+                 <option>rainbow</option><option>chase</option><option>breathe</option></select></p>",
+                self.strip_length
+            ),
+            // Only on boards with a buzzer
+            "tone" if self.has_buzzer => writeln!(
+                value,
+                "<p><input type=\"button\" value=\"Beep\" onclick=\"beep()\" /></p>"
+            ),
+            _ => Ok(()),
+        };
+
+        value
+    }
+}
+
+/// The [Piece]s of a [StatusPage] in order, `{{leds}}` giving one row per LED.
+struct Pieces<'a> {
+    page: &'a StatusPage,
+    /// What is left of the template.
+    rest: &'static str,
+    /// Index of the next row in [StatusPage::leds], while in `{{leds}}`.
+    next_led: Option<usize>,
+}
+
+impl Iterator for Pieces<'_> {
+    type Item = Piece;
+
+    fn next(&mut self) -> Option<Piece> {
+        if let Some(index) = self.next_led {
+            match self.page.leds.get(index) {
+                Some(row) => {
+                    self.next_led = Some(index + 1);
+                    return Some(Piece::Value(row.render()));
+                }
+                None => self.next_led = None,
+            }
+        }
+
+        if self.rest.is_empty() {
+            return None;
+        }
+
+        let (text, placeholder) = match self.rest.split_once("{{") {
+            Some((text, _)) if !text.is_empty() => {
+                self.rest = &self.rest[text.len()..];
+                return Some(Piece::Text(text));
+            }
+            Some((_, after)) => after.split_once("}}").unwrap_or((after, "")),
+            None => {
+                let text = self.rest;
+                self.rest = "";
+                return Some(Piece::Text(text));
+            }
+        };
+        self.rest = placeholder;
+
+        if text == "leds" {
+            self.next_led = Some(0);
+            return self.next();
+        }
+
+        Some(Piece::Value(self.page.value(text)))
+    }
+}
+
+impl Content for StatusPage {
+    fn content_type(&self) -> &'static str {
+        "text/html; charset=utf-8"
+    }
+
+    fn content_length(&self) -> usize {
+        self.pieces().map(|piece| piece.as_bytes().len()).sum()
+    }
+
+    async fn write_content<R: Read, W: Write>(
+        self,
+        _connection: Connection<'_, R>,
+        mut writer: W,
+    ) -> Result<(), W::Error> {
+        for piece in self.pieces() {
+            writer.write_all(piece.as_bytes()).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// `GET /`: the control panel, never cached as it changes with the board.
+pub(crate) async fn get_index<C: LedControl, T: Clock>(
+    State(control): State<C>,
+    State(clock): State<T>,
+    State(address): State<LocalAddress>,
+    State(button): State<&'static ButtonStats>,
+    State(sensors): State<&'static SensorStats>,
+) -> impl IntoResponse {
+    let leds = (0..MAX_LEDS as u8)
+        .filter(|&led| control.has_led(led))
+        .map(|led| LedRow {
+            led,
+            on: control.state(led),
+            brightness: control.brightness(led),
+        })
+        .collect();
+
+    Response::new(
+        StatusCode::OK,
+        StatusPage {
+            leds,
+            uptime: clock.uptime(),
+            address,
+            button_presses: button.presses(),
+            sensor: sensors.latest().map(|(reading, _)| reading),
+            strip_length: control.strip_length(),
+            has_buzzer: control.has_buzzer(),
+        },
+    )
+    .with_header("Cache-Control", "no-store")
+}
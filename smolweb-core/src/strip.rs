@@ -0,0 +1,173 @@
+//! WS2812 (NeoPixel) strip set with `POST /api/strip`, either pixel by pixel or to a named [Animation].
+//!
+//! The handler passes a [StripCommand] to [LedControl::set_strip]. Boards with a strip forward it to the task driving
+//! it, which fills the strip with [StripCommand::render] and redraws animations every [FRAME_INTERVAL].
+
+use core::time::Duration;
+
+use picoserve::{
+    extract::State,
+    response::{Json as JsonResponse, StatusCode},
+};
+
+use crate::{auth::RequireAuth, error::ApiError, json::Json, metrics::Metrics, LedControl};
+
+/// Most pixels of a strip, and of a `POST /api/strip` body.
+///
+/// A body setting all of them is about 900 bytes, which leaves room for the headers in the `http` buffer of
+/// [BufferConfig::WEB](crate::buffers::BufferConfig::WEB).
+pub const MAX_STRIP_LENGTH: usize = 64;
+
+/// How often animations are redrawn, fast enough to look smooth.
+pub const FRAME_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Color of a pixel, `[r, g, b]` in JSON.
+pub type Rgb = [u8; 3];
+
+/// Animations played on the strip until another command, with `{"animation":"<name>"}`.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[serde(rename_all = "snake_case")]
+pub enum Animation {
+    /// Every hue along the strip, scrolling.
+    Rainbow,
+    /// A white pixel running along the strip with a fading tail.
+    Chase,
+    /// The whole strip fading in and out in warm white.
+    Breathe,
+}
+
+/// What the strip shows.
+#[derive(Clone, Debug, PartialEq)]
+pub enum StripCommand {
+    /// These pixels, repeated along the strip when there are fewer of them, so that one pixel fills it.
+    Pixels(heapless::Vec<Rgb, MAX_STRIP_LENGTH>),
+    Animation(Animation),
+}
+
+impl StripCommand {
+    /// Whether the strip must be redrawn every [FRAME_INTERVAL].
+    pub fn is_animated(&self) -> bool {
+        matches!(self, Self::Animation(_))
+    }
+
+    /// Fill `strip` as it is shown at `frame`, counted in [FRAME_INTERVAL]s since the command.
+    pub fn render(&self, frame: u32, strip: &mut [Rgb]) {
+        if strip.is_empty() {
+            return;
+        }
+        let length = strip.len() as u32;
+
+        match self {
+            Self::Pixels(pixels) if pixels.is_empty() => strip.fill([0; 3]),
+            Self::Pixels(pixels) => {
+                for (pixel, color) in strip.iter_mut().zip(pixels.iter().cycle()) {
+                    *pixel = *color;
+                }
+            }
+            Self::Animation(Animation::Rainbow) => {
+                for (index, pixel) in (0..).zip(strip.iter_mut()) {
+                    *pixel = wheel((index * 256 / length + frame) as u8);
+                }
+            }
+            Self::Animation(Animation::Chase) => {
+                // Moving one pixel every 3 frames
+                let head = frame / 3 % length;
+                for (index, pixel) in (0..).zip(strip.iter_mut()) {
+                    let behind = (head + length - index) % length;
+                    let level = 255_u32.checked_shr(2 * behind).unwrap_or(0) as u8;
+                    *pixel = [level; 3];
+                }
+            }
+            Self::Animation(Animation::Breathe) => {
+                // A cycle of 4 seconds
+                let phase = frame % 200;
+                let level = if phase < 100 { phase } else { 200 - phase };
+                let scale = |channel: u32| (channel * level * level / 10_000) as u8;
+                strip.fill([scale(255), scale(160), scale(60)]);
+            }
+        }
+    }
+}
+
+/// The color at `position` on a wheel going from red to green to blue and back to red.
+fn wheel(position: u8) -> Rgb {
+    let position = 255 - position;
+    match position {
+        0..=84 => [255 - position * 3, 0, position * 3],
+        85..=169 => {
+            let position = position - 85;
+            [0, position * 3, 255 - position * 3]
+        }
+        _ => {
+            let position = position - 170;
+            [position * 3, 255 - position * 3, 0]
+        }
+    }
+}
+
+/// Body of `POST /api/strip`, either `{"pixels":[[255,0,0],[0,0,255]]}` or `{"animation":"rainbow"}`.
+#[derive(serde::Deserialize)]
+pub(crate) struct StripRequest {
+    #[serde(default)]
+    pixels: Option<heapless::Vec<Rgb, MAX_STRIP_LENGTH>>,
+    #[serde(default)]
+    animation: Option<Animation>,
+}
+
+/// Answer to `POST /api/strip`, e.g. `{"length":30,"animation":"rainbow"}`, the animation being `null` for pixels.
+#[derive(serde::Serialize)]
+pub(crate) struct StripStatus {
+    length: usize,
+    animation: Option<Animation>,
+}
+
+/// `POST /api/strip`: set the pixels of the strip, or play an animation on it.
+pub(crate) async fn set_strip<C: LedControl>(
+    _: RequireAuth,
+    State(control): State<C>,
+    State(metrics): State<&'static Metrics>,
+    Json(request): Json<StripRequest>,
+) -> Result<JsonResponse<StripStatus>, ApiError> {
+    metrics.count_led();
+
+    let length = control.strip_length();
+    if length == 0 {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "This board has no LED strip",
+        ));
+    }
+
+    let command = match request {
+        StripRequest {
+            pixels: Some(pixels),
+            animation: None,
+        } => StripCommand::Pixels(pixels),
+        StripRequest {
+            pixels: None,
+            animation: Some(animation),
+        } => StripCommand::Animation(animation),
+        _ => {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "Give either pixels or an animation",
+            ))
+        }
+    };
+
+    let animation = match command {
+        StripCommand::Animation(animation) => Some(animation),
+        StripCommand::Pixels(_) => None,
+    };
+
+    if !control.set_strip(command) {
+        return Err(ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "The strip is busy, try again",
+        ));
+    }
+
+    log_debug!("Strip set, animation {:?}", animation);
+    Ok(JsonResponse(StripStatus { length, animation }))
+}
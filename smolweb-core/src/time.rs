@@ -0,0 +1,44 @@
+use core::{fmt, time::Duration};
+
+/// Source of the current wall-clock time.
+pub trait Clock {
+    /// Seconds since the Unix epoch, or `None` until the clock has been set.
+    fn unix_time(&self) -> Option<u64>;
+
+    /// Time since boot, or since the process started on hosted targets.
+    fn uptime(&self) -> Duration;
+}
+
+/// Formats a Unix timestamp as an ISO-8601 UTC date and time, e.g. `2024-05-01T12:34:56Z`.
+pub struct Iso8601(pub u64);
+
+impl fmt::Display for Iso8601 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let days = self.0 / 86400;
+        let seconds_of_day = self.0 % 86400;
+
+        // Civil date from days since 1970-01-01, see http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+        let days = days + 719468;
+        let era = days / 146097;
+        let day_of_era = days % 146097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let shifted_month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+        let month = if shifted_month < 10 {
+            shifted_month + 3
+        } else {
+            shifted_month - 9
+        };
+        let year = year_of_era + era * 400 + u64::from(month <= 2);
+
+        write!(
+            f,
+            "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+            seconds_of_day / 3600,
+            seconds_of_day / 60 % 60,
+            seconds_of_day % 60,
+        )
+    }
+}
@@ -0,0 +1,90 @@
+//! Tones played on the buzzer of the board with `POST /api/tone`, for audible feedback.
+//!
+//! The handler passes a [Tone] to [LedControl::play_tone]. Boards with a buzzer forward it to the task driving it,
+//! which plays a square wave of half a [Tone::period] high and half low, a new tone cutting short the one playing.
+
+use core::time::Duration;
+
+use picoserve::{
+    extract::State,
+    response::{Json as JsonResponse, StatusCode},
+};
+
+use crate::{auth::RequireAuth, error::ApiError, json::Json, LedControl};
+
+/// Lowest frequency played, below which a piezo buzzer only clicks.
+pub const MIN_FREQUENCY_HZ: u16 = 20;
+
+/// Highest frequency played, about the end of hearing.
+pub const MAX_FREQUENCY_HZ: u16 = 20_000;
+
+/// Longest tone, so that a request can't keep the board beeping.
+pub const MAX_DURATION_MS: u16 = 5_000;
+
+/// A tone, e.g. `{"frequency_hz":440,"duration_ms":200}`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Tone {
+    pub frequency_hz: u16,
+    pub duration_ms: u16,
+}
+
+impl Tone {
+    /// The short beep of the button of the page.
+    pub const BEEP: Self = Self {
+        frequency_hz: 2_000,
+        duration_ms: 100,
+    };
+
+    /// Why the tone can't be played, as the body of a `400 Bad Request`, or `None` if it can.
+    pub fn error(&self) -> Option<&'static str> {
+        if !(MIN_FREQUENCY_HZ..=MAX_FREQUENCY_HZ).contains(&self.frequency_hz) {
+            Some("frequency_hz must be between 20 and 20000")
+        } else if !(1..=MAX_DURATION_MS).contains(&self.duration_ms) {
+            Some("duration_ms must be between 1 and 5000")
+        } else {
+            None
+        }
+    }
+
+    /// Duration of one cycle of the square wave.
+    pub fn period(&self) -> Duration {
+        Duration::from_micros(1_000_000 / u64::from(self.frequency_hz.max(1)))
+    }
+
+    pub fn duration(&self) -> Duration {
+        Duration::from_millis(self.duration_ms.into())
+    }
+}
+
+/// `POST /api/tone`: play a tone on the buzzer, answered with the tone.
+pub(crate) async fn play_tone<C: LedControl>(
+    _: RequireAuth,
+    State(control): State<C>,
+    Json(tone): Json<Tone>,
+) -> Result<JsonResponse<Tone>, ApiError> {
+    if !control.has_buzzer() {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "This board has no buzzer",
+        ));
+    }
+
+    if let Some(error) = tone.error() {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, error));
+    }
+
+    if !control.play_tone(tone) {
+        return Err(ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "The buzzer is busy, try again",
+        ));
+    }
+
+    log_debug!(
+        "Playing {} Hz for {} ms",
+        tone.frequency_hz,
+        tone.duration_ms
+    );
+    Ok(JsonResponse(tone))
+}
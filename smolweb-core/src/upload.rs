@@ -0,0 +1,422 @@
+//! `POST /api/upload`: a file written into the [AssetStore], so that the files served under `/static` can be replaced
+//! over the network without flashing the board.
+//!
+//! The file is either the raw body, named by an `X-Filename` header, e.g.
+//! `curl -u admin:smolweb -H 'X-Filename: css/theme.css' --data-binary @theme.css http://<ip>:8080/api/upload`, or
+//! the first part of a `multipart/form-data` body, named by its `filename`, as sent by an HTML form or
+//! `curl -F file=@theme.css`. Only files of [ALLOWED_EXTENSIONS] up to [MAX_UPLOAD_SIZE] are taken, one upload at a
+//! time, and a file whose upload fails is removed rather than served cut short.
+
+use core::{fmt::Write as _, marker::PhantomData, sync::atomic::Ordering};
+
+use picoserve::{
+    extract::{FromRef, FromRequestParts},
+    io::Read,
+    request::Request,
+    response::{IntoResponse, Json as JsonResponse, ResponseWriter, StatusCode},
+    routing::RequestHandlerService,
+    ResponseSent,
+};
+use portable_atomic::AtomicBool;
+
+use crate::{
+    assets::{is_safe_path, AssetStore},
+    auth::{Credentials, RequireAuth},
+    error::ApiError,
+    limits::{CutShort, Limits},
+    rate_limit::{ClientAddress, ClientRateLimit},
+    session::SessionContext,
+    static_files::trim,
+};
+
+/// Largest file taken, which the SD card holds many times over and the board receives in a few seconds.
+pub const MAX_UPLOAD_SIZE: usize = 256 * 1024;
+
+/// Extensions of the files taken, those of a web UI, so that nothing else is written to the store.
+pub const ALLOWED_EXTENSIONS: [&str; 12] = [
+    "html", "css", "js", "json", "txt", "svg", "png", "jpg", "jpeg", "gif", "ico", "woff2",
+];
+
+/// Longest path of a file below `/static`.
+const MAX_PATH_LEN: usize = 64;
+
+/// Bytes of a multipart body besides the file, its boundaries and the headers of its part.
+const MULTIPART_OVERHEAD: usize = 1024;
+
+/// Longest boundary of a multipart body, as allowed by RFC 2046.
+const MAX_BOUNDARY_LEN: usize = 70;
+
+/// Bytes read from the body at once, which also holds the headers of the part of a multipart body.
+const BUFFER_SIZE: usize = 512;
+
+/// Lets one upload in at a time, e.g. so that two uploads don't write the same file, or the same flash, at once.
+pub struct UploadLock(AtomicBool);
+
+impl UploadLock {
+    pub const fn new() -> Self {
+        Self(AtomicBool::new(false))
+    }
+
+    /// Start an upload, or return `None` while another one is in progress.
+    pub fn acquire(&self) -> Option<UploadGuard<'_>> {
+        (!self.0.swap(true, Ordering::Acquire)).then_some(UploadGuard(self))
+    }
+}
+
+impl Default for UploadLock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// An upload in progress, which ends when dropped, even if the connection fails halfway.
+pub struct UploadGuard<'a>(&'a UploadLock);
+
+impl Drop for UploadGuard<'_> {
+    fn drop(&mut self) {
+        self.0 .0.store(false, Ordering::Release);
+    }
+}
+
+/// Held while a file is being received.
+static FILE_UPLOAD: UploadLock = UploadLock::new();
+
+/// Answer to `POST /api/upload`, e.g. `{"path":"/static/css/theme.css","size":1234}`.
+#[derive(serde::Serialize)]
+pub struct Uploaded {
+    path: heapless::String<{ MAX_PATH_LEN + 7 }>,
+    size: usize,
+}
+
+/// The path in the store of the file `name`, e.g. `/css/theme.css` for `css/theme.css`, if it may be written.
+fn store_path(name: &str) -> Option<heapless::String<MAX_PATH_LEN>> {
+    let mut path = heapless::String::new();
+    write!(path, "/{}", name.trim_start_matches('/')).ok()?;
+
+    let extension = path.rsplit_once('.').map(|(_, extension)| extension)?;
+    (is_safe_path(&path) && ALLOWED_EXTENSIONS.contains(&extension)).then_some(path)
+}
+
+/// The first index of `needle` in `haystack`.
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// The boundary of a `multipart/form-data` content type, e.g. `multipart/form-data; boundary=XyZ`.
+fn multipart_boundary(content_type: &[u8]) -> Option<&[u8]> {
+    const MULTIPART: &[u8] = b"multipart/form-data;";
+
+    if content_type.len() < MULTIPART.len()
+        || !content_type[..MULTIPART.len()].eq_ignore_ascii_case(MULTIPART)
+    {
+        return None;
+    }
+
+    content_type[MULTIPART.len()..]
+        .split(|&b| b == b';')
+        .map(trim)
+        .find_map(|parameter| {
+            let (name, value) = parameter.split_at(parameter.iter().position(|&b| b == b'=')?);
+            name.eq_ignore_ascii_case(b"boundary")
+                .then_some(&value[1..])
+        })
+        .map(|boundary| {
+            boundary
+                .strip_prefix(b"\"")
+                .and_then(|boundary| boundary.strip_suffix(b"\""))
+                .unwrap_or(boundary)
+        })
+        .filter(|boundary| (1..=MAX_BOUNDARY_LEN).contains(&boundary.len()))
+}
+
+/// The `filename` of the `Content-Disposition` among the headers of a part.
+fn part_filename(headers: &[u8]) -> Option<&str> {
+    const DISPOSITION: &[u8] = b"content-disposition:";
+
+    let disposition = headers.split(|&b| b == b'\n').map(trim).find(|line| {
+        line.len() > DISPOSITION.len()
+            && line[..DISPOSITION.len()].eq_ignore_ascii_case(DISPOSITION)
+    })?;
+
+    let filename = disposition
+        .split(|&b| b == b';')
+        .map(trim)
+        .find_map(|parameter| parameter.strip_prefix(b"filename="))?;
+    let filename = filename
+        .strip_prefix(b"\"")
+        .and_then(|filename| filename.strip_suffix(b"\""))
+        .unwrap_or(filename);
+
+    core::str::from_utf8(filename).ok()
+}
+
+/// Refused or failed uploads, as JSON like the rest of `/api`.
+type UploadResult<T> = Result<T, ApiError>;
+
+/// Write the body read from `reader` to `path` in the store, starting with the `filled` bytes of `buffer` read
+/// already, up to `end` if given, which must then be found, or to the end of the body. Returns the size of the file.
+async fn copy<A: AssetStore, R: Read>(
+    store: &A,
+    path: &str,
+    reader: &mut R,
+    buffer: &mut [u8; BUFFER_SIZE],
+    mut filled: usize,
+    end: Option<&[u8]>,
+) -> Result<UploadResult<usize>, R::Error> {
+    let write_error = ApiError::new(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Failed to write the file",
+    );
+
+    // Creates the file, or empties it, even if nothing is written to it
+    if !store.write(path, 0, &[]).await {
+        return Ok(Err(write_error));
+    }
+
+    let mut size = 0;
+
+    loop {
+        // The headers of a multipart body may have left the buffer full
+        let ended = filled < BUFFER_SIZE && {
+            let read = reader.read(&mut buffer[filled..]).await?;
+            filled += read;
+            read == 0
+        };
+
+        let found = end.and_then(|end| find(&buffer[..filled], end));
+        let length = match (found, end) {
+            (Some(index), _) => index,
+            (None, Some(_)) if ended => {
+                return Ok(Err(ApiError::new(
+                    StatusCode::BAD_REQUEST,
+                    "The multipart body ends without its boundary",
+                )));
+            }
+            // What could be the start of `end` is kept for the next read
+            (None, Some(end)) => filled.saturating_sub(end.len() - 1),
+            (None, None) => filled,
+        };
+
+        if size + length > MAX_UPLOAD_SIZE {
+            return Ok(Err(ApiError::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "The file is larger than 256 KiB",
+            )));
+        }
+
+        if length > 0 && !store.write(path, size, &buffer[..length]).await {
+            return Ok(Err(write_error));
+        }
+        size += length;
+
+        if found.is_some() || ended {
+            return Ok(Ok(size));
+        }
+
+        buffer.copy_within(length..filled, 0);
+        filled -= length;
+    }
+}
+
+/// Receive the file of `request` into the store.
+async fn receive<A: AssetStore, R: Read>(
+    store: &A,
+    request: &mut Request<'_, R>,
+) -> Result<UploadResult<Uploaded>, R::Error> {
+    let length = request.body_connection.content_length();
+    let headers = request.parts.headers();
+    let content_type = headers
+        .get("Content-Type")
+        .map_or(&[][..], |content_type| content_type.as_raw());
+
+    let mut boundary = heapless::Vec::<u8, { MAX_BOUNDARY_LEN + 4 }>::new();
+    let mut path = None;
+
+    match multipart_boundary(content_type) {
+        Some(multipart) => {
+            if length > MAX_UPLOAD_SIZE + MULTIPART_OVERHEAD {
+                return Ok(Err(ApiError::new(
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    "The file is larger than 256 KiB",
+                )));
+            }
+            // The delimiter ending the file
+            let _ = boundary.extend_from_slice(b"\r\n--");
+            let _ = boundary.extend_from_slice(multipart);
+        }
+        None => {
+            if length > MAX_UPLOAD_SIZE {
+                return Ok(Err(ApiError::new(
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    "The file is larger than 256 KiB",
+                )));
+            }
+
+            let Some(name) = headers
+                .get("X-Filename")
+                .and_then(|name| core::str::from_utf8(name.as_raw()).ok())
+            else {
+                return Ok(Err(ApiError::new(
+                    StatusCode::BAD_REQUEST,
+                    "Name the file with X-Filename, or send it as multipart/form-data",
+                )));
+            };
+            path = Some(store_path(name));
+        }
+    }
+
+    if length == 0 {
+        return Ok(Err(ApiError::new(
+            StatusCode::LENGTH_REQUIRED,
+            "The file must be sent with a Content-Length",
+        )));
+    }
+
+    let mut reader = request.body_connection.body().reader();
+    let mut buffer = [0; BUFFER_SIZE];
+    let mut filled = 0;
+
+    // The headers of the first part, which must be the file, up to the blank line ending them
+    if path.is_none() {
+        let headers_end = loop {
+            if let Some(index) = find(&buffer[..filled], b"\r\n\r\n") {
+                break index;
+            }
+
+            let read = reader.read(&mut buffer[filled..]).await?;
+            if read == 0 {
+                return Ok(Err(ApiError::new(
+                    StatusCode::BAD_REQUEST,
+                    "The part of the file is missing, or its headers are too long",
+                )));
+            }
+            filled += read;
+        };
+
+        let delimiter = &boundary[2..];
+        if !buffer.starts_with(delimiter) {
+            return Ok(Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "The multipart body doesn't start with its boundary",
+            )));
+        }
+
+        let Some(name) = part_filename(&buffer[delimiter.len()..headers_end]) else {
+            return Ok(Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "The first part of the multipart body must be a file with a filename",
+            )));
+        };
+        path = Some(store_path(name));
+
+        buffer.copy_within(headers_end + 4..filled, 0);
+        filled -= headers_end + 4;
+    }
+
+    let Some(Some(path)) = path else {
+        return Ok(Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "The file must have a plain name with the extension of a web file",
+        )));
+    };
+
+    log_info!("Receiving {} into the asset store", path.as_str());
+
+    let end = (!boundary.is_empty()).then_some(&boundary[..]);
+    let result = match copy(store, &path, &mut reader, &mut buffer, filled, end).await {
+        Ok(Ok(size)) if end.is_none() && size != length => Ok(Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "The file is shorter than its Content-Length",
+        ))),
+        result => result,
+    };
+
+    if !matches!(result, Ok(Ok(_))) {
+        log_warn!("Upload of {} failed, removing it", path.as_str());
+        if !store.remove(&path).await {
+            log_warn!("Failed to remove {}", path.as_str());
+        }
+    }
+
+    Ok(result?.map(|size| {
+        let mut static_path = heapless::String::new();
+        let _ = write!(static_path, "/static{}", path);
+        Uploaded {
+            path: static_path,
+            size,
+        }
+    }))
+}
+
+/// Handler of `POST /api/upload`, writing the file of the request into the [AssetStore] `A`.
+pub struct AssetUpload<A>(PhantomData<fn() -> A>);
+
+impl<A> AssetUpload<A> {
+    pub const fn new() -> Self {
+        Self(PhantomData)
+    }
+}
+
+impl<A> Default for AssetUpload<A> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<State, A> RequestHandlerService<State, ()> for AssetUpload<A>
+where
+    A: AssetStore + FromRef<State>,
+    Credentials: FromRef<State>,
+    SessionContext: FromRef<State>,
+    &'static ClientRateLimit: FromRef<State>,
+    ClientAddress: FromRef<State>,
+    Limits: FromRef<State>,
+    CutShort: FromRef<State>,
+{
+    async fn call_request_handler_service<R: Read, W: ResponseWriter<Error = R::Error>>(
+        &self,
+        state: &State,
+        _path_parameters: (),
+        mut request: Request<'_, R>,
+        response_writer: W,
+    ) -> Result<ResponseSent, W::Error> {
+        let result = match RequireAuth::from_request_parts(state, &request.parts).await {
+            Err(rejection) => {
+                return rejection
+                    .write_to(request.body_connection.finalize().await?, response_writer)
+                    .await;
+            }
+            Ok(RequireAuth) => {
+                let store = A::from_ref(state);
+
+                if !store.is_writable() {
+                    Err(ApiError::new(
+                        StatusCode::SERVICE_UNAVAILABLE,
+                        "This board has no writable asset store",
+                    ))
+                } else {
+                    match FILE_UPLOAD.acquire() {
+                        Some(_guard) => receive(&store, &mut request).await?,
+                        None => Err(ApiError::new(
+                            StatusCode::CONFLICT,
+                            "Another upload is in progress",
+                        )),
+                    }
+                }
+            }
+        };
+
+        let connection = request.body_connection.finalize().await?;
+
+        match result {
+            Ok(uploaded) => {
+                JsonResponse(uploaded)
+                    .write_to(connection, response_writer)
+                    .await
+            }
+            Err(err) => err.write_to(connection, response_writer).await,
+        }
+    }
+}
@@ -0,0 +1,198 @@
+//! Statistics of each web worker, the web tasks of the boards, served by `GET /api/workers`.
+//!
+//! [Metrics::open_connection](crate::metrics::Metrics::open_connection) counts each connection a worker accepts, and
+//! [OpenConnection::count_bytes](crate::metrics::OpenConnection::count_bytes) wraps its socket to count what goes
+//! through it.
+
+use core::sync::atomic::Ordering;
+
+use embedded_io_async::ErrorType;
+use picoserve::{
+    extract::State,
+    io::{Read, Socket, Write},
+    response::Json,
+    Error, Timeouts, Timer,
+};
+use portable_atomic::AtomicU32;
+
+use crate::{
+    auth::RequireAuth,
+    metrics::{Metrics, MAX_WORKERS},
+};
+
+/// Counters of one worker. The totals wrap at 4 GiB, as 64 bit atomics aren't available on every target.
+pub(crate) struct WorkerCounters {
+    /// Connections open right now, more than one only on the tokio demo, whose connections all count as worker 0.
+    pub(crate) open: AtomicU32,
+    pub(crate) accepted: AtomicU32,
+    /// Requests of the connections closed so far, as picoserve only tells how many there were at the end.
+    pub(crate) requests: AtomicU32,
+    pub(crate) bytes_in: AtomicU32,
+    pub(crate) bytes_out: AtomicU32,
+}
+
+impl WorkerCounters {
+    pub(crate) const fn new() -> Self {
+        Self {
+            open: AtomicU32::new(0),
+            accepted: AtomicU32::new(0),
+            requests: AtomicU32::new(0),
+            bytes_in: AtomicU32::new(0),
+            bytes_out: AtomicU32::new(0),
+        }
+    }
+}
+
+/// What a worker is doing.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WorkerState {
+    /// Waiting for a connection, or for the network to come up.
+    Idle,
+    /// Serving a connection.
+    Serving,
+}
+
+/// One worker in `GET /api/workers`, e.g.
+/// `{"worker":0,"state":"serving","accepted":12,"requests":40,"bytes_in":5120,"bytes_out":90000}`.
+#[derive(serde::Serialize)]
+pub struct WorkerStatus {
+    pub worker: usize,
+    pub state: WorkerState,
+    pub accepted: u32,
+    pub requests: u32,
+    pub bytes_in: u32,
+    pub bytes_out: u32,
+}
+
+impl WorkerStatus {
+    fn read(worker: usize, counters: &WorkerCounters) -> Self {
+        let load = |counter: &AtomicU32| counter.load(Ordering::Relaxed);
+
+        Self {
+            worker,
+            state: if load(&counters.open) > 0 {
+                WorkerState::Serving
+            } else {
+                WorkerState::Idle
+            },
+            accepted: load(&counters.accepted),
+            requests: load(&counters.requests),
+            bytes_in: load(&counters.bytes_in),
+            bytes_out: load(&counters.bytes_out),
+        }
+    }
+}
+
+/// `GET /api/workers`: the statistics of every worker started with [Metrics::start_worker].
+pub(crate) async fn get_workers(
+    _: RequireAuth,
+    State(metrics): State<&'static Metrics>,
+) -> Json<heapless::Vec<WorkerStatus, MAX_WORKERS>> {
+    Json(
+        metrics
+            .worker_counters()
+            .iter()
+            .enumerate()
+            .map(|(worker, counters)| WorkerStatus::read(worker, counters))
+            .collect(),
+    )
+}
+
+/// One half of a [CountBytes] socket, adding what it reads or writes to a counter.
+pub struct CountedHalf<'a, H> {
+    half: H,
+    bytes: Option<&'a AtomicU32>,
+}
+
+impl<H> CountedHalf<'_, H> {
+    fn count(&self, bytes: usize) {
+        if let Some(counter) = self.bytes {
+            counter.fetch_add(bytes as u32, Ordering::Relaxed);
+        }
+    }
+}
+
+impl<H: ErrorType> ErrorType for CountedHalf<'_, H> {
+    type Error = H::Error;
+}
+
+impl<H: Read> Read for CountedHalf<'_, H> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let read = self.half.read(buf).await?;
+        self.count(read);
+        Ok(read)
+    }
+}
+
+impl<H: Write> Write for CountedHalf<'_, H> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        let written = self.half.write(buf).await?;
+        self.count(written);
+        Ok(written)
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self.half.flush().await
+    }
+}
+
+/// Socket served by picoserve in place of `S`, counting the bytes in and out of a worker.
+pub struct CountBytes<'a, S> {
+    socket: S,
+    bytes_in: Option<&'a AtomicU32>,
+    bytes_out: Option<&'a AtomicU32>,
+}
+
+impl<'a, S> CountBytes<'a, S> {
+    pub(crate) fn new(socket: S, counters: Option<&'a WorkerCounters>) -> Self {
+        Self {
+            socket,
+            bytes_in: counters.map(|counters| &counters.bytes_in),
+            bytes_out: counters.map(|counters| &counters.bytes_out),
+        }
+    }
+}
+
+impl<S: Socket> Socket for CountBytes<'_, S> {
+    type Error = S::Error;
+    type ReadHalf<'b>
+        = CountedHalf<'b, S::ReadHalf<'b>>
+    where
+        Self: 'b;
+    type WriteHalf<'b>
+        = CountedHalf<'b, S::WriteHalf<'b>>
+    where
+        Self: 'b;
+
+    fn split(&mut self) -> (Self::ReadHalf<'_>, Self::WriteHalf<'_>) {
+        let (read_half, write_half) = self.socket.split();
+
+        (
+            CountedHalf {
+                half: read_half,
+                bytes: self.bytes_in,
+            },
+            CountedHalf {
+                half: write_half,
+                bytes: self.bytes_out,
+            },
+        )
+    }
+
+    async fn abort<T: Timer>(
+        self,
+        timeouts: &Timeouts<T::Duration>,
+        timer: &mut T,
+    ) -> Result<(), Error<Self::Error>> {
+        self.socket.abort(timeouts, timer).await
+    }
+
+    async fn shutdown<T: Timer>(
+        self,
+        timeouts: &Timeouts<T::Duration>,
+        timer: &mut T,
+    ) -> Result<(), Error<Self::Error>> {
+        self.socket.shutdown(timeouts, timer).await
+    }
+}
@@ -0,0 +1,63 @@
+use embassy_futures::select::{select, Either};
+use picoserve::{
+    io::{Read, Write},
+    response::ws::{Message, SocketRx, SocketTx, WebSocketCallback},
+};
+
+use crate::{
+    events::{led_json, BoardEvent, EventSubscriber, LedChange},
+    rate_limit::MAX_LEDS,
+    LedControl,
+};
+
+/// WebSocket callback sending the state of every LED, then each [LedChange] as it happens.
+pub(crate) struct LedUpdates<C, S> {
+    pub(crate) control: C,
+    pub(crate) subscriber: S,
+}
+
+impl<C: LedControl, S: EventSubscriber> WebSocketCallback for LedUpdates<C, S> {
+    async fn run<R: Read, W: Write<Error = R::Error>>(
+        mut self,
+        mut rx: SocketRx<R>,
+        mut tx: SocketTx<W>,
+    ) -> Result<(), W::Error> {
+        log_debug!("WebSocket opened");
+
+        for led in (0..MAX_LEDS as u8).filter(|&led| self.control.has_led(led)) {
+            let on = self.control.state(led);
+            tx.send_text(&led_json(LedChange { led, on })).await?;
+        }
+
+        // The page never sends anything, so the messages are only read to notice the socket closing
+        let mut buffer = [0; 128];
+        let receive = async {
+            loop {
+                match rx.next_message(&mut buffer).await {
+                    Ok(Message::Close(_)) | Err(_) => break,
+                    Ok(_) => continue,
+                }
+            }
+        };
+
+        let send = async {
+            loop {
+                // Other events are only sent to `/events`
+                let BoardEvent::Led(change) = self.subscriber.next_event().await else {
+                    continue;
+                };
+                if let Err(err) = tx.send_text(&led_json(change)).await {
+                    break err;
+                }
+            }
+        };
+
+        match select(receive, send).await {
+            Either::First(()) => (),
+            Either::Second(err) => return Err(err),
+        }
+
+        log_debug!("WebSocket closed");
+        tx.close(None).await
+    }
+}
@@ -0,0 +1,97 @@
+//! Assets read from [Config::assets_dir](crate::Config::assets_dir) on the host, and written there by `/api/upload`.
+
+use std::{
+    io::SeekFrom,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use log::warn;
+use smolweb_core::assets::{AssetFile, AssetStore};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+/// Serves the files below a directory, or none if there is no directory.
+#[derive(Clone)]
+pub(crate) struct DirectoryAssets(pub(crate) Option<Arc<Path>>);
+
+impl AssetStore for DirectoryAssets {
+    type File = DirectoryFile;
+
+    async fn open(&self, path: &str) -> Option<DirectoryFile> {
+        let dir = self.0.as_ref()?;
+        let file = tokio::fs::File::open(dir.join(path.trim_start_matches('/')))
+            .await
+            .ok()?;
+        let metadata = file.metadata().await.ok()?;
+
+        metadata.is_file().then(|| DirectoryFile {
+            file,
+            size: metadata.len() as usize,
+        })
+    }
+
+    fn is_writable(&self) -> bool {
+        self.0.is_some()
+    }
+
+    async fn write(&self, path: &str, offset: usize, bytes: &[u8]) -> bool {
+        let Some(path) = self.file_path(path) else {
+            return false;
+        };
+
+        write_file(&path, offset, bytes)
+            .await
+            .inspect_err(|err| warn!("Failed to write {}: {err}", path.display()))
+            .is_ok()
+    }
+
+    async fn remove(&self, path: &str) -> bool {
+        let Some(path) = self.file_path(path) else {
+            return false;
+        };
+
+        tokio::fs::remove_file(&path)
+            .await
+            .inspect_err(|err| warn!("Failed to remove {}: {err}", path.display()))
+            .is_ok()
+    }
+}
+
+impl DirectoryAssets {
+    /// Where the file at `path` below `/static` is on the host.
+    fn file_path(&self, path: &str) -> Option<PathBuf> {
+        Some(self.0.as_ref()?.join(path.trim_start_matches('/')))
+    }
+}
+
+/// Write `bytes` at `offset` of the file at `path`, creating it and its directories or emptying it at offset 0.
+async fn write_file(path: &Path, offset: usize, bytes: &[u8]) -> std::io::Result<()> {
+    let mut file = if offset == 0 {
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::File::create(path).await?
+    } else {
+        let mut file = tokio::fs::OpenOptions::new().write(true).open(path).await?;
+        file.seek(SeekFrom::Start(offset as u64)).await?;
+        file
+    };
+
+    file.write_all(bytes).await?;
+    file.flush().await
+}
+
+pub(crate) struct DirectoryFile {
+    file: tokio::fs::File,
+    size: usize,
+}
+
+impl AssetFile for DirectoryFile {
+    fn size(&self) -> usize {
+        self.size
+    }
+
+    async fn read(&mut self, buffer: &mut [u8]) -> Option<usize> {
+        self.file.read(buffer).await.ok()
+    }
+}
@@ -0,0 +1,370 @@
+use std::{
+    collections::BTreeMap,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use clap::Parser;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+
+/// Load a running demo, the tokio one or a board, with concurrent keep-alive connections, and report the latency of
+/// its responses.
+///
+/// Each connection sends the requests of the mix in turn, waiting for each response before sending the next, and
+/// reconnects when the server closes it. Streaming routes, like `/ws`, `/events` and `/logs`, never finish their
+/// response, so don't belong in the mix.
+#[derive(Parser)]
+#[command(version)]
+struct Args {
+    /// Address of the demo, as host:port
+    #[arg(default_value = "127.0.0.1:8080")]
+    address: String,
+
+    /// Concurrent connections
+    #[arg(long, short, default_value_t = 4)]
+    connections: usize,
+
+    /// Seconds to send requests for
+    #[arg(long, default_value_t = 10)]
+    seconds: u64,
+
+    /// Stop after this many requests over all connections, before the end of `--seconds`
+    #[arg(long)]
+    requests: Option<u64>,
+
+    /// A request of the mix, as "METHOD PATH" or "METHOD PATH BODY" with a JSON body, repeated for each request
+    #[arg(long = "request", short, default_values = ["GET /", "GET /api/leds", "GET /api/sysinfo"])]
+    mix: Vec<Mix>,
+
+    /// Credentials sent with every request, as user:password, for the routes behind authentication
+    #[arg(long, short, env = "SMOLWEB_BENCH_USER")]
+    user: Option<String>,
+
+    /// Seconds to wait for a response before counting a timeout and reconnecting
+    #[arg(long, default_value_t = 5)]
+    timeout: u64,
+}
+
+/// A request of the mix.
+#[derive(Clone, Debug)]
+struct Mix {
+    method: String,
+    path: String,
+    body: Option<String>,
+}
+
+impl FromStr for Mix {
+    type Err = String;
+
+    fn from_str(request: &str) -> Result<Self, Self::Err> {
+        let mut parts = request.splitn(3, ' ');
+        let method = parts.next().unwrap_or_default().to_ascii_uppercase();
+        let path = parts.next().unwrap_or_default();
+
+        if method.is_empty() || !path.starts_with('/') {
+            return Err(format!("{request:?} isn't \"METHOD /path\""));
+        }
+
+        Ok(Self {
+            method,
+            path: path.into(),
+            body: parts.next().map(Into::into),
+        })
+    }
+}
+
+impl std::fmt::Display for Mix {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.method, self.path)?;
+        if let Some(body) = &self.body {
+            write!(f, " {body}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Mix {
+    fn encode(&self, host: &str, authorization: Option<&str>) -> Vec<u8> {
+        let mut request = format!("{} {} HTTP/1.1\r\nHost: {host}\r\n", self.method, self.path);
+        if let Some(authorization) = authorization {
+            request += &format!("Authorization: Basic {authorization}\r\n");
+        }
+        match &self.body {
+            Some(body) => {
+                request += &format!(
+                    "Content-Type: application/json\r\nContent-Length: {}\r\n\r\n{body}",
+                    body.len()
+                )
+            }
+            None => request += "\r\n",
+        }
+        request.into_bytes()
+    }
+}
+
+/// What the connections saw, merged at the end.
+#[derive(Default)]
+struct Tally {
+    latencies: Vec<Duration>,
+    statuses: BTreeMap<u16, u64>,
+    connect_errors: u64,
+    timeouts: u64,
+    io_errors: u64,
+    connections: u64,
+}
+
+impl Tally {
+    fn merge(&mut self, other: Tally) {
+        self.latencies.extend(other.latencies);
+        for (status, count) in other.statuses {
+            *self.statuses.entry(status).or_default() += count;
+        }
+        self.connect_errors += other.connect_errors;
+        self.timeouts += other.timeouts;
+        self.io_errors += other.io_errors;
+        self.connections += other.connections;
+    }
+}
+
+/// Read a response to its end, returning its status and whether the connection can be reused.
+async fn read_response(reader: &mut BufReader<TcpStream>) -> anyhow::Result<(u16, bool)> {
+    let mut line = String::new();
+    if reader.read_line(&mut line).await? == 0 {
+        anyhow::bail!("Connection closed before the response");
+    }
+    // picoserve sends no reason phrase, so the status ends the line
+    let status = line
+        .trim_end()
+        .split(' ')
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .with_context(|| format!("Bad status line {line:?}"))?;
+
+    let mut content_length = None;
+    let mut chunked = false;
+    let mut keep_alive = true;
+
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).await? == 0 {
+            anyhow::bail!("Connection closed in the headers");
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let Some((name, value)) = header.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("Content-Length") {
+            content_length = Some(value.parse::<u64>()?);
+        } else if name.eq_ignore_ascii_case("Transfer-Encoding") {
+            chunked = value.eq_ignore_ascii_case("chunked");
+        } else if name.eq_ignore_ascii_case("Connection") {
+            keep_alive = !value.eq_ignore_ascii_case("close");
+        }
+    }
+
+    if chunked {
+        loop {
+            line.clear();
+            reader.read_line(&mut line).await?;
+            let size = u64::from_str_radix(line.trim_end().split(';').next().unwrap_or(""), 16)
+                .with_context(|| format!("Bad chunk size {line:?}"))?;
+            // The chunk and its CRLF, or the CRLF ending the body
+            tokio::io::copy(&mut (&mut *reader).take(size + 2), &mut tokio::io::sink()).await?;
+            if size == 0 {
+                break;
+            }
+        }
+    } else if let Some(length) = content_length {
+        tokio::io::copy(&mut (&mut *reader).take(length), &mut tokio::io::sink()).await?;
+    } else {
+        // The body ends with the connection
+        tokio::io::copy(reader, &mut tokio::io::sink()).await?;
+        keep_alive = false;
+    }
+
+    Ok((status, keep_alive))
+}
+
+/// Send the mix over one connection at a time until `deadline`, or until `budget` runs out.
+async fn connection(
+    address: String,
+    requests: Vec<Vec<u8>>,
+    timeout: Duration,
+    deadline: Instant,
+    budget: std::sync::Arc<std::sync::atomic::AtomicI64>,
+) -> Tally {
+    let mut tally = Tally::default();
+    let mut next = 0;
+
+    'connect: while Instant::now() < deadline {
+        let stream = match tokio::time::timeout(timeout, TcpStream::connect(&address)).await {
+            Ok(Ok(stream)) => stream,
+            Ok(Err(_)) | Err(_) => {
+                tally.connect_errors += 1;
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let _ = stream.set_nodelay(true);
+        let mut reader = BufReader::new(stream);
+        tally.connections += 1;
+
+        while Instant::now() < deadline {
+            if budget.fetch_sub(1, std::sync::atomic::Ordering::Relaxed) <= 0 {
+                break 'connect;
+            }
+
+            let request = &requests[next % requests.len()];
+            next += 1;
+
+            let start = Instant::now();
+            let exchange = async {
+                reader.get_mut().write_all(request).await?;
+                read_response(&mut reader).await
+            };
+
+            match tokio::time::timeout(timeout, exchange).await {
+                Ok(Ok((status, keep_alive))) => {
+                    tally.latencies.push(start.elapsed());
+                    *tally.statuses.entry(status).or_default() += 1;
+                    if !keep_alive {
+                        continue 'connect;
+                    }
+                }
+                Ok(Err(_)) => {
+                    tally.io_errors += 1;
+                    continue 'connect;
+                }
+                Err(_) => {
+                    tally.timeouts += 1;
+                    continue 'connect;
+                }
+            }
+        }
+    }
+
+    tally
+}
+
+/// Standard base64 of `input`, for the `Authorization` header.
+fn base64(input: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut output = String::new();
+    for chunk in input.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let bits = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for index in 0..4 {
+            if index <= chunk.len() {
+                output.push(ALPHABET[((bits >> (18 - 6 * index)) & 0x3f) as usize] as char);
+            } else {
+                output.push('=');
+            }
+        }
+    }
+    output
+}
+
+/// The latency below which `percent` of the sorted `latencies` are.
+fn percentile(latencies: &[Duration], percent: usize) -> Duration {
+    let index = (latencies.len() * percent).div_ceil(100).saturating_sub(1);
+    latencies[index.min(latencies.len() - 1)]
+}
+
+fn milliseconds(duration: Duration) -> String {
+    format!("{:.2} ms", duration.as_secs_f64() * 1000.0)
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    anyhow::ensure!(args.connections > 0, "At least one connection is needed");
+
+    let authorization = args.user.as_deref().map(|user| base64(user.as_bytes()));
+    let requests: Vec<_> = args
+        .mix
+        .iter()
+        .map(|mix| mix.encode(&args.address, authorization.as_deref()))
+        .collect();
+
+    println!(
+        "{} connections to {} for {} s, sending {}",
+        args.connections,
+        args.address,
+        args.seconds,
+        args.mix
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+
+    let start = Instant::now();
+    let deadline = start + Duration::from_secs(args.seconds);
+    let budget = std::sync::Arc::new(std::sync::atomic::AtomicI64::new(
+        args.requests.map_or(i64::MAX, |requests| requests as i64),
+    ));
+
+    let workers: Vec<_> = (0..args.connections)
+        .map(|_| {
+            tokio::spawn(connection(
+                args.address.clone(),
+                requests.clone(),
+                Duration::from_secs(args.timeout),
+                deadline,
+                budget.clone(),
+            ))
+        })
+        .collect();
+
+    let mut tally = Tally::default();
+    for worker in workers {
+        tally.merge(worker.await?);
+    }
+    let elapsed = start.elapsed();
+
+    let completed = tally.latencies.len();
+    println!(
+        "{} responses in {:.1} s, {:.1}/s, over {} connections",
+        completed,
+        elapsed.as_secs_f64(),
+        completed as f64 / elapsed.as_secs_f64(),
+        tally.connections
+    );
+
+    if completed > 0 {
+        tally.latencies.sort_unstable();
+        println!(
+            "latency p50 {}, p90 {}, p99 {}, max {}",
+            milliseconds(percentile(&tally.latencies, 50)),
+            milliseconds(percentile(&tally.latencies, 90)),
+            milliseconds(percentile(&tally.latencies, 99)),
+            milliseconds(tally.latencies[completed - 1])
+        );
+    }
+    for (status, count) in &tally.statuses {
+        println!("status {status}: {count}");
+    }
+    println!(
+        "errors: {} connect, {} timeout, {} connection lost",
+        tally.connect_errors, tally.timeouts, tally.io_errors
+    );
+
+    if completed == 0 {
+        anyhow::bail!("No response from {}", args.address);
+    }
+
+    Ok(())
+}
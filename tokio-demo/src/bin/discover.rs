@@ -0,0 +1,85 @@
+use std::{
+    collections::HashSet,
+    net::{Ipv4Addr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use clap::Parser;
+use smolweb_core::discovery::{Beacon, DISCOVERY_PORT};
+
+/// List the smolweb boards on the LAN, from the beacon each of them broadcasts every 5 seconds.
+///
+/// Each board is printed once, as its name, the URL of its web server and its firmware version.
+#[derive(Parser)]
+#[command(version)]
+struct Args {
+    /// Seconds to listen for, 0 to keep listening
+    #[arg(long, default_value_t = 10)]
+    seconds: u64,
+
+    /// UDP port the beacons are broadcast to
+    #[arg(long, default_value_t = DISCOVERY_PORT)]
+    port: u16,
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, args.port))
+        .with_context(|| format!("Failed to listen on UDP port {}", args.port))?;
+    let deadline = (args.seconds > 0).then(|| Instant::now() + Duration::from_secs(args.seconds));
+
+    let mut found = HashSet::new();
+    let mut datagram = [0; 512];
+
+    loop {
+        let timeout = match deadline {
+            Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                Some(timeout) if !timeout.is_zero() => Some(timeout),
+                _ => break,
+            },
+            None => None,
+        };
+        socket.set_read_timeout(timeout)?;
+
+        let (length, sender) = match socket.recv_from(&mut datagram) {
+            Ok(received) => received,
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) =>
+            {
+                break
+            }
+            Err(err) => return Err(err).context("Failed to receive a beacon"),
+        };
+
+        // Anything else sent to the port is skipped
+        let Some(beacon) = Beacon::from_json(&datagram[..length]) else {
+            continue;
+        };
+
+        if found.insert((beacon.name.clone(), beacon.ip.clone(), beacon.port)) {
+            println!(
+                "{}\thttp://{}:{}/\t{}\t(from {})",
+                beacon.name,
+                beacon.ip,
+                beacon.port,
+                beacon.version,
+                sender.ip()
+            );
+            // Link-local, so only reachable through the interface the beacon came in on
+            if let Some(ipv6) = &beacon.ipv6 {
+                println!("\tIPv6 {}", ipv6);
+            }
+        }
+    }
+
+    if found.is_empty() {
+        anyhow::bail!("No board found within {} seconds", args.seconds);
+    }
+
+    Ok(())
+}
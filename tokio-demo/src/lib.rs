@@ -0,0 +1,963 @@
+//! The tokio demo server, split from `main` so the tests can run it on their own listener.
+
+// The nested router types make the connection future too deep for the default limit
+#![recursion_limit = "512"]
+
+use std::{
+    ops::RangeInclusive,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    runtime::RuntimeFlavor,
+    sync::{broadcast, mpsc},
+    task::LocalSet,
+};
+
+use log::{info, warn};
+use picoserve::{extract::State, response::Json as JsonResponse};
+pub use tokio_util::sync::CancellationToken;
+use tokio_util::task::{AbortOnDropHandle, LocalPoolHandle};
+
+use smolweb_core::{
+    access_log::ConnectionId,
+    api::Rebooting,
+    auth::{Credentials, RequireAuth},
+    buffers::BufferConfig,
+    button::ButtonStats,
+    cors::{api_post, CorsOrigins},
+    diagnostics::Diagnostics,
+    events::{BoardEvent, BoardEvents, EventSubscriber, LedChange},
+    limits::{CutShort, HeadCut, LimitedSocket, Limits},
+    metrics::{Metrics, OpenConnection},
+    patterns::{Pattern, PatternPlayer},
+    rate_limit::{ClientAddress, ClientRateLimit, ToggleRateLimit, CLIENT_BURST},
+    schedules::{self, Scheduler},
+    sensors::{SensorStats, SENSOR_INTERVAL},
+    session::{SessionContext, Sessions, KEY_LEN},
+    settings::SettingsStore,
+    status_page::LocalAddress,
+    strip::StripCommand,
+    time::Clock,
+    tone::Tone,
+    LedControl,
+};
+
+mod assets;
+mod settings;
+mod simulator;
+mod socket;
+
+use assets::DirectoryAssets;
+use settings::FileSettings;
+use simulator::simulated_reading;
+use socket::{Socket, TokioTimer};
+
+/// Events buffered for a WebSocket or event stream before the oldest ones are dropped.
+const BOARD_EVENT_CAPACITY: usize = 16;
+
+/// Pixels of the simulated strip, the same as the strip of the Pico W.
+const SIMULATED_STRIP_LENGTH: usize = 30;
+
+/// Patterns waiting for the pattern task before `POST /api/leds/<n>/pattern` answers 503.
+const PATTERN_COMMAND_CAPACITY: usize = 4;
+
+struct Control {
+    /// Brightness of LED1 to LED3 in percent, so that dimming can be tried on the host.
+    leds: [u8; 3],
+    /// The LEDs of the host, LED2 only unless the whole [simulator] runs.
+    has_leds: RangeInclusive<u8>,
+    /// Shown by the [simulator].
+    strip: StripCommand,
+    /// The tone playing and when it ends, shown by the [simulator].
+    tone: Option<(Tone, std::time::Instant)>,
+    events: broadcast::Sender<BoardEvent>,
+    patterns: mpsc::Sender<(u8, Pattern)>,
+}
+
+impl Control {
+    /// Brightness of LED `led`, 0 for a number without one.
+    fn brightness(&self, led: u8) -> u8 {
+        let index = usize::from(led).wrapping_sub(1);
+        self.leds.get(index).copied().unwrap_or(0)
+    }
+
+    fn set_brightness(&mut self, led: u8, percent: u8) {
+        let index = usize::from(led).wrapping_sub(1);
+        if let Some(brightness) = self.leds.get_mut(index) {
+            *brightness = percent;
+        }
+    }
+}
+
+/// Shared by the connections, which may run on any thread of the runtime.
+///
+/// The lock is never held across an `.await`, so a blocking mutex is enough.
+#[derive(Clone)]
+struct SharedControl(Arc<Mutex<Control>>);
+
+impl SharedControl {
+    fn lock(&self) -> MutexGuard<'_, Control> {
+        // `Control` is valid after every update, so a panic while it was locked leaves nothing to repair
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn notify(&self, led: u8) {
+        let control = self.lock();
+        // Fails only when no WebSocket or event stream is open
+        let _ = control.events.send(BoardEvent::Led(LedChange {
+            led,
+            on: control.brightness(led) > 0,
+        }));
+    }
+}
+
+impl LedControl for SharedControl {
+    fn has_led(&self, led: u8) -> bool {
+        self.lock().has_leds.contains(&led)
+    }
+
+    fn toggle(&self, led: u8) {
+        {
+            let mut control = self.lock();
+            let percent = if control.brightness(led) > 0 { 0 } else { 100 };
+            control.set_brightness(led, percent);
+        }
+        self.notify(led);
+    }
+
+    fn set(&self, led: u8, on: bool) {
+        self.set_brightness(led, if on { 100 } else { 0 });
+    }
+
+    fn state(&self, led: u8) -> bool {
+        self.lock().brightness(led) > 0
+    }
+
+    fn brightness(&self, led: u8) -> Option<u8> {
+        Some(self.lock().brightness(led))
+    }
+
+    fn set_brightness(&self, led: u8, percent: u8) {
+        self.lock().set_brightness(led, percent);
+        self.notify(led);
+    }
+
+    fn set_pattern(&self, led: u8, pattern: Pattern) -> bool {
+        self.lock().patterns.try_send((led, pattern)).is_ok()
+    }
+
+    /// A simulated strip, so that its widget can be tried on the host.
+    fn strip_length(&self) -> usize {
+        SIMULATED_STRIP_LENGTH
+    }
+
+    fn set_strip(&self, command: StripCommand) -> bool {
+        info!("Strip set to {command:?}");
+        self.lock().strip = command;
+        true
+    }
+
+    /// A simulated buzzer, like the strip.
+    fn has_buzzer(&self) -> bool {
+        true
+    }
+
+    fn play_tone(&self, tone: Tone) -> bool {
+        info!(
+            "Playing {} Hz for {} ms",
+            tone.frequency_hz, tone.duration_ms
+        );
+        self.lock().tone = Some((tone, std::time::Instant::now() + tone.duration()));
+        true
+    }
+}
+
+struct BoardEventReceiver(broadcast::Receiver<BoardEvent>);
+
+impl EventSubscriber for BoardEventReceiver {
+    async fn next_event(&mut self) -> BoardEvent {
+        loop {
+            match self.0.recv().await {
+                Ok(event) => return event,
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                // The sender lives in `Control`, which outlives every connection
+                Err(broadcast::error::RecvError::Closed) => std::future::pending().await,
+            }
+        }
+    }
+}
+
+impl BoardEvents for SharedControl {
+    type Subscriber = BoardEventReceiver;
+
+    fn subscribe(&self) -> Option<BoardEventReceiver> {
+        Some(BoardEventReceiver(self.lock().events.subscribe()))
+    }
+}
+
+/// The host clock is assumed to be synchronized already.
+#[derive(Clone, Copy)]
+struct SystemClock {
+    started: std::time::Instant,
+}
+
+impl Clock for SystemClock {
+    fn unix_time(&self) -> Option<u64> {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .ok()
+            .map(|since_epoch| since_epoch.as_secs())
+    }
+
+    fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+}
+
+static METRICS: Metrics = Metrics::new();
+
+/// A key for the session cookies, different for each server, from the random keys the standard library seeds its
+/// hash maps with.
+fn session_key() -> [u8; KEY_LEN] {
+    use std::hash::{BuildHasher, Hasher};
+
+    let mut key = [0; KEY_LEN];
+    for part in key.chunks_mut(8) {
+        let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+        hasher.write(part);
+        part.copy_from_slice(&hasher.finish().to_le_bytes());
+    }
+    key
+}
+
+/// Show the memory of the process in `/metrics`, where `/proc/self/status` has it.
+fn update_resident_memory() {
+    let resident_kib = std::fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| {
+            status
+                .lines()
+                .find_map(|line| line.strip_prefix("VmRSS:"))
+                .and_then(|value| {
+                    value
+                        .trim()
+                        .trim_end_matches("kB")
+                        .trim()
+                        .parse::<u64>()
+                        .ok()
+                })
+        });
+
+    if let Some(resident_kib) = resident_kib {
+        METRICS.set_resident_memory(resident_kib * 1024);
+    }
+}
+
+/// Tasks share the stacks of the runtime threads, so none is followed, but `/readyz` waits for the background tasks of
+/// [serve] to beat. The network is up once the listener is bound.
+static DIAGNOSTICS: Diagnostics = Diagnostics::new(env!("CARGO_PKG_VERSION"), &[])
+    .with_heartbeats(&["uptime", "sensor", "patterns", "schedules"]);
+
+/// Show the clock speed of the first CPU in `/api/sysinfo`, where `/proc/cpuinfo` has it.
+fn read_cpu_frequency() {
+    let cpu_mhz = std::fs::read_to_string("/proc/cpuinfo")
+        .ok()
+        .and_then(|cpuinfo| {
+            cpuinfo
+                .lines()
+                .find_map(|line| line.strip_prefix("cpu MHz"))
+                .and_then(|value| {
+                    value
+                        .trim_start_matches([' ', '\t', ':'])
+                        .parse::<f64>()
+                        .ok()
+                })
+        });
+
+    if let Some(cpu_mhz) = cpu_mhz {
+        DIAGNOSTICS.set_cpu_frequency((cpu_mhz * 1e6) as u32);
+    }
+}
+
+/// The simulated LED has nothing to wear out, so toggles are not limited.
+static TOGGLE_RATE_LIMIT: ToggleRateLimit = ToggleRateLimit::new(Duration::ZERO);
+
+/// Pressed with Enter in the [simulator], never otherwise.
+static BUTTON_STATS: ButtonStats = ButtonStats::new();
+
+/// Sampled from [simulated_reading], so that the sensor chart of the page works on the host.
+static SENSOR_STATS: SensorStats = SensorStats::new();
+
+/// Delay between `POST /api/reboot` and the shutdown, so that the response is written first.
+const REBOOT_DELAY: Duration = Duration::from_millis(500);
+
+/// Why [run] returned.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stopped {
+    /// The shutdown token was cancelled from outside.
+    Shutdown,
+    /// `POST /api/reboot` asked for a restart, which is up to the caller, e.g. by exiting for a service manager.
+    Reboot,
+}
+
+/// Stops the server on `POST /api/reboot`, the way the boards reset.
+#[derive(Clone)]
+struct Reboot {
+    shutdown_token: CancellationToken,
+    requested: Arc<AtomicBool>,
+}
+
+async fn reboot(_: RequireAuth, State(reboot): State<Reboot>) -> JsonResponse<Rebooting> {
+    info!("Reboot requested");
+    reboot.requested.store(true, Ordering::Relaxed);
+
+    tokio::spawn(async move {
+        tokio::time::sleep(REBOOT_DELAY).await;
+        reboot.shutdown_token.cancel();
+    });
+
+    JsonResponse(Rebooting {
+        reboot_in_ms: REBOOT_DELAY.as_millis() as u64,
+    })
+}
+
+struct AppState {
+    shared_control: SharedControl,
+    clock: SystemClock,
+    metrics: &'static Metrics,
+    diagnostics: &'static Diagnostics,
+    toggle_rate_limit: &'static ToggleRateLimit,
+    client_rate_limit: &'static ClientRateLimit,
+    sessions: &'static Sessions,
+    button_stats: &'static ButtonStats,
+    sensor_stats: &'static SensorStats,
+    assets: DirectoryAssets,
+    settings: FileSettings,
+    reboot: Reboot,
+    credentials: Credentials,
+    cors_origins: CorsOrigins,
+    limits: Limits,
+    connection: ConnectionId,
+    /// Address the connection was accepted on, shown by the page.
+    local_address: LocalAddress,
+    /// Address the connection was accepted from, whose requests the [ClientRateLimit] counts.
+    client_address: ClientAddress,
+    /// Set by the [LimitedSocket] of the connection when it cuts a head short, so that the request is refused.
+    head_cut: HeadCut,
+}
+
+impl picoserve::extract::FromRef<AppState> for SharedControl {
+    fn from_ref(state: &AppState) -> Self {
+        state.shared_control.clone()
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for DirectoryAssets {
+    fn from_ref(state: &AppState) -> Self {
+        state.assets.clone()
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for FileSettings {
+    fn from_ref(state: &AppState) -> Self {
+        state.settings.clone()
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for Reboot {
+    fn from_ref(state: &AppState) -> Self {
+        state.reboot.clone()
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for ConnectionId {
+    fn from_ref(state: &AppState) -> Self {
+        state.connection
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for LocalAddress {
+    fn from_ref(state: &AppState) -> Self {
+        state.local_address
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for ClientAddress {
+    fn from_ref(state: &AppState) -> Self {
+        state.client_address
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for Credentials {
+    fn from_ref(state: &AppState) -> Self {
+        state.credentials
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for CorsOrigins {
+    fn from_ref(state: &AppState) -> Self {
+        state.cors_origins
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for Limits {
+    fn from_ref(state: &AppState) -> Self {
+        state.limits
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for CutShort {
+    fn from_ref(state: &AppState) -> Self {
+        state.head_cut.get()
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for SessionContext {
+    fn from_ref(state: &AppState) -> Self {
+        SessionContext {
+            sessions: state.sessions,
+            uptime: state.clock.uptime(),
+        }
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for SystemClock {
+    fn from_ref(state: &AppState) -> Self {
+        state.clock
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for &'static Metrics {
+    fn from_ref(state: &AppState) -> Self {
+        state.metrics
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for &'static Diagnostics {
+    fn from_ref(state: &AppState) -> Self {
+        state.diagnostics
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for &'static ToggleRateLimit {
+    fn from_ref(state: &AppState) -> Self {
+        state.toggle_rate_limit
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for &'static ClientRateLimit {
+    fn from_ref(state: &AppState) -> Self {
+        state.client_rate_limit
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for &'static ButtonStats {
+    fn from_ref(state: &AppState) -> Self {
+        state.button_stats
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for &'static SensorStats {
+    fn from_ref(state: &AppState) -> Self {
+        state.sensor_stats
+    }
+}
+
+/// How long to wait for open connections to finish after the shutdown token is cancelled before aborting them.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a client may take to complete the TLS handshake before the connection is dropped.
+#[cfg(feature = "tls")]
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How accepted connections are turned into the stream given to picoserve.
+#[derive(Clone)]
+enum Transport {
+    Plain,
+    #[cfg(feature = "tls")]
+    Tls(tokio_rustls::TlsAcceptor),
+}
+
+/// Options of the server. The defaults only serve the embedded files.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Directory whose files are served under `/static` in front of the embedded ones.
+    pub assets_dir: Option<PathBuf>,
+    /// JSON file holding the settings of `/api/settings`, created when they are first saved.
+    /// Without one, the defaults apply at each start.
+    pub settings_file: Option<PathBuf>,
+    /// Required by the control and API routes.
+    pub credentials: Credentials,
+    /// Origins allowed to call `/api` from a browser, none by default.
+    pub cors_origins: CorsOrigins,
+    /// Largest requests served, whose heads must fit in [BufferConfig::WEB].
+    pub limits: Limits,
+    /// Connections served at the same time, like the web task pool of the boards.
+    /// Further connections wait in the listen backlog until one closes.
+    pub workers: usize,
+    /// Keep connections open for further requests once a response is sent.
+    pub keep_alive: bool,
+    /// How long a connection may wait before sending a request.
+    pub start_read_request_timeout: Duration,
+    /// How long the rest of a request may take once it started.
+    pub read_request_timeout: Duration,
+    /// How long writing a response may take.
+    pub write_timeout: Duration,
+    /// Requests each client IP may make in a row to the routes taking credentials, like `/toggle_led` and `/api`.
+    pub client_burst: u32,
+    /// How often a client gets one of those requests back, zero not to limit clients at all.
+    pub client_refill_interval: Duration,
+    /// Socket to answer CoAP requests for the LED on, usually bound to
+    /// [COAP_PORT](smolweb_core::coap::COAP_PORT). Only those with the API key of the credentials change it, so there
+    /// is none by default.
+    pub coap_socket: Option<Arc<tokio::net::UdpSocket>>,
+    /// Simulate the Nucleo: its three LEDs, drawn in the terminal with the strip and the sensor, and its button,
+    /// pressed by Enter on stdin. See [simulator].
+    pub simulator: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            assets_dir: None,
+            settings_file: None,
+            credentials: Credentials::default(),
+            cors_origins: CorsOrigins::default(),
+            limits: Limits::WEB,
+            workers: 16,
+            keep_alive: true,
+            start_read_request_timeout: Duration::from_secs(5),
+            read_request_timeout: Duration::from_secs(1),
+            write_timeout: Duration::from_secs(1),
+            // Unlike on the boards, as tests and scripts on the host send requests in quick succession
+            client_burst: CLIENT_BURST,
+            client_refill_interval: Duration::ZERO,
+            coap_socket: None,
+            simulator: false,
+        }
+    }
+}
+
+/// Serve the app on `listener` until `shutdown_token` is cancelled or `POST /api/reboot` is requested,
+/// then wait for open connections to finish.
+///
+/// New connections are no longer accepted once the token is cancelled. Connections still open after
+/// [SHUTDOWN_TIMEOUT] are aborted. Background tasks stop with the token too, and other tasks can watch a clone of it.
+///
+/// Connections stay on the thread they started on: the thread of the runtime if it has a single one, like on the
+/// boards, or one of a pool of as many threads as the workers of a multi-threaded runtime.
+pub async fn run(
+    listener: tokio::net::TcpListener,
+    config: Config,
+    shutdown_token: CancellationToken,
+) -> anyhow::Result<Stopped> {
+    LocalSet::new()
+        .run_until(serve(listener, Transport::Plain, config, shutdown_token))
+        .await
+}
+
+/// Like [run], but over TLS. Connections which fail the handshake are logged and dropped.
+#[cfg(feature = "tls")]
+pub async fn run_tls(
+    listener: tokio::net::TcpListener,
+    acceptor: tokio_rustls::TlsAcceptor,
+    config: Config,
+    shutdown_token: CancellationToken,
+) -> anyhow::Result<Stopped> {
+    LocalSet::new()
+        .run_until(serve(
+            listener,
+            Transport::Tls(acceptor),
+            config,
+            shutdown_token,
+        ))
+        .await
+}
+
+/// Serve the requests read from `stream`, which may be a TLS stream, counting them and their bytes in `connection`.
+async fn serve_connection(
+    app: &picoserve::Router<impl picoserve::routing::PathRouter<AppState>, AppState>,
+    config: &picoserve::Config<Duration>,
+    stream: impl AsyncRead + AsyncWrite + Unpin,
+    state: &AppState,
+    connection: &OpenConnection<'_>,
+) -> Result<u64, picoserve::Error<socket::IoError>> {
+    let requests = picoserve::serve_with_state(
+        app,
+        TokioTimer,
+        config,
+        &mut [0; BufferConfig::WEB.http],
+        LimitedSocket::with_limits(
+            connection.count_bytes(Socket(stream)),
+            state.limits,
+            &state.head_cut,
+        ),
+        state,
+    )
+    .await?;
+
+    connection.served(requests);
+    Ok(requests)
+}
+
+/// Answer the CoAP requests of `socket` with the simulated LED until `shutdown_token` is cancelled, limiting each
+/// sender like the HTTP clients with `client_rate_limit`.
+async fn serve_coap(
+    socket: Arc<tokio::net::UdpSocket>,
+    shared_control: SharedControl,
+    clock: SystemClock,
+    credentials: Credentials,
+    client_rate_limit: &'static ClientRateLimit,
+    shutdown_token: CancellationToken,
+) {
+    let mut message = [0; smolweb_core::coap::MAX_MESSAGE_SIZE];
+    let mut response = [0; smolweb_core::coap::MAX_MESSAGE_SIZE];
+    let mut message_id = 0_u16;
+
+    loop {
+        let (length, sender) = tokio::select! {
+            received = socket.recv_from(&mut message) => match received {
+                Ok(received) => received,
+                Err(err) => {
+                    warn!("Failed to receive CoAP request: {err}");
+                    continue;
+                }
+            },
+            () = shutdown_token.cancelled() => break,
+        };
+
+        message_id = message_id.wrapping_add(1);
+        let Some(response_length) = smolweb_core::coap::respond(
+            &message[..length],
+            &shared_control,
+            &clock,
+            &credentials,
+            &METRICS,
+            &TOGGLE_RATE_LIMIT,
+            client_rate_limit,
+            ClientAddress(Some(sender.ip())),
+            message_id,
+            &mut response,
+        ) else {
+            continue;
+        };
+
+        if let Err(err) = socket.send_to(&response[..response_length], sender).await {
+            warn!("Failed to send CoAP response to {sender}: {err}");
+        }
+    }
+}
+
+async fn serve(
+    listener: tokio::net::TcpListener,
+    transport: Transport,
+    Config {
+        assets_dir,
+        settings_file,
+        credentials,
+        cors_origins,
+        limits,
+        workers,
+        keep_alive,
+        start_read_request_timeout,
+        read_request_timeout,
+        write_timeout,
+        client_burst,
+        client_refill_interval,
+        coap_socket,
+        simulator,
+    }: Config,
+    shutdown_token: CancellationToken,
+) -> anyhow::Result<Stopped> {
+    let app = Arc::new(smolweb_core::add_middleware::<AppState, SystemClock, _>(
+        smolweb_core::make_routes::<
+            AppState,
+            SharedControl,
+            SystemClock,
+            DirectoryAssets,
+            FileSettings,
+        >()
+        .route("/api/reboot", api_post(reboot)),
+    ));
+
+    // Lives as long as the process like the statics, once per server
+    let client_rate_limit: &'static ClientRateLimit = Box::leak(Box::new(ClientRateLimit::new(
+        client_burst,
+        client_refill_interval,
+    )));
+
+    let sessions: &'static Sessions = Box::leak(Box::new(Sessions::new(session_key())));
+
+    let reboot = Reboot {
+        shutdown_token: shutdown_token.clone(),
+        requested: Arc::new(AtomicBool::new(false)),
+    };
+
+    let config = picoserve::Config::new(picoserve::Timeouts {
+        start_read_request: Some(start_read_request_timeout),
+        read_request: Some(read_request_timeout),
+        write: Some(write_timeout),
+    });
+    let config = if keep_alive {
+        config.keep_connection_alive()
+    } else {
+        config.close_connection_after_response()
+    };
+
+    let settings = FileSettings::load(settings_file);
+    read_cpu_frequency();
+    DIAGNOSTICS.set_network_up(true);
+
+    let (pattern_sender, mut pattern_receiver) = mpsc::channel(PATTERN_COMMAND_CAPACITY);
+    let shared_control = SharedControl(Arc::new(Mutex::new(Control {
+        leds: [1, 2, 3].map(|led| {
+            if settings.settings().led_on_at_boot(led) {
+                100
+            } else {
+                0
+            }
+        }),
+        has_leds: if simulator {
+            simulator::SIMULATED_LEDS
+        } else {
+            2..=2
+        },
+        strip: StripCommand::Pixels(heapless::Vec::new()),
+        tone: None,
+        events: broadcast::channel(BOARD_EVENT_CAPACITY).0,
+        patterns: pattern_sender,
+    })));
+    let clock = SystemClock {
+        started: std::time::Instant::now(),
+    };
+    let assets = DirectoryAssets(assets_dir.map(Arc::from));
+
+    let mut connections = tokio::task::JoinSet::new();
+    // picoserve's futures aren't `Send`, so connections are spawned on the `LocalSet` of `run`, or pinned to a thread
+    // of the pool on a multi-threaded runtime
+    let runtime = tokio::runtime::Handle::current();
+    let pool = (runtime.runtime_flavor() == RuntimeFlavor::MultiThread)
+        .then(|| LocalPoolHandle::new(runtime.metrics().num_workers()));
+
+    let events = shared_control.lock().events.clone();
+    let uptime = tokio::spawn({
+        let shutdown_token = shutdown_token.clone();
+        async move {
+            let mut interval = tokio::time::interval(smolweb_core::events::UPTIME_INTERVAL);
+            // The first tick completes immediately
+            interval.tick().await;
+            update_resident_memory();
+            DIAGNOSTICS.heartbeat("uptime");
+
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    () = shutdown_token.cancelled() => break,
+                }
+                let _ = events.send(BoardEvent::Uptime(clock.uptime().as_secs()));
+                update_resident_memory();
+            }
+        }
+    });
+
+    // A first reading straight away, so that `/api/sensors` answers from the start
+    SENSOR_STATS.record(simulated_reading(clock.uptime()), clock.uptime());
+
+    let events = shared_control.lock().events.clone();
+    let sensor = tokio::spawn({
+        let shutdown_token = shutdown_token.clone();
+        async move {
+            let mut interval = tokio::time::interval(SENSOR_INTERVAL);
+            // The first tick completes immediately
+            interval.tick().await;
+            DIAGNOSTICS.heartbeat("sensor");
+
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    () = shutdown_token.cancelled() => break,
+                }
+                let reading = simulated_reading(clock.uptime());
+                SENSOR_STATS.record(reading, clock.uptime());
+                let _ = events.send(BoardEvent::Sensor(reading));
+            }
+        }
+    });
+
+    // Plays the patterns selected with `POST /api/leds/<n>/pattern`, sleeping until the next change
+    let patterns = tokio::spawn({
+        let shared_control = shared_control.clone();
+        let shutdown_token = shutdown_token.clone();
+        async move {
+            let mut player = PatternPlayer::new();
+            DIAGNOSTICS.heartbeat("patterns");
+
+            loop {
+                let next_change = player.update(&shared_control, clock.uptime());
+                let sleep = async {
+                    match next_change {
+                        Some(wait) => tokio::time::sleep(wait).await,
+                        None => std::future::pending().await,
+                    }
+                };
+
+                tokio::select! {
+                    Some((led, pattern)) = pattern_receiver.recv() => {
+                        player.set(&shared_control, led, pattern, clock.uptime());
+                    }
+                    () = sleep => {}
+                    () = shutdown_token.cancelled() => break,
+                }
+            }
+        }
+    });
+
+    // Runs the schedules of `/api/schedules`, kept in the settings file
+    let schedules = tokio::spawn({
+        let shared_control = shared_control.clone();
+        let settings = settings.clone();
+        let shutdown_token = shutdown_token.clone();
+        async move {
+            let mut scheduler = Scheduler::new();
+            let mut interval = tokio::time::interval(schedules::CHECK_INTERVAL);
+            DIAGNOSTICS.heartbeat("schedules");
+
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    () = shutdown_token.cancelled() => break,
+                }
+                scheduler.run_due(&shared_control, &clock, &settings);
+            }
+        }
+    });
+
+    let simulator = simulator.then(|| {
+        tokio::spawn(simulator::run(
+            shared_control.clone(),
+            clock,
+            shutdown_token.clone(),
+        ))
+    });
+
+    let coap = coap_socket.map(|socket| {
+        tokio::spawn(serve_coap(
+            socket,
+            shared_control.clone(),
+            clock,
+            credentials,
+            client_rate_limit,
+            shutdown_token.clone(),
+        ))
+    });
+
+    loop {
+        let (stream, remote_address) = tokio::select! {
+            connection = listener.accept(), if connections.len() < workers => connection?,
+            Some(_) = connections.join_next(), if !connections.is_empty() => continue,
+            () = shutdown_token.cancelled() => break,
+        };
+
+        let connection = ConnectionId::next();
+        info!("Connection {connection} from {remote_address}");
+
+        let app = app.clone();
+        let config = config.clone();
+        let state = AppState {
+            shared_control: shared_control.clone(),
+            clock,
+            metrics: &METRICS,
+            diagnostics: &DIAGNOSTICS,
+            toggle_rate_limit: &TOGGLE_RATE_LIMIT,
+            client_rate_limit,
+            sessions,
+            button_stats: &BUTTON_STATS,
+            sensor_stats: &SENSOR_STATS,
+            assets: assets.clone(),
+            settings: settings.clone(),
+            reboot: reboot.clone(),
+            credentials,
+            cors_origins,
+            limits,
+            connection,
+            local_address: LocalAddress(stream.local_addr().ok().map(|address| address.ip())),
+            client_address: ClientAddress(Some(remote_address.ip())),
+            head_cut: HeadCut::new(),
+        };
+
+        let transport = transport.clone();
+
+        let serve = move || async move {
+            // Connections share the threads of the runtime, so they are counted as a single worker
+            let connection = METRICS.open_connection(0);
+
+            match transport {
+                Transport::Plain => {
+                    serve_connection(&app, &config, stream, &state, &connection).await
+                }
+                #[cfg(feature = "tls")]
+                Transport::Tls(acceptor) => {
+                    match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await
+                    {
+                        Ok(Ok(stream)) => {
+                            serve_connection(&app, &config, stream, &state, &connection).await
+                        }
+                        Ok(Err(err)) => {
+                            warn!("TLS handshake with {remote_address} failed: {err}");
+                            Ok(0)
+                        }
+                        Err(_) => {
+                            warn!("TLS handshake with {remote_address} timed out");
+                            Ok(0)
+                        }
+                    }
+                }
+            }
+        };
+
+        match &pool {
+            // Aborted along with the task of the set when shutting down
+            Some(pool) => connections.spawn(AbortOnDropHandle::new(pool.spawn_pinned(serve))),
+            None => connections.spawn_local(async move { Ok(serve().await) }),
+        };
+    }
+
+    // Stopped by the token, which is cancelled by now
+    let _ = uptime.await;
+    let _ = sensor.await;
+    let _ = patterns.await;
+    let _ = schedules.await;
+    if let Some(simulator) = simulator {
+        let _ = simulator.await;
+    }
+    if let Some(coap) = coap {
+        let _ = coap.await;
+    }
+
+    info!(
+        "Shutting down, waiting for {} connection(s) to finish",
+        connections.len()
+    );
+
+    let drain = async { while connections.join_next().await.is_some() {} };
+
+    if tokio::time::timeout(SHUTDOWN_TIMEOUT, drain).await.is_err() {
+        info!("Aborting {} remaining connection(s)", connections.len());
+        connections.shutdown().await;
+    }
+
+    info!("Shutdown complete");
+
+    Ok(if reboot.requested.load(Ordering::Relaxed) {
+        Stopped::Reboot
+    } else {
+        Stopped::Shutdown
+    })
+}
@@ -1,98 +1,267 @@
+// `run` is instantiated here, so its nested router types need the raised limit too
+#![recursion_limit = "512"]
+
 use std::{
-    cell::RefCell,
-    rc::Rc,
-    sync::{Arc, Mutex},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
     time::Duration,
 };
 
-use log::{debug, info};
-use picoserve::{
-    extract::State,
-    response::{DebugValue, IntoResponse},
-    routing::{get, parse_path_segment},
-};
+use anyhow::Context;
+use clap::Parser;
+use log::{error, info};
+use smolweb_core::{auth::Credentials, cors::CorsOrigins, limits::Limits};
+
+/// Serve the smolweb demo application on the host, with a simulated LED.
+///
+/// The credentials are read from `SMOLWEB_USERNAME`, `SMOLWEB_PASSWORD` and `SMOLWEB_API_KEY`.
+#[derive(Parser)]
+#[command(version)]
+struct Args {
+    /// Address to listen on, e.g. 0.0.0.0 to accept connections from the LAN
+    #[arg(long, env = "SMOLWEB_ADDR", default_value_t = IpAddr::V4(Ipv4Addr::LOCALHOST))]
+    bind: IpAddr,
+
+    /// TCP port to listen on
+    #[arg(long, env = "SMOLWEB_PORT", default_value_t = smolweb_core::DEFAULT_PORT)]
+    port: u16,
+
+    /// Connections served at the same time
+    #[arg(
+        long,
+        default_value_t = tokio_demo::Config::default().workers as u16,
+        value_parser = clap::value_parser!(u16).range(1..),
+    )]
+    workers: u16,
+
+    /// Threads of the tokio runtime. With 1, everything runs on the main thread like on the boards
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..))]
+    threads: u16,
+
+    /// Keep connections open for further requests
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set)]
+    keep_alive: bool,
+
+    /// Milliseconds a connection may wait before sending a request
+    #[arg(long, default_value_t = millis(tokio_demo::Config::default().start_read_request_timeout))]
+    start_read_timeout_ms: u64,
+
+    /// Milliseconds the rest of a request may take
+    #[arg(long, default_value_t = millis(tokio_demo::Config::default().read_request_timeout))]
+    read_timeout_ms: u64,
+
+    /// Milliseconds writing a response may take
+    #[arg(long, default_value_t = millis(tokio_demo::Config::default().write_timeout))]
+    write_timeout_ms: u64,
+
+    /// Largest Content-Length of a request, larger bodies get 413
+    #[arg(long, default_value_t = tokio_demo::Config::default().limits.max_body_bytes)]
+    max_body_bytes: usize,
+
+    /// Requests each client IP may make in a row to the routes taking credentials, like /toggle_led and /api
+    #[arg(long, default_value_t = tokio_demo::Config::default().client_burst)]
+    client_burst: u32,
+
+    /// Milliseconds after which a client gets one of those requests back, 0 not to limit clients
+    #[arg(long, default_value_t = millis(tokio_demo::Config::default().client_refill_interval))]
+    client_refill_ms: u64,
 
-struct Control {
-    led2: bool,
+    /// UDP port to answer CoAP requests for the LED on, e.g. 5683, none by default as they aren't authenticated
+    #[arg(long, env = "SMOLWEB_COAP_PORT")]
+    coap_port: Option<u16>,
+
+    /// Directory whose files are served under /static in front of the embedded ones
+    #[arg(long, env = "SMOLWEB_ASSETS_DIR")]
+    assets_dir: Option<PathBuf>,
+
+    /// JSON file holding the settings of /api/settings, created when they are first saved
+    #[arg(long, env = "SMOLWEB_SETTINGS_FILE")]
+    settings_file: Option<PathBuf>,
+
+    /// Origins allowed to call /api from a browser, comma separated, or * for any
+    #[arg(long, env = "SMOLWEB_CORS_ORIGINS")]
+    cors_origins: Option<String>,
+
+    /// Simulate the Nucleo board, drawn in the terminal, with its three LEDs and a button pressed by Enter
+    #[arg(long)]
+    simulator: bool,
+
+    /// Exit code after POST /api/reboot, for a service manager to restart the process
+    #[arg(long, default_value_t = 0)]
+    reboot_exit_code: i32,
+}
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_millis() as u64
 }
 
-type SharedControl = Rc<RefCell<Control>>;
+/// Read `SMOLWEB_USERNAME`, `SMOLWEB_PASSWORD` and `SMOLWEB_API_KEY`, each falling back to the value set when building.
+fn credentials() -> Credentials {
+    // Read once at startup, so leaking them costs nothing
+    let var = |name: &str| std::env::var(name).ok().map(|value| &*value.leak());
+    let built = Credentials::from_build_env();
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() -> anyhow::Result<()> {
-    env_logger::init();
-    info!("App started");
+    Credentials {
+        username: var("SMOLWEB_USERNAME").unwrap_or(built.username),
+        password: var("SMOLWEB_PASSWORD").unwrap_or(built.password),
+        api_key: var("SMOLWEB_API_KEY").or(built.api_key),
+    }
+}
+
+/// Build the TLS acceptor from the PEM files named by `SMOLWEB_TLS_CERT` and `SMOLWEB_TLS_KEY`.
+#[cfg(feature = "tls")]
+fn tls_acceptor() -> anyhow::Result<tokio_rustls::TlsAcceptor> {
+    let open = |name: &str| -> anyhow::Result<_> {
+        let path = std::env::var(name).with_context(|| format!("{name} is not set"))?;
+        let file = std::fs::File::open(&path).with_context(|| format!("Failed to open {path}"))?;
+        Ok((path, std::io::BufReader::new(file)))
+    };
+
+    let (cert_path, mut certs) = open("SMOLWEB_TLS_CERT")?;
+    let certs = rustls_pemfile::certs(&mut certs)
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Invalid certificate in {cert_path}"))?;
+
+    let (key_path, mut key) = open("SMOLWEB_TLS_KEY")?;
+    let key = rustls_pemfile::private_key(&mut key)
+        .with_context(|| format!("Invalid private key in {key_path}"))?
+        .with_context(|| format!("No private key in {key_path}"))?;
 
-    let port = 8000;
-
-    let app = std::rc::Rc::new(
-        picoserve::Router::new()
-            .route(
-                "/",
-                get(|| picoserve::response::File::html(include_str!("index.html"))),
-            )
-            .route(
-                "/index.css",
-                get(|| picoserve::response::File::css(include_str!("index.css"))),
-            )
-            .route(
-                "/index.js",
-                get(|| picoserve::response::File::javascript(include_str!("index.js"))),
-            )
-            .route(
-                ("/toggle_led", parse_path_segment()),
-                get(
-                    |led_type: u8, State(state): State<SharedControl>| async move {
-                        info!("Toggling LED{}", led_type);
-                        let led2 = &mut state.borrow_mut().led2;
-                        *led2 = !*led2;
-                        // debug!("State LED value after toggle: {}", state.borrow().led2);
-                        debug!("LED value after toggle: {}", led2);
-                        if *led2 {
-                            "ON"
-                        } else {
-                            "OFF"
-                        }
-                    },
-                ),
-            ),
-    );
-
-    let config = picoserve::Config::new(picoserve::Timeouts {
-        start_read_request: Some(Duration::from_secs(5)),
-        read_request: Some(Duration::from_secs(1)),
-        write: Some(Duration::from_secs(1)),
-    })
-    .keep_connection_alive();
-
-    let shared_control = Rc::new(RefCell::new(Control { led2: true }));
-
-    let socket = tokio::net::TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 8000)).await?;
-
-    info!("http://localhost:{port}/");
-
-    tokio::task::LocalSet::new()
-        .run_until(async {
-            loop {
-                let (stream, remote_address) = socket.accept().await?;
-
-                info!("Connection from {remote_address}");
-
-                let app = app.clone();
-                let config = config.clone();
-                let shared_control_clone = shared_control.clone();
-
-                tokio::task::spawn_local(async move {
-                    picoserve::serve_with_state(
-                        &app,
-                        &config,
-                        &mut [0; 2048],
-                        stream,
-                        &shared_control_clone
-                    )
-                    .await
-                });
+    let config = tokio_rustls::rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("Invalid certificate or private key")?;
+
+    Ok(tokio_rustls::TlsAcceptor::from(std::sync::Arc::new(config)))
+}
+
+/// Wait for Ctrl+C, or for SIGTERM from a service manager or `docker stop`.
+async fn wait_for_signal() {
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
             }
-        })
+            Err(err) => {
+                error!("Failed to listen for SIGTERM: {err}");
+                std::future::pending().await
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => match result {
+            Ok(()) => info!("Received Ctrl+C, shutting down"),
+            Err(err) => error!("Failed to listen for Ctrl+C, shutting down: {err}"),
+        },
+        () = terminate => info!("Received SIGTERM, shutting down"),
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+
+    // Records are also kept for `/logs`
+    let logger = env_logger::Builder::from_default_env().build();
+    log::set_max_level(logger.filter());
+    log::set_boxed_logger(Box::new(smolweb_core::logs::TeeLogger(logger)))
+        .context("Failed to install the logger")?;
+    info!("App started");
+
+    let runtime = if args.threads == 1 {
+        tokio::runtime::Builder::new_current_thread()
+    } else {
+        let mut builder = tokio::runtime::Builder::new_multi_thread();
+        builder.worker_threads(args.threads.into());
+        builder
+    }
+    .enable_all()
+    .build()
+    .context("Failed to start the tokio runtime")?;
+
+    let reboot_exit_code = args.reboot_exit_code;
+    let stopped = runtime.block_on(serve(args))?;
+
+    if stopped == tokio_demo::Stopped::Reboot {
+        info!("Exiting with code {reboot_exit_code} to be restarted");
+        drop(runtime);
+        std::process::exit(reboot_exit_code);
+    }
+
+    info!("Exiting");
+
+    Ok(())
+}
+
+async fn serve(args: Args) -> anyhow::Result<tokio_demo::Stopped> {
+    let address = SocketAddr::new(args.bind, args.port);
+
+    let listener = tokio::net::TcpListener::bind(address)
         .await
+        .with_context(|| format!("Failed to bind to {address}"))?;
+
+    let coap_socket = match args.coap_port {
+        Some(port) => {
+            let address = SocketAddr::new(args.bind, port);
+            let socket = tokio::net::UdpSocket::bind(address)
+                .await
+                .with_context(|| format!("Failed to bind to UDP {address}"))?;
+            info!("coap://{address}/leds");
+            Some(std::sync::Arc::new(socket))
+        }
+        None => None,
+    };
+
+    #[cfg(feature = "tls")]
+    let acceptor = tls_acceptor()?;
+
+    let config = tokio_demo::Config {
+        assets_dir: args.assets_dir,
+        settings_file: args.settings_file,
+        credentials: credentials(),
+        // Read once at startup, so leaking them costs nothing
+        cors_origins: args
+            .cors_origins
+            .map_or_else(CorsOrigins::from_build_env, |origins| {
+                CorsOrigins(origins.leak())
+            }),
+        workers: args.workers.into(),
+        keep_alive: args.keep_alive,
+        start_read_request_timeout: Duration::from_millis(args.start_read_timeout_ms),
+        read_request_timeout: Duration::from_millis(args.read_timeout_ms),
+        write_timeout: Duration::from_millis(args.write_timeout_ms),
+        limits: Limits {
+            max_body_bytes: args.max_body_bytes,
+            ..Limits::WEB
+        },
+        client_burst: args.client_burst,
+        client_refill_interval: Duration::from_millis(args.client_refill_ms),
+        coap_socket,
+        simulator: args.simulator,
+    };
+
+    let shutdown_token = tokio_demo::CancellationToken::new();
+    tokio::spawn({
+        let shutdown_token = shutdown_token.clone();
+        async move {
+            wait_for_signal().await;
+            shutdown_token.cancel();
+        }
+    });
+
+    #[cfg(not(feature = "tls"))]
+    {
+        info!("http://{address}/");
+        tokio_demo::run(listener, config, shutdown_token).await
+    }
+
+    #[cfg(feature = "tls")]
+    {
+        info!("https://{address}/");
+        tokio_demo::run_tls(listener, acceptor, config, shutdown_token).await
+    }
 }
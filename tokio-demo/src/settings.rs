@@ -0,0 +1,64 @@
+//! Settings kept in the JSON file named by [Config::settings_file](crate::Config::settings_file).
+
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use log::warn;
+use smolweb_core::settings::{Settings, SettingsStore};
+
+/// The settings loaded at startup and saved since, written to the file if there is one.
+#[derive(Clone)]
+pub(crate) struct FileSettings {
+    path: Option<Arc<Path>>,
+    current: Arc<Mutex<Settings>>,
+}
+
+impl FileSettings {
+    /// Load the settings from `path`, falling back to the defaults if there is no such file or it is invalid.
+    pub(crate) fn load(path: Option<PathBuf>) -> Self {
+        let settings = path
+            .as_deref()
+            .and_then(|path| match std::fs::read(path) {
+                Ok(json) => Settings::from_json(&json).or_else(|| {
+                    warn!("Invalid settings in {}, using the defaults", path.display());
+                    None
+                }),
+                // Created by the first save
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+                Err(err) => {
+                    warn!("Failed to read {}: {err}", path.display());
+                    None
+                }
+            })
+            .unwrap_or_default();
+
+        Self {
+            path: path.map(Arc::from),
+            current: Arc::new(Mutex::new(settings)),
+        }
+    }
+}
+
+impl SettingsStore for FileSettings {
+    fn settings(&self) -> Settings {
+        self.current
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Without a file, the settings are only kept until the server stops.
+    fn save(&self, settings: &Settings) -> bool {
+        if let Some(path) = &self.path {
+            if let Err(err) = std::fs::write(path, settings.to_json()) {
+                warn!("Failed to write {}: {err}", path.display());
+                return false;
+            }
+        }
+
+        *self.current.lock().unwrap_or_else(PoisonError::into_inner) = settings.clone();
+        true
+    }
+}
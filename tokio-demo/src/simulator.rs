@@ -0,0 +1,181 @@
+//! The board simulated in the terminal with [Config::simulator](crate::Config::simulator), to develop the web app
+//! without hardware.
+//!
+//! The host then has the three LEDs of the Nucleo, drawn with the strip, the sensor, the buzzer and the button on a
+//! status line redrawn every [REDRAW_INTERVAL], and each line read from stdin, that is each press of Enter, presses the
+//! button the way `button_task` of the board does. The sensor is simulated either way, by [simulated_reading].
+
+use std::{
+    fmt::Write as _,
+    io::{BufRead, IsTerminal, Write as _},
+    ops::RangeInclusive,
+    time::Duration,
+};
+
+use log::{info, warn};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+use smolweb_core::{
+    events::BoardEvent,
+    sensors::SensorReading,
+    strip::{Rgb, FRAME_INTERVAL},
+    time::Clock,
+    LedControl,
+};
+
+use crate::{
+    Control, SharedControl, SystemClock, BUTTON_STATS, SENSOR_STATS, SIMULATED_STRIP_LENGTH,
+};
+
+/// LEDs of the simulated board, those of the Nucleo.
+pub(crate) const SIMULATED_LEDS: RangeInclusive<u8> = 1..=3;
+
+/// How often the status line is redrawn, often enough for the strip animations to move.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Colors of LED1 to LED3 on the Nucleo: green, yellow and red.
+const LED_COLORS: [Rgb; 3] = [[0, 200, 0], [230, 180, 0], [220, 0, 0]];
+
+/// A room slowly warming and cooling around 22 °C, drier as it warms, around 45 %, with the noise of a real sensor.
+pub(crate) fn simulated_reading(uptime: Duration) -> SensorReading {
+    let minutes = uptime.as_secs_f32() / 60.0;
+    let warming = (minutes * std::f32::consts::TAU / 10.0).sin();
+    let weather = (minutes * std::f32::consts::TAU / 37.0).sin();
+
+    // Between -0.5 and 0.5, from a hash of the time so that readings don't need a random number generator
+    let noise = |seed: u32| {
+        let hash = (uptime.as_millis() as u32 ^ seed).wrapping_mul(0x9E37_79B1);
+        (hash >> 16) as f32 / 65536.0 - 0.5
+    };
+
+    SensorReading {
+        temperature_c: 22.0 + 1.2 * warming + 0.1 * noise(0),
+        humidity_percent: 45.0 - 4.0 * warming + 2.0 * weather + 0.4 * noise(0x5EED),
+    }
+}
+
+/// Press the button, as `button_task` of the board does: count it, publish it and toggle LED2.
+fn press_button(shared_control: &SharedControl, clock: &SystemClock) {
+    info!("Button pressed");
+    BUTTON_STATS.record_press(clock.uptime());
+    // Fails only when no WebSocket or event stream is open
+    let _ = shared_control.lock().events.send(BoardEvent::ButtonPressed);
+    shared_control.toggle(2);
+}
+
+/// `text` in the 24-bit `color` of the terminal.
+fn colored(line: &mut String, [r, g, b]: Rgb, text: &str) {
+    let _ = write!(line, "\x1b[38;2;{r};{g};{b}m{text}\x1b[0m");
+}
+
+/// The status line of the board, e.g. `LED1 ● LED2 ● 40% LED3 ○ | strip ●●●… | 22.4 °C 45.1 % | button ×3`.
+fn status_line(control: &Control, uptime: Duration) -> String {
+    let mut line = String::new();
+
+    for (led, color) in SIMULATED_LEDS.zip(LED_COLORS) {
+        let brightness = control.brightness(led);
+        let _ = write!(line, "LED{led} ");
+        match brightness {
+            0 => line.push('○'),
+            // Dimmed LEDs are drawn darker, but still visibly lit
+            _ => colored(
+                &mut line,
+                color
+                    .map(|level| (u32::from(level) * (25 + 3 * u32::from(brightness)) / 325) as u8),
+                "●",
+            ),
+        }
+        if (1..100).contains(&brightness) {
+            let _ = write!(line, " {brightness}%");
+        }
+        line.push(' ');
+    }
+
+    line.push_str("| strip ");
+    let mut pixels = [[0; 3]; SIMULATED_STRIP_LENGTH];
+    let frame = (uptime.as_millis() / FRAME_INTERVAL.as_millis()) as u32;
+    control.strip.render(frame, &mut pixels);
+    for pixel in pixels {
+        match pixel {
+            [0, 0, 0] => line.push('·'),
+            color => colored(&mut line, color, "●"),
+        }
+    }
+
+    // Shown for as long as the tone would play
+    if let Some((tone, ends)) = control.tone {
+        if std::time::Instant::now() < ends {
+            let _ = write!(line, " | ♪ {} Hz", tone.frequency_hz);
+        }
+    }
+
+    match SENSOR_STATS.latest() {
+        Some((reading, _)) => {
+            let _ = write!(
+                line,
+                " | {:.1} °C {:.1} %",
+                reading.temperature_c, reading.humidity_percent
+            );
+        }
+        None => line.push_str(" | no reading"),
+    }
+
+    let _ = write!(
+        line,
+        " | button ×{}, Enter to press",
+        BUTTON_STATS.presses()
+    );
+    line
+}
+
+/// Read lines from stdin on a thread of its own, as a read from the stdin of tokio can't be cancelled and would hold
+/// up the shutdown, sending one press of the button per line.
+fn read_presses() -> mpsc::Receiver<()> {
+    let (sender, receiver) = mpsc::channel(1);
+
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lock().lines() {
+            if line.is_err() || sender.blocking_send(()).is_err() {
+                break;
+            }
+        }
+    });
+
+    receiver
+}
+
+/// Draw the board in the terminal and press the button on Enter, until `shutdown_token` is cancelled.
+pub(crate) async fn run(
+    shared_control: SharedControl,
+    clock: SystemClock,
+    shutdown_token: CancellationToken,
+) {
+    let draw = std::io::stdout().is_terminal();
+    if !draw {
+        warn!("Standard output is not a terminal, the simulated board is not drawn");
+    }
+
+    let mut presses = read_presses();
+    let mut interval = tokio::time::interval(REDRAW_INTERVAL);
+
+    loop {
+        tokio::select! {
+            Some(()) = presses.recv() => press_button(&shared_control, &clock),
+            _ = interval.tick() => {}
+            () = shutdown_token.cancelled() => break,
+        }
+
+        if draw {
+            let line = status_line(&shared_control.lock(), clock.uptime());
+            // Ending at the start of the line, so that a log written meanwhile replaces it instead of following it
+            let mut stdout = std::io::stdout().lock();
+            let _ = write!(stdout, "\r\x1b[2K{line}\r");
+            let _ = stdout.flush();
+        }
+    }
+
+    if draw {
+        println!();
+    }
+}
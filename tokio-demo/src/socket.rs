@@ -0,0 +1,141 @@
+//! Lets picoserve serve any tokio stream, not only a `TcpStream`.
+//!
+//! picoserve's `tokio` feature only accepts a `TcpStream`, so the demo uses its runtime-agnostic `serve_with_state`
+//! with the timer and socket below instead, which also serve a TLS stream.
+
+use std::future::Future;
+
+use picoserve::{Error, Timeouts, Timer};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+
+/// [Timer] backed by `tokio::time`.
+pub struct TokioTimer;
+
+impl Timer for TokioTimer {
+    type Duration = std::time::Duration;
+    type TimeoutError = tokio::time::error::Elapsed;
+
+    async fn run_with_timeout<F: Future>(
+        &mut self,
+        duration: Self::Duration,
+        future: F,
+    ) -> Result<F::Output, Self::TimeoutError> {
+        tokio::time::timeout(duration, future).await
+    }
+}
+
+#[derive(Debug)]
+pub struct IoError(std::io::Error);
+
+impl embedded_io_async::Error for IoError {
+    fn kind(&self) -> embedded_io_async::ErrorKind {
+        use embedded_io_async::ErrorKind;
+
+        match self.0.kind() {
+            std::io::ErrorKind::ConnectionReset => ErrorKind::ConnectionReset,
+            std::io::ErrorKind::ConnectionAborted => ErrorKind::ConnectionAborted,
+            std::io::ErrorKind::BrokenPipe => ErrorKind::BrokenPipe,
+            std::io::ErrorKind::InvalidData => ErrorKind::InvalidData,
+            std::io::ErrorKind::TimedOut => ErrorKind::TimedOut,
+            _ => ErrorKind::Other,
+        }
+    }
+}
+
+/// One half of a split [Socket].
+pub struct Io<T>(T);
+
+impl<T> embedded_io_async::ErrorType for Io<T> {
+    type Error = IoError;
+}
+
+impl<T: AsyncRead + Unpin> embedded_io_async::Read for Io<T> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        self.0.read(buf).await.map_err(IoError)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> embedded_io_async::Write for Io<T> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.0.write(buf).await.map_err(IoError)
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self.0.flush().await.map_err(IoError)
+    }
+}
+
+/// Run `future`, failing with `timeout_error` if it doesn't complete within `duration`.
+async fn with_maybe_timeout<T: Timer, F: Future>(
+    timer: &mut T,
+    duration: Option<T::Duration>,
+    future: F,
+    timeout_error: Error<IoError>,
+) -> Result<F::Output, Error<IoError>> {
+    match duration {
+        Some(duration) => timer
+            .run_with_timeout(duration, future)
+            .await
+            .map_err(|_| timeout_error),
+        None => Ok(future.await),
+    }
+}
+
+/// A tokio stream served by picoserve.
+pub struct Socket<S>(pub S);
+
+impl<S: AsyncRead + AsyncWrite + Unpin> picoserve::io::Socket for Socket<S> {
+    type Error = IoError;
+    type ReadHalf<'a>
+        = Io<ReadHalf<&'a mut S>>
+    where
+        S: 'a;
+    type WriteHalf<'a>
+        = Io<WriteHalf<&'a mut S>>
+    where
+        S: 'a;
+
+    fn split(&mut self) -> (Self::ReadHalf<'_>, Self::WriteHalf<'_>) {
+        let (read_half, write_half) = tokio::io::split(&mut self.0);
+        (Io(read_half), Io(write_half))
+    }
+
+    async fn abort<T: Timer>(
+        self,
+        _timeouts: &Timeouts<T::Duration>,
+        _timer: &mut T,
+    ) -> Result<(), Error<Self::Error>> {
+        Ok(())
+    }
+
+    /// Shut down writing, which sends a TLS `close_notify` first on a TLS stream, then wait for the client to close.
+    async fn shutdown<T: Timer>(
+        mut self,
+        timeouts: &Timeouts<T::Duration>,
+        timer: &mut T,
+    ) -> Result<(), Error<Self::Error>> {
+        with_maybe_timeout(
+            timer,
+            timeouts.write.clone(),
+            self.0.shutdown(),
+            Error::WriteTimeout,
+        )
+        .await?
+        .map_err(|err| Error::Write(IoError(err)))?;
+
+        let mut buffer = [0; 128];
+
+        while with_maybe_timeout(
+            timer,
+            timeouts.read_request.clone(),
+            self.0.read(&mut buffer),
+            Error::ReadTimeout,
+        )
+        .await?
+        .map_err(|err| Error::Read(IoError(err)))?
+            > 0
+        {}
+
+        Ok(())
+    }
+}
@@ -0,0 +1,1403 @@
+// `tokio_demo::run` is instantiated here, so its nested router types need the raised limit too
+#![recursion_limit = "512"]
+
+use std::future::Future;
+
+use smolweb_core::{auth::Credentials, limits::Limits};
+use tokio::net::TcpListener;
+
+/// Run the server on an ephemeral port while `client` runs, then shut it down.
+async fn with_server<F, Fut>(client: F)
+where
+    F: FnOnce(String) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    with_configured_server(tokio_demo::Config::default(), client).await
+}
+
+/// Like [with_server], with the given configuration.
+async fn with_configured_server<F, Fut>(config: tokio_demo::Config, client: F)
+where
+    F: FnOnce(String) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let base_url = format!("http://{}", listener.local_addr().unwrap());
+
+    let shutdown_token = tokio_demo::CancellationToken::new();
+
+    let server = tokio_demo::run(listener, config, shutdown_token.clone());
+
+    let client = async {
+        client(base_url).await;
+        shutdown_token.cancel();
+    };
+
+    let (server_result, ()) = tokio::join!(server, client);
+    server_result.unwrap();
+}
+
+#[tokio::test]
+async fn index_is_html() {
+    with_server(|base_url| async move {
+        let response = reqwest::get(format!("{base_url}/")).await.unwrap();
+
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert!(response.headers()["content-type"]
+            .to_str()
+            .unwrap()
+            .starts_with("text/html"));
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn index_is_rendered_with_the_board_state() {
+    with_server(|base_url| async move {
+        let response = reqwest::get(format!("{base_url}/")).await.unwrap();
+
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert_eq!(response.headers()["cache-control"], "no-store");
+
+        let page = response.text().await.unwrap();
+        assert!(!page.contains("{{"), "unrendered placeholder in {page:?}");
+        // The default settings turn the simulated LED on at start, and it can be dimmed
+        assert!(
+            page.contains(r#"<label id="led2Label">ON</label>"#),
+            "{page:?}"
+        );
+        assert!(
+            page.contains(r#"value="100" onchange="set_brightness(2, this.value)""#),
+            "{page:?}"
+        );
+        assert!(page.contains(r#"<span id="uptime">00:00:0"#), "{page:?}");
+        assert!(page.contains(" at 127.0.0.1</p>"), "{page:?}");
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn stylesheet_is_css() {
+    with_server(|base_url| async move {
+        let response = reqwest::get(format!("{base_url}/index.css")).await.unwrap();
+
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert_eq!(response.headers()["content-type"], "text/css");
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn toggle_flips_led_state() {
+    with_server(|base_url| async move {
+        let client = reqwest::Client::new();
+
+        let toggle = || async {
+            let response = client
+                .get(format!("{base_url}/toggle_led/2"))
+                .basic_auth("admin", Some("smolweb"))
+                .send()
+                .await
+                .unwrap();
+
+            assert_eq!(response.status(), reqwest::StatusCode::OK);
+            response.text().await.unwrap()
+        };
+
+        let first = toggle().await;
+        let second = toggle().await;
+
+        assert!(
+            first == "ON" || first == "OFF",
+            "unexpected state {first:?}"
+        );
+        assert_ne!(first, second);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn toggle_with_state_sets_led() {
+    with_server(|base_url| async move {
+        let client = reqwest::Client::new();
+
+        let set = |state: &'static str| {
+            client
+                .get(format!("{base_url}/toggle_led/2?state={state}"))
+                .basic_auth("admin", Some("smolweb"))
+                .send()
+        };
+
+        // Setting twice leaves the LED as asked, where toggling would flip it back
+        for _ in 0..2 {
+            let response = set("off").await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::OK);
+            assert_eq!(response.headers()["content-type"], "application/json");
+            let body = response.text().await.unwrap();
+            assert!(body.starts_with(r#"{"led":2,"state":"off""#), "{body:?}");
+        }
+
+        let body = set("on").await.unwrap().text().await.unwrap();
+        assert!(body.starts_with(r#"{"led":2,"state":"on""#), "{body:?}");
+
+        let response = set("blink").await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn matching_etag_is_not_modified() {
+    with_server(|base_url| async move {
+        let client = reqwest::Client::new();
+
+        let response = client
+            .get(format!("{base_url}/index.js"))
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert_eq!(response.headers()["cache-control"], "max-age=300");
+        let etag = response.headers()["etag"].clone();
+
+        let response = client
+            .get(format!("{base_url}/index.js"))
+            .header("If-None-Match", etag)
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), reqwest::StatusCode::NOT_MODIFIED);
+        assert!(response.bytes().await.unwrap().is_empty());
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn head_keeps_the_connection_for_the_next_request() {
+    with_server(|base_url| async move {
+        // One connection, reused by each request
+        let client = reqwest::Client::builder()
+            .pool_max_idle_per_host(1)
+            .build()
+            .unwrap();
+
+        let get = client
+            .get(format!("{base_url}/index.js"))
+            .send()
+            .await
+            .unwrap();
+        let length = get.headers()["content-length"].clone();
+        let body = get.bytes().await.unwrap();
+
+        for _ in 0..2 {
+            let head = client
+                .head(format!("{base_url}/index.js"))
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(head.status(), reqwest::StatusCode::OK);
+            assert_eq!(head.headers()["content-length"], length);
+            assert!(head.bytes().await.unwrap().is_empty());
+        }
+
+        let get = client
+            .get(format!("{base_url}/index.js"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(get.bytes().await.unwrap(), body);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn page_is_gzipped_when_accepted() {
+    with_server(|base_url| async move {
+        let client = reqwest::Client::new();
+
+        let response = client
+            .get(format!("{base_url}/index.js"))
+            .header("Accept-Encoding", "gzip, deflate")
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert_eq!(response.headers()["content-encoding"], "gzip");
+        assert_eq!(response.headers()["vary"], "Accept-Encoding");
+        // The gzip magic number
+        assert_eq!(response.bytes().await.unwrap()[..2], [0x1f, 0x8b]);
+
+        let response = client
+            .get(format!("{base_url}/index.js"))
+            .send()
+            .await
+            .unwrap();
+
+        assert!(response.headers().get("content-encoding").is_none());
+        assert!(response.text().await.unwrap().contains("function"));
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn static_files_take_cache_control_from_config() {
+    with_server(|base_url| async move {
+        let client = reqwest::Client::new();
+
+        let response = client
+            .get(format!("{base_url}/static/favicon.svg"))
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert_eq!(response.headers()["cache-control"], "max-age=86400");
+        let etag = response.headers()["etag"].clone();
+
+        let response = client
+            .get(format!("{base_url}/static/favicon.svg"))
+            .header("If-None-Match", etag)
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), reqwest::StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()["cache-control"], "max-age=86400");
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn unknown_paths_are_not_found() {
+    with_server(|base_url| async move {
+        let response = reqwest::get(format!("{base_url}/no-such-page"))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+        assert!(response.headers()["content-type"]
+            .to_str()
+            .unwrap()
+            .starts_with("text/html"));
+
+        let response = reqwest::get(format!("{base_url}/api/no-such-endpoint"))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+        assert_eq!(response.headers()["content-type"], "application/json");
+        assert_eq!(
+            response.text().await.unwrap(),
+            r#"{"error":"No such endpoint"}"#
+        );
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn toggle_unknown_led_is_not_found() {
+    with_server(|base_url| async move {
+        let client = reqwest::Client::new();
+
+        let toggle = |led: u8| {
+            client
+                .get(format!("{base_url}/toggle_led/{led}"))
+                .basic_auth("admin", Some("smolweb"))
+                .send()
+        };
+
+        let before = toggle(2).await.unwrap().text().await.unwrap();
+
+        let response = toggle(99).await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+
+        // LED2 flips from its state before the failed request, so that request didn't toggle it
+        let after = toggle(2).await.unwrap().text().await.unwrap();
+        assert_ne!(before, after);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn api_lists_and_sets_leds() {
+    with_server(|base_url| async move {
+        let client = reqwest::Client::new();
+
+        let set = |led: u8, state: &'static str| {
+            client
+                .post(format!("{base_url}/api/leds/{led}"))
+                .basic_auth("admin", Some("smolweb"))
+                .header("Content-Type", "application/json")
+                .body(format!(r#"{{"state":"{state}"}}"#))
+                .send()
+        };
+
+        let response = set(2, "off").await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert_eq!(
+            response.text().await.unwrap(),
+            r#"{"led":2,"state":"off","brightness":0}"#
+        );
+
+        let response = set(2, "toggle").await.unwrap();
+        assert_eq!(
+            response.text().await.unwrap(),
+            r#"{"led":2,"state":"on","brightness":100}"#
+        );
+
+        let response = set(99, "on").await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+
+        let response = client
+            .get(format!("{base_url}/api/leds"))
+            .basic_auth("admin", Some("smolweb"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert_eq!(response.headers()["content-type"], "application/json");
+        assert_eq!(
+            response.text().await.unwrap(),
+            r#"[{"led":2,"state":"on","brightness":100}]"#
+        );
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn api_dims_leds() {
+    with_server(|base_url| async move {
+        let client = reqwest::Client::new();
+
+        let dim = |led: u8, brightness: u8| {
+            client
+                .post(format!("{base_url}/api/leds/{led}/brightness"))
+                .basic_auth("admin", Some("smolweb"))
+                .header("Content-Type", "application/json")
+                .body(format!(r#"{{"brightness":{brightness}}}"#))
+                .send()
+        };
+
+        let response = dim(2, 40).await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert_eq!(
+            response.text().await.unwrap(),
+            r#"{"led":2,"state":"on","brightness":40}"#
+        );
+
+        let response = dim(2, 0).await.unwrap();
+        assert_eq!(
+            response.text().await.unwrap(),
+            r#"{"led":2,"state":"off","brightness":0}"#
+        );
+
+        let response = dim(2, 101).await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+
+        let response = dim(99, 50).await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn api_plays_led_patterns() {
+    with_server(|base_url| async move {
+        let client = reqwest::Client::new();
+
+        let play = |led: u8, body: &'static str| {
+            client
+                .post(format!("{base_url}/api/leds/{led}/pattern"))
+                .basic_auth("admin", Some("smolweb"))
+                .header("Content-Type", "application/json")
+                .body(body)
+                .send()
+        };
+
+        let response = play(2, r#"{"pattern":"blink","period_ms":200}"#)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert_eq!(
+            response.text().await.unwrap(),
+            r#"{"led":2,"pattern":"blink","period_ms":200}"#
+        );
+
+        // Half of the period on and half off, so the LED is seen both ways within a period
+        let mut seen = [false; 2];
+        for _ in 0..8 {
+            let body = client
+                .get(format!("{base_url}/api/leds"))
+                .basic_auth("admin", Some("smolweb"))
+                .send()
+                .await
+                .unwrap()
+                .text()
+                .await
+                .unwrap();
+            seen[usize::from(body.contains(r#""state":"on""#))] = true;
+            tokio::time::sleep(std::time::Duration::from_millis(30)).await;
+        }
+        assert_eq!(seen, [true, true]);
+
+        let response = play(2, r#"{"pattern":"sos"}"#).await.unwrap();
+        assert_eq!(
+            response.text().await.unwrap(),
+            r#"{"led":2,"pattern":"sos","period_ms":6800}"#
+        );
+
+        let response = play(2, r#"{"pattern":"steady"}"#).await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+
+        let response = play(2, r#"{"pattern":"blink","period_ms":10}"#)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+
+        let response = play(2, r#"{"pattern":"strobe"}"#).await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+
+        let response = play(99, r#"{"pattern":"blink"}"#).await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn coap_mirrors_the_led_api() {
+    let coap_socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let coap_address = coap_socket.local_addr().unwrap();
+    let config = tokio_demo::Config {
+        credentials: Credentials {
+            api_key: Some("0123456789abcdef"),
+            ..Credentials::default()
+        },
+        coap_socket: Some(std::sync::Arc::new(coap_socket)),
+        ..Default::default()
+    };
+
+    with_configured_server(config, |base_url| async move {
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(coap_address).await.unwrap();
+        let exchange = |request: Vec<u8>| {
+            let client = &client;
+            async move {
+                client.send(&request).await.unwrap();
+                let mut response = [0; 512];
+                let length = tokio::time::timeout(
+                    std::time::Duration::from_secs(5),
+                    client.recv(&mut response),
+                )
+                .await
+                .unwrap()
+                .unwrap();
+                response[..length].to_vec()
+            }
+        };
+        // Uri-Path options for /leds/<n>
+        let leds = |led: u8| [0xB4, b'l', b'e', b'd', b's', 0x01, led];
+        // Uri-Query option following them, for a 16 byte key with its length extended by a byte
+        let key = |key: &[u8; 16]| [&[0x4D, 7][..], b"key=", key].concat();
+
+        // Confirmable PUT without the API key, refused with 4.01 Unauthorized
+        let mut put = vec![0x41, 0x03, 0x12, 0x30, 0x42];
+        put.extend(leds(b'2'));
+        put.push(0xFF);
+        put.extend(br#"{"state":"off"}"#);
+        let response = exchange(put).await;
+        assert_eq!(response[..6], [0x61, 0x81, 0x12, 0x30, 0x42, 0xC0]);
+        assert_eq!(&response[7..], b"PUT requires the API key as ?key=<key>");
+
+        // With a wrong one
+        let mut put = vec![0x41, 0x03, 0x12, 0x31, 0x42];
+        put.extend(leds(b'2'));
+        put.extend(key(b"fedcba9876543210"));
+        put.push(0xFF);
+        put.extend(br#"{"state":"off"}"#);
+        let response = exchange(put).await;
+        assert_eq!(response[..2], [0x61, 0x81]);
+
+        // Confirmable PUT with the API key, with token 0x42 and message ID 0x1234
+        let mut put = vec![0x41, 0x03, 0x12, 0x34, 0x42];
+        put.extend(leds(b'2'));
+        put.extend(key(b"0123456789abcdef"));
+        put.push(0xFF);
+        put.extend(br#"{"state":"off"}"#);
+        let response = exchange(put).await;
+        // Acknowledged with 2.04 Changed and the JSON of the API
+        assert_eq!(
+            response[..8],
+            [0x61, 0x44, 0x12, 0x34, 0x42, 0xC1, 50, 0xFF]
+        );
+        assert_eq!(&response[8..], br#"{"led":2,"state":"off","brightness":0}"#);
+
+        let body = reqwest::Client::new()
+            .get(format!("{base_url}/api/leds"))
+            .basic_auth("admin", Some("smolweb"))
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert_eq!(body, r#"[{"led":2,"state":"off","brightness":0}]"#);
+
+        // Non-confirmable GET, answered with 2.05 Content in a message of its own
+        let mut get = vec![0x51, 0x01, 0x00, 0x01, 0x07];
+        get.extend(leds(b'2'));
+        let response = exchange(get).await;
+        assert_eq!(response[..2], [0x51, 0x45]);
+        assert_eq!(response[4..8], [0x07, 0xC1, 50, 0xFF]);
+        assert_eq!(&response[8..], br#"{"led":2,"state":"off","brightness":0}"#);
+
+        let mut unknown = vec![0x40, 0x01, 0x00, 0x02];
+        unknown.extend(leds(b'1'));
+        let response = exchange(unknown).await;
+        // 4.04 Not Found, with a text diagnostic
+        assert_eq!(response[..5], [0x60, 0x84, 0x00, 0x02, 0xC0]);
+        assert_eq!(&response[6..], b"Unknown LED");
+
+        // An empty confirmable message is a ping, answered with a reset
+        let response = exchange(vec![0x40, 0x00, 0x00, 0x03]).await;
+        assert_eq!(response, [0x70, 0x00, 0x00, 0x03]);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn coap_clients_over_their_burst_are_refused() {
+    let coap_socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let coap_address = coap_socket.local_addr().unwrap();
+    let config = tokio_demo::Config {
+        credentials: Credentials {
+            api_key: Some("0123456789abcdef"),
+            ..Credentials::default()
+        },
+        client_burst: 2,
+        client_refill_interval: std::time::Duration::from_secs(60),
+        coap_socket: Some(std::sync::Arc::new(coap_socket)),
+        ..Default::default()
+    };
+
+    with_configured_server(config, |_| async move {
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(coap_address).await.unwrap();
+        // Confirmable PUT /leds/2 guessing the API key
+        let put = |message_id: u8| {
+            let mut put = vec![0x41, 0x03, 0x12, message_id, 0x42];
+            put.extend([0xB4, b'l', b'e', b'd', b's', 0x01, b'2']);
+            put.extend([&[0x4D, 7][..], b"key=fedcba9876543210"].concat());
+            put.push(0xFF);
+            put.extend(br#"{"state":"off"}"#);
+            put
+        };
+        let mut response = [0; 512];
+
+        for message_id in 0..2 {
+            client.send(&put(message_id)).await.unwrap();
+            let length = client.recv(&mut response).await.unwrap();
+            // 4.01 Unauthorized
+            assert_eq!(response[..2], [0x61, 0x81], "{:?}", &response[..length]);
+        }
+
+        // The burst spent, the next guess is refused with 4.29 Too Many Requests before the key is checked, with
+        // the seconds until the bucket has a request again as Max-Age
+        client.send(&put(2)).await.unwrap();
+        let length = client.recv(&mut response).await.unwrap();
+        assert_eq!(response[..7], [0x61, 0x9D, 0x12, 0x02, 0x42, 0xC0, 0x21]);
+        assert!((1..=60).contains(&response[7]), "{}", response[7]);
+        assert_eq!(response[8], 0xFF);
+        assert_eq!(&response[9..length], b"Too many requests");
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn api_strip_takes_pixels_or_an_animation() {
+    with_server(|base_url| async move {
+        let client = reqwest::Client::new();
+
+        let post = |body: String| {
+            client
+                .post(format!("{base_url}/api/strip"))
+                .basic_auth("admin", Some("smolweb"))
+                .header("Content-Type", "application/json")
+                .body(body)
+                .send()
+        };
+
+        // Every pixel of the largest strip, close to a kilobyte
+        let pixels = vec!["[255,128,0]"; 64].join(",");
+        let response = post(format!(r#"{{"pixels":[{pixels}]}}"#)).await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert_eq!(
+            response.text().await.unwrap(),
+            r#"{"length":30,"animation":null}"#
+        );
+
+        let response = post(r#"{"animation":"rainbow"}"#.into()).await.unwrap();
+        assert_eq!(
+            response.text().await.unwrap(),
+            r#"{"length":30,"animation":"rainbow"}"#
+        );
+
+        let too_many = vec!["[0,0,0]"; 65].join(",");
+        let response = post(format!(r#"{{"pixels":[{too_many}]}}"#)).await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+
+        let response = post(r#"{"pixels":[[256,0,0]]}"#.into()).await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+
+        let response = post(r#"{"pixels":[],"animation":"chase"}"#.into())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+
+        // The page has the color picker of the strip
+        let page = client.get(format!("{base_url}/")).send().await.unwrap();
+        assert!(page.text().await.unwrap().contains("LED strip (30 pixels)"));
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn api_tone_plays_on_the_simulated_buzzer() {
+    with_server(|base_url| async move {
+        let client = reqwest::Client::new();
+
+        let post = |body: &'static str| {
+            client
+                .post(format!("{base_url}/api/tone"))
+                .basic_auth("admin", Some("smolweb"))
+                .header("Content-Type", "application/json")
+                .body(body)
+                .send()
+        };
+
+        let response = post(r#"{"frequency_hz":440,"duration_ms":200}"#)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert_eq!(
+            response.text().await.unwrap(),
+            r#"{"frequency_hz":440,"duration_ms":200}"#
+        );
+
+        for body in [
+            r#"{"frequency_hz":5,"duration_ms":200}"#,
+            r#"{"frequency_hz":440,"duration_ms":60000}"#,
+            r#"{"frequency_hz":440}"#,
+        ] {
+            let response = post(body).await.unwrap();
+            assert_eq!(
+                response.status(),
+                reqwest::StatusCode::BAD_REQUEST,
+                "{body}"
+            );
+        }
+
+        // The page has the beep button
+        let page = client.get(format!("{base_url}/")).send().await.unwrap();
+        assert!(page.text().await.unwrap().contains("onclick=\"beep()\""));
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn events_stream_led_changes() {
+    with_server(|base_url| async move {
+        let client = reqwest::Client::new();
+
+        let mut events = client
+            .get(format!("{base_url}/events"))
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(events.status(), reqwest::StatusCode::OK);
+        assert_eq!(events.headers()["content-type"], "text/event-stream");
+
+        client
+            .post(format!("{base_url}/api/leds/2"))
+            .basic_auth("admin", Some("smolweb"))
+            .body(r#"{"state":"off"}"#)
+            .send()
+            .await
+            .unwrap();
+
+        let mut received = String::new();
+        while !received.contains("\n\n") {
+            let chunk = events.chunk().await.unwrap().unwrap();
+            received.push_str(std::str::from_utf8(&chunk).unwrap());
+        }
+
+        // The clock of the tokio demo is always synchronized, so every event has its time
+        assert!(
+            received.starts_with("event:led\ndata:{\"led\":2,\"state\":\"off\",\"time\":\"20"),
+            "unexpected events {received:?}"
+        );
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn api_sensors_read_the_simulated_sensor() {
+    with_server(|base_url| async move {
+        let client = reqwest::Client::new();
+
+        let response = client
+            .get(format!("{base_url}/api/sensors"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+        let response = client
+            .get(format!("{base_url}/api/sensors"))
+            .basic_auth("admin", Some("smolweb"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert_eq!(response.headers()["content-type"], "application/json");
+
+        // The simulated room stays between 20.5 and 23.5 °C
+        let body = response.text().await.unwrap();
+        let temperature: f32 = body
+            .strip_prefix(r#"{"temperature_c":"#)
+            .and_then(|rest| rest.split(',').next())
+            .and_then(|temperature| temperature.parse().ok())
+            .unwrap_or_else(|| panic!("unexpected reading {body:?}"));
+        assert!((20.5..=23.5).contains(&temperature), "{body:?}");
+        assert!(body.contains(r#","humidity_percent":"#), "{body:?}");
+        assert!(body.contains(r#","sampled_uptime_ms":"#), "{body:?}");
+
+        // The page shows the same reading before its script runs
+        let page = client.get(format!("{base_url}/")).send().await.unwrap();
+        assert!(page.text().await.unwrap().contains(" % humidity</p>"));
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn button_is_never_pressed() {
+    with_server(|base_url| async move {
+        let response = reqwest::Client::new()
+            .get(format!("{base_url}/api/button"))
+            .basic_auth("admin", Some("smolweb"))
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert_eq!(
+            response.text().await.unwrap(),
+            r#"{"presses":0,"last_press_uptime_ms":null,"last_press":null}"#
+        );
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn api_time_is_iso_8601() {
+    with_server(|base_url| async move {
+        let response = reqwest::Client::new()
+            .get(format!("{base_url}/api/time"))
+            .basic_auth("admin", Some("smolweb"))
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert_eq!(response.headers()["content-type"], "application/json");
+
+        let body = response.text().await.unwrap();
+        let time = body
+            .strip_prefix(r#"{"time":""#)
+            .and_then(|rest| rest.split_once('"'))
+            .map(|(time, _)| time)
+            .unwrap_or_else(|| panic!("no time in {body:?}"));
+
+        // e.g. 2024-05-01T12:34:56Z
+        assert_eq!(time.len(), 20, "unexpected time {time:?}");
+        assert_eq!(&time[10..11], "T");
+        assert!(time.ends_with('Z'));
+        assert!(body.contains(r#","unix_time":"#));
+        assert!(body.contains(r#","uptime_seconds":"#));
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn api_sysinfo_has_process_equivalents() {
+    with_server(|base_url| async move {
+        let response = reqwest::Client::new()
+            .get(format!("{base_url}/api/sysinfo"))
+            .basic_auth("admin", Some("smolweb"))
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert_eq!(response.headers()["content-type"], "application/json");
+
+        let body = response.text().await.unwrap();
+        assert!(body.starts_with(r#"{"uptime_seconds":"#), "{body:?}");
+        assert!(
+            body.contains(concat!(r#","version":""#, env!("CARGO_PKG_VERSION"), '"')),
+            "{body:?}"
+        );
+        assert!(body.contains(r#","git_hash":""#), "{body:?}");
+        // The heap of the process has no fixed size, and its tasks no stack of their own
+        assert!(body.contains(r#","free_heap_bytes":null,"#), "{body:?}");
+        // Nor a link to watch
+        assert!(body.contains(r#","link_up":null,"#), "{body:?}");
+        assert!(
+            body.ends_with(r#","stack_headroom_bytes":null,"tasks":[]}"#),
+            "{body:?}"
+        );
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn healthz_and_readyz_are_public() {
+    with_server(|base_url| async move {
+        let client = reqwest::Client::new();
+
+        let response = client
+            .get(format!("{base_url}/healthz"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        let body = response.text().await.unwrap();
+        assert!(body.starts_with(r#"{"status":"ok","#), "{body:?}");
+
+        // Ready once the background tasks have started, which they do straight away
+        let mut response = None;
+        for _ in 0..50 {
+            let attempt = client
+                .get(format!("{base_url}/readyz"))
+                .send()
+                .await
+                .unwrap();
+            if attempt.status() == reqwest::StatusCode::OK {
+                response = Some(attempt);
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        let body = response.expect("never ready").text().await.unwrap();
+        assert!(
+            body.starts_with(r#"{"ready":true,"network_up":true,"link_up":null,"tasks":[{"name":"uptime","heartbeat":true},"#),
+            "{body:?}"
+        );
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn api_workers_count_the_connections() {
+    with_server(|base_url| async move {
+        let client = reqwest::Client::new();
+        let workers = || {
+            client
+                .get(format!("{base_url}/api/workers"))
+                .basic_auth("admin", Some("smolweb"))
+                .send()
+        };
+
+        // The second request is read and answered on the connection of the first
+        let response = workers().await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        response.text().await.unwrap();
+
+        let response = workers().await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert_eq!(response.headers()["content-type"], "application/json");
+
+        // Every connection of the tokio demo counts as worker 0, which is serving this one
+        let body = response.text().await.unwrap();
+        assert!(
+            body.starts_with(r#"[{"worker":0,"state":"serving","accepted":"#),
+            "{body:?}"
+        );
+        assert!(!body.contains(r#""bytes_in":0,"#), "{body:?}");
+        assert!(!body.contains(r#""bytes_out":0}"#), "{body:?}");
+        assert!(body.ends_with("}]"), "{body:?}");
+
+        let response = client
+            .get(format!("{base_url}/api/workers"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::UNAUTHORIZED);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn logs_are_plain_text() {
+    with_server(|base_url| async move {
+        // The tests run without the logger of `main`, so the line is written directly
+        smolweb_core::logs::LOGS.push_line("INFO", format_args!("logs test marker"));
+
+        let response = reqwest::Client::new()
+            .get(format!("{base_url}/logs"))
+            .basic_auth("admin", Some("smolweb"))
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert_eq!(
+            response.headers()["content-type"],
+            "text/plain; charset=utf-8"
+        );
+
+        let body = response.text().await.unwrap();
+        assert!(body.contains("INFO logs test marker\n"), "{body:?}");
+
+        let response = reqwest::get(format!("{base_url}/logs")).await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::UNAUTHORIZED);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn assets_dir_comes_before_embedded_files() {
+    let assets_dir = std::env::temp_dir().join(format!("smolweb-assets-{}", std::process::id()));
+    std::fs::create_dir_all(&assets_dir).unwrap();
+    std::fs::write(assets_dir.join("hello.txt"), "Hello from disk\n").unwrap();
+
+    let config = tokio_demo::Config {
+        assets_dir: Some(assets_dir.clone()),
+        ..Default::default()
+    };
+
+    with_configured_server(config, |base_url| async move {
+        let response = reqwest::get(format!("{base_url}/static/hello.txt"))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert_eq!(
+            response.headers()["content-type"],
+            "text/plain; charset=utf-8"
+        );
+        assert_eq!(response.text().await.unwrap(), "Hello from disk\n");
+
+        // Not in the directory, so the embedded file is served
+        let response = reqwest::get(format!("{base_url}/static/favicon.svg"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+
+        let response = reqwest::get(format!("{base_url}/static/../Cargo.toml"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+    })
+    .await;
+
+    std::fs::remove_dir_all(assets_dir).unwrap();
+}
+
+#[tokio::test]
+async fn bodies_over_the_limit_are_refused() {
+    let config = tokio_demo::Config {
+        limits: Limits {
+            max_body_bytes: 64,
+            ..Limits::WEB
+        },
+        ..Default::default()
+    };
+
+    with_configured_server(config, |base_url| async move {
+        let client = reqwest::Client::new();
+        let send = |body: &'static str| {
+            client
+                .post(format!("{base_url}/api/strip"))
+                .basic_auth("admin", Some("smolweb"))
+                .header("Content-Type", "application/json")
+                .body(body)
+                .send()
+        };
+
+        let response =
+            send(r#"{"animation":"rainbow","padding":"                                  "}"#)
+                .await
+                .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(
+            response.text().await.unwrap(),
+            r#"{"error":"Request body is too large"}"#
+        );
+
+        // Within the limit, the body reaches the handler
+        let response = send(r#"{"animation":"rainbow"}"#).await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn uploads_are_written_to_the_assets_dir() {
+    let assets_dir = std::env::temp_dir().join(format!("smolweb-uploads-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&assets_dir);
+
+    let config = tokio_demo::Config {
+        assets_dir: Some(assets_dir.clone()),
+        ..Default::default()
+    };
+    let uploaded = &assets_dir;
+
+    with_configured_server(config, |base_url| async move {
+        let client = reqwest::Client::new();
+        let upload = || {
+            client
+                .post(format!("{base_url}/api/upload"))
+                .basic_auth("admin", Some("smolweb"))
+        };
+
+        let response = upload()
+            .header("X-Filename", "css/theme.css")
+            .body("body { color: red; }\n")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert_eq!(
+            response.text().await.unwrap(),
+            // serde-json-core escapes the slashes
+            r#"{"path":"\/static\/css\/theme.css","size":21}"#
+        );
+
+        let response = reqwest::get(format!("{base_url}/static/css/theme.css"))
+            .await
+            .unwrap();
+        assert_eq!(response.headers()["content-type"], "text/css");
+        assert_eq!(response.text().await.unwrap(), "body { color: red; }\n");
+
+        // The file part of a form, between the boundaries
+        let response = upload()
+            .header("Content-Type", "multipart/form-data; boundary=XyZ")
+            .body(concat!(
+                "--XyZ\r\n",
+                "Content-Disposition: form-data; name=\"file\"; filename=\"hello.txt\"\r\n",
+                "Content-Type: text/plain\r\n\r\n",
+                "Hello\r\nfrom a form\r\n",
+                "--XyZ--\r\n",
+            ))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert_eq!(
+            std::fs::read_to_string(uploaded.join("hello.txt")).unwrap(),
+            "Hello\r\nfrom a form"
+        );
+
+        for name in ["../escape.txt", "firmware.bin", "noextension"] {
+            let response = upload()
+                .header("X-Filename", name)
+                .body("data")
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(
+                response.status(),
+                reqwest::StatusCode::BAD_REQUEST,
+                "{name}"
+            );
+        }
+
+        let response = upload()
+            .header("X-Filename", "large.txt")
+            .body(vec![b'a'; 256 * 1024 + 1])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::PAYLOAD_TOO_LARGE);
+
+        let response = reqwest::Client::new()
+            .post(format!("{base_url}/api/upload"))
+            .header("X-Filename", "hello.txt")
+            .body("data")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::UNAUTHORIZED);
+    })
+    .await;
+
+    assert!(!assets_dir.join("escape.txt").exists());
+    assert!(!assets_dir.join("large.txt").exists());
+    std::fs::remove_dir_all(assets_dir).unwrap();
+}
+
+#[tokio::test]
+async fn api_settings_are_saved_and_applied_at_start() {
+    let settings_file =
+        std::env::temp_dir().join(format!("smolweb-settings-{}.json", std::process::id()));
+    let _ = std::fs::remove_file(&settings_file);
+
+    let config = || tokio_demo::Config {
+        settings_file: Some(settings_file.clone()),
+        ..Default::default()
+    };
+
+    with_configured_server(config(), |base_url| async move {
+        let client = reqwest::Client::new();
+
+        let save = |body: &'static str| {
+            client
+                .post(format!("{base_url}/api/settings"))
+                .basic_auth("admin", Some("smolweb"))
+                .header("Content-Type", "application/json")
+                .body(body)
+                .send()
+        };
+
+        let response = save(r#"{"hostname":"-bench","leds_on_at_boot":[]}"#)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+
+        let response = save(r#"{"hostname":"bench","leds_on_at_boot":[]}"#)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+    })
+    .await;
+
+    with_configured_server(config(), |base_url| async move {
+        let client = reqwest::Client::new();
+
+        let response = client
+            .get(format!("{base_url}/api/settings"))
+            .basic_auth("admin", Some("smolweb"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert_eq!(
+            response.text().await.unwrap(),
+            r#"{"hostname":"bench","leds_on_at_boot":[],"blink_period_ms":1000}"#
+        );
+
+        // Applied at start, so LED2 is off
+        let response = client
+            .get(format!("{base_url}/api/leds"))
+            .basic_auth("admin", Some("smolweb"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(
+            response.text().await.unwrap(),
+            r#"[{"led":2,"state":"off","brightness":0}]"#
+        );
+    })
+    .await;
+
+    std::fs::remove_file(settings_file).unwrap();
+}
+
+#[tokio::test]
+async fn metrics_are_in_prometheus_format() {
+    with_server(|base_url| async move {
+        let response = reqwest::get(format!("{base_url}/metrics")).await.unwrap();
+
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert_eq!(
+            response.headers()["content-type"],
+            "text/plain; version=0.0.4; charset=utf-8"
+        );
+
+        let body = response.text().await.unwrap();
+        let value = |name: &str| -> u64 {
+            let line = body
+                .lines()
+                .find(|line| line.starts_with(&format!("{name} ")))
+                .unwrap_or_else(|| panic!("no {name} in {body:?}"));
+            line[name.len() + 1..].parse().unwrap()
+        };
+
+        assert!(body.contains("# TYPE http_requests_total counter\n"));
+        // The tests share the counters, so others may have been counted too
+        assert!(value(r#"http_route_requests_total{route="/metrics"}"#) >= 1);
+        assert!(value(r#"http_active_connections{worker="0"}"#) >= 1);
+        assert!(value("process_resident_memory_bytes") > 0);
+        assert!(value("time_seconds") > 1_700_000_000);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn api_accepts_api_key_instead_of_basic_credentials() {
+    let config = tokio_demo::Config {
+        credentials: Credentials {
+            username: "operator",
+            password: "hunter2",
+            api_key: Some("0123456789abcdef"),
+        },
+        ..Default::default()
+    };
+
+    with_configured_server(config, |base_url| async move {
+        let client = reqwest::Client::new();
+        let leds = || client.get(format!("{base_url}/api/leds"));
+
+        let response = leds().send().await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+        let response = leds()
+            .header("X-Api-Key", "0123456789abcdef")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+
+        let response = leds().header("X-Api-Key", "wrong").send().await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+        // The built-in defaults are replaced, not added to
+        let response = leds()
+            .basic_auth("admin", Some("smolweb"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+        let response = leds()
+            .basic_auth("operator", Some("hunter2"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+
+        // The page stays public
+        let response = client.get(format!("{base_url}/")).send().await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn clients_over_their_burst_are_refused() {
+    let config = tokio_demo::Config {
+        client_burst: 3,
+        client_refill_interval: std::time::Duration::from_secs(60),
+        ..Default::default()
+    };
+
+    with_configured_server(config, |base_url| async move {
+        let client = reqwest::Client::new();
+        let get = |path: &str| {
+            client
+                .get(format!("{base_url}{path}"))
+                .basic_auth("admin", Some("smolweb"))
+                .send()
+        };
+
+        for _ in 0..3 {
+            let response = get("/api/time").await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::OK);
+        }
+
+        let response = get("/api/leds").await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()["retry-after"], "60");
+        assert_eq!(
+            response.text().await.unwrap(),
+            r#"{"error":"Too many requests"}"#
+        );
+
+        let response = get("/toggle_led/2").await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.text().await.unwrap(), "Too many requests\n");
+
+        // The page and its assets aren't limited
+        let response = get("/").await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+    })
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_toggles_on_multi_threaded_runtime() {
+    with_server(|base_url| async move {
+        let client = reqwest::Client::new();
+
+        let toggles = (0..8).map(|_| {
+            let request = client
+                .get(format!("{base_url}/toggle_led/2"))
+                .basic_auth("admin", Some("smolweb"));
+            tokio::spawn(async move { request.send().await.unwrap().status() })
+        });
+
+        for toggle in toggles.collect::<Vec<_>>() {
+            assert_eq!(toggle.await.unwrap(), reqwest::StatusCode::OK);
+        }
+
+        // An even number of toggles leaves the LED as it started
+        let response = client
+            .get(format!("{base_url}/api/leds"))
+            .basic_auth("admin", Some("smolweb"))
+            .send()
+            .await
+            .unwrap();
+
+        assert!(response.text().await.unwrap().contains(r#""state":"on""#));
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn api_reboot_stops_the_server() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let base_url = format!("http://{}", listener.local_addr().unwrap());
+
+    let server = tokio_demo::run(
+        listener,
+        tokio_demo::Config::default(),
+        tokio_demo::CancellationToken::new(),
+    );
+
+    let client = async {
+        let response = reqwest::Client::new()
+            .post(format!("{base_url}/api/reboot"))
+            .basic_auth("admin", Some("smolweb"))
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert_eq!(response.text().await.unwrap(), r#"{"reboot_in_ms":500}"#);
+    };
+
+    // Stops on its own, without the token being cancelled
+    let (stopped, ()) = tokio::join!(server, client);
+    assert_eq!(stopped.unwrap(), tokio_demo::Stopped::Reboot);
+}
+
+#[tokio::test]
+async fn shutdown_stops_accepting_connections() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+
+    let shutdown_token = tokio_demo::CancellationToken::new();
+    shutdown_token.cancel();
+
+    tokio_demo::run(listener, tokio_demo::Config::default(), shutdown_token)
+        .await
+        .unwrap();
+
+    // The listener is dropped once `run` returns
+    assert!(tokio::net::TcpStream::connect(address).await.is_err());
+}